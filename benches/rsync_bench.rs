@@ -5,11 +5,18 @@ extern crate criterion;
 #[allow(dead_code)]
 #[allow(unused_imports)]
 mod crc;
+#[path = "../src/seed.rs"]
+#[allow(dead_code)]
+#[allow(unused_imports)]
+mod seed;
 
 use crate::crc::Crc;
 use criterion::{black_box, BenchmarkId, Criterion, Throughput};
-use fast_rsync::{apply_limited, diff, Signature, SignatureOptions};
+use fast_rsync::{
+    apply_limited, diff, diff_with_options, DiffOptions, Signature, SignatureOptions,
+};
 use std::io;
+use std::num::NonZeroU32;
 
 fn random_block(len: usize) -> Vec<u8> {
     use rand::RngCore;
@@ -52,6 +59,7 @@ fn calculate_signature(c: &mut Criterion) {
                     SignatureOptions {
                         block_size: 4096,
                         crypto_hash_size: 8,
+                        ..Default::default()
                     },
                 )
                 .into_serialized();
@@ -91,6 +99,7 @@ fn bench_diff(
         SignatureOptions {
             block_size: 4096,
             crypto_hash_size: 8,
+            ..Default::default()
         },
     )
     .into_serialized();
@@ -152,6 +161,40 @@ fn calculate_diff(c: &mut Criterion) {
     );
 }
 
+fn search_stride(c: &mut Criterion) {
+    let data = random_block(1 << 22);
+    let signature = Signature::calculate(
+        &data,
+        SignatureOptions {
+            block_size: 4096,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let index = signature.index();
+    let new_data = random_block(1 << 22);
+    let mut group = c.benchmark_group("diff (random, max_search_stride)");
+    group.sample_size(15);
+    for &max_stride in &[1, 1 << 10, 1 << 16] {
+        let options = DiffOptions {
+            max_search_stride: NonZeroU32::new(max_stride),
+            ..Default::default()
+        };
+        group.bench_with_input(
+            BenchmarkId::new("fast_rsync::diff_with_options", max_stride),
+            &new_data,
+            |b, new_data| {
+                b.iter(|| {
+                    let mut out = Vec::new();
+                    diff_with_options(&index, black_box(new_data), &mut out, &options).unwrap();
+                    out
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
 fn apply_delta(c: &mut Criterion) {
     let data = random_block(1 << 22);
     let mut new_data = data.clone();
@@ -163,6 +206,7 @@ fn apply_delta(c: &mut Criterion) {
             SignatureOptions {
                 block_size: 4096,
                 crypto_hash_size: 8,
+                ..Default::default()
             },
         )
         .index(),
@@ -197,6 +241,12 @@ fn apply_delta(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(rsync, calculate_signature, calculate_diff, apply_delta);
+criterion_group!(
+    rsync,
+    calculate_signature,
+    calculate_diff,
+    search_stride,
+    apply_delta
+);
 
 criterion_main!(crc, rsync);
@@ -0,0 +1,226 @@
+//! Applying deltas to files on disk, replacing the destination atomically.
+
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+#[cfg(feature = "mmap")]
+use crate::mmap::Mapped;
+#[cfg(feature = "mmap")]
+use crate::patch::apply;
+use crate::patch::{apply_seek, ApplyError};
+
+/// Apply `delta` to the file at `base`, and store the output at `dest`, replacing whatever file
+/// was there in one step.
+///
+/// The output is written to a temporary file next to `dest`, flushed to disk, and then renamed
+/// over `dest`, after which the directory is synced as well on Unix, so the rename itself is
+/// durable. Other processes, and the file after a crash, see either the old contents of `dest`
+/// or the complete output, never a mix of the two or a partly written file. If anything fails
+/// before the rename, including the delta being invalid, the temporary file is removed and
+/// `dest` is left as it was.
+///
+/// `base` is read as the delta needs it, as with [apply_seek()], so it can be larger than the
+/// memory available, and it can be the same path as `dest`, to update a file to its new
+/// version. With the `mmap` feature, it is mapped into memory if it can be, in which case this
+/// fails with [ApplyError::Io] if it changes size before the output is complete, and truncating
+/// it can even crash the process on some platforms. Otherwise, it is read as described above.
+/// If `dest` already exists, the output gets its permissions.
+///
+/// # Security
+/// As with [apply()](crate::apply), the output should be verified with a cryptographic hash
+/// before it is trusted, or [apply_verified()](crate::apply_verified) used instead, in which
+/// case the file should be written elsewhere first.
+pub fn apply_to_path(base: &Path, delta: &[u8], dest: &Path) -> Result<(), ApplyError> {
+    let base = File::open(base)?;
+    replace_file(dest, None, |out| apply_file_base(&base, delta, out))
+}
+
+/// Replace the file at `dest` with what `write` writes, in the same way as [apply_to_path()],
+/// and set its modification time to `mtime` if given.
+pub(crate) fn replace_file(
+    dest: &Path,
+    mtime: Option<SystemTime>,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), ApplyError>,
+) -> Result<(), ApplyError> {
+    let name = dest.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the destination path doesn't name a file",
+        )
+    })?;
+    let dir = match dest.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let (temp, file) = TempFile::create(dir, name)?;
+    if let Ok(metadata) = fs::metadata(dest) {
+        file.set_permissions(metadata.permissions())?;
+    }
+    let mut out = BufWriter::new(file);
+    write(&mut out)?;
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    if let Some(mtime) = mtime {
+        file.set_modified(mtime)?;
+    }
+    file.sync_all()?;
+    drop(file);
+    temp.persist(dest)?;
+    sync_dir(dir)?;
+    Ok(())
+}
+
+/// Apply `delta` to `base`, mapping it if the `mmap` feature is enabled and it can be.
+pub(crate) fn apply_file_base(
+    base: &File,
+    delta: &[u8],
+    out: &mut impl Write,
+) -> Result<(), ApplyError> {
+    #[cfg(feature = "mmap")]
+    if let Some(map) = Mapped::new(base)? {
+        apply(&map, delta, out)?;
+        return Ok(map.check_size(base)?);
+    }
+    apply_seek(base, delta, out)
+}
+
+/// Distinguishes the temporary files of one process.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The path of a temporary file, which is removed when dropped unless it was renamed into place.
+struct TempFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    /// Create a new file in `dir`, named after `name` so that it is clear which file it is for if
+    /// it is left behind by a crash.
+    fn create(dir: &Path, name: &OsStr) -> io::Result<(TempFile, File)> {
+        let mut attempts = 0;
+        loop {
+            let mut temp_name = OsStr::new(".").to_os_string();
+            temp_name.push(name);
+            temp_name.push(format!(
+                ".{}.{}.tmp",
+                process::id(),
+                TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            let path = dir.join(temp_name);
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    let temp = TempFile {
+                        path,
+                        persisted: false,
+                    };
+                    return Ok((temp, file));
+                }
+                // left behind by an earlier process with the same id
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempts < 100 => {
+                    attempts += 1
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn persist(mut self, dest: &Path) -> io::Result<()> {
+        fs::rename(&self.path, dest)?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Make the entries of `dir`, such as a file just renamed into it, durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened to be synced here, and renames are durable once done.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::apply_to_path;
+    use crate::tests::Fixture;
+    use crate::{diff, ApplyError, Signature, SignatureOptions};
+    use std::path::Path;
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn apply_to_path_replaces_dest() {
+        let Fixture {
+            base, data, delta, ..
+        } = Fixture::new(100_000, SignatureOptions::default());
+        let dir = tempfile::tempdir().unwrap();
+        let (base_path, dest_path) = (dir.path().join("base"), dir.path().join("dest"));
+        std::fs::write(&base_path, &base).unwrap();
+        apply_to_path(&base_path, &delta, &dest_path).unwrap();
+        assert_eq!(std::fs::read(&dest_path).unwrap(), data);
+        assert_eq!(entries(dir.path()), ["base", "dest"]);
+
+        // an invalid delta leaves the old file, and no temporary file
+        std::fs::write(&dest_path, b"old").unwrap();
+        assert!(matches!(
+            apply_to_path(&base_path, &delta[..delta.len() - 1], &dest_path),
+            Err(ApplyError::UnexpectedEof { .. })
+        ));
+        assert_eq!(std::fs::read(&dest_path).unwrap(), b"old");
+        assert_eq!(entries(dir.path()), ["base", "dest"]);
+
+        // updating the base to the new version
+        apply_to_path(&base_path, &delta, &base_path).unwrap();
+        assert_eq!(std::fs::read(&base_path).unwrap(), data);
+        assert_eq!(entries(dir.path()), ["base", "dest"]);
+
+        assert!(matches!(
+            apply_to_path(&dir.path().join("missing"), &delta, &dest_path),
+            Err(ApplyError::Io(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn apply_to_path_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (base_path, dest_path) = (dir.path().join("base"), dir.path().join("dest"));
+        std::fs::write(&base_path, b"#!/bin/sh\n").unwrap();
+        std::fs::write(&dest_path, b"").unwrap();
+        let permissions = std::fs::Permissions::from_mode(0o751);
+        std::fs::set_permissions(&dest_path, permissions).unwrap();
+        let mut delta = vec![];
+        let signature = Signature::calculate(b"", SignatureOptions::default());
+        diff(&signature.index(), b"#!/bin/sh\ntrue\n", &mut delta).unwrap();
+        apply_to_path(&base_path, &delta, &dest_path).unwrap();
+        assert_eq!(std::fs::read(&dest_path).unwrap(), b"#!/bin/sh\ntrue\n");
+        let mode = std::fs::metadata(&dest_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o751);
+    }
+}
@@ -0,0 +1,79 @@
+//! A bloom filter over rolling checksums, which rules out most lookups of a checksum that isn't in
+//! a signature without touching the (much larger) index itself.
+
+/// A blocked bloom filter of rolling checksums: both bits for a checksum are in the same word, so
+/// a lookup costs at most one cache miss.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct WeakSumFilter {
+    words: Box<[u64]>,
+}
+
+impl WeakSumFilter {
+    /// About 16 bits per checksum, for a false positive rate of around 2%.
+    const BITS_PER_SUM: usize = 16;
+
+    pub fn new(weak_sums: impl ExactSizeIterator<Item = u32>) -> Self {
+        let word_count = (weak_sums.len().saturating_mul(Self::BITS_PER_SUM) / 64)
+            .max(1)
+            .next_power_of_two();
+        let mut words = vec![0u64; word_count].into_boxed_slice();
+        for weak_sum in weak_sums {
+            let (word, bits) = Self::locate(weak_sum, word_count);
+            words[word] |= bits;
+        }
+        WeakSumFilter { words }
+    }
+
+    /// Whether `weak_sum` may be one of the checksums the filter was built from.
+    #[inline]
+    pub fn may_contain(&self, weak_sum: u32) -> bool {
+        let (word, bits) = Self::locate(weak_sum, self.words.len());
+        self.words[word] & bits == bits
+    }
+
+    /// The heap memory used by the filter.
+    pub fn heap_size(&self) -> usize {
+        self.words.len() * 8
+    }
+
+    #[inline]
+    fn locate(weak_sum: u32, word_count: usize) -> (usize, u64) {
+        // the avalanche function from xxhash, since the checksums themselves aren't uniform
+        let mut hash = weak_sum as u64;
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xC2B2AE3D27D4EB4F);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(0x165667B19E3779F9);
+        hash ^= hash >> 32;
+        let word = (hash >> 12) as usize & (word_count - 1);
+        (word, 1 << (hash & 63) | 1 << ((hash >> 6) & 63))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WeakSumFilter;
+    use quickcheck_macros::quickcheck;
+
+    #[quickcheck]
+    fn no_false_negatives(weak_sums: Vec<u32>) -> bool {
+        let filter = WeakSumFilter::new(weak_sums.iter().copied());
+        weak_sums
+            .iter()
+            .all(|&weak_sum| filter.may_contain(weak_sum))
+    }
+
+    #[test]
+    fn few_false_positives() {
+        let filter = WeakSumFilter::new((0..10000u32).map(|i| i.wrapping_mul(0x9e3779b9)));
+        let false_positives = (0..100000u32)
+            .map(|i| i.wrapping_mul(0x9e3779b9) ^ 1)
+            .filter(|&weak_sum| filter.may_contain(weak_sum))
+            .count();
+        assert!(
+            false_positives < 5000,
+            "{} false positives",
+            false_positives
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! A bounded cache of signatures and their indexes.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::signature::{OwnedIndexedSignature, Signature};
+
+/// A thread-safe cache of signatures, and of indexes built from them on demand, which evicts the
+/// least recently used entries to stay within a memory budget.
+///
+/// Entries are keyed by an opaque id chosen by the caller, e.g. a file path along with its
+/// modification time, so that stale signatures are simply never looked up again. Signatures and
+/// indexes are handed out as [Arc]s, so they remain usable after they are evicted.
+#[derive(Debug)]
+pub struct SignatureCache<K> {
+    max_bytes: usize,
+    inner: Mutex<CacheInner<K>>,
+}
+
+#[derive(Debug)]
+struct CacheInner<K> {
+    entries: HashMap<K, CacheEntry>,
+    /// Keys by the time they were last used, oldest first.
+    recency: BTreeMap<u64, K>,
+    clock: u64,
+    bytes: usize,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    signature: Arc<Signature>,
+    index: Option<Arc<OwnedIndexedSignature>>,
+    bytes: usize,
+    last_used: u64,
+}
+
+impl<K: Clone + Eq + Hash> SignatureCache<K> {
+    /// Create a cache which holds at most about `max_bytes` of signatures and indexes.
+    pub fn new(max_bytes: usize) -> Self {
+        SignatureCache {
+            max_bytes,
+            inner: Mutex::new(CacheInner {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+                bytes: 0,
+            }),
+        }
+    }
+
+    /// Add a signature to the cache, replacing any previous one for `key`, and return it.
+    ///
+    /// If the signature alone is larger than the cache, it is not kept, and nothing else is
+    /// evicted to make room for it.
+    pub fn insert(&self, key: K, signature: Signature) -> Arc<Signature> {
+        let signature = Arc::new(signature);
+        let mut inner = self.lock();
+        inner.remove(&key);
+        let bytes = signature.serialized().len();
+        if bytes > self.max_bytes {
+            return signature;
+        }
+        let last_used = inner.tick();
+        inner.bytes += bytes;
+        inner.recency.insert(last_used, key.clone());
+        inner.entries.insert(
+            key,
+            CacheEntry {
+                signature: signature.clone(),
+                index: None,
+                bytes,
+                last_used,
+            },
+        );
+        inner.evict(self.max_bytes);
+        signature
+    }
+
+    /// Look up the signature for `key`.
+    pub fn get(&self, key: &K) -> Option<Arc<Signature>> {
+        let mut inner = self.lock();
+        let signature = inner.entries.get(key)?.signature.clone();
+        inner.touch(key);
+        Some(signature)
+    }
+
+    /// Look up the index of the signature for `key`, building it if it isn't cached yet.
+    ///
+    /// The index is built without holding the cache's lock, so other lookups aren't blocked
+    /// meanwhile. Once built, it counts towards the memory budget along with its signature,
+    /// unless the two together are larger than the cache, in which case the index is returned
+    /// without being kept.
+    pub fn get_index(&self, key: &K) -> Option<Arc<OwnedIndexedSignature>> {
+        let signature = {
+            let mut inner = self.lock();
+            let entry = inner.entries.get(key)?;
+            if let Some(index) = &entry.index {
+                let index = index.clone();
+                inner.touch(key);
+                return Some(index);
+            }
+            entry.signature.clone()
+        };
+        let index = Arc::new(signature.index().into_owned());
+        let mut inner = self.lock();
+        // Only keep the index if the signature wasn't replaced or evicted in the meantime, and
+        // another thread didn't get there first.
+        let bytes = match inner.entries.get_mut(key) {
+            Some(entry) if Arc::ptr_eq(&entry.signature, &signature) => match &entry.index {
+                Some(existing) => return Some(existing.clone()),
+                None if entry.bytes.saturating_add(index.memory_usage()) > self.max_bytes => {
+                    return Some(index)
+                }
+                None => {
+                    let bytes = index.memory_usage();
+                    entry.index = Some(index.clone());
+                    entry.bytes += bytes;
+                    bytes
+                }
+            },
+            _ => return Some(index),
+        };
+        inner.bytes += bytes;
+        inner.touch(key);
+        inner.evict(self.max_bytes);
+        Some(index)
+    }
+
+    /// Remove the signature for `key`, along with its index, and return it.
+    pub fn remove(&self, key: &K) -> Option<Arc<Signature>> {
+        self.lock().remove(key)
+    }
+
+    /// The number of signatures in the cache.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// Whether the cache holds no signatures.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The approximate number of bytes used by the cached signatures and indexes.
+    pub fn memory_usage(&self) -> usize {
+        self.lock().bytes
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheInner<K>> {
+        // The cache is consistent between statements which could panic, so a poisoned lock is
+        // still usable.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<K: Clone + Eq + Hash> CacheInner<K> {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Mark an existing entry as the most recently used.
+    fn touch(&mut self, key: &K) {
+        let now = self.tick();
+        if let Some(entry) = self.entries.get_mut(key) {
+            let key = self
+                .recency
+                .remove(&entry.last_used)
+                .expect("every entry is in the recency list");
+            entry.last_used = now;
+            self.recency.insert(now, key);
+        }
+    }
+
+    fn remove(&mut self, key: &K) -> Option<Arc<Signature>> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.last_used);
+        self.bytes -= entry.bytes;
+        Some(entry.signature)
+    }
+
+    /// Evict the least recently used entries until the cache fits in `max_bytes`.
+    fn evict(&mut self, max_bytes: usize) {
+        while self.bytes > max_bytes {
+            let key = match self.recency.values().next() {
+                Some(key) => key.clone(),
+                None => break,
+            };
+            self.remove(&key);
+        }
+    }
+}
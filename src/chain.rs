@@ -0,0 +1,333 @@
+//! Applying several deltas in a row, without the versions in between.
+
+use std::io::{self, Write};
+
+use crate::blake2::Blake2Hasher;
+use crate::consts::{DELTA_CHECKSUM, EXTENDED_DELTA_MAGIC};
+use crate::diff::{DeltaCommand, DeltaWriter};
+#[cfg(feature = "zstd")]
+use crate::patch::{compressed_len, decompress};
+use crate::patch::{ApplyError, DeltaReader};
+
+/// Where a piece of a version comes from.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Source<'a> {
+    /// The base, from this offset on.
+    Base(u64),
+    /// Literal data from one of the deltas.
+    Data(&'a [u8]),
+}
+
+/// A piece of a version: `len` bytes from `source`, starting at `start`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Segment<'a> {
+    pub start: u64,
+    pub len: u64,
+    pub source: Source<'a>,
+}
+
+/// A version, as the pieces of the base and of literals which it is made up of.
+#[derive(Debug)]
+pub(crate) struct Version<'a> {
+    pub segments: Vec<Segment<'a>>,
+    pub len: u64,
+}
+
+impl<'a> Version<'a> {
+    /// The base itself.
+    pub fn base(len: u64) -> Self {
+        let mut version = Version {
+            segments: Vec::new(),
+            len: 0,
+        };
+        version.push(len, Source::Base(0));
+        version
+    }
+
+    /// Apply `delta` to this version, and return the next one along with the checksum of the
+    /// delta, if any. Compressed literals are taken from `decompressed` in turn.
+    ///
+    /// Fails with [ApplyError::OutputLimit] as soon as the next version is longer than `limit`,
+    /// which also bounds how many pieces it has, since self-copies can double them each time.
+    pub fn apply(
+        &self,
+        delta: &'a [u8],
+        decompressed: &mut impl Iterator<Item = &'a [u8]>,
+        limit: u64,
+    ) -> Result<(Version<'a>, Option<&'a [u8]>), ApplyError> {
+        let mut reader = DeltaReader::new(delta)?.with_base_len(self.len);
+        let mut next = Version {
+            segments: Vec::new(),
+            len: 0,
+        };
+        let compressed = reader.has_compressed_literals();
+        let mut pieces = Vec::new();
+        for command in &mut reader {
+            match command? {
+                DeltaCommand::Copy { offset, len } => {
+                    self.range(offset, len, |len, source| next.push(len, source))
+                }
+                DeltaCommand::Literal(_) if compressed => {
+                    let literal = decompressed.next().expect("every literal is decompressed");
+                    next.push(literal.len() as u64, Source::Data(literal));
+                }
+                DeltaCommand::Literal(literal) => {
+                    next.push(literal.len() as u64, Source::Data(literal))
+                }
+                DeltaCommand::SelfCopy { offset, len } => {
+                    next.range(offset, len, |len, source| pieces.push((len, source)));
+                    for (len, source) in pieces.drain(..) {
+                        next.push(len, source);
+                    }
+                }
+                DeltaCommand::End => {}
+            }
+            if next.len > limit {
+                return Err(ApplyError::OutputLimit {
+                    what: "output",
+                    wanted: next.len.min(usize::MAX as u64) as usize,
+                    available: limit.min(usize::MAX as u64) as usize,
+                });
+            }
+        }
+        Ok((next, reader.checksum()))
+    }
+
+    /// Add `len` bytes from `source` to the end, merging them into the last piece if they
+    /// continue it.
+    pub fn push(&mut self, len: u64, source: Source<'a>) {
+        if len == 0 {
+            return;
+        }
+        if let Some(last) = self.segments.last_mut() {
+            if let (Source::Base(offset), Source::Base(next)) = (last.source, source) {
+                if offset + last.len == next {
+                    last.len += len;
+                    self.len += len;
+                    return;
+                }
+            }
+        }
+        self.segments.push(Segment {
+            start: self.len,
+            len,
+            source,
+        });
+        self.len += len;
+    }
+
+    /// Pass the pieces of the `len` bytes from `offset` on to `f`, which the caller has checked
+    /// are within the version.
+    pub fn range(&self, mut offset: u64, len: u64, mut f: impl FnMut(u64, Source<'a>)) {
+        let end = offset + len;
+        let first = self.segments.partition_point(|s| s.start + s.len <= offset);
+        for segment in &self.segments[first..] {
+            if offset == end {
+                break;
+            }
+            let skip = offset - segment.start;
+            let n = (segment.len - skip).min(end - offset);
+            f(
+                n,
+                match segment.source {
+                    Source::Base(from) => Source::Base(from + skip),
+                    Source::Data(data) => Source::Data(&data[skip as usize..(skip + n) as usize]),
+                },
+            );
+            offset += n;
+        }
+    }
+
+    /// Pass the data of the version to `f` a piece at a time.
+    pub fn for_each(
+        &self,
+        base: &[u8],
+        mut f: impl FnMut(&[u8]) -> Result<(), ApplyError>,
+    ) -> Result<(), ApplyError> {
+        for segment in &self.segments {
+            match segment.source {
+                Source::Base(from) => f(&base[from as usize..(from + segment.len) as usize])?,
+                Source::Data(data) => f(data)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decompress the compressed literals of `deltas`, in order, failing with
+/// [ApplyError::OutputLimit] rather than decompress more than `limit` bytes in all.
+#[cfg(feature = "zstd")]
+pub(crate) fn decompress_all(deltas: &[&[u8]], limit: usize) -> Result<Vec<Vec<u8>>, ApplyError> {
+    let mut decompressed = Vec::new();
+    let mut available = limit;
+    for &delta in deltas {
+        let mut reader = DeltaReader::new(delta)?;
+        if !reader.has_compressed_literals() {
+            continue;
+        }
+        for command in &mut reader {
+            if let DeltaCommand::Literal(literal) = command? {
+                let size = compressed_len(literal)?;
+                if size > available as u64 {
+                    return Err(ApplyError::OutputLimit {
+                        what: "literal",
+                        wanted: size.min(usize::MAX as u64) as usize,
+                        available,
+                    });
+                }
+                available -= size as usize;
+                decompressed.push(decompress(literal, size)?);
+            }
+        }
+    }
+    Ok(decompressed)
+}
+
+/// Apply `deltas` to `base` one after the other, writing the output of the last one to `out`.
+///
+/// Rather than applying each delta to the output of the one before, this works out which pieces
+/// of the base and of the literals of the deltas each version is made of, and only writes out
+/// the last one. So it takes memory for the pieces of one version at a time, which is about as
+/// many as there are commands in the deltas, and the literals of compressed deltas, but never a
+/// whole version. The [checksums](crate::DiffOptions::checksum) of the versions in between are
+/// still checked, by hashing them a piece at a time, so they cost a pass over the data each.
+///
+/// With no deltas, `base` itself is written. An error doesn't say which delta it came from, and
+/// nothing is written unless all the deltas but the last apply.
+///
+/// Fails with [ApplyError::OutputLimit], before writing anything, if any of the versions the
+/// deltas create would be longer than `limit` bytes, or the compressed literals of the deltas
+/// would take more than that decompressed, as with [apply_limited()](crate::apply_limited).
+///
+/// # Security
+/// Unless `limit` is bounded, the deltas may create an arbitrarily large output, and the
+/// literals they hold in memory along the way as well.
+pub fn apply_chain(
+    base: &[u8],
+    deltas: &[&[u8]],
+    out: &mut impl Write,
+    limit: usize,
+) -> Result<(), ApplyError> {
+    #[cfg(feature = "zstd")]
+    let decompressed = decompress_all(deltas, limit)?;
+    #[cfg(feature = "zstd")]
+    let mut decompressed = decompressed.iter().map(|literal| &literal[..]);
+    #[cfg(not(feature = "zstd"))]
+    let mut decompressed = std::iter::empty::<&[u8]>();
+
+    let mut version = Version::base(base.len() as u64);
+    let mut checksum = None;
+    for delta in deltas {
+        if let Some(checksum) = checksum {
+            check(&version, base, checksum, |_| Ok(()))?;
+        }
+        let (next, next_checksum) = version.apply(delta, &mut decompressed, limit as u64)?;
+        version = next;
+        checksum = next_checksum;
+    }
+    match checksum {
+        Some(checksum) => check(&version, base, checksum, |data| Ok(out.write_all(data)?)),
+        None => version.for_each(base, |data| Ok(out.write_all(data)?)),
+    }
+}
+
+/// Compose `delta_ab`, from a version A to a version B, and `delta_bc`, from B to C, into one
+/// delta from A to C, without needing A or B.
+///
+/// The copies of `delta_bc` are remapped through `delta_ab`, into copies from A and the literals
+/// of `delta_ab`, so e.g. a chain of deltas can be collapsed ahead of time into one which applies
+/// as fast as any other. Both deltas are checked as with [DeltaReader], so the copies of
+/// `delta_bc` have to be within B, but the checksum of `delta_ab`, if it has one, can't be
+/// checked without A.
+///
+/// The composed delta is in the librsync format, with uncompressed literals and no
+/// self-copies, except that it keeps the [checksum](crate::DiffOptions::checksum) of `delta_bc`
+/// if it has one, since its output is the same. Fails with [ApplyError::OutputLimit] if B or C
+/// would be longer than `limit` bytes, if the literals of the composed delta would take more
+/// than that, or if the compressed literals of the two deltas would take more than that
+/// decompressed. Bounding the versions also bounds the work and memory of remapping them, which
+/// self-copies could otherwise double with every command.
+pub fn compose(delta_ab: &[u8], delta_bc: &[u8], limit: usize) -> Result<Vec<u8>, ApplyError> {
+    #[cfg(feature = "zstd")]
+    let decompressed = decompress_all(&[delta_ab, delta_bc], limit)?;
+    #[cfg(feature = "zstd")]
+    let mut decompressed = decompressed.iter().map(|literal| &literal[..]);
+    #[cfg(not(feature = "zstd"))]
+    let mut decompressed = std::iter::empty::<&[u8]>();
+
+    let (b, _) = Version::base(u64::MAX).apply(delta_ab, &mut decompressed, limit as u64)?;
+    let (c, checksum) = b.apply(delta_bc, &mut decompressed, limit as u64)?;
+    let mut builder = DeltaBuilder::new()?;
+    let mut available = limit;
+    for segment in &c.segments {
+        match segment.source {
+            Source::Base(offset) => builder.copy(offset, segment.len)?,
+            Source::Data(data) if data.len() > available => {
+                return Err(ApplyError::OutputLimit {
+                    what: "literal",
+                    wanted: data.len(),
+                    available,
+                })
+            }
+            Source::Data(data) => {
+                available -= data.len();
+                builder.literal(data)
+            }
+        }
+    }
+    Ok(builder.finish(checksum)?)
+}
+
+/// Writes a delta into memory, merging literals which end up next to each other.
+pub(crate) struct DeltaBuilder {
+    writer: DeltaWriter<Vec<u8>>,
+    literal: Vec<u8>,
+}
+
+impl DeltaBuilder {
+    pub fn new() -> io::Result<Self> {
+        Ok(DeltaBuilder {
+            writer: DeltaWriter::new(Vec::new())?,
+            literal: Vec::new(),
+        })
+    }
+
+    pub fn copy(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.writer.literal(&self.literal)?;
+        self.literal.clear();
+        self.writer.copy(offset, len)
+    }
+
+    pub fn literal(&mut self, data: &[u8]) {
+        self.literal.extend_from_slice(data);
+    }
+
+    /// End the delta, with `checksum` of its output after it if there is one.
+    pub fn finish(mut self, checksum: Option<&[u8]>) -> io::Result<Vec<u8>> {
+        self.writer.literal(&self.literal)?;
+        let mut delta = self.writer.finish()?;
+        if let Some(checksum) = checksum {
+            delta[..4].copy_from_slice(&(EXTENDED_DELTA_MAGIC | DELTA_CHECKSUM).to_be_bytes());
+            delta.extend_from_slice(checksum);
+        }
+        Ok(delta)
+    }
+}
+
+/// Hash `version` while passing it to `f`, and check it against `checksum`.
+fn check(
+    version: &Version<'_>,
+    base: &[u8],
+    checksum: &[u8],
+    mut f: impl FnMut(&[u8]) -> Result<(), ApplyError>,
+) -> Result<(), ApplyError> {
+    let mut hasher = Blake2Hasher::default();
+    version.for_each(base, |data| {
+        hasher.update(data);
+        f(data)
+    })?;
+    if hasher.finalize()[..] != *checksum {
+        return Err(ApplyError::ChecksumMismatch);
+    }
+    Ok(())
+}
@@ -1,7 +1,55 @@
 pub const MD4_MAGIC: u32 = 0x72730136;
 pub const BLAKE2_MAGIC: u32 = 0x72730137;
+pub const RK_MD4_MAGIC: u32 = 0x72730146;
+pub const RK_BLAKE2_MAGIC: u32 = 0x72730147;
 pub const DELTA_MAGIC: u32 = 0x72730236;
 
+// Delta formats which only fast_rsync understands: the magic is `EXTENDED_DELTA_MAGIC` with the
+// bits of the extensions the delta uses set.
+pub const EXTENDED_DELTA_MAGIC: u32 = 0x66720240;
+// Literals are compressed with zstd.
+pub const DELTA_ZSTD_LITERALS: u32 = 0x1;
+// `RS_OP_SELF_COPY_*` commands may appear.
+pub const DELTA_SELF_COPIES: u32 = 0x2;
+// `RS_OP_END` is followed by the BLAKE2 hash of the output.
+pub const DELTA_CHECKSUM: u32 = 0x4;
+// Written before extensions had a bit each, and still read: zstd literals, self-copies, and both.
+pub const ZSTD_DELTA_MAGIC: u32 = 0x66720236;
+pub const SELF_COPY_DELTA_MAGIC: u32 = 0x66720237;
+pub const ZSTD_SELF_COPY_DELTA_MAGIC: u32 = 0x66720238;
+// How far back in the output a self-copy may reach.
+pub const SELF_COPY_WINDOW: u64 = 1 << 24;
+
+// Checkpoints of a `DiffState`, which aren't meant to outlive the version of fast_rsync that
+// wrote them.
+pub const DIFF_CHECKPOINT_MAGIC: u32 = 0x66720301;
+
+// Signature types which only fast_rsync understands.
+pub const CUSTOM_MAGIC: u32 = 0x66720170;
+pub const RK_CUSTOM_MAGIC: u32 = 0x66720171;
+pub const KEYED_BLAKE2_MAGIC: u32 = 0x6672013a;
+pub const RK_KEYED_BLAKE2_MAGIC: u32 = 0x6672014a;
+#[cfg(feature = "blake3")]
+pub const BLAKE3_MAGIC: u32 = 0x66720138;
+#[cfg(feature = "blake3")]
+pub const RK_BLAKE3_MAGIC: u32 = 0x66720148;
+#[cfg(feature = "xxhash")]
+pub const XXH3_MAGIC: u32 = 0x66720139;
+#[cfg(feature = "xxhash")]
+pub const RK_XXH3_MAGIC: u32 = 0x66720149;
+
+// Precedes the librsync header of signatures which carry extension fields.
+pub const EXTENDED_MAGIC: u32 = 0x66720100;
+
+// Not a librsync format: the on-disk block index written by `DiskIndexedSignature`.
+pub const DISK_INDEX_MAGIC: u32 = 0x66726958;
+
+// Not librsync formats: the manifests and tree deltas of the `tree` module.
+#[cfg(feature = "fs")]
+pub const MANIFEST_MAGIC: u32 = 0x66727401;
+#[cfg(feature = "fs")]
+pub const TREE_DELTA_MAGIC: u32 = 0x66727402;
+
 pub const RS_OP_END: u8 = 0;
 
 pub const RS_OP_LITERAL_1: u8 = 0x1;
@@ -28,3 +76,8 @@ pub const RS_OP_COPY_N1_N1: u8 = 0x45;
 // pub const RS_OP_COPY_N8_N2: u8 = 0x52;
 // pub const RS_OP_COPY_N8_N4: u8 = 0x53;
 pub const RS_OP_COPY_N8_N8: u8 = 0x54;
+
+// Only in deltas with `DELTA_SELF_COPIES`: copies from the output rather than the base, with
+// the offset and length encoded as for `RS_OP_COPY_*`.
+pub const RS_OP_SELF_COPY_N1_N1: u8 = 0x55;
+pub const RS_OP_SELF_COPY_N8_N8: u8 = 0x64;
@@ -1,21 +1,11 @@
+use crate::seed::SeedTable;
+
 const CRC_MAGIC: u16 = 31;
 
 #[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Crc(pub u32);
 
 impl Crc {
-    pub const SIZE: usize = 4;
-
-    #[inline]
-    pub fn to_bytes(self) -> [u8; Self::SIZE] {
-        self.0.to_be_bytes()
-    }
-
-    #[inline]
-    pub fn from_bytes(b: [u8; Self::SIZE]) -> Self {
-        Crc(u32::from_be_bytes(b))
-    }
-
     #[inline]
     fn split(self) -> (u16, u16) {
         (self.0 as u16, (self.0 >> 16) as u16)
@@ -31,7 +21,6 @@ impl Crc {
         Crc(0)
     }
 
-    #[allow(dead_code)]
     pub fn rollout(self, size: u32, old_byte: u8) -> Crc {
         let size = size as u16;
         let old_byte = old_byte as u16;
@@ -54,7 +43,7 @@ impl Crc {
         Crc::combine(s1, s2)
     }
 
-    #[allow(dead_code)]
+    #[inline]
     pub fn rollin(self, new_byte: u8) -> Crc {
         let (mut s1, mut s2) = self.split();
         s1 = s1.wrapping_add(new_byte as u16);
@@ -67,20 +56,41 @@ impl Crc {
     pub fn update(self, buf: &[u8]) -> Crc {
         macro_rules! imp {
             ($($x:tt)*) => {$($x)* (init: Crc, buf: &[u8]) -> Crc {
-                let (mut s1, mut s2) = init.split();
-                let len = buf.len() as u32;
-                s2 = s2.wrapping_add(s1.wrapping_mul(len as u16));
-                for (idx, &byte) in buf.iter().enumerate() {
-                    s1 = s1.wrapping_add(byte as u16);
-                    s2 = s2.wrapping_add(
-                        (byte as u16).wrapping_mul((len as u16).wrapping_sub(idx as u16)),
-                    );
+                init.update_inner(buf)
+            }};
+        }
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if is_x86_feature_detected!("avx2") {
+                imp!(#[target_feature(enable = "avx2")] unsafe fn imp_avx2);
+                unsafe {
+                    return imp_avx2(self, buf);
+                }
+            }
+            if is_x86_feature_detected!("sse2") {
+                imp!(#[target_feature(enable = "sse2")] unsafe fn imp_sse2);
+                unsafe {
+                    return imp_sse2(self, buf);
                 }
-                s1 = s1.wrapping_add((len as u16).wrapping_mul(CRC_MAGIC));
-                s2 = s2.wrapping_add(
-                    ((len.wrapping_mul(len.wrapping_add(1)) / 2) as u16).wrapping_mul(CRC_MAGIC),
-                );
-                Crc::combine(s1, s2)
+            }
+        }
+        imp!(fn imp_baseline);
+        imp_baseline(self, buf)
+    }
+
+    /// Like `Crc::update`, but with every byte first mapped through `seed`. The result can be
+    /// rolled with `Crc::rotate`, as long as the bytes passed to it are mapped too.
+    pub fn update_seeded(self, seed: &SeedTable, buf: &[u8]) -> Crc {
+        buf.iter()
+            .fold(self, |crc, &byte| crc.rollin(seed.map(byte)))
+    }
+
+    /// Like `Crc::update`, but specialized for a block length known at compile time, which
+    /// lets the compiler fully unroll and vectorize the loop.
+    pub fn update_fixed<const N: usize>(self, buf: &[u8; N]) -> Crc {
+        macro_rules! imp {
+            ($($x:tt)*) => {$($x)* <const N: usize>(init: Crc, buf: &[u8; N]) -> Crc {
+                init.update_inner(buf)
             }};
         }
         #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -102,6 +112,24 @@ impl Crc {
         imp_baseline(self, buf)
     }
 
+    // This is always inlined into the callers above, so that it gets compiled with their target
+    // features (and, for `update_fixed`, a constant length).
+    #[inline(always)]
+    fn update_inner(self, buf: &[u8]) -> Crc {
+        let (mut s1, mut s2) = self.split();
+        let len = buf.len() as u32;
+        s2 = s2.wrapping_add(s1.wrapping_mul(len as u16));
+        for (idx, &byte) in buf.iter().enumerate() {
+            s1 = s1.wrapping_add(byte as u16);
+            s2 = s2.wrapping_add((byte as u16).wrapping_mul((len as u16).wrapping_sub(idx as u16)));
+        }
+        s1 = s1.wrapping_add((len as u16).wrapping_mul(CRC_MAGIC));
+        s2 = s2.wrapping_add(
+            ((len.wrapping_mul(len.wrapping_add(1)) / 2) as u16).wrapping_mul(CRC_MAGIC),
+        );
+        Crc::combine(s1, s2)
+    }
+
     /// Like `Crc::update`, but not autovectorizable.
     #[allow(dead_code)]
     pub fn basic_update(self, buf: &[u8]) -> Crc {
@@ -122,6 +150,7 @@ impl Crc {
 #[cfg(test)]
 mod tests {
     use super::Crc;
+    use crate::seed::SeedTable;
     use quickcheck_macros::quickcheck;
 
     #[quickcheck]
@@ -138,6 +167,15 @@ mod tests {
         sum1 == sum2
     }
 
+    #[quickcheck]
+    fn fixed_update(initial: u32, seed: Vec<u8>) -> bool {
+        let mut buf = [0; 2048];
+        for (dst, src) in buf.iter_mut().zip(seed.iter().cycle()) {
+            *dst = *src;
+        }
+        Crc(initial).update_fixed(&buf) == Crc(initial).update(&buf)
+    }
+
     #[quickcheck]
     fn update_twice(initial: u32, mut buf1: Vec<u8>, buf2: Vec<u8>) -> bool {
         let sum1 = Crc(initial).update(&buf1).update(&buf2);
@@ -168,4 +206,20 @@ mod tests {
         let sum2 = Crc::new().update(&buf[1..]);
         sum1 == sum2
     }
+
+    #[quickcheck]
+    fn rotate_seeded(seed: u32, mut buf: Vec<u8>, byte: u8) -> bool {
+        if buf.is_empty() {
+            return true;
+        }
+        let table = SeedTable::new(seed);
+        let sum1 = Crc::new().update_seeded(&table, &buf).rotate(
+            buf.len() as u32,
+            table.map(buf[0]),
+            table.map(byte),
+        );
+        buf.push(byte);
+        let sum2 = Crc::new().update_seeded(&table, &buf[1..]);
+        sum1 == sum2
+    }
 }
@@ -0,0 +1,199 @@
+//! Writing out the commands of a delta for people to read, e.g. to find out why one doesn't
+//! apply as expected.
+
+use std::ascii;
+use std::io::{self, Write};
+
+use arrayref::array_ref;
+
+use crate::consts::DELTA_MAGIC;
+use crate::diff::DeltaCommand;
+use crate::patch::{ApplyError, DeltaReader};
+
+/// Options for [describe_delta()].
+#[derive(Clone, Debug)]
+pub struct DescribeOptions {
+    /// Write each line as a JSON object, for other tools to read, rather than as text.
+    pub json: bool,
+    /// How many bytes of the data of each literal to show. 32 by default.
+    pub preview_len: usize,
+}
+
+impl Default for DescribeOptions {
+    fn default() -> Self {
+        DescribeOptions {
+            json: false,
+            preview_len: 32,
+        }
+    }
+}
+
+/// Write `delta` to `out` as one line for each of its commands, giving where the command starts
+/// in the delta and in the output, its offset and length, and the start of the data of
+/// literals, after a line with the format of the delta.
+///
+/// ```
+/// use fast_rsync::{describe_delta, DeltaWriter, DescribeOptions};
+///
+/// let mut writer = DeltaWriter::new(Vec::new()).unwrap();
+/// writer.copy(0, 6).unwrap();
+/// writer.literal(b"there").unwrap();
+/// let delta = writer.finish().unwrap();
+///
+/// let mut out = Vec::new();
+/// describe_delta(&delta, &mut out, &DescribeOptions::default()).unwrap();
+/// assert_eq!(
+///     String::from_utf8(out).unwrap(),
+///     "librsync delta
+/// delta_offset=4 output_offset=0 copy offset=0 len=6
+/// delta_offset=7 output_offset=6 literal len=5 data=\"there\"
+/// delta_offset=13 output_offset=11 end
+/// "
+/// );
+/// ```
+///
+/// As text, the data of literals is escaped like a Rust string, and followed by `...` if it is
+/// cut off. As JSON, each line has the same fields, with the kind of command as `command`, and
+/// data in hex. The first line has the `format`, `librsync` or `extended`, and the
+/// `extensions` of the delta. Compressed literals (see `DiffOptions::compress_literals`, with
+/// the `zstd` feature) have their length once decompressed as `len`, and that of the compressed
+/// data as `compressed_len`, but no data, and the end of a delta with a
+/// [checksum](crate::DiffOptions::checksum) has it in hex.
+///
+/// The delta is checked as with [DeltaReader]. If it is invalid, the commands before the
+/// problem are written before the error is returned, so the last line tells where it is.
+pub fn describe_delta(
+    delta: &[u8],
+    mut out: impl Write,
+    options: &DescribeOptions,
+) -> Result<(), ApplyError> {
+    let mut reader = DeltaReader::new(delta)?;
+    let mut extensions = Vec::new();
+    if reader.has_self_copies() {
+        extensions.push("self-copies");
+    }
+    if reader.has_checksum() {
+        extensions.push("checksum");
+    }
+    if reader.has_compressed_literals() {
+        extensions.push("compressed-literals");
+    }
+    let magic = u32::from_be_bytes(*array_ref![delta, 0, 4]);
+    let format = if magic == DELTA_MAGIC {
+        "librsync"
+    } else {
+        "extended"
+    };
+    if options.json {
+        let extensions: Vec<_> = extensions.iter().map(|e| format!("\"{}\"", e)).collect();
+        writeln!(
+            out,
+            "{{\"format\":\"{}\",\"extensions\":[{}]}}",
+            format,
+            extensions.join(",")
+        )?;
+    } else if extensions.is_empty() {
+        writeln!(out, "{} delta", format)?;
+    } else {
+        writeln!(out, "{} delta: {}", format, extensions.join(", "))?;
+    }
+
+    let compressed = reader.has_compressed_literals();
+    loop {
+        let delta_offset = reader.delta_offset();
+        let output_offset = reader.output_len();
+        let command = match reader.next() {
+            Some(command) => command?,
+            None => return Ok(()),
+        };
+        let mut fields = vec![
+            ("delta_offset", Value::Int(delta_offset)),
+            ("output_offset", Value::Int(output_offset)),
+        ];
+        match command {
+            DeltaCommand::Copy { offset, len } => fields.extend_from_slice(&[
+                ("command", Value::Name("copy")),
+                ("offset", Value::Int(offset)),
+                ("len", Value::Int(len)),
+            ]),
+            DeltaCommand::SelfCopy { offset, len } => fields.extend_from_slice(&[
+                ("command", Value::Name("self-copy")),
+                ("offset", Value::Int(offset)),
+                ("len", Value::Int(len)),
+            ]),
+            DeltaCommand::Literal(data) if compressed => fields.extend_from_slice(&[
+                ("command", Value::Name("literal")),
+                ("len", Value::Int(reader.output_len() - output_offset)),
+                ("compressed_len", Value::Int(data.len() as u64)),
+            ]),
+            DeltaCommand::Literal(data) => {
+                let preview = &data[..data.len().min(options.preview_len)];
+                fields.extend_from_slice(&[
+                    ("command", Value::Name("literal")),
+                    ("len", Value::Int(data.len() as u64)),
+                    ("data", Value::Data(preview, preview.len() < data.len())),
+                ])
+            }
+            DeltaCommand::End => {
+                fields.push(("command", Value::Name("end")));
+                if let Some(checksum) = reader.checksum() {
+                    fields.push(("checksum", Value::Hex(checksum)));
+                }
+            }
+        }
+        write_line(&mut out, &fields, options.json)?;
+    }
+}
+
+/// The value of a field of a line of [describe_delta()].
+#[derive(Copy, Clone)]
+enum Value<'a> {
+    Int(u64),
+    /// A name, which is written on its own in text.
+    Name(&'static str),
+    /// Data, and whether it is cut off.
+    Data(&'a [u8], bool),
+    Hex(&'a [u8]),
+}
+
+fn write_line(out: &mut impl Write, fields: &[(&str, Value<'_>)], json: bool) -> io::Result<()> {
+    for (i, &(name, value)) in fields.iter().enumerate() {
+        if json {
+            write!(out, "{}\"{}\":", if i == 0 { "{" } else { "," }, name)?;
+        } else if i > 0 {
+            out.write_all(b" ")?;
+        }
+        match value {
+            Value::Int(n) if json => write!(out, "{}", n)?,
+            Value::Int(n) => write!(out, "{}={}", name, n)?,
+            Value::Name(s) if json => write!(out, "\"{}\"", s)?,
+            Value::Name(s) => out.write_all(s.as_bytes())?,
+            Value::Data(data, _) | Value::Hex(data) if json => {
+                out.write_all(b"\"")?;
+                write_hex(out, data)?;
+                out.write_all(b"\"")?;
+            }
+            Value::Data(data, truncated) => {
+                write!(out, "{}=\"", name)?;
+                for &byte in data {
+                    for c in ascii::escape_default(byte) {
+                        out.write_all(&[c])?;
+                    }
+                }
+                out.write_all(if truncated { b"\"..." } else { b"\"" })?;
+            }
+            Value::Hex(data) => {
+                write!(out, "{}=", name)?;
+                write_hex(out, data)?;
+            }
+        }
+    }
+    out.write_all(if json { b"}\n" } else { b"\n" })
+}
+
+fn write_hex(out: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    for byte in data {
+        write!(out, "{:02x}", byte)?;
+    }
+    Ok(())
+}
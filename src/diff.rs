@@ -1,29 +1,84 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt;
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
+#[cfg(feature = "digest")]
+use std::marker::PhantomData;
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
+use arrayref::array_ref;
+
+use crate::blake2::{blake2_many, blake2_many_lanes, Blake2Hasher, BLAKE2_SIZE};
+#[cfg(feature = "zstd")]
+use crate::consts::DELTA_ZSTD_LITERALS;
 use crate::consts::{
-    DELTA_MAGIC, RS_OP_COPY_N1_N1, RS_OP_END, RS_OP_LITERAL_1, RS_OP_LITERAL_N1, RS_OP_LITERAL_N2,
-    RS_OP_LITERAL_N4, RS_OP_LITERAL_N8,
+    DELTA_CHECKSUM, DELTA_MAGIC, DELTA_SELF_COPIES, DIFF_CHECKPOINT_MAGIC, EXTENDED_DELTA_MAGIC,
+    RS_OP_COPY_N1_N1, RS_OP_END, RS_OP_LITERAL_1, RS_OP_LITERAL_N1, RS_OP_LITERAL_N2,
+    RS_OP_LITERAL_N4, RS_OP_LITERAL_N8, RS_OP_SELF_COPY_N1_N1, SELF_COPY_WINDOW,
 };
 use crate::crc::Crc;
+use crate::error::ErrorKind;
 use crate::hasher::BuildCrcHasher;
-use crate::md4::{md4, MD4_SIZE};
-use crate::signature::{IndexedSignature, SignatureType};
+use crate::md4::{md4_many, md4_many_lanes};
+use crate::rabinkarp::RabinKarp;
+use crate::seed::SeedTable;
+use crate::signature::{
+    BlockIndex, HashKey, RollingHash, SignatureHash, SignatureOptions, SignatureType,
+};
 
 /// This controls how many times we will allow ourselves to fail at matching a
 /// given crc before permanently giving up on it (essentially removing it from
-/// the signature).
+/// the signature), unless overridden by `DiffOptions::max_weak_sum_collisions`.
 const MAX_CRC_COLLISIONS: u32 = 1024;
 
+/// How many distinct rolling checksums we keep collision counts for, unless overridden by
+/// `DiffOptions::max_tracked_collisions`. This keeps the counts to around 20 MiB, however many
+/// distinct checksums the data manages to collide.
+const MAX_TRACKED_COLLISIONS: usize = 1 << 20;
+
 /// Indicates that a delta could not be calculated
+///
+/// New variants may be added in any release: use [kind()](DiffError::kind) to tell broad cases
+/// apart.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum DiffError {
     /// Indicates the signature is invalid or unsupported
     InvalidSignature,
     /// Indicates an IO error occured when writing the delta
     Io(io::Error),
+    /// Indicates the diff was stopped through [DiffOptions::cancel]
+    Cancelled,
+    /// Indicates the delta would have been longer than [DiffOptions::max_delta_len]
+    OutputLimit,
+    /// Indicates the index leaves out some blocks of the signature (see
+    /// [BlockIndex::is_complete]), so the delta would have been larger than necessary
+    IncompleteIndex,
+    /// Indicates a checkpoint passed to [DiffState::resume] is corrupt, or was taken with a
+    /// different signature or options
+    InvalidCheckpoint,
+    /// Indicates an option was set which needs a feature this build doesn't enable
+    Unsupported {
+        /// The feature which is needed.
+        feature: &'static str,
+    },
+}
+
+impl DiffError {
+    /// What kind of failure this is.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::InvalidSignature | Self::InvalidCheckpoint => ErrorKind::InvalidData,
+            Self::Io(_) => ErrorKind::Io,
+            Self::Cancelled => ErrorKind::Cancelled,
+            Self::OutputLimit => ErrorKind::LimitExceeded,
+            Self::IncompleteIndex => ErrorKind::InvalidArgument,
+            Self::Unsupported { .. } => ErrorKind::Unsupported,
+        }
+    }
 }
 
 impl fmt::Display for DiffError {
@@ -31,6 +86,17 @@ impl fmt::Display for DiffError {
         match self {
             Self::InvalidSignature => f.write_str("invalid or unsupported signature for diff"),
             Self::Io(source) => write!(f, "Encountered IO error when calculating diff: {}", source),
+            Self::Cancelled => f.write_str("diff was cancelled"),
+            Self::OutputLimit => f.write_str("delta exceeded the output limit"),
+            Self::IncompleteIndex => f.write_str("index doesn't cover the whole signature"),
+            Self::InvalidCheckpoint => f.write_str("invalid checkpoint for this diff"),
+            Self::Unsupported { feature } => {
+                write!(
+                    f,
+                    "the options need the {} feature, which isn't enabled",
+                    feature
+                )
+            }
         }
     }
 }
@@ -39,11 +105,158 @@ impl Error for DiffError {}
 
 impl From<io::Error> for DiffError {
     fn from(source: io::Error) -> Self {
+        if source
+            .get_ref()
+            .map_or(false, |inner| inner.is::<OutputLimitExceeded>())
+        {
+            return Self::OutputLimit;
+        }
         Self::Io(source)
     }
 }
 
-fn insert_command(len: u64, out: &mut impl Write) -> io::Result<()> {
+/// The error a [CountingWriter] fails with when writing past its limit.
+#[derive(Debug)]
+struct OutputLimitExceeded;
+
+impl fmt::Display for OutputLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("delta exceeded the output limit")
+    }
+}
+
+impl Error for OutputLimitExceeded {}
+
+/// Options for [diff_with_options()].
+#[derive(Clone, Debug, Default)]
+pub struct DiffOptions {
+    /// If set, literal commands are split so that none of them crosses a multiple of this many
+    /// bytes of output. This makes the literal framing depend only on the output position, which
+    /// is useful when the delta is later encrypted or compressed in fixed-size chunks.
+    pub literal_segment_size: Option<NonZeroUsize>,
+    /// If set, and `data` is more than this many times longer than the largest base the signature
+    /// could describe, skip searching for matches and emit `data` as literals. Few blocks could
+    /// match in that case, so scanning the whole input would mostly be wasted work.
+    pub max_size_ratio: Option<NonZeroU64>,
+    /// The secret key the signature was calculated with, which is required for signatures using
+    /// [SignatureHash::KeyedBlake2](crate::SignatureHash::KeyedBlake2).
+    pub hash_key: Option<HashKey>,
+    /// How many times a rolling checksum may match without the strong hash matching before it is
+    /// ignored for the rest of the diff. This bounds the work adversarial data can cause, and
+    /// defaults to 1024.
+    pub max_weak_sum_collisions: Option<u32>,
+    /// How many distinct rolling checksums to count collisions for, which bounds the memory the
+    /// counts use. Once that many are tracked, the counts of checksums which haven't reached
+    /// `max_weak_sum_collisions` are dropped; if that doesn't free at least half of them, no
+    /// further checksums are tracked for the rest of the diff. Defaults to 2^20.
+    pub max_tracked_collisions: Option<NonZeroUsize>,
+    /// If set, only look for matching blocks at offsets of `data` which are a multiple of this.
+    /// By default a match can start at any offset.
+    pub match_alignment: Option<NonZeroU32>,
+    /// Only look for matching blocks at offsets of `data` which are a multiple of the block size,
+    /// as if [match_alignment](Self::match_alignment) were set to it. This suits data which is
+    /// only ever changed a whole block at a time, like database pages or disk images, if the
+    /// signature's block size is a multiple of the page size: each window is checksummed once
+    /// rather than rolled through byte by byte, which makes the diff several times faster.
+    pub block_aligned: bool,
+    /// If set, compress each literal with zstd at this compression level, in an extension of the
+    /// delta format which librsync can't apply. This pays off for text and other data which
+    /// compresses well, since literals are otherwise sent as they are. Literals are compressed
+    /// separately, so [literal_segment_size](Self::literal_segment_size) also bounds how much
+    /// each one can be compressed.
+    ///
+    /// This needs the `zstd` feature: without it, setting this fails with
+    /// [DiffError::Unsupported].
+    pub compress_literals: Option<i32>,
+    /// Copy data which repeats earlier unmatched data from the output produced so far, rather
+    /// than sending it as literals again, in an extension of the delta format which librsync
+    /// can't apply. Repeats are found a block at a time, and only within the last 16 MiB of
+    /// output, which is as much as applying the delta keeps around. [DiffState] only finds
+    /// repeats of the unmatched data it still holds.
+    pub self_copies: bool,
+    /// Append the BLAKE2 hash of `data` to the delta, in an extension of the delta format which
+    /// librsync can't apply. [apply()](crate::apply) checks the output against it and fails
+    /// with [ApplyError::ChecksumMismatch](crate::ApplyError::ChecksumMismatch) if a wrong match
+    /// made it differ from `data`, so it needn't be hashed separately.
+    pub checksum: bool,
+    /// If set, copies shorter than this many bytes are sent as literals instead. Besides making
+    /// the delta apply with fewer, longer reads from the base, this avoids copies that barely
+    /// save anything over their own encoding.
+    pub min_copy_len: Option<NonZeroUsize>,
+    /// Extend each matching block byte by byte, forwards and backwards, for as long as `data`
+    /// still agrees with the base. This finds the exact extent of unchanged runs even when edits
+    /// aren't aligned to blocks, at some cost in speed. It needs the base data itself, so it
+    /// only has an effect in [diff_with_base()].
+    pub extend_matches: bool,
+    /// Which block to copy when data matches several identical blocks of the base.
+    pub duplicate_blocks: DuplicateBlocks,
+    /// If set, once this many bytes in a row haven't matched, stop checking every offset and only
+    /// check the windows at multiples of the block size, until one of those matches. This makes
+    /// diffing unrelated data much faster, at the cost of missing matches which aren't aligned to
+    /// blocks within long stretches of changed data.
+    pub sparse_search_after: Option<NonZeroU64>,
+    /// If set, look up fewer windows the longer nothing has matched: after every block's worth of
+    /// unmatched data, the distance between lookups roughly doubles, up to this many bytes. The
+    /// distance is always odd, so a run of matching blocks is still found within a few blocks
+    /// of where it starts, and it goes back to checking every window after a match. This saves
+    /// most of the lookups in changed data, at the cost of copying a little less.
+    pub max_search_stride: Option<NonZeroU32>,
+    /// A flag which can be set from another thread to stop the diff, which then fails with
+    /// [DiffError::Cancelled]. It is checked after every MiB or so of `data`, and the delta
+    /// written up to then is incomplete.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// If set, fail with [DiffError::OutputLimit] rather than write a delta longer than this
+    /// many bytes. Unless they are compressed, literals take up at least as much of the delta as
+    /// of `data`, so this is noticed while still searching a long run of unmatched data, not only
+    /// once it is written.
+    /// What was written up to then is an incomplete delta. [DiffState] ignores this limit.
+    pub max_delta_len: Option<u64>,
+    /// If set, flush `out` whenever at least this many bytes have been written to it since it was
+    /// last flushed, besides once the delta is complete. With a buffered writer in front of a
+    /// socket, this keeps the delta trickling out while a long run of input is searched, rather
+    /// than waiting for the buffer to fill. [DiffState] ignores this, since it doesn't write to
+    /// `out` itself.
+    pub flush_interval: Option<NonZeroU64>,
+    /// Guarantee that the delta only depends on `data`, the blocks in the signature and these
+    /// options, so that it stays byte-for-byte the same across versions of this crate and across
+    /// platforms, e.g. for content-addressed storage of deltas.
+    ///
+    /// Of several identical blocks, the delta always copies the one with the highest index, which
+    /// is the one every index in this crate finds; custom [BlockIndex] implementations must do
+    /// the same. [duplicate_blocks](Self::duplicate_blocks) is ignored, since its choice depends
+    /// on how the signature was indexed. New ways of searching for matches will not change the
+    /// delta unless they are enabled by another option.
+    ///
+    /// This holds for functions given the whole of `data` at once, while [DiffState] and the
+    /// functions built on it may split literals differently.
+    pub deterministic: bool,
+}
+
+/// Which of several identical blocks of the base a delta copies from; see
+/// [DiffOptions::duplicate_blocks].
+///
+/// Choosing blocks close together keeps the copies in a delta sequential, so it compresses better
+/// and reads the base in order when applied. Only an index built with
+/// [IndexOptions::keep_duplicates](crate::IndexOptions::keep_duplicates) knows where all the
+/// identical blocks are; other indexes always find the same one.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DuplicateBlocks {
+    /// Whichever block the index finds first.
+    Any,
+    /// The block closest to where the previous copy ended.
+    NearPrevious,
+    /// The block closest to the same position in the base as the matched data has in the new
+    /// data.
+    SamePosition,
+}
+
+impl Default for DuplicateBlocks {
+    fn default() -> Self {
+        DuplicateBlocks::Any
+    }
+}
+
+pub(crate) fn insert_command(len: u64, out: &mut impl Write) -> io::Result<()> {
     assert!(len != 0);
     if len <= 64 {
         out.write_all(&[RS_OP_LITERAL_1 + (len - 1) as u8])?;
@@ -63,7 +276,13 @@ fn insert_command(len: u64, out: &mut impl Write) -> io::Result<()> {
     Ok(())
 }
 
-fn copy_command(offset: u64, len: u64, out: &mut impl Write) -> io::Result<()> {
+/// Write a copy command, of the kind whose first opcode is `first_op`.
+pub(crate) fn copy_command(
+    first_op: u8,
+    offset: u64,
+    len: u64,
+    out: &mut impl Write,
+) -> io::Result<()> {
     fn u64_size_class(val: u64) -> u8 {
         if val <= u8::max_value() as u64 {
             0
@@ -76,11 +295,11 @@ fn copy_command(offset: u64, len: u64, out: &mut impl Write) -> io::Result<()> {
         }
     }
 
-    fn size_class_marker(offset: u64, len: u64) -> u8 {
+    fn size_class_marker(first_op: u8, offset: u64, len: u64) -> u8 {
         let offset_len = u64_size_class(offset);
         let len_len = u64_size_class(len);
 
-        RS_OP_COPY_N1_N1 + offset_len * 4 + len_len
+        first_op + offset_len * 4 + len_len
     }
 
     fn write_varint(val: u64, out: &mut impl Write) -> io::Result<()> {
@@ -97,7 +316,7 @@ fn copy_command(offset: u64, len: u64, out: &mut impl Write) -> io::Result<()> {
         Ok(())
     }
 
-    let marker = size_class_marker(offset, len);
+    let marker = size_class_marker(first_op, offset, len);
     out.write_all(&[marker])?;
     write_varint(offset, out)?;
     write_varint(len, out)?;
@@ -105,25 +324,332 @@ fn copy_command(offset: u64, len: u64, out: &mut impl Write) -> io::Result<()> {
     Ok(())
 }
 
+/// A command found by a diff, as passed to the callback of [diff_commands()].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DiffCommand<'a> {
+    /// Copy `len` bytes from `offset` in the base.
+    Copy {
+        /// Where in the base to copy from.
+        offset: u64,
+        /// How many bytes to copy.
+        len: u64,
+    },
+    /// Append these bytes, which aren't in the base.
+    Literal(&'a [u8]),
+}
+
+/// A command of a delta, as read by [DeltaReader](crate::DeltaReader).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DeltaCommand<'a> {
+    /// Copy `len` bytes from `offset` in the base.
+    Copy {
+        /// Where in the base to copy from.
+        offset: u64,
+        /// How many bytes to copy.
+        len: u64,
+    },
+    /// Append these bytes, which aren't in the base.
+    Literal(&'a [u8]),
+    /// Copy `len` bytes from `offset` in the output so far, in a delta with
+    /// [self-copies](DiffOptions::self_copies).
+    SelfCopy {
+        /// Where in the output to copy from.
+        offset: u64,
+        /// How many bytes to copy.
+        len: u64,
+    },
+    /// The end of the delta.
+    End,
+}
+
+/// Writes a delta in the librsync format from commands worked out elsewhere, e.g. matches found
+/// with a different chunking scheme, so that [apply()](crate::apply) or librsync can apply it.
+///
+/// Copies of adjacent ranges of the base are merged into one command, as [diff()] does.
+///
+/// ```
+/// use fast_rsync::{apply, DeltaWriter};
+///
+/// let base = b"hello world";
+/// let mut writer = DeltaWriter::new(vec![]).unwrap();
+/// writer.copy(0, 6).unwrap();
+/// writer.literal(b"there").unwrap();
+/// let delta = writer.finish().unwrap();
+/// let mut out = vec![];
+/// apply(base, &delta, &mut out).unwrap();
+/// assert_eq!(out, b"hello there");
+/// ```
+#[derive(Debug)]
+pub struct DeltaWriter<W: Write> {
+    out: W,
+    /// A copy which is held back in case the next one extends it.
+    queued_copy: Option<(u64, u64)>,
+}
+
+impl<W: Write> DeltaWriter<W> {
+    /// Start a delta, writing its header to `out`.
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(&DELTA_MAGIC.to_be_bytes())?;
+        Ok(DeltaWriter {
+            out,
+            queued_copy: None,
+        })
+    }
+
+    /// Append `len` bytes from `offset` in the base to the output.
+    pub fn copy(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        if let Some((queued_offset, queued_len)) = &mut self.queued_copy {
+            if queued_offset.checked_add(*queued_len) == Some(offset) {
+                if let Some(sum) = queued_len.checked_add(len) {
+                    *queued_len = sum;
+                    return Ok(());
+                }
+            }
+        }
+        self.flush_copy()?;
+        self.queued_copy = Some((offset, len));
+        Ok(())
+    }
+
+    /// Append `data` to the output.
+    pub fn literal(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.flush_copy()?;
+        insert_command(data.len() as u64, &mut self.out)?;
+        self.out.write_all(data)
+    }
+
+    /// End the delta, and return the writer it was written to.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_copy()?;
+        self.out.write_all(&[RS_OP_END])?;
+        Ok(self.out)
+    }
+
+    fn flush_copy(&mut self) -> io::Result<()> {
+        match self.queued_copy.take() {
+            Some((offset, len)) => copy_command(RS_OP_COPY_N1_N1, offset, len, &mut self.out),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Where [OutputState] sends the commands of a delta.
+trait CommandSink {
+    fn command(&mut self, command: DiffCommand<'_>) -> io::Result<()>;
+    /// Copy `len` bytes from `offset` in the output, for [DiffOptions::self_copies].
+    fn copy_output(&mut self, offset: u64, len: u64) -> io::Result<()>;
+    /// End the delta, following the end command with `checksum` if there is one.
+    fn finish(&mut self, checksum: Option<[u8; BLAKE2_SIZE]>) -> io::Result<()>;
+}
+
+/// Writes deltas in the librsync format, or its extensions.
+impl<W: Write> CommandSink for W {
+    fn command(&mut self, command: DiffCommand<'_>) -> io::Result<()> {
+        match command {
+            DiffCommand::Copy { offset, len } => copy_command(RS_OP_COPY_N1_N1, offset, len, self),
+            DiffCommand::Literal(literal) => {
+                insert_command(literal.len() as u64, self)?;
+                self.write_all(literal)
+            }
+        }
+    }
+
+    fn copy_output(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        copy_command(RS_OP_SELF_COPY_N1_N1, offset, len, self)
+    }
+
+    fn finish(&mut self, checksum: Option<[u8; BLAKE2_SIZE]>) -> io::Result<()> {
+        self.write_all(&[RS_OP_END])?;
+        if let Some(checksum) = checksum {
+            self.write_all(&checksum)?;
+        }
+        self.flush()
+    }
+}
+
+/// Passes the commands of a delta to a callback, for [diff_commands()].
+struct CommandCallback<F>(F);
+
+impl<F: FnMut(DiffCommand<'_>) -> io::Result<()>> CommandSink for CommandCallback<F> {
+    fn command(&mut self, command: DiffCommand<'_>) -> io::Result<()> {
+        (self.0)(command)
+    }
+
+    fn copy_output(&mut self, _offset: u64, _len: u64) -> io::Result<()> {
+        unreachable!("diff_commands() doesn't look for self-copies")
+    }
+
+    fn finish(&mut self, _checksum: Option<[u8; BLAKE2_SIZE]>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Where [diff_impl()] sends a delta.
+trait DeltaOutput: CommandSink {
+    /// Begin the delta, which starts with `magic` if it is serialized.
+    fn start(&mut self, magic: u32) -> io::Result<()>;
+    /// How many bytes of the delta have been written, if it is serialized.
+    fn written(&self) -> u64;
+}
+
+impl<W: Write> DeltaOutput for CountingWriter<W> {
+    fn start(&mut self, magic: u32) -> io::Result<()> {
+        self.write_all(&magic.to_be_bytes())
+    }
+
+    fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+impl<F: FnMut(DiffCommand<'_>) -> io::Result<()>> DeltaOutput for CommandCallback<F> {
+    fn start(&mut self, _magic: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn written(&self) -> u64 {
+        0
+    }
+}
+
 struct OutputState {
+    /// Everything in `data` before this has either been written or is covered by `queued_copy`.
     emitted: usize,
+    /// A copy ending at `emitted`, which is held back in case the next match extends it.
     queued_copy: Option<(u64, usize)>,
+    /// The number of input bytes which were dropped from the front of `data` so far.
+    discarded: u64,
+    literal_segment_size: Option<NonZeroUsize>,
+    min_copy_len: usize,
+    #[cfg(feature = "zstd")]
+    compress_literals: Option<i32>,
+    self_copies: Option<SelfMatcher>,
+    /// The hash of the input so far, if the delta ends with one.
+    checksum: Option<Blake2Hasher>,
 }
 
 impl OutputState {
-    fn emit(&mut self, until: usize, data: &[u8], mut out: impl Write) -> io::Result<()> {
-        if self.emitted == until {
-            return Ok(());
+    fn new(options: &DiffOptions, block_size: usize) -> Self {
+        OutputState {
+            emitted: 0,
+            queued_copy: None,
+            discarded: 0,
+            literal_segment_size: options.literal_segment_size,
+            min_copy_len: options.min_copy_len.map_or(0, NonZeroUsize::get),
+            #[cfg(feature = "zstd")]
+            compress_literals: options.compress_literals,
+            self_copies: if options.self_copies {
+                Some(SelfMatcher::new(block_size, options))
+            } else {
+                None
+            },
+            checksum: if options.checksum {
+                Some(Blake2Hasher::default())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// The magic number the delta starts with.
+    fn magic(&self) -> u32 {
+        let mut extensions = 0;
+        #[cfg(feature = "zstd")]
+        if self.compress_literals.is_some() {
+            extensions |= DELTA_ZSTD_LITERALS;
+        }
+        if self.self_copies.is_some() {
+            extensions |= DELTA_SELF_COPIES;
+        }
+        if self.checksum.is_some() {
+            extensions |= DELTA_CHECKSUM;
+        }
+        if extensions == 0 {
+            DELTA_MAGIC
+        } else {
+            EXTENDED_DELTA_MAGIC | extensions
+        }
+    }
+
+    /// Add the next piece of the input to the checksum, if the delta ends with one.
+    fn hash_input(&mut self, data: &[u8]) {
+        if let Some(checksum) = &mut self.checksum {
+            checksum.update(data);
+        }
+    }
+
+    /// Write the end of the delta, once everything else is written.
+    fn finish(&mut self, out: &mut impl CommandSink) -> io::Result<()> {
+        out.finish(self.checksum.as_ref().map(Blake2Hasher::finalize))
+    }
+
+    /// Whether each literal takes up at least as many bytes of the delta as of `data`.
+    fn literals_verbatim(&self) -> bool {
+        #[cfg(feature = "zstd")]
+        if self.compress_literals.is_some() {
+            return false;
+        }
+        self.self_copies.is_none()
+    }
+
+    fn emit(&mut self, until: usize, data: &[u8], out: &mut impl CommandSink) -> io::Result<()> {
+        if let Some((offset, len)) = self.queued_copy.take() {
+            if len >= self.min_copy_len {
+                out.command(DiffCommand::Copy {
+                    offset,
+                    len: len as u64,
+                })?;
+            } else {
+                // too short, so send it along with the literals below
+                self.emitted -= len;
+            }
         }
-        if let Some((offset, len)) = self.queued_copy {
-            copy_command(offset as u64, len as u64, &mut out)?;
-            self.emitted += len as usize;
+        if let Some(mut matcher) = self.self_copies.take() {
+            while let Some((start, source, len)) =
+                matcher.find(data, self.discarded, self.emitted, until)
+            {
+                self.emit_literals(start, data, out)?;
+                out.copy_output(source, len as u64)?;
+                self.emitted = start + len;
+            }
+            self.self_copies = Some(matcher);
         }
-        if self.emitted < until {
-            let to_emit = &data[self.emitted..until];
-            insert_command(to_emit.len() as u64, &mut out)?;
-            out.write_all(to_emit)?;
-            self.emitted = until;
+        self.emit_literals(until, data, out)
+    }
+
+    /// Write `data` from `emitted` up to `until` as literals.
+    fn emit_literals(
+        &mut self,
+        until: usize,
+        data: &[u8],
+        out: &mut impl CommandSink,
+    ) -> io::Result<()> {
+        while self.emitted < until {
+            let end = match self.literal_segment_size {
+                Some(segment) => {
+                    let segment = segment.get();
+                    let position = self.discarded + self.emitted as u64;
+                    let into_segment = (position % segment as u64) as usize;
+                    until.min(self.emitted.saturating_add(segment - into_segment))
+                }
+                None => until,
+            };
+            let to_emit = &data[self.emitted..end];
+            #[cfg(feature = "zstd")]
+            if let Some(level) = self.compress_literals {
+                let compressed = zstd::bulk::compress(to_emit, level)?;
+                out.command(DiffCommand::Literal(&compressed))?;
+                self.emitted = end;
+                continue;
+            }
+            out.command(DiffCommand::Literal(to_emit))?;
+            self.emitted = end;
         }
 
         Ok(())
@@ -135,92 +661,1999 @@ impl OutputState {
         len: usize,
         here: usize,
         data: &[u8],
-        out: &mut impl Write,
+        out: &mut impl CommandSink,
     ) -> io::Result<()> {
-        if let Some((queued_offset, queued_len)) = self.queued_copy {
-            if self.emitted + queued_len == here && queued_offset + queued_len as u64 == offset {
+        if let Some((queued_offset, queued_len)) = &mut self.queued_copy {
+            if self.emitted == here && *queued_offset + *queued_len as u64 == offset {
                 // just extend the copy
-                self.queued_copy = Some((queued_offset, queued_len + len));
+                *queued_len += len;
+                self.emitted += len;
                 return Ok(());
             }
         }
         self.emit(here, data, out)?;
         self.queued_copy = Some((offset, len));
+        self.emitted += len;
 
         Ok(())
     }
+
+    /// The first position in `data` which may still be needed to write the output.
+    fn needed_from(&self) -> usize {
+        match self.queued_copy {
+            Some((_, len)) if len < self.min_copy_len => self.emitted - len,
+            _ => self.emitted,
+        }
+    }
 }
 
 /// Calculate a delta and write it to `out`.
 /// This delta can be applied to the base data represented by `signature` to
 /// attempt to reconstruct `data`.
 ///
+/// The delta is written to `out` as it is worked out, without buffering it, so a writer which
+/// blocks until it can take more, like a socket, holds the diff back rather than letting the
+/// delta pile up in memory. `out` is flushed once the delta is complete (see
+/// [DiffOptions::flush_interval] to flush it along the way). Writers which fail with
+/// [io::ErrorKind::WouldBlock] aren't supported; `diff_async`, with the `tokio` feature, or
+/// [DiffState] suit non-blocking output instead.
+///
 /// # Security
 /// Since `fast_rsync` uses the insecure MD4 hash algorithm, the resulting delta must not be
 /// trusted to correctly reconstruct `data`. The delta might fail to apply or produce the wrong
 /// data entirely. Always use another mechanism, like a cryptographic hash function, to validate
 /// the final reconstructed data.
-pub fn diff(
-    signature: &IndexedSignature<'_>,
-    data: &[u8],
+pub fn diff(signature: &impl BlockIndex, data: &[u8], out: impl Write) -> Result<(), DiffError> {
+    diff_with_options(signature, data, out, &DiffOptions::default())
+}
+
+/// Like [diff()], but reads the data from `data` rather than needing it all in memory.
+///
+/// `data` is read a few blocks at a time, and only a bounded part of it is held at once (see
+/// [DiffState]), so this works for files or streams of any size.
+///
+/// # Security
+/// The caveats for [diff()] apply here as well.
+pub fn diff_from_reader(
+    signature: &impl BlockIndex,
+    data: impl Read,
+    out: impl Write,
+) -> Result<(), DiffError> {
+    diff_reader_with_options(signature, data, out, &DiffOptions::default())
+}
+
+/// Like [diff_from_reader()], but with the options of [diff_with_options()].
+pub(crate) fn diff_reader_with_options(
+    signature: &impl BlockIndex,
+    mut data: impl Read,
     mut out: impl Write,
+    options: &DiffOptions,
 ) -> Result<(), DiffError> {
-    let block_size = signature.block_size;
-    let crypto_hash_size = signature.crypto_hash_size as usize;
-    if let SignatureType::Md4 = signature.signature_type {
-        if crypto_hash_size > MD4_SIZE {
-            return Err(DiffError::InvalidSignature);
+    let mut state = DiffState::new(signature, options)?;
+    let mut buf = vec![0; read_size(&signature.options())];
+    let mut delta = Vec::new();
+    loop {
+        let n = match data.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        state.feed(&buf[..n], &mut delta)?;
+        out.write_all(&delta)?;
+        delta.clear();
+    }
+    state.finish(&mut delta)?;
+    out.write_all(&delta)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Like [diff_from_reader()], but reading and writing asynchronously, and with the options of
+/// [diff_with_options()].
+///
+/// Between pieces of `data`, which are at most 1 MiB, this yields to the executor, so that a
+/// large diff doesn't hold up other tasks on the same thread even if `data` is always ready.
+/// As with [DiffState], the [max_size_ratio](DiffOptions::max_size_ratio) option is ignored.
+///
+/// # Security
+/// The caveats for [diff()] apply here as well.
+#[cfg(feature = "tokio")]
+pub async fn diff_async<R, W>(
+    signature: &impl BlockIndex,
+    mut data: R,
+    mut out: W,
+    options: &DiffOptions,
+) -> Result<(), DiffError>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut state = DiffState::new(signature, options)?;
+    let mut buf = vec![0; read_size(&signature.options())];
+    let mut delta = Vec::new();
+    let mut room = options.max_delta_len.unwrap_or(u64::MAX);
+    loop {
+        let n = data.read(&mut buf).await?;
+        if n == 0 {
+            break;
         }
-    } else {
+        state.feed(&buf[..n], &mut delta)?;
+        room = room
+            .checked_sub(delta.len() as u64)
+            .ok_or(DiffError::OutputLimit)?;
+        out.write_all(&delta).await?;
+        delta.clear();
+        YieldNow(false).await;
+    }
+    state.finish(&mut delta)?;
+    if delta.len() as u64 > room {
+        return Err(DiffError::OutputLimit);
+    }
+    out.write_all(&delta).await?;
+    out.flush().await?;
+    Ok(())
+}
+
+/// How much of `data` to read at a time: a few blocks, within reason.
+fn read_size(signature_options: &SignatureOptions) -> usize {
+    (signature_options.block_size as usize)
+        .saturating_mul(4)
+        .clamp(4 << 10, 1 << 20)
+}
+
+/// A future which lets the executor run other tasks before it completes, without depending on
+/// a particular runtime.
+#[cfg(feature = "tokio")]
+pub(crate) struct YieldNow(pub(crate) bool);
+
+#[cfg(feature = "tokio")]
+impl std::future::Future for YieldNow {
+    type Output = ();
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<()> {
+        if self.0 {
+            return std::task::Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        std::task::Poll::Pending
+    }
+}
+
+/// Like [diff_with_options()], but searching for matches on the rayon thread pool.
+///
+/// `data` is split into segments of a few MiB which are searched independently, and the matches
+/// from all of them are then stitched into one delta. Where a match runs across the end of a
+/// segment, up to a block of the next segment may be sent as literals even if [diff()] would have
+/// matched it, so the delta can be slightly larger.
+///
+/// All of `options` apply except [extend_matches](DiffOptions::extend_matches), which needs the
+/// base. [cancel](DiffOptions::cancel) is checked as each segment is started.
+///
+/// # Security
+/// The caveats for [diff()] apply here as well.
+#[cfg(feature = "rayon")]
+pub fn diff_parallel(
+    signature: &(impl BlockIndex + Sync),
+    data: &[u8],
+    out: impl Write,
+    options: &DiffOptions,
+) -> Result<(), DiffError> {
+    let signature_type = check_builtin_signature(&signature.options(), options)?;
+    check_complete(signature)?;
+    let crypto_hash = BuiltinHash::new(signature_type, options.hash_key);
+    let mut out = CountingWriter::limited(out, options.max_delta_len);
+    out.flush_interval = options.flush_interval.map_or(u64::MAX, NonZeroU64::get);
+    match signature.options().rolling_hash {
+        RollingHash::Rollsum => {
+            diff_parallel_impl::<Crc>(signature, data, out, options, crypto_hash)
+        }
+        RollingHash::RabinKarp => {
+            diff_parallel_impl::<RabinKarp>(signature, data, out, options, crypto_hash)
+        }
+    }
+}
+
+/// Calculate the size of the delta [diff()] would produce, without producing it.
+///
+/// Nothing is allocated for the delta or copied into it, so this is a cheap way to decide whether
+/// sending a delta is worthwhile before setting up somewhere to write it. It takes as long as
+/// the diff itself, though; [similarity()] gives a rougher answer much faster.
+pub fn estimate_delta_size(signature: &impl BlockIndex, data: &[u8]) -> Result<u64, DiffError> {
+    let mut counter = CountingWriter::new(io::sink());
+    diff(signature, data, &mut counter)?;
+    Ok(counter.written)
+}
+
+/// Estimate the fraction of `data` which a delta against the base represented by `signature`
+/// could copy from the base, between 0 and 1.
+///
+/// For large inputs, this only looks for matches around a sample of about a thousand positions
+/// in `data`, so it is much faster than a diff, and suitable for choosing the most similar of
+/// several candidate bases. Empty data counts as entirely similar.
+///
+/// Of `options`, only those which decide what a match is apply, such as
+/// [hash_key](DiffOptions::hash_key) for keyed signatures.
+pub fn similarity(
+    signature: &impl BlockIndex,
+    data: &[u8],
+    options: &DiffOptions,
+) -> Result<f64, DiffError> {
+    let signature_type = check_builtin_signature(&signature.options(), options)?;
+    check_complete(signature)?;
+    let crypto_hash = BuiltinHash::new(signature_type, options.hash_key);
+    Ok(match signature.options().rolling_hash {
+        RollingHash::Rollsum => similarity_impl::<Crc>(signature, data, options, crypto_hash)?,
+        RollingHash::RabinKarp => {
+            similarity_impl::<RabinKarp>(signature, data, options, crypto_hash)?
+        }
+    })
+}
+
+fn similarity_impl<R: RollingChecksum>(
+    signature: &impl BlockIndex,
+    data: &[u8],
+    options: &DiffOptions,
+    crypto_hash: impl CryptoHash,
+) -> Result<f64, DiffError> {
+    const SIMILARITY_SAMPLES: usize = 1024;
+    if data.is_empty() {
+        return Ok(1.0);
+    }
+    let signature_options = signature.options();
+    let block_size = signature_options.block_size as usize;
+    let mut scanner = Scanner::<R>::new(&signature_options, options);
+    if data.len() / SIMILARITY_SAMPLES <= 2 * block_size {
+        // small enough that sampling wouldn't save much
+        let (mut matched, mut matched_to) = (0, 0);
+        scanner.scan(signature, data, 0, &crypto_hash, |here, _| {
+            matched += block_size;
+            matched_to = here + block_size;
+            Ok(block_size)
+        })?;
+        // the base's final block may be partial, as diff() matches it at the end of the data
+        if let Some((here, _)) = scanner.scan_tail(signature, data, matched_to, 0, &crypto_hash)? {
+            matched += data.len() - here;
+        }
+        return Ok(matched as f64 / data.len() as f64);
+    }
+    let mut covered = 0;
+    for i in 0..SIMILARITY_SAMPLES {
+        let sample = (2 * i + 1) * (data.len() / SIMILARITY_SAMPLES) / 2;
+        // every window which could include `sample`
+        let start = (sample + 1).saturating_sub(block_size);
+        let end = (sample + block_size).min(data.len());
+        let mut found = false;
+        scanner.here = 0;
+        scanner.sum = None;
+        scanner.scan(
+            signature,
+            &data[start..end],
+            start as u64,
+            &crypto_hash,
+            |_, _| {
+                found = true;
+                Ok(block_size)
+            },
+        )?;
+        if !found && end == data.len() {
+            let tail = scanner.scan_tail(signature, data, start, 0, &crypto_hash)?;
+            found = tail.map_or(false, |(here, _)| here <= sample);
+        }
+        covered += found as usize;
+    }
+    Ok(covered as f64 / SIMILARITY_SAMPLES as f64)
+}
+
+/// A writer which counts how many bytes pass through it.
+struct CountingWriter<W> {
+    inner: W,
+    written: u64,
+    /// Writes which would take `written` past this fail instead, with [OutputLimitExceeded].
+    limit: u64,
+    /// The inner writer is flushed once this many bytes have been written since the last flush.
+    flush_interval: u64,
+    unflushed: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self::limited(inner, None)
+    }
+
+    fn limited(inner: W, limit: Option<u64>) -> Self {
+        CountingWriter {
+            inner,
+            written: 0,
+            limit: limit.unwrap_or(u64::MAX),
+            flush_interval: u64::MAX,
+            unflushed: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.limit - self.written {
+            return Err(io::Error::other(OutputLimitExceeded));
+        }
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        self.unflushed += n as u64;
+        if self.unflushed >= self.flush_interval {
+            self.flush()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.unflushed = 0;
+        self.inner.flush()
+    }
+}
+
+/// Like [diff()], but with additional control over the produced delta.
+///
+/// # Security
+/// The caveats for [diff()] apply here as well.
+pub fn diff_with_options(
+    signature: &impl BlockIndex,
+    data: &[u8],
+    out: impl Write,
+    options: &DiffOptions,
+) -> Result<(), DiffError> {
+    let signature_type = check_builtin_signature(&signature.options(), options)?;
+    let crypto_hash = BuiltinHash::new(signature_type, options.hash_key);
+    diff_dispatch(signature, None, data, out, options, crypto_hash, |_| {})
+}
+
+/// Like [diff()], but fails with [DiffError::OutputLimit] rather than write a delta longer than
+/// `max_delta_len` bytes. This gives up early when the delta is going to be bigger than is worth
+/// sending, e.g. bigger than `data` itself.
+///
+/// # Security
+/// The caveats for [diff()] apply here as well.
+pub fn diff_limited(
+    signature: &impl BlockIndex,
+    data: &[u8],
+    out: impl Write,
+    max_delta_len: usize,
+) -> Result<(), DiffError> {
+    let options = DiffOptions {
+        max_delta_len: Some(max_delta_len as u64),
+        ..Default::default()
+    };
+    diff_with_options(signature, data, out, &options)
+}
+
+/// How far a diff has got, as reported to the callback of [diff_with_progress()].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DiffProgress {
+    /// How many bytes of `data` have been searched for matches.
+    pub consumed: u64,
+    /// How many bytes of delta have been written.
+    pub emitted: u64,
+}
+
+/// Like [diff_with_options()], but calling `progress` every so often, and once at the end.
+///
+/// # Security
+/// The caveats for [diff()] apply here as well.
+pub fn diff_with_progress(
+    signature: &impl BlockIndex,
+    data: &[u8],
+    out: impl Write,
+    options: &DiffOptions,
+    progress: impl FnMut(DiffProgress),
+) -> Result<(), DiffError> {
+    let signature_type = check_builtin_signature(&signature.options(), options)?;
+    let crypto_hash = BuiltinHash::new(signature_type, options.hash_key);
+    diff_dispatch(signature, None, data, out, options, crypto_hash, progress)
+}
+
+/// Like [diff_with_options()], but with the base data at hand as well as its signature, which
+/// allows [extending](DiffOptions::extend_matches) matches beyond whole blocks.
+///
+/// `base` must be the data `signature` was calculated from.
+///
+/// # Security
+/// The caveats for [diff()] apply here as well.
+pub fn diff_with_base(
+    signature: &impl BlockIndex,
+    base: &[u8],
+    data: &[u8],
+    out: impl Write,
+    options: &DiffOptions,
+) -> Result<(), DiffError> {
+    let signature_type = check_builtin_signature(&signature.options(), options)?;
+    let crypto_hash = BuiltinHash::new(signature_type, options.hash_key);
+    diff_dispatch(
+        signature,
+        Some(base),
+        data,
+        out,
+        options,
+        crypto_hash,
+        |_| {},
+    )
+}
+
+/// Like [diff_with_options()], for a signature calculated by
+/// [Signature::calculate_with_digest](crate::Signature::calculate_with_digest) with the strong
+/// hash `D`.
+///
+/// # Security
+/// The caveats for [diff()] apply here as well, unless `D` is collision resistant.
+#[cfg(feature = "digest")]
+pub fn diff_with_digest<D: digest::Digest>(
+    signature: &impl BlockIndex,
+    data: &[u8],
+    out: impl Write,
+    options: &DiffOptions,
+) -> Result<(), DiffError> {
+    let signature_options = signature.options();
+    if signature_options.hash != SignatureHash::Custom {
         return Err(DiffError::InvalidSignature);
     }
-    out.write_all(&DELTA_MAGIC.to_be_bytes())?;
-    let mut state = OutputState {
-        emitted: 0,
-        queued_copy: None,
+    check_signature(&signature_options, <D as digest::Digest>::output_size())?;
+    check_features(options)?;
+    diff_dispatch(
+        signature,
+        None,
+        data,
+        out,
+        options,
+        DigestHash::<D>(PhantomData),
+        |_| {},
+    )
+}
+
+/// Like [diff_with_options()], but passing the commands of the delta to `f` in order rather than
+/// serializing them. Copies of adjacent blocks are merged, as in a serialized delta.
+///
+/// The options which only affect the serialized format are ignored:
+/// [max_delta_len](DiffOptions::max_delta_len), [self_copies](DiffOptions::self_copies) and
+/// [checksum](DiffOptions::checksum), as well as `compress_literals`. An error returned by `f`
+/// ends the diff, as [DiffError::Io].
+///
+/// # Security
+/// The caveats for [diff()] apply here as well.
+pub fn diff_commands(
+    signature: &impl BlockIndex,
+    data: &[u8],
+    options: &DiffOptions,
+    f: impl FnMut(DiffCommand<'_>) -> io::Result<()>,
+) -> Result<(), DiffError> {
+    let signature_type = check_builtin_signature(&signature.options(), options)?;
+    check_complete(signature)?;
+    let crypto_hash = BuiltinHash::new(signature_type, options.hash_key);
+    let options = DiffOptions {
+        max_delta_len: None,
+        self_copies: false,
+        checksum: false,
+        compress_literals: None,
+        ..options.clone()
     };
-    let mut here = 0;
-    let mut collisions: HashMap<Crc, u32, BuildCrcHasher> =
-        HashMap::with_hasher(BuildCrcHasher::default());
-    while data.len() - here >= block_size as usize {
-        let mut crc = Crc::new().update(&data[here..here + block_size as usize]);
-        loop {
-            // if we detect too many CRC collisions, blacklist the CRC to avoid DoS
-            if collisions
-                .get(&crc)
-                .map_or(true, |&count| count < MAX_CRC_COLLISIONS)
-            {
-                if let Some(blocks) = signature.blocks.get(&crc) {
-                    let digest = md4(&data[here..here + block_size as usize]);
-                    if let Some(&idx) = blocks.get(&&digest[..crypto_hash_size]) {
-                        // match found
-                        state.copy(
-                            idx as u64 * block_size as u64,
-                            block_size as usize,
-                            here,
-                            data,
-                            &mut out,
-                        )?;
-                        here += block_size as usize;
-                        break;
-                    }
-                    // CRC collision
-                    *collisions.entry(crc).or_insert(0) += 1;
-                }
-            }
-            // no match, try to extend
-            here += 1;
-            if here + block_size as usize > data.len() {
-                break;
-            }
-            crc = crc.rotate(
-                block_size,
-                data[here - 1],
-                data[here + block_size as usize - 1],
-            );
+    let out = CommandCallback(f);
+    match signature.options().rolling_hash {
+        RollingHash::Rollsum => {
+            diff_impl::<Crc>(signature, None, data, out, &options, crypto_hash, |_| {})
+        }
+        RollingHash::RabinKarp => {
+            diff_impl::<RabinKarp>(signature, None, data, out, &options, crypto_hash, |_| {})
         }
     }
-    state.emit(data.len(), data, &mut out)?;
-    out.write_all(&[RS_OP_END])?;
-    Ok(())
+}
+
+/// Check that a signature uses one of the built-in strong hashes, and that `options` has a key
+/// exactly if it is keyed.
+fn check_builtin_signature(
+    signature_options: &SignatureOptions,
+    options: &DiffOptions,
+) -> Result<SignatureType, DiffError> {
+    let signature_type = SignatureType::new(signature_options.rolling_hash, signature_options.hash);
+    let max_crypto_hash_size = signature_type
+        .max_crypto_hash_size()
+        .ok_or(DiffError::InvalidSignature)?;
+    check_signature(signature_options, max_crypto_hash_size)?;
+    check_features(options)?;
+    if options.hash_key.is_some() != (signature_options.hash == SignatureHash::KeyedBlake2) {
+        return Err(DiffError::InvalidSignature);
+    }
+    Ok(signature_type)
+}
+
+/// Check that the options don't need a feature which isn't enabled.
+fn check_features(options: &DiffOptions) -> Result<(), DiffError> {
+    if cfg!(not(feature = "zstd")) && options.compress_literals.is_some() {
+        return Err(DiffError::Unsupported { feature: "zstd" });
+    }
+    Ok(())
+}
+
+fn check_signature(
+    signature_options: &SignatureOptions,
+    max_crypto_hash_size: usize,
+) -> Result<(), DiffError> {
+    if signature_options.block_size == 0
+        || signature_options.crypto_hash_size as usize > max_crypto_hash_size
+    {
+        return Err(DiffError::InvalidSignature);
+    }
+    Ok(())
+}
+
+fn check_complete(signature: &impl BlockIndex) -> Result<(), DiffError> {
+    if signature.is_complete() {
+        Ok(())
+    } else {
+        Err(DiffError::IncompleteIndex)
+    }
+}
+
+fn diff_dispatch(
+    signature: &impl BlockIndex,
+    base: Option<&[u8]>,
+    data: &[u8],
+    out: impl Write,
+    options: &DiffOptions,
+    crypto_hash: impl CryptoHash,
+    progress: impl FnMut(DiffProgress),
+) -> Result<(), DiffError> {
+    check_complete(signature)?;
+    let mut out = CountingWriter::limited(out, options.max_delta_len);
+    out.flush_interval = options.flush_interval.map_or(u64::MAX, NonZeroU64::get);
+    match signature.options().rolling_hash {
+        RollingHash::Rollsum => {
+            diff_impl::<Crc>(signature, base, data, out, options, crypto_hash, progress)
+        }
+        RollingHash::RabinKarp => {
+            diff_impl::<RabinKarp>(signature, base, data, out, options, crypto_hash, progress)
+        }
+    }
+}
+
+/// The strong hash a diff compares windows of the input to blocks of the signature with.
+trait CryptoHash {
+    type Output: AsRef<[u8]>;
+    fn hash(&self, block: &[u8]) -> Self::Output;
+    /// How many blocks are worth hashing at once with [hash_blocks()](Self::hash_blocks), or 1 if
+    /// that is no faster than hashing them one at a time. Only hashes at most [BLAKE2_SIZE] long
+    /// may be hashed in batches.
+    fn batch_size(&self) -> usize {
+        1
+    }
+    /// Hash each `block_size` chunk of `data`, in order.
+    fn hash_blocks(&self, data: &[u8], block_size: usize, mut found: impl FnMut(&[u8])) {
+        for block in data.chunks(block_size) {
+            found(self.hash(block).as_ref());
+        }
+    }
+}
+
+impl<C: CryptoHash> CryptoHash for &C {
+    type Output = C::Output;
+    #[inline]
+    fn hash(&self, block: &[u8]) -> Self::Output {
+        (**self).hash(block)
+    }
+    fn batch_size(&self) -> usize {
+        (**self).batch_size()
+    }
+    fn hash_blocks(&self, data: &[u8], block_size: usize, found: impl FnMut(&[u8])) {
+        (**self).hash_blocks(data, block_size, found)
+    }
+}
+
+/// One of the strong hashes built into this crate, which can use SIMD to hash several blocks at
+/// once.
+#[derive(Copy, Clone)]
+struct BuiltinHash {
+    signature_type: SignatureType,
+    key: Option<HashKey>,
+    batch_size: usize,
+}
+
+impl BuiltinHash {
+    fn new(signature_type: SignatureType, key: Option<HashKey>) -> Self {
+        let batch_size = match signature_type.hash() {
+            SignatureHash::Md4 => md4_many_lanes(),
+            SignatureHash::Blake2 | SignatureHash::KeyedBlake2 => blake2_many_lanes(),
+            _ => 1,
+        };
+        BuiltinHash {
+            signature_type,
+            key,
+            batch_size,
+        }
+    }
+}
+
+impl CryptoHash for BuiltinHash {
+    type Output = [u8; BLAKE2_SIZE];
+    #[inline]
+    fn hash(&self, block: &[u8]) -> Self::Output {
+        self.signature_type.crypto_hash(self.key.as_ref(), block)
+    }
+    fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+    fn hash_blocks(&self, data: &[u8], block_size: usize, mut found: impl FnMut(&[u8])) {
+        match self.signature_type.hash() {
+            SignatureHash::Md4 => {
+                for (_, hash) in md4_many(data.chunks(block_size)) {
+                    found(&hash);
+                }
+            }
+            SignatureHash::Blake2 | SignatureHash::KeyedBlake2 => {
+                let key = self.key.as_ref().map(|key| &key.0[..]);
+                for (_, hash) in blake2_many(key, data, block_size) {
+                    found(&hash);
+                }
+            }
+            _ => {
+                for block in data.chunks(block_size) {
+                    found(&self.hash(block));
+                }
+            }
+        }
+    }
+}
+
+/// A strong hash implemented with the [digest] crate, for [diff_with_digest()].
+#[cfg(feature = "digest")]
+struct DigestHash<D>(PhantomData<D>);
+
+#[cfg(feature = "digest")]
+impl<D: digest::Digest> CryptoHash for DigestHash<D> {
+    type Output = digest::Output<D>;
+    #[inline]
+    fn hash(&self, block: &[u8]) -> Self::Output {
+        D::digest(block)
+    }
+}
+
+/// A rolling checksum which can be used to search for blocks from a signature.
+trait RollingChecksum: Copy {
+    fn of(block: &[u8], seed: Option<&SeedTable>) -> Self;
+    fn rotate(self, size: u32, old_byte: u8, new_byte: u8) -> Self;
+    fn rollout(self, size: u32, old_byte: u8) -> Self;
+    fn digest(self) -> u32;
+}
+
+impl RollingChecksum for Crc {
+    #[inline]
+    fn of(block: &[u8], seed: Option<&SeedTable>) -> Self {
+        match seed {
+            Some(seed) => Crc::new().update_seeded(seed, block),
+            None => Crc::new().update(block),
+        }
+    }
+    #[inline]
+    fn rotate(self, size: u32, old_byte: u8, new_byte: u8) -> Self {
+        Crc::rotate(self, size, old_byte, new_byte)
+    }
+    #[inline]
+    fn rollout(self, size: u32, old_byte: u8) -> Self {
+        Crc::rollout(self, size, old_byte)
+    }
+    #[inline]
+    fn digest(self) -> u32 {
+        self.0
+    }
+}
+
+impl RollingChecksum for RabinKarp {
+    #[inline]
+    fn of(block: &[u8], seed: Option<&SeedTable>) -> Self {
+        match seed {
+            Some(seed) => RabinKarp::new().update_seeded(seed, block),
+            None => RabinKarp::new().update(block),
+        }
+    }
+    #[inline]
+    fn rotate(self, _size: u32, old_byte: u8, new_byte: u8) -> Self {
+        RabinKarp::rotate(self, old_byte, new_byte)
+    }
+    #[inline]
+    fn rollout(self, _size: u32, old_byte: u8) -> Self {
+        RabinKarp::rollout(self, old_byte)
+    }
+    #[inline]
+    fn digest(self) -> u32 {
+        RabinKarp::digest(self)
+    }
+}
+
+/// How often each rolling checksum matched without the strong hash matching, so that checksums
+/// which keep colliding can be ignored.
+struct CollisionCounts {
+    counts: HashMap<u32, u32, BuildCrcHasher>,
+    max_collisions: u32,
+    max_tracked: usize,
+    /// Set once the counts are full of checksums which are already ignored.
+    full: bool,
+}
+
+impl CollisionCounts {
+    fn new(options: &DiffOptions) -> Self {
+        CollisionCounts {
+            counts: HashMap::with_hasher(BuildCrcHasher::default()),
+            max_collisions: options
+                .max_weak_sum_collisions
+                .unwrap_or(MAX_CRC_COLLISIONS),
+            max_tracked: options
+                .max_tracked_collisions
+                .map_or(MAX_TRACKED_COLLISIONS, NonZeroUsize::get),
+            full: false,
+        }
+    }
+
+    /// Save the counts, so that checksums which were being ignored still are once the diff is
+    /// resumed.
+    fn write_checkpoint(&self, out: &mut Vec<u8>) {
+        out.push(self.full as u8);
+        out.extend_from_slice(&(self.counts.len() as u64).to_be_bytes());
+        for (&weak_sum, &count) in &self.counts {
+            out.extend_from_slice(&weak_sum.to_be_bytes());
+            out.extend_from_slice(&count.to_be_bytes());
+        }
+    }
+
+    /// Restore the counts saved by [write_checkpoint()](Self::write_checkpoint).
+    fn read_checkpoint(&mut self, checkpoint: &mut CheckpointReader<'_>) -> Option<()> {
+        self.full = checkpoint.flag()?;
+        for _ in 0..checkpoint.u64()? {
+            let (weak_sum, count) = (checkpoint.u32()?, checkpoint.u32()?);
+            self.counts.insert(weak_sum, count);
+        }
+        Some(())
+    }
+
+    /// Whether `weak_sum` is still worth looking up.
+    #[inline]
+    fn allows(&self, weak_sum: u32) -> bool {
+        self.counts
+            .get(&weak_sum)
+            .map_or(self.max_collisions > 0, |&count| {
+                count < self.max_collisions
+            })
+    }
+
+    fn record(&mut self, weak_sum: u32) {
+        if let Some(count) = self.counts.get_mut(&weak_sum) {
+            *count += 1;
+            return;
+        }
+        if self.counts.len() >= self.max_tracked {
+            if self.full {
+                return;
+            }
+            // Forget the checksums which haven't been blacklisted yet. Each of those can cost
+            // another `max_collisions` lookups, but blacklisting is only a safeguard anyway.
+            let max_collisions = self.max_collisions;
+            self.counts.retain(|_, &mut count| count >= max_collisions);
+            if self.counts.len() > self.max_tracked / 2 {
+                // Clearing out so few entries again and again would take quadratic time.
+                self.full = true;
+                return;
+            }
+        }
+        self.counts.insert(weak_sum, 1);
+    }
+}
+
+/// Finds data which repeats earlier unmatched data, for [DiffOptions::self_copies].
+struct SelfMatcher {
+    block_size: usize,
+    /// The position in the whole input of the last indexed block with each rolling checksum.
+    /// Only unmatched blocks at multiples of the block size are indexed.
+    blocks: HashMap<u32, u64, BuildCrcHasher>,
+    /// The position up to which unmatched data has been indexed.
+    indexed_to: u64,
+    collisions: CollisionCounts,
+}
+
+impl SelfMatcher {
+    fn new(block_size: usize, options: &DiffOptions) -> Self {
+        SelfMatcher {
+            block_size,
+            blocks: HashMap::with_hasher(BuildCrcHasher::default()),
+            indexed_to: 0,
+            collisions: CollisionCounts::new(options),
+        }
+    }
+
+    /// Find the first window of `data[start..end]` which repeats an indexed block, and index the
+    /// blocks of `data[start..end]` before it. Returns the position of the window in `data`, the
+    /// position of the block it repeats in the whole input, and how long the repeat is.
+    ///
+    /// `discarded` is where `data` starts in the whole input.
+    fn find(
+        &mut self,
+        data: &[u8],
+        discarded: u64,
+        start: usize,
+        end: usize,
+    ) -> Option<(usize, u64, usize)> {
+        let block_size = self.block_size;
+        let first_block = (discarded + start as u64).div_ceil(block_size as u64);
+        self.indexed_to = self.indexed_to.max(first_block * block_size as u64);
+        if end - start < block_size {
+            return None;
+        }
+        let mut here = start;
+        let mut sum = Crc::of(&data[here..here + block_size], None);
+        loop {
+            self.index(data, discarded, here);
+            let weak_sum = sum.digest();
+            let position = discarded + here as u64;
+            match self.blocks.get(&weak_sum) {
+                Some(&source)
+                    if source >= discarded
+                        && position - source <= SELF_COPY_WINDOW
+                        && self.collisions.allows(weak_sum) =>
+                {
+                    let from = (source - discarded) as usize;
+                    if data[from..from + block_size] == data[here..here + block_size] {
+                        // extend it as far as it goes without overlapping itself
+                        let len = block_size
+                            + data[here + block_size..end]
+                                .iter()
+                                .zip(&data[from + block_size..here])
+                                .take_while(|(a, b)| a == b)
+                                .count();
+                        return Some((here, source, len));
+                    }
+                    self.collisions.record(weak_sum);
+                }
+                _ => {}
+            }
+            if here + block_size >= end {
+                break;
+            }
+            sum = sum.rotate(block_size as u32, data[here], data[here + block_size]);
+            here += 1;
+        }
+        self.index(data, discarded, end);
+        None
+    }
+
+    fn write_checkpoint(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.indexed_to.to_be_bytes());
+        out.extend_from_slice(&(self.blocks.len() as u64).to_be_bytes());
+        for (&weak_sum, &position) in &self.blocks {
+            out.extend_from_slice(&weak_sum.to_be_bytes());
+            out.extend_from_slice(&position.to_be_bytes());
+        }
+        self.collisions.write_checkpoint(out);
+    }
+
+    /// Restore the matcher, given that the input up to `needed_from` has been written, and
+    /// `end` is the end of the input so far, both as positions in the whole input.
+    fn read_checkpoint(
+        &mut self,
+        checkpoint: &mut CheckpointReader<'_>,
+        needed_from: u64,
+        end: u64,
+    ) -> Option<()> {
+        let block_size = self.block_size as u64;
+        self.indexed_to = checkpoint.u64()?;
+        // rounded up to a block past what was written at most
+        if self.indexed_to > end.checked_add(block_size)? {
+            return None;
+        }
+        for _ in 0..checkpoint.u64()? {
+            let (weak_sum, position) = (checkpoint.u32()?, checkpoint.u64()?);
+            // only blocks of what was already written are indexed
+            if position.checked_add(block_size)? > needed_from {
+                return None;
+            }
+            self.blocks.insert(weak_sum, position);
+        }
+        self.collisions.read_checkpoint(checkpoint)
+    }
+
+    /// Index the blocks from `indexed_to` on which end by `end` in `data`.
+    fn index(&mut self, data: &[u8], discarded: u64, end: usize) {
+        let block_size = self.block_size as u64;
+        while self.indexed_to + block_size <= discarded + end as u64 {
+            let from = (self.indexed_to - discarded) as usize;
+            let sum = Crc::of(&data[from..from + self.block_size], None);
+            self.blocks.insert(sum.digest(), self.indexed_to);
+            self.indexed_to += block_size;
+        }
+        if self.blocks.len() as u64 > 2 * SELF_COPY_WINDOW / block_size {
+            // forget the blocks which are too far back to copy anymore
+            let oldest = self.indexed_to.saturating_sub(SELF_COPY_WINDOW);
+            self.blocks.retain(|_, &mut position| position >= oldest);
+        }
+    }
+}
+
+/// The distance between lookups `unmatched` bytes after the end of the last match, with
+/// [DiffOptions::max_search_stride] set to `max_stride`.
+#[inline]
+fn search_stride(unmatched: u64, block_size: usize, max_stride: u64) -> u64 {
+    let doublings = (unmatched / block_size as u64).min(63);
+    // odd, so that it has no factor of two in common with the usual block sizes
+    ((1u64 << doublings).min(max_stride) - 1) | 1
+}
+
+/// Whether to look up the window `unmatched` bytes after the end of the last match, with
+/// [DiffOptions::max_search_stride] set to `max_stride`.
+#[inline]
+fn on_search_stride(unmatched: u64, block_size: usize, max_stride: u64) -> bool {
+    unmatched % search_stride(unmatched, block_size, max_stride) == 0
+}
+
+/// The first window after the one `unmatched` bytes after the end of the last match which
+/// [on_search_stride()] looks up.
+fn next_search_stride(unmatched: u64, block_size: usize, max_stride: u64) -> u64 {
+    let mut next = unmatched.saturating_add(1);
+    loop {
+        let stride = search_stride(next, block_size, max_stride);
+        // the stride only grows, so no window before the next multiple of it is looked up
+        match next % stride {
+            0 => return next,
+            into_stride => next = next.saturating_add(stride - into_stride),
+        }
+    }
+}
+
+/// Windows of the input which were hashed ahead of time, along with one that was looked up, since
+/// hashing several blocks at once is faster.
+struct Lookahead<R> {
+    /// The position in the whole input, rolling checksum and truncated strong hash of each window,
+    /// in order.
+    windows: VecDeque<(u64, R, [u8; BLAKE2_SIZE])>,
+}
+
+impl<R> Lookahead<R> {
+    /// The window at `position`, if it was hashed. Windows before it are forgotten.
+    #[inline]
+    fn get(&mut self, position: u64) -> Option<&(u64, R, [u8; BLAKE2_SIZE])> {
+        while self.windows.front()?.0 < position {
+            self.windows.pop_front();
+        }
+        self.windows.front().filter(|window| window.0 == position)
+    }
+}
+
+/// The matching state of a diff, which can pick up where it left off once more data is available.
+struct Scanner<R> {
+    block_size: usize,
+    crypto_hash_size: usize,
+    seed: Option<SeedTable>,
+    alignment: usize,
+    collisions: CollisionCounts,
+    duplicate_blocks: DuplicateBlocks,
+    /// The offset in the base at which the last match ended.
+    previous_end: u64,
+    sparse_search_after: u64,
+    max_search_stride: u64,
+    /// The position in the whole input at which the last match ended.
+    unmatched_from: u64,
+    /// The start of the window being matched.
+    here: usize,
+    /// The checksum of the window at `here`, if it was already looked up without a match.
+    sum: Option<R>,
+    lookahead: Lookahead<R>,
+}
+
+impl<R: RollingChecksum> Scanner<R> {
+    fn new(signature_options: &SignatureOptions, options: &DiffOptions) -> Self {
+        Scanner {
+            block_size: signature_options.block_size as usize,
+            crypto_hash_size: signature_options.crypto_hash_size as usize,
+            seed: signature_options.rolling_seed.map(SeedTable::new),
+            alignment: if options.block_aligned {
+                signature_options.block_size as usize
+            } else {
+                options.match_alignment.map_or(1, |a| a.get() as usize)
+            },
+            collisions: CollisionCounts::new(options),
+            duplicate_blocks: if options.deterministic {
+                DuplicateBlocks::Any
+            } else {
+                options.duplicate_blocks
+            },
+            previous_end: 0,
+            sparse_search_after: options
+                .sparse_search_after
+                .map_or(u64::MAX, NonZeroU64::get),
+            max_search_stride: options.max_search_stride.map_or(1, |s| s.get() as u64),
+            unmatched_from: 0,
+            here: 0,
+            sum: None,
+            lookahead: Lookahead {
+                windows: VecDeque::new(),
+            },
+        }
+    }
+
+    /// How much of the start of `data` is the same as the start of the base, in whole blocks,
+    /// as when the base was only appended to. This only hashes and looks up one block after
+    /// another, in large batches, without the bookkeeping of a full [scan()](Self::scan).
+    ///
+    /// The last of those blocks is left out, so that the scan picks up from it exactly as if it
+    /// had matched everything before it itself.
+    fn matching_prefix(
+        &self,
+        signature: &impl BlockIndex,
+        data: &[u8],
+        crypto_hash: impl CryptoHash,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<usize, DiffError> {
+        /// How many batches of blocks to hash at once, at the cost of hashing that many more
+        /// blocks than needed when the prefix ends.
+        const BATCHES: usize = 8;
+        let block_size = self.block_size;
+        if self.here != 0 || block_size % self.alignment != 0 {
+            return Ok(0);
+        }
+        let blocks = (data.len() / block_size)
+            .min(usize::try_from(signature.block_count()).unwrap_or(usize::MAX));
+        let group = crypto_hash.batch_size().saturating_mul(BATCHES);
+        let mut hashes = Vec::with_capacity(group * self.crypto_hash_size);
+        let mut matched = 0;
+        while matched < blocks {
+            check_cancel(cancel)?;
+            let end = matched.saturating_add(group).min(blocks);
+            hashes.clear();
+            crypto_hash.hash_blocks(
+                &data[matched * block_size..end * block_size],
+                block_size,
+                |hash| hashes.extend_from_slice(&hash[..self.crypto_hash_size]),
+            );
+            for (i, hash) in (matched..end).zip(hashes.chunks(self.crypto_hash_size)) {
+                let block = &data[i * block_size..(i + 1) * block_size];
+                let weak_sum = R::of(block, self.seed.as_ref()).digest();
+                // a scan would look up a block right after a match near its own index
+                let idx = match self.duplicate_blocks {
+                    DuplicateBlocks::Any => signature.find_block(weak_sum, hash),
+                    _ => signature.find_block_near(weak_sum, hash, i as u64),
+                };
+                if !self.collisions.allows(weak_sum) || idx != Some(i as u64) {
+                    return Ok(i.saturating_sub(1) * block_size);
+                }
+            }
+            matched = end;
+        }
+        Ok(matched.saturating_sub(1) * block_size)
+    }
+
+    /// Carry on from `position` of the input, which a copy of the same offset of the base ended
+    /// at.
+    fn skip_to(&mut self, position: usize) {
+        self.here = position;
+        self.unmatched_from = position as u64;
+        self.previous_end = position as u64;
+    }
+
+    /// Save the position of the scanner, given that `data` is the input it was last given.
+    ///
+    /// The rolling checksum and the lookahead aren't saved, since they can be worked out from
+    /// `data` again.
+    fn write_checkpoint(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.here as u64).to_be_bytes());
+        out.push(self.sum.is_some() as u8);
+        out.extend_from_slice(&self.previous_end.to_be_bytes());
+        out.extend_from_slice(&self.unmatched_from.to_be_bytes());
+        self.collisions.write_checkpoint(out);
+    }
+
+    /// Restore the scanner, given that `data` starts at `position` of the whole input, and that
+    /// the base is at most `base_len` bytes long.
+    fn read_checkpoint(
+        &mut self,
+        checkpoint: &mut CheckpointReader<'_>,
+        data: &[u8],
+        position: u64,
+        base_len: u64,
+    ) -> Option<()> {
+        self.here = checkpoint.usize()?;
+        if checkpoint.flag()? {
+            // the window at `here` was already looked up
+            let window = data.get(self.here..self.here.checked_add(self.block_size)?)?;
+            self.sum = Some(R::of(window, self.seed.as_ref()));
+        } else if self.here > data.len() {
+            return None;
+        }
+        self.previous_end = checkpoint.u64()?;
+        self.unmatched_from = checkpoint.u64()?;
+        // the last match ended in the base, and before the window at `here`
+        if self.previous_end > base_len || self.unmatched_from > position + self.here as u64 {
+            return None;
+        }
+        self.collisions.read_checkpoint(checkpoint)
+    }
+
+    /// Match as many windows of `data` as possible, stopping when the next one would run past the
+    /// end of `data`. `found` is called with the position and base offset of each matching block,
+    /// and returns how many bytes from that position on the match ended up covering.
+    ///
+    /// `position` is where `data` starts in the whole input, which alignment is relative to.
+    fn scan(
+        &mut self,
+        signature: &impl BlockIndex,
+        data: &[u8],
+        position: u64,
+        crypto_hash: impl CryptoHash,
+        mut found: impl FnMut(usize, u64) -> io::Result<usize>,
+    ) -> Result<(), DiffError> {
+        let block_size = self.block_size;
+        let crypto_hash_size = self.crypto_hash_size;
+        let alignment = self.alignment as u64;
+        let collisions = &mut self.collisions;
+        let lookahead = &mut self.lookahead;
+        let batch_size = crypto_hash.batch_size();
+        let seed = self.seed.as_ref();
+        let roll = |sum: R, old_byte: u8, new_byte: u8| match seed {
+            Some(seed) => sum.rotate(block_size as u32, seed.map(old_byte), seed.map(new_byte)),
+            None => sum.rotate(block_size as u32, old_byte, new_byte),
+        };
+        let duplicate_blocks = self.duplicate_blocks;
+        let mut previous_end = self.previous_end;
+        let (sparse_search_after, mut unmatched_from) =
+            (self.sparse_search_after, self.unmatched_from);
+        let max_search_stride = self.max_search_stride;
+        let mut here = self.here;
+        let mut sum = self.sum.take();
+        'windows: loop {
+            let mut current = match sum.take() {
+                // this window was looked up before we ran out of data
+                Some(sum) => {
+                    if here + block_size >= data.len() {
+                        self.sum = Some(sum);
+                        break;
+                    }
+                    here += 1;
+                    roll(sum, data[here - 1], data[here + block_size - 1])
+                }
+                None => {
+                    if data.len() - here < block_size {
+                        break;
+                    }
+                    match lookahead.get(position + here as u64) {
+                        Some(&(_, sum, _)) => sum,
+                        None => R::of(&data[here..here + block_size], seed),
+                    }
+                }
+            };
+            loop {
+                let weak_sum = current.digest();
+                // if we detect too many CRC collisions, blacklist the CRC to avoid DoS
+                if (alignment == 1 || (position + here as u64) % alignment == 0)
+                    && (max_search_stride == 1
+                        || on_search_stride(
+                            position + here as u64 - unmatched_from,
+                            block_size,
+                            max_search_stride,
+                        ))
+                    && collisions.allows(weak_sum)
+                    && signature.contains_weak_sum(weak_sum)
+                {
+                    let (digest, batched);
+                    let crypto_hash = match lookahead.get(position + here as u64) {
+                        Some(&(_, _, hash)) => {
+                            batched = hash;
+                            &batched[..crypto_hash_size]
+                        }
+                        None if batch_size > 1 && position + here as u64 == unmatched_from => {
+                            // This window follows a match, so the next few windows a block apart
+                            // are likely to match as well: hash those which could all at once.
+                            let windows = &mut lookahead.windows;
+                            windows.clear();
+                            windows.push_back((position + here as u64, current, [0; BLAKE2_SIZE]));
+                            for next in (here + block_size..data.len())
+                                .step_by(block_size)
+                                .take(batch_size - 1)
+                            {
+                                if data.len() - next < block_size {
+                                    break;
+                                }
+                                let sum = R::of(&data[next..next + block_size], seed);
+                                let next_position = position + next as u64;
+                                if !((alignment == 1 || next_position % alignment == 0)
+                                    && collisions.allows(sum.digest())
+                                    && signature.contains_weak_sum(sum.digest()))
+                                {
+                                    break;
+                                }
+                                windows.push_back((next_position, sum, [0; BLAKE2_SIZE]));
+                            }
+                            let end = here + windows.len() * block_size;
+                            let mut slots = windows.iter_mut();
+                            crypto_hash.hash_blocks(&data[here..end], block_size, |hash| {
+                                if let Some((_, _, slot)) = slots.next() {
+                                    slot[..crypto_hash_size]
+                                        .copy_from_slice(&hash[..crypto_hash_size]);
+                                }
+                            });
+                            batched = windows[0].2;
+                            &batched[..crypto_hash_size]
+                        }
+                        None => {
+                            digest = crypto_hash.hash(&data[here..here + block_size]);
+                            &digest.as_ref()[..crypto_hash_size]
+                        }
+                    };
+                    let idx = match duplicate_blocks {
+                        DuplicateBlocks::Any => signature.find_block(weak_sum, crypto_hash),
+                        DuplicateBlocks::NearPrevious => signature.find_block_near(
+                            weak_sum,
+                            crypto_hash,
+                            previous_end / block_size as u64,
+                        ),
+                        DuplicateBlocks::SamePosition => signature.find_block_near(
+                            weak_sum,
+                            crypto_hash,
+                            (position + here as u64) / block_size as u64,
+                        ),
+                    };
+                    if let Some(idx) = idx {
+                        // match found
+                        let offset = idx
+                            .checked_mul(block_size as u64)
+                            .ok_or(DiffError::InvalidSignature)?;
+                        let len = found(here, offset)?;
+                        previous_end = offset + len as u64;
+                        here += len;
+                        unmatched_from = position + here as u64;
+                        continue 'windows;
+                    }
+                    // CRC collision
+                    collisions.record(weak_sum);
+                }
+                // no match, try to extend
+                let unmatched = position + here as u64 - unmatched_from;
+                let step = if alignment >= block_size as u64 {
+                    // the next window we'd look up doesn't overlap this one, so rolling all the
+                    // way there would take longer than checksumming it from scratch
+                    alignment
+                } else if unmatched >= sparse_search_after {
+                    block_size as u64
+                } else if max_search_stride > 1
+                    && search_stride(unmatched, block_size, max_search_stride) >= block_size as u64
+                {
+                    // likewise, checksum the next window on the stride from scratch
+                    let next = next_search_stride(unmatched, block_size, max_search_stride);
+                    here = here
+                        .saturating_add((next - unmatched) as usize)
+                        .min(data.len());
+                    continue 'windows;
+                } else {
+                    0
+                };
+                if step != 0 {
+                    // only try the next window at a multiple of `step`
+                    let into_step = ((position + here as u64) % step) as usize;
+                    here = here
+                        .saturating_add(step as usize - into_step)
+                        .min(data.len());
+                    continue 'windows;
+                }
+                if here + block_size >= data.len() {
+                    self.sum = Some(current);
+                    break 'windows;
+                }
+                here += 1;
+                current = roll(current, data[here - 1], data[here + block_size - 1]);
+            }
+        }
+        self.previous_end = previous_end;
+        self.unmatched_from = unmatched_from;
+        self.here = here;
+        Ok(())
+    }
+
+    /// Try to match the end of `data`, from `start` on, against a block shorter than the block
+    /// size, i.e. the last block of the base. Returns the position and base offset of the longest
+    /// such match.
+    ///
+    /// This should only be called once `data` is complete and has been scanned.
+    fn scan_tail(
+        &mut self,
+        signature: &impl BlockIndex,
+        data: &[u8],
+        start: usize,
+        position: u64,
+        crypto_hash: impl CryptoHash,
+    ) -> Result<Option<(usize, u64)>, DiffError> {
+        let first = start.max((data.len() + 1).saturating_sub(self.block_size));
+        if first >= data.len() {
+            return Ok(None);
+        }
+        let seed = self.seed.as_ref();
+        let mut sum = R::of(&data[first..], seed);
+        for here in first..data.len() {
+            let weak_sum = sum.digest();
+            if (position + here as u64) % self.alignment as u64 == 0
+                && self.collisions.allows(weak_sum)
+                && signature.contains_weak_sum(weak_sum)
+            {
+                let digest = crypto_hash.hash(&data[here..]);
+                if let Some(idx) =
+                    signature.find_block(weak_sum, &digest.as_ref()[..self.crypto_hash_size])
+                {
+                    let offset = idx
+                        .checked_mul(self.block_size as u64)
+                        .ok_or(DiffError::InvalidSignature)?;
+                    return Ok(Some((here, offset)));
+                }
+                self.collisions.record(weak_sum);
+            }
+            let old_byte = seed.map_or(data[here], |seed| seed.map(data[here]));
+            sum = sum.rollout((data.len() - here) as u32, old_byte);
+        }
+        Ok(None)
+    }
+
+    /// Like [scan()](Self::scan), adding the matches to `output`. If `base` is given, matches
+    /// are extended as far as it agrees with `data`.
+    fn scan_into(
+        &mut self,
+        signature: &impl BlockIndex,
+        base: Option<&[u8]>,
+        data: &[u8],
+        output: &mut OutputState,
+        crypto_hash: impl CryptoHash,
+        out: &mut impl CommandSink,
+    ) -> Result<(), DiffError> {
+        let block_size = self.block_size;
+        let position = output.discarded;
+        self.scan(signature, data, position, crypto_hash, |here, offset| {
+            let (start, offset, len) = match base {
+                Some(base) => extend_match(base, data, output.emitted, here, offset, block_size),
+                None => (here, offset, block_size),
+            };
+            output.copy(offset, len, start, data, out)?;
+            Ok(start + len - here)
+        })
+    }
+
+    /// Like [scan_tail()](Self::scan_tail), adding the match to `output`, then write out the
+    /// rest of the delta.
+    fn finish_into(
+        &mut self,
+        signature: &impl BlockIndex,
+        data: &[u8],
+        output: &mut OutputState,
+        crypto_hash: impl CryptoHash,
+        out: &mut impl CommandSink,
+    ) -> Result<(), DiffError> {
+        let tail = self.scan_tail(
+            signature,
+            data,
+            output.emitted,
+            output.discarded,
+            crypto_hash,
+        )?;
+        if let Some((here, offset)) = tail {
+            output.copy(offset, data.len() - here, here, data, out)?;
+        }
+        output.emit(data.len(), data, out)?;
+        output.finish(out)?;
+        Ok(())
+    }
+}
+
+/// Grow a match of `len` bytes, at `here` in `data` and `offset` in `base`, in both directions for
+/// as long as the two agree, without reaching back before `floor` in `data`. Returns the new
+/// position, offset and length.
+fn extend_match(
+    base: &[u8],
+    data: &[u8],
+    floor: usize,
+    here: usize,
+    offset: u64,
+    len: usize,
+) -> (usize, u64, usize) {
+    if offset > base.len() as u64 || base.len() - (offset as usize) < len {
+        // the signature doesn't describe `base`; nothing sensible to compare against
+        return (here, offset, len);
+    }
+    let base_offset = offset as usize;
+    let backward = data[floor..here]
+        .iter()
+        .rev()
+        .zip(base[..base_offset].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let forward = data[here + len..]
+        .iter()
+        .zip(&base[base_offset + len..])
+        .take_while(|(a, b)| a == b)
+        .count();
+    (
+        here - backward,
+        offset - backward as u64,
+        backward + len + forward,
+    )
+}
+
+fn diff_impl<R: RollingChecksum>(
+    signature: &impl BlockIndex,
+    base: Option<&[u8]>,
+    data: &[u8],
+    mut out: impl DeltaOutput,
+    options: &DiffOptions,
+    crypto_hash: impl CryptoHash,
+    mut progress: impl FnMut(DiffProgress),
+) -> Result<(), DiffError> {
+    let signature_options = signature.options();
+    let block_size = signature_options.block_size as usize;
+    let limit = options.max_delta_len.unwrap_or(u64::MAX);
+    let mut scanner = Scanner::<R>::new(&signature_options, options);
+    let mut output = OutputState::new(options, block_size);
+    out.start(output.magic())?;
+    output.hash_input(data);
+    if let Some(ratio) = options.max_size_ratio {
+        let base_len = signature.block_count().saturating_mul(block_size as u64);
+        if data.len() as u64 > base_len.saturating_mul(ratio.get()) {
+            // not worth scanning
+            output.emit(data.len(), data, &mut out)?;
+            output.finish(&mut out)?;
+            progress(DiffProgress {
+                consumed: data.len() as u64,
+                emitted: out.written(),
+            });
+            return Ok(());
+        }
+    }
+    let base = base.filter(|_| options.extend_matches);
+    let prefix =
+        scanner.matching_prefix(signature, data, &crypto_hash, options.cancel.as_deref())?;
+    if prefix > 0 {
+        output.copy(0, prefix, 0, data, &mut out)?;
+        scanner.skip_to(prefix);
+    }
+    // The scanner stops wherever the data it is given ends, so handing it more and more of `data`
+    // lets us check in between.
+    let mut end = prefix;
+    while end < data.len() {
+        check_cancel(options.cancel.as_deref())?;
+        end = end.saturating_add(CHECK_INTERVAL).min(data.len());
+        let data = &data[..end];
+        scanner.scan_into(signature, base, data, &mut output, &crypto_hash, &mut out)?;
+        // the unmatched data searched so far will take up at least as much space as literals
+        let pending = scanner.here.saturating_sub(output.emitted) as u64;
+        if output.literals_verbatim() && pending > limit - out.written() {
+            return Err(DiffError::OutputLimit);
+        }
+        progress(DiffProgress {
+            consumed: scanner.here as u64,
+            emitted: out.written(),
+        });
+    }
+    scanner.finish_into(signature, data, &mut output, &crypto_hash, &mut out)?;
+    progress(DiffProgress {
+        consumed: data.len() as u64,
+        emitted: out.written(),
+    });
+    Ok(())
+}
+
+#[cfg(feature = "rayon")]
+fn diff_parallel_impl<R: RollingChecksum>(
+    signature: &(impl BlockIndex + Sync),
+    data: &[u8],
+    mut out: impl DeltaOutput,
+    options: &DiffOptions,
+    crypto_hash: impl CryptoHash + Sync,
+) -> Result<(), DiffError> {
+    use rayon::prelude::*;
+
+    const PARALLEL_SEGMENT_SIZE: usize = 4 << 20;
+    let signature_options = signature.options();
+    let block_size = signature_options.block_size as usize;
+    let mut output = OutputState::new(options, block_size);
+    out.start(output.magic())?;
+    output.hash_input(data);
+    if let Some(ratio) = options.max_size_ratio {
+        let base_len = signature.block_count().saturating_mul(block_size as u64);
+        if data.len() as u64 > base_len.saturating_mul(ratio.get()) {
+            // not worth scanning
+            output.emit(data.len(), data, &mut out)?;
+            output.finish(&mut out)?;
+            return Ok(());
+        }
+    }
+    let segment_size = PARALLEL_SEGMENT_SIZE.max(block_size.saturating_mul(8));
+    let starts: Vec<usize> = (0..data.len()).step_by(segment_size).collect();
+    let segments = starts
+        .into_par_iter()
+        .map(|start| {
+            // Search the windows which start in this segment, even if they end in the next one.
+            let end = start
+                .saturating_add(segment_size)
+                .saturating_add(block_size - 1)
+                .min(data.len());
+            check_cancel(options.cancel.as_deref())?;
+            let mut scanner = Scanner::<R>::new(&signature_options, options);
+            // nothing before the segment is known to be unmatched
+            scanner.unmatched_from = start as u64;
+            let mut matches = Vec::new();
+            scanner.scan(
+                signature,
+                &data[start..end],
+                start as u64,
+                &crypto_hash,
+                |here, offset| {
+                    matches.push((start + here, offset));
+                    Ok(block_size)
+                },
+            )?;
+            Ok(matches)
+        })
+        .collect::<Result<Vec<Vec<(usize, u64)>>, DiffError>>()?;
+
+    for (here, offset) in segments.into_iter().flatten() {
+        // The first matches of a segment may overlap the last one of the previous segment.
+        if here >= output.emitted {
+            output.copy(offset, block_size, here, data, &mut out)?;
+        }
+    }
+    Scanner::<R>::new(&signature_options, options).finish_into(
+        signature,
+        data,
+        &mut output,
+        &crypto_hash,
+        &mut out,
+    )
+}
+
+/// How much of `data` to search between progress reports and checks for cancellation.
+const CHECK_INTERVAL: usize = 1 << 20;
+
+/// Fail with [DiffError::Cancelled] if `cancel` has been set.
+fn check_cancel(cancel: Option<&AtomicBool>) -> Result<(), DiffError> {
+    if cancel.map_or(false, |cancel| cancel.load(Ordering::Relaxed)) {
+        return Err(DiffError::Cancelled);
+    }
+    Ok(())
+}
+
+/// How many bytes of unmatched input a [DiffState] holds on to before writing them out. Holding
+/// some back lets them go out in fewer literal commands when the input is fed in small pieces.
+const MAX_PENDING_LITERAL: usize = 1 << 16;
+
+/// An incremental version of [diff_with_options()], for when `data` arrives in pieces and
+/// can't be held in memory all at once.
+///
+/// A `DiffState` doesn't do any IO itself: each piece of `data` is passed to
+/// [feed()](DiffState::feed), which appends whatever part of the delta it could already work out
+/// to a buffer. [finish()](DiffState::finish) then appends the rest. Only the current window of
+/// `data` and a bounded amount of unmatched input are kept in between.
+///
+/// The delta can differ from the one [diff_with_options()] produces for the same data, since
+/// literals may be split into several commands, but it reconstructs the same data. The
+/// [max_size_ratio](DiffOptions::max_size_ratio) option is ignored, since the length of `data`
+/// isn't known up front.
+///
+/// A diff can be saved with [checkpoint()](DiffState::checkpoint) in between pieces of `data`,
+/// and picked up again later, even by another process, with [resume()](DiffState::resume).
+///
+/// The [cancel](DiffOptions::cancel) flag is checked by [feed()](DiffState::feed) before each
+/// MiB or so of the piece it searches, and by [finish()](DiffState::finish) before it starts. A
+/// piece whose feed was cancelled has been taken in all the same, so once the flag is cleared,
+/// the diff can carry on with the next one.
+///
+/// # Security
+/// The caveats for [diff()] apply here as well.
+pub struct DiffState<'a, I> {
+    signature: &'a I,
+    crypto_hash: BuiltinHash,
+    scanner: AnyScanner,
+    output: OutputState,
+    /// The input which is still needed, starting from where the positions in `scanner` and
+    /// `output` are counted.
+    buffer: Vec<u8>,
+    started: bool,
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+enum AnyScanner {
+    Crc(Scanner<Crc>),
+    RabinKarp(Scanner<RabinKarp>),
+}
+
+impl<'a, I: BlockIndex> DiffState<'a, I> {
+    /// Start a delta against the base data represented by `signature`.
+    pub fn new(signature: &'a I, options: &DiffOptions) -> Result<Self, DiffError> {
+        let signature_options = signature.options();
+        let signature_type = check_builtin_signature(&signature_options, options)?;
+        check_complete(signature)?;
+        let scanner = match signature_options.rolling_hash {
+            RollingHash::Rollsum => AnyScanner::Crc(Scanner::new(&signature_options, options)),
+            RollingHash::RabinKarp => {
+                AnyScanner::RabinKarp(Scanner::new(&signature_options, options))
+            }
+        };
+        Ok(DiffState {
+            signature,
+            crypto_hash: BuiltinHash::new(signature_type, options.hash_key),
+            scanner,
+            output: OutputState::new(options, signature_options.block_size as usize),
+            buffer: Vec::new(),
+            started: false,
+            cancel: options.cancel.clone(),
+        })
+    }
+
+    /// Pick up a diff from a [checkpoint()](DiffState::checkpoint), with the same `signature` and
+    /// `options` it was started with.
+    ///
+    /// The delta continues right after what had been appended to `out` when the checkpoint was
+    /// taken, and `data` from [consumed()](DiffState::consumed) bytes in.
+    ///
+    /// Fails with [DiffError::InvalidCheckpoint] if the checkpoint is corrupt, or evidently comes
+    /// from a diff with a different signature or options. Not every such mismatch can be
+    /// detected, and any other one produces a wrong delta. A checkpoint which was damaged, say
+    /// in storage, fails its hash; one which was forged to pass it can still only make for a
+    /// wrong delta, never a panic.
+    pub fn resume(
+        signature: &'a I,
+        options: &DiffOptions,
+        checkpoint: &[u8],
+    ) -> Result<Self, DiffError> {
+        let mut state = Self::new(signature, options)?;
+        let body_len = checkpoint
+            .len()
+            .checked_sub(BLAKE2_SIZE)
+            .ok_or(DiffError::InvalidCheckpoint)?;
+        let (body, hash) = checkpoint.split_at(body_len);
+        if checkpoint_hash(body)[..] != *hash {
+            return Err(DiffError::InvalidCheckpoint);
+        }
+        state
+            .read_checkpoint(&mut CheckpointReader(body))
+            .ok_or(DiffError::InvalidCheckpoint)?;
+        Ok(state)
+    }
+
+    /// Save the state of the diff, so that it can be [resumed](DiffState::resume) later.
+    ///
+    /// The checkpoint holds the part of `data` which is still needed, which is a few blocks plus
+    /// a bounded amount of unmatched input, along with the collision counts of the rolling checksums
+    /// (see [max_tracked_collisions](DiffOptions::max_tracked_collisions)), and ends with a hash
+    /// of the rest. It is only valid along with everything appended to `out` so far, and only
+    /// for the same version of this crate.
+    ///
+    /// Returns `None` if the delta ends with a [checksum](DiffOptions::checksum), since the
+    /// state of the hash can't be saved.
+    pub fn checkpoint(&self) -> Option<Vec<u8>> {
+        if self.output.checksum.is_some() {
+            return None;
+        }
+        let mut out = Vec::new();
+        out.extend_from_slice(&DIFF_CHECKPOINT_MAGIC.to_be_bytes());
+        out.extend_from_slice(&self.output.magic().to_be_bytes());
+        let signature_options = self.signature.options();
+        out.extend_from_slice(&signature_options.block_size.to_be_bytes());
+        out.extend_from_slice(&signature_options.crypto_hash_size.to_be_bytes());
+        out.extend_from_slice(&self.signature.block_count().to_be_bytes());
+        out.push(self.started as u8);
+        out.extend_from_slice(&self.output.discarded.to_be_bytes());
+        out.extend_from_slice(&(self.output.emitted as u64).to_be_bytes());
+        let (offset, len) = self.output.queued_copy.unwrap_or((0, 0));
+        out.push(self.output.queued_copy.is_some() as u8);
+        out.extend_from_slice(&offset.to_be_bytes());
+        out.extend_from_slice(&(len as u64).to_be_bytes());
+        out.extend_from_slice(&(self.buffer.len() as u64).to_be_bytes());
+        out.extend_from_slice(&self.buffer);
+        match &self.scanner {
+            AnyScanner::Crc(scanner) => scanner.write_checkpoint(&mut out),
+            AnyScanner::RabinKarp(scanner) => scanner.write_checkpoint(&mut out),
+        }
+        if let Some(matcher) = &self.output.self_copies {
+            matcher.write_checkpoint(&mut out);
+        }
+        let hash = checkpoint_hash(&out);
+        out.extend_from_slice(&hash);
+        Some(out)
+    }
+
+    /// How many bytes of `data` have been fed so far.
+    pub fn consumed(&self) -> u64 {
+        self.output.discarded + self.buffer.len() as u64
+    }
+
+    fn read_checkpoint(&mut self, checkpoint: &mut CheckpointReader<'_>) -> Option<()> {
+        let signature_options = self.signature.options();
+        if checkpoint.u32()? != DIFF_CHECKPOINT_MAGIC
+            || checkpoint.u32()? != self.output.magic()
+            || checkpoint.u32()? != signature_options.block_size
+            || checkpoint.u32()? != signature_options.crypto_hash_size
+            || checkpoint.u64()? != self.signature.block_count()
+        {
+            return None;
+        }
+        let base_len =
+            (signature_options.block_size as u64).saturating_mul(self.signature.block_count());
+        self.started = checkpoint.flag()?;
+        self.output.discarded = checkpoint.u64()?;
+        // no input is anywhere near this long, which leaves room for the rest of it
+        if self.output.discarded > u64::MAX / 2 {
+            return None;
+        }
+        self.output.emitted = checkpoint.usize()?;
+        let queued = checkpoint.flag()?;
+        let (offset, len) = (checkpoint.u64()?, checkpoint.usize()?);
+        if queued {
+            // a copy which is too short is still in the buffer, to go out as literals instead
+            if (len < self.output.min_copy_len && len > self.output.emitted)
+                || offset.checked_add(len as u64)? > base_len
+            {
+                return None;
+            }
+            self.output.queued_copy = Some((offset, len));
+        }
+        let buffer_len = checkpoint.usize()?;
+        self.buffer = checkpoint.bytes(buffer_len)?.to_vec();
+        if self.output.emitted > self.buffer.len() {
+            return None;
+        }
+        let position = self.output.discarded;
+        match &mut self.scanner {
+            AnyScanner::Crc(scanner) => {
+                scanner.read_checkpoint(checkpoint, &self.buffer, position, base_len)?
+            }
+            AnyScanner::RabinKarp(scanner) => {
+                scanner.read_checkpoint(checkpoint, &self.buffer, position, base_len)?
+            }
+        }
+        // everything before the window at `here` has been written, or is about to be
+        if self.output.emitted > *self.scanner.here_mut() {
+            return None;
+        }
+        let needed_from = position + self.output.needed_from() as u64;
+        let end = position + self.buffer.len() as u64;
+        if let Some(matcher) = &mut self.output.self_copies {
+            matcher.read_checkpoint(checkpoint, needed_from, end)?;
+        }
+        if checkpoint.0.is_empty() {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    /// Process the next piece of `data`, appending any delta bytes that are ready to `out`.
+    pub fn feed(&mut self, data: &[u8], out: &mut Vec<u8>) -> Result<(), DiffError> {
+        self.start(out);
+        self.output.hash_input(data);
+        let mut end = self.buffer.len();
+        self.buffer.extend_from_slice(data);
+        // as in diff_impl(), the scanner is handed more and more of the buffer to check in between
+        while end < self.buffer.len() {
+            check_cancel(self.cancel.as_deref())?;
+            end = end.saturating_add(CHECK_INTERVAL).min(self.buffer.len());
+            self.scan(end, out)?;
+        }
+        let here = *self.scanner.here_mut();
+        // Literals before `here` can't become part of a match anymore.
+        if here - self.output.emitted >= MAX_PENDING_LITERAL {
+            self.output.emit(here, &self.buffer, &mut *out)?;
+        }
+        let unneeded = self.output.needed_from();
+        self.buffer.drain(..unneeded);
+        self.output.emitted -= unneeded;
+        self.output.discarded += unneeded as u64;
+        *self.scanner.here_mut() -= unneeded;
+        Ok(())
+    }
+
+    /// Append the rest of the delta to `out`, given that all of `data` has been fed.
+    pub fn finish(mut self, out: &mut Vec<u8>) -> Result<(), DiffError> {
+        check_cancel(self.cancel.as_deref())?;
+        self.start(out);
+        // catch up on the rest of a piece whose feed was cancelled
+        self.scan(self.buffer.len(), out)?;
+        let (buffer, output, crypto_hash) = (&self.buffer, &mut self.output, &self.crypto_hash);
+        match &mut self.scanner {
+            AnyScanner::Crc(scanner) => {
+                scanner.finish_into(self.signature, buffer, output, crypto_hash, out)
+            }
+            AnyScanner::RabinKarp(scanner) => {
+                scanner.finish_into(self.signature, buffer, output, crypto_hash, out)
+            }
+        }
+    }
+
+    /// Search the buffer up to `end` for matches, appending the delta for them to `out`.
+    fn scan(&mut self, end: usize, out: &mut Vec<u8>) -> Result<(), DiffError> {
+        let (buffer, output, crypto_hash) =
+            (&self.buffer[..end], &mut self.output, &self.crypto_hash);
+        match &mut self.scanner {
+            AnyScanner::Crc(scanner) => {
+                scanner.scan_into(self.signature, None, buffer, output, crypto_hash, out)
+            }
+            AnyScanner::RabinKarp(scanner) => {
+                scanner.scan_into(self.signature, None, buffer, output, crypto_hash, out)
+            }
+        }
+    }
+
+    fn start(&mut self, out: &mut Vec<u8>) {
+        if !self.started {
+            out.extend_from_slice(&self.output.magic().to_be_bytes());
+            self.started = true;
+        }
+    }
+}
+
+/// The hash at the end of a checkpoint, of everything before it.
+fn checkpoint_hash(body: &[u8]) -> [u8; BLAKE2_SIZE] {
+    let mut hasher = Blake2Hasher::default();
+    hasher.update(body);
+    hasher.finalize()
+}
+
+/// Reads the fields of a checkpoint in order, returning `None` once it runs out.
+struct CheckpointReader<'a>(&'a [u8]);
+
+impl<'a> CheckpointReader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn flag(&mut self) -> Option<bool> {
+        match self.bytes(1)? {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(*array_ref![self.bytes(4)?, 0, 4]))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(*array_ref![self.bytes(8)?, 0, 8]))
+    }
+
+    fn usize(&mut self) -> Option<usize> {
+        usize::try_from(self.u64()?).ok()
+    }
+}
+
+impl AnyScanner {
+    fn here_mut(&mut self) -> &mut usize {
+        match self {
+            AnyScanner::Crc(scanner) => &mut scanner.here,
+            AnyScanner::RabinKarp(scanner) => &mut scanner.here,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        diff_dispatch, next_search_stride, on_search_stride, BuiltinHash, CollisionCounts,
+        CryptoHash, DiffOptions,
+    };
+    use crate::signature::SignatureType;
+    use crate::{Signature, SignatureHash, SignatureOptions};
+    use rand::Rng;
+    use std::num::NonZeroUsize;
+
+    /// A [BuiltinHash] which hashes one block at a time.
+    struct Unbatched(BuiltinHash);
+
+    impl CryptoHash for Unbatched {
+        type Output = <BuiltinHash as CryptoHash>::Output;
+        fn hash(&self, block: &[u8]) -> Self::Output {
+            self.0.hash(block)
+        }
+    }
+
+    #[test]
+    fn search_stride_jumps() {
+        // jumping from one window on the stride to the next skips none of them
+        for &(block_size, max_stride) in &[(16, 64), (16, 1 << 20), (64, 7), (1000, 4096)] {
+            let mut unmatched = 0;
+            while unmatched < 20_000 {
+                let next = next_search_stride(unmatched, block_size, max_stride);
+                assert!(on_search_stride(next, block_size, max_stride));
+                assert!((unmatched + 1..next).all(|u| !on_search_stride(u, block_size, max_stride)));
+                unmatched = next;
+            }
+        }
+    }
+
+    #[test]
+    fn batched_hashing() {
+        let mut rng = rand::thread_rng();
+        let mut base = vec![0u8; 64 * 1024];
+        rng.fill(&mut base[..]);
+        let mut data = base.clone();
+        for i in 0..50 {
+            let at = rng.gen_range(0..data.len());
+            data[at] = data[at].wrapping_add(1 + i);
+        }
+        data.extend_from_slice(&base[1000..5000]);
+        for &hash in &[SignatureHash::Md4, SignatureHash::Blake2] {
+            let signature_options = SignatureOptions {
+                block_size: 256,
+                crypto_hash_size: 16,
+                hash,
+                ..Default::default()
+            };
+            let signature = Signature::calculate(&base, signature_options);
+            let index = signature.index();
+            let signature_type = SignatureType::new(signature_options.rolling_hash, hash);
+            let crypto_hash = BuiltinHash::new(signature_type, None);
+            let options = DiffOptions::default();
+            let mut batched = vec![];
+            diff_dispatch(
+                &index,
+                None,
+                &data,
+                &mut batched,
+                &options,
+                crypto_hash,
+                |_| {},
+            )
+            .unwrap();
+            let mut unbatched = vec![];
+            let crypto_hash = Unbatched(crypto_hash);
+            diff_dispatch(
+                &index,
+                None,
+                &data,
+                &mut unbatched,
+                &options,
+                crypto_hash,
+                |_| {},
+            )
+            .unwrap();
+            assert_eq!(batched, unbatched);
+        }
+    }
+
+    #[test]
+    fn collision_counts_stay_bounded() {
+        let mut counts = CollisionCounts::new(&DiffOptions {
+            max_weak_sum_collisions: Some(2),
+            max_tracked_collisions: NonZeroUsize::new(4),
+            ..Default::default()
+        });
+        for _ in 0..2 {
+            counts.record(1);
+        }
+        assert!(!counts.allows(1));
+        for weak_sum in 2..100 {
+            counts.record(weak_sum);
+            assert!(counts.counts.len() <= 4);
+        }
+        // the blacklisted checksum survives eviction
+        assert!(!counts.allows(1));
+        assert!(!counts.full);
+
+        for weak_sum in 100..103 {
+            for _ in 0..2 {
+                counts.record(weak_sum);
+            }
+        }
+        assert_eq!(counts.counts.len(), 4);
+        // the counts are full of blacklisted checksums, so new ones aren't tracked
+        counts.record(200);
+        counts.record(200);
+        assert!(counts.full && counts.allows(200));
+        assert!((100..103).all(|weak_sum| !counts.allows(weak_sum)));
+    }
 }
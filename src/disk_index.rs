@@ -0,0 +1,236 @@
+//! A block index stored as a sorted table, which can be searched without loading it into memory.
+
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::io::{self, Write};
+
+use arrayref::array_ref;
+
+use crate::consts::DISK_INDEX_MAGIC;
+use crate::flat_index::sorted_blocks;
+use crate::signature::{
+    BlockIndex, IndexedSignature, Signature, SignatureOptions, SignatureParseError, SignatureType,
+};
+
+/// magic, signature magic, block_size, crypto_hash_size, block_count, then whether there is a
+/// rolling seed and its value
+const HEADER_SIZE: usize = 4 + 4 + 4 + 4 + 8 + 4 + 4;
+/// After the header, like in a git pack index, the number of entries whose rolling checksum
+/// starts with each byte value or a smaller one, so that searches start from a narrow range.
+const FANOUT_SIZE: usize = 256 * 8;
+const TABLE_START: usize = HEADER_SIZE + FANOUT_SIZE;
+/// Each entry is a rolling checksum and a 64-bit block index, followed by the strong hash.
+const ENTRY_PREFIX_SIZE: usize = 4 + 8;
+
+/// A signature index stored in a flat, sorted table, suitable for diffing against signatures
+/// whose index is too large to keep in memory.
+///
+/// The table is written by [DiskIndexedSignature::write] (or
+/// [write_index](DiskIndexedSignature::write_index), to persist an index which is already in
+/// memory) and read from any byte container, most usefully a memory map of the file it was
+/// written to. Reading it takes constant time, and lookups are binary searches over a small part
+/// of the table, so only the pages they touch need to be resident. Writing it does need memory
+/// in proportion to the signature, though less than a hash map index would.
+#[derive(Clone, Debug)]
+pub struct DiskIndexedSignature<B: AsRef<[u8]>> {
+    data: B,
+    signature_type: SignatureType,
+    block_size: u32,
+    crypto_hash_size: u32,
+    block_count: u64,
+    entry_size: usize,
+    entry_count: usize,
+}
+
+/// An entry of the table: rolling checksum, strong hash, and block index.
+type Entry<'a> = (u32, &'a [u8], u64);
+
+impl DiskIndexedSignature<Vec<u8>> {
+    /// Write the index of `signature` to `out`, in the format read by
+    /// [DiskIndexedSignature::new]. The table is written in small pieces, so `out` should be
+    /// buffered.
+    ///
+    /// The blocks are sorted in memory before they are written, which takes 32 bytes for each
+    /// block on top of the signature itself, so the signature and this much must fit in memory.
+    pub fn write(signature: &Signature, out: impl Write) -> io::Result<()> {
+        let blocks = signature.blocks();
+        let block_count = blocks.len() as u64;
+        let entries = sorted_blocks(blocks);
+        Self::write_entries(signature.options(), block_count, &entries, out)
+    }
+
+    /// Write an index which has already been built in memory to `out`, in the format read by
+    /// [DiskIndexedSignature::new], so that it doesn't have to be rebuilt from the signature
+    /// again. The table is written in small pieces, so `out` should be buffered.
+    ///
+    /// This finds the same blocks as [write](DiskIndexedSignature::write) would, except that
+    /// blocks beyond the first 2^32, which `index` leaves out, are not found either.
+    pub fn write_index<K>(index: &IndexedSignature<'_, K>, out: impl Write) -> io::Result<()>
+    where
+        K: Borrow<[u8]> + Eq + Hash,
+    {
+        let mut entries: Vec<Entry<'_>> = index
+            .blocks
+            .iter()
+            .flat_map(|(&weak_sum, hashes)| {
+                hashes
+                    .iter()
+                    .map(move |(crypto_hash, &idx)| (weak_sum, crypto_hash.borrow(), idx as u64))
+            })
+            .collect();
+        entries.sort_unstable();
+        Self::write_entries(index.options(), index.block_count(), &entries, out)
+    }
+
+    /// Write the header and `entries`, which must be sorted by rolling checksum and strong hash.
+    fn write_entries(
+        options: SignatureOptions,
+        block_count: u64,
+        entries: &[Entry<'_>],
+        mut out: impl Write,
+    ) -> io::Result<()> {
+        let signature_type = SignatureType::new(options.rolling_hash, options.hash);
+        out.write_all(&DISK_INDEX_MAGIC.to_be_bytes())?;
+        out.write_all(&signature_type.to_magic())?;
+        out.write_all(&options.block_size.to_be_bytes())?;
+        out.write_all(&options.crypto_hash_size.to_be_bytes())?;
+        out.write_all(&block_count.to_be_bytes())?;
+        out.write_all(&(options.rolling_seed.is_some() as u32).to_be_bytes())?;
+        out.write_all(&options.rolling_seed.unwrap_or(0).to_be_bytes())?;
+        let mut fanout = [0u64; 256];
+        for &(weak_sum, _, _) in entries {
+            fanout[(weak_sum >> 24) as usize] += 1;
+        }
+        let mut total = 0;
+        for count in fanout.iter_mut() {
+            total += *count;
+            out.write_all(&total.to_be_bytes())?;
+        }
+        for &(weak_sum, crypto_hash, idx) in entries {
+            out.write_all(&weak_sum.to_be_bytes())?;
+            out.write_all(&idx.to_be_bytes())?;
+            out.write_all(crypto_hash)?;
+        }
+        Ok(())
+    }
+}
+
+impl<B: AsRef<[u8]>> DiskIndexedSignature<B> {
+    /// Read an index written by [DiskIndexedSignature::write] or
+    /// [write_index](DiskIndexedSignature::write_index).
+    ///
+    /// Only the header, the fanout table, and the length of `data` are checked; a table which is
+    /// not sorted correctly will simply fail to find some blocks.
+    pub fn new(data: B) -> Result<Self, SignatureParseError> {
+        let bytes = data.as_ref();
+        if bytes.len() < TABLE_START
+            || u32::from_be_bytes(*array_ref![bytes, 0, 4]) != DISK_INDEX_MAGIC
+        {
+            return Err(SignatureParseError::Corrupt);
+        }
+        let signature_type = SignatureType::from_magic(*array_ref![bytes, 4, 4])?;
+        let block_size = u32::from_be_bytes(*array_ref![bytes, 8, 4]);
+        let crypto_hash_size = u32::from_be_bytes(*array_ref![bytes, 12, 4]);
+        let block_count = u64::from_be_bytes(*array_ref![bytes, 16, 8]);
+        let rolling_seed = match u32::from_be_bytes(*array_ref![bytes, 24, 4]) {
+            0 => None,
+            1 => Some(u32::from_be_bytes(*array_ref![bytes, 28, 4])),
+            _ => return Err(SignatureParseError::Corrupt),
+        };
+        let signature_type = signature_type.with_rolling_seed(rolling_seed);
+        let entry_size = ENTRY_PREFIX_SIZE + crypto_hash_size as usize;
+        let table_len = bytes.len() - TABLE_START;
+        if table_len % entry_size != 0 {
+            return Err(SignatureParseError::Corrupt);
+        }
+        let entry_count = table_len / entry_size;
+        let mut previous = 0;
+        for bucket in 0..256 {
+            let end = Self::fanout_in(bytes, bucket);
+            if end < previous || end > entry_count as u64 {
+                return Err(SignatureParseError::Corrupt);
+            }
+            previous = end;
+        }
+        if previous != entry_count as u64 {
+            return Err(SignatureParseError::Corrupt);
+        }
+        Ok(DiskIndexedSignature {
+            data,
+            signature_type,
+            block_size,
+            crypto_hash_size,
+            block_count,
+            entry_size,
+            entry_count,
+        })
+    }
+
+    /// Get back the underlying storage.
+    pub fn into_inner(self) -> B {
+        self.data
+    }
+
+    /// The number of entries whose rolling checksum's first byte is at most `bucket`.
+    fn fanout_in(bytes: &[u8], bucket: usize) -> u64 {
+        u64::from_be_bytes(*array_ref![bytes, HEADER_SIZE + bucket * 8, 8])
+    }
+
+    fn entry(&self, i: usize) -> (u32, u64, &[u8]) {
+        let start = TABLE_START + i * self.entry_size;
+        let entry = &self.data.as_ref()[start..start + self.entry_size];
+        (
+            u32::from_be_bytes(*array_ref![entry, 0, 4]),
+            u64::from_be_bytes(*array_ref![entry, 4, 8]),
+            &entry[ENTRY_PREFIX_SIZE..],
+        )
+    }
+
+    /// The index of the first entry which is not less than `key`.
+    fn partition_point(&self, key: (u32, &[u8])) -> usize {
+        let bucket = (key.0 >> 24) as usize;
+        let bytes = self.data.as_ref();
+        // Checked by `new` to be in bounds.
+        let mut lo = match bucket {
+            0 => 0,
+            _ => Self::fanout_in(bytes, bucket - 1) as usize,
+        };
+        let mut hi = Self::fanout_in(bytes, bucket) as usize;
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let (weak_sum, _, crypto_hash) = self.entry(mid);
+            if (weak_sum, crypto_hash) < key {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+}
+
+impl<B: AsRef<[u8]>> BlockIndex for DiskIndexedSignature<B> {
+    fn options(&self) -> SignatureOptions {
+        self.signature_type
+            .options(self.block_size, self.crypto_hash_size)
+    }
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+    fn contains_weak_sum(&self, weak_sum: u32) -> bool {
+        let i = self.partition_point((weak_sum, &[]));
+        i < self.entry_count && self.entry(i).0 == weak_sum
+    }
+    fn find_block(&self, weak_sum: u32, crypto_hash: &[u8]) -> Option<u64> {
+        let i = self.partition_point((weak_sum, crypto_hash));
+        if i == self.entry_count {
+            return None;
+        }
+        let (entry_weak_sum, idx, entry_hash) = self.entry(i);
+        if entry_weak_sum == weak_sum && entry_hash == crypto_hash {
+            Some(idx)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! A classification of errors shared by the error types of this crate.
+
+/// What kind of failure an error is, as returned by [ApplyError::kind()](crate::ApplyError::kind),
+/// [DiffError::kind()](crate::DiffError::kind),
+/// [SignatureParseError::kind()](crate::SignatureParseError::kind),
+/// [ConcatError::kind()](crate::ConcatError::kind) and `TreeError::kind()` (with
+/// the `fs` feature).
+///
+/// The error types themselves may gain variants in any release, so code which only needs to tell
+/// broad cases apart, e.g. whether to retry, can match on this instead. New kinds may be added as
+/// well, but existing variants of the error types keep their kind.
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ErrorKind {
+    /// A delta, signature or checkpoint is truncated, corrupt, or not one at all.
+    InvalidData,
+    /// A delta or signature uses a format or an extension which this build doesn't support.
+    Unsupported,
+    /// The arguments can't be used together, e.g. an index which leaves out blocks of its
+    /// signature.
+    InvalidArgument,
+    /// A limit given by the caller would have been exceeded.
+    LimitExceeded,
+    /// The output doesn't match the checksum or digest it should have, so it is not the data
+    /// it was meant to be.
+    VerificationFailed,
+    /// The operation was cancelled by the caller.
+    Cancelled,
+    /// Reading or writing failed with an IO error.
+    Io,
+}
@@ -0,0 +1,86 @@
+//! A block index stored as a sorted array, which is more compact than a hash map.
+
+use crate::signature::{BlockIndex, SignatureOptions, SignatureType};
+
+/// A signature index stored as a sorted array of blocks, searched by binary search.
+///
+/// Compared to [IndexedSignature](crate::IndexedSignature), this takes less memory and has
+/// better locality, at the cost of slower lookups for large signatures. It suits indexes which
+/// are built once and kept around, or signatures with many blocks. It is built with
+/// [Signature::index_flat](crate::Signature::index_flat), and can index any number of blocks.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FlatIndexedSignature<'a> {
+    signature_type: SignatureType,
+    block_size: u32,
+    crypto_hash_size: u32,
+    block_count: u64,
+    /// The rolling checksum of every distinct block, in ascending order.
+    weak_sums: Vec<u32>,
+    /// The strong hash and index of each block in `weak_sums`, ordered by strong hash among
+    /// blocks with the same rolling checksum.
+    blocks: Vec<(&'a [u8], u64)>,
+}
+
+impl<'a> FlatIndexedSignature<'a> {
+    pub(crate) fn new(
+        signature_type: SignatureType,
+        block_size: u32,
+        crypto_hash_size: u32,
+        blocks: impl ExactSizeIterator<Item = (u32, &'a [u8])>,
+    ) -> Self {
+        let block_count = blocks.len() as u64;
+        let entries = sorted_blocks(blocks);
+        FlatIndexedSignature {
+            signature_type,
+            block_size,
+            crypto_hash_size,
+            block_count,
+            weak_sums: entries.iter().map(|&(weak_sum, _, _)| weak_sum).collect(),
+            blocks: entries
+                .into_iter()
+                .map(|(_, crypto_hash, idx)| (crypto_hash, idx))
+                .collect(),
+        }
+    }
+}
+
+/// The distinct blocks of a signature with their indexes, sorted by rolling checksum and then
+/// strong hash, as stored by [FlatIndexedSignature] and
+/// [DiskIndexedSignature](crate::DiskIndexedSignature).
+pub(crate) fn sorted_blocks<'a>(
+    blocks: impl Iterator<Item = (u32, &'a [u8])>,
+) -> Vec<(u32, &'a [u8], u64)> {
+    let mut entries: Vec<(u32, &'a [u8], u64)> = blocks
+        .zip(0..)
+        .map(|((weak_sum, crypto_hash), idx)| (weak_sum, crypto_hash, idx))
+        .collect();
+    // Sort by block, then by descending index so that the first of several identical blocks is
+    // the last one in the signature, which is the one `IndexedSignature` would find.
+    entries.sort_unstable_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)).then(b.2.cmp(&a.2)));
+    entries.dedup_by(|a, b| (a.0, a.1) == (b.0, b.1));
+    entries
+}
+
+impl BlockIndex for FlatIndexedSignature<'_> {
+    fn options(&self) -> SignatureOptions {
+        self.signature_type
+            .options(self.block_size, self.crypto_hash_size)
+    }
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+    #[inline]
+    fn contains_weak_sum(&self, weak_sum: u32) -> bool {
+        self.weak_sums.binary_search(&weak_sum).is_ok()
+    }
+    fn find_block(&self, weak_sum: u32, crypto_hash: &[u8]) -> Option<u64> {
+        let start = self.weak_sums.partition_point(|&x| x < weak_sum);
+        // Blocks with the same rolling checksum are rare, so just scan them.
+        self.weak_sums[start..]
+            .iter()
+            .take_while(|&&x| x == weak_sum)
+            .zip(&self.blocks[start..])
+            .find(|(_, &(block_hash, _))| block_hash == crypto_hash)
+            .map(|(_, &(_, idx))| idx)
+    }
+}
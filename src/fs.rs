@@ -0,0 +1,140 @@
+//! Signing, diffing and patching files by path.
+//!
+//! These do the same as [Signature::calculate()], [diff()](crate::diff) and
+//! [apply()](crate::apply), for files which needn't fit in memory: each is read a piece at a
+//! time, and the output of [apply_file()] is written as with [apply_to_path()].
+//!
+//! With the `mmap` feature, files are mapped into memory instead, which is faster than copying
+//! them out with reads, unless they can't be, like pipes. A file which changes size while it is
+//! mapped is caught afterwards, but truncating one can crash the process on some platforms, so
+//! the files shouldn't be modified until the helper is done with them.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+#[cfg(feature = "mmap")]
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+
+use crate::atomic::apply_to_path;
+#[cfg(not(feature = "mmap"))]
+use crate::diff::diff_from_reader;
+use crate::diff::DiffError;
+#[cfg(feature = "mmap")]
+use crate::diff::DiffOptions;
+#[cfg(feature = "mmap")]
+use crate::mmap::{diff_mapped, Mapped};
+use crate::patch::ApplyError;
+use crate::signature::{BlockIndex, Signature, SignatureBuilder, SignatureOptions};
+
+/// How much of a file [sign_file()] reads at a time.
+const READ_SIZE: usize = 1 << 16;
+
+/// Calculate the signature of the file at `path`, as [Signature::calculate()] would for its
+/// contents.
+///
+/// The file is read 64 KiB at a time, so it can be larger than the memory available. With the
+/// `mmap` feature, it is mapped instead, and read again if it changes size while mapped.
+///
+/// Panics if the provided options are invalid; see [Signature::calculate()].
+pub fn sign_file(path: &Path, options: SignatureOptions) -> io::Result<Signature> {
+    let mut file = File::open(path)?;
+    #[cfg(feature = "mmap")]
+    if let Some(map) = Mapped::new(&file)? {
+        let signature = Signature::calculate(&map, options);
+        if !map.resized(&file)? {
+            return Ok(signature);
+        }
+        file.seek(SeekFrom::Start(0))?;
+    }
+    let mut builder = SignatureBuilder::new(options);
+    let mut buf = vec![0; READ_SIZE];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => builder.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(builder.finish())
+}
+
+/// Calculate a delta from `signature` to the contents of the file at `path`, and write it to
+/// `out`, as with [diff_from_reader()](crate::diff_from_reader).
+///
+/// A file which can't be opened or read fails with [DiffError::Io]. With the `mmap` feature,
+/// the file is diffed with `diff_mapped`, so this fails in the same way if it changes size
+/// while mapped.
+///
+/// # Security
+/// The caveats for [diff()](crate::diff) apply here as well.
+pub fn diff_file(
+    signature: &impl BlockIndex,
+    path: &Path,
+    out: impl Write,
+) -> Result<(), DiffError> {
+    #[cfg(feature = "mmap")]
+    {
+        diff_mapped(signature, path, out, &DiffOptions::default())
+    }
+    #[cfg(not(feature = "mmap"))]
+    {
+        diff_from_reader(signature, File::open(path)?, out)
+    }
+}
+
+/// Apply `delta` to the file at `base_path`, and store the output at `out_path`, replacing
+/// whatever file was there.
+///
+/// This is [apply_to_path()]: the output is renamed into place once it is complete and on disk,
+/// so `out_path` never holds a partly written file, and it can be the same as `base_path`.
+///
+/// # Security
+/// The caveats for [apply_to_path()] apply here as well.
+pub fn apply_file(base_path: &Path, delta: &[u8], out_path: &Path) -> Result<(), ApplyError> {
+    apply_to_path(base_path, delta, out_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_file, diff_file, sign_file};
+    use crate::tests::Fixture;
+    use crate::{DiffError, Signature, SignatureOptions};
+
+    #[test]
+    fn file_helpers_match_in_memory() {
+        let options = SignatureOptions {
+            block_size: 1000,
+            crypto_hash_size: 8,
+            ..Default::default()
+        };
+        let Fixture {
+            base,
+            data,
+            delta: expected,
+        } = Fixture::new(200_000, options);
+        let dir = tempfile::tempdir().unwrap();
+        let (base_path, data_path, out_path) = (
+            dir.path().join("base"),
+            dir.path().join("data"),
+            dir.path().join("out"),
+        );
+        std::fs::write(&base_path, &base).unwrap();
+        std::fs::write(&data_path, &data).unwrap();
+
+        let signature = sign_file(&base_path, options).unwrap();
+        assert_eq!(signature, Signature::calculate(&base, options));
+        let mut delta = vec![];
+        diff_file(&signature.index(), &data_path, &mut delta).unwrap();
+        assert_eq!(delta, expected);
+        apply_file(&base_path, &delta, &out_path).unwrap();
+        assert_eq!(std::fs::read(&out_path).unwrap(), data);
+
+        let missing = dir.path().join("missing");
+        assert!(sign_file(&missing, options).is_err());
+        assert!(matches!(
+            diff_file(&signature.index(), &missing, &mut vec![]),
+            Err(DiffError::Io(_))
+        ));
+    }
+}
@@ -1,7 +1,7 @@
 //! Contains a hashmap optimized for the second layer of the
 //! [`IndexedSignature`][crate::signature::IndexedSignature]
 
-use std::{collections::HashMap, hash::Hash, mem};
+use std::{borrow::Borrow, collections::HashMap, hash::Hash, mem};
 
 /// A single entry optimized hashmap intended for use in the second layer map in
 /// [`IndexedSignature`][crate::signature::IndexedSignature]
@@ -46,6 +46,9 @@ where
 
         let (new_state, ret) = match old_state {
             Self::Empty => (Self::Single(key, val), None),
+            Self::Single(old_key, old_val) if old_key == key => {
+                (Self::Single(key, val), Some(old_val))
+            }
             Self::Single(old_key, old_val) => {
                 let mut map = Box::new(HashMap::with_capacity(2));
                 map.insert(key, val);
@@ -63,10 +66,14 @@ where
     }
 
     /// Analogous to [`HashMap::get`]
-    pub fn get(&self, needle: &K) -> Option<&V> {
+    pub fn get<Q>(&self, needle: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
         match self {
             Self::Single(key, val) => {
-                if needle == key {
+                if needle == key.borrow() {
                     Some(val)
                 } else {
                     None
@@ -76,4 +83,29 @@ where
             Self::Empty => None,
         }
     }
+
+    /// Analogous to [`HashMap::iter`]
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        let (single, map) = match self {
+            Self::Empty => (None, None),
+            Self::Single(key, val) => (Some((key, val)), None),
+            Self::TwoOrMore(map) => (None, Some(map.iter())),
+        };
+        single.into_iter().chain(map.into_iter().flatten())
+    }
+
+    /// Convert every key with `f`, e.g. to take ownership of borrowed keys
+    pub fn map_keys<K2, F>(self, mut f: F) -> SecondLayerMap<K2, V>
+    where
+        K2: Eq + Hash,
+        F: FnMut(K) -> K2,
+    {
+        match self {
+            Self::Empty => SecondLayerMap::Empty,
+            Self::Single(key, val) => SecondLayerMap::Single(f(key), val),
+            Self::TwoOrMore(map) => SecondLayerMap::TwoOrMore(Box::new(
+                map.into_iter().map(|(k, v)| (f(k), v)).collect(),
+            )),
+        }
+    }
 }
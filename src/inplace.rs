@@ -0,0 +1,348 @@
+//! Applying a delta to a file in place, with the output overwriting the base.
+
+use std::borrow::Cow;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::blake2::Blake2Hasher;
+use crate::diff::DeltaCommand;
+#[cfg(feature = "zstd")]
+use crate::patch::{compressed_len, decompress};
+use crate::patch::{ApplyError, DeltaReader};
+
+/// How much of a copy is moved at a time.
+const CHUNK_SIZE: usize = 1 << 16;
+
+/// A copy of `len` bytes from `source` to `target`.
+#[derive(Clone, Copy, Debug)]
+struct Move {
+    source: u64,
+    target: u64,
+    len: u64,
+}
+
+/// A step of moving data around in place, as ordered by [schedule()].
+#[derive(Debug, Eq, PartialEq)]
+enum Step {
+    /// Do the move with this index.
+    Move(usize),
+    /// Read the source of the move with this index into memory, to write it out at the end.
+    Save(usize),
+}
+
+/// Apply `delta` to the base data in `file`, overwriting it with the output, and return the
+/// length of the output.
+///
+/// The output is written over the base rather than next to it, so patching a large file doesn't
+/// take twice its size on disk. Copies to where the data already is are skipped, so the parts of
+/// the file which the delta leaves in place aren't rewritten at all. The other copies are ordered
+/// so that none of them overwrites data which another one still has to read. Where copies depend
+/// on each other in a cycle, e.g. two blocks which trade places, the data of the shortest one is
+/// read into memory first instead. Self-copies (see
+/// [DiffOptions::self_copies](crate::DiffOptions::self_copies)) are applied last, and a
+/// [checksum](crate::DiffOptions::checksum) is checked by reading the output back.
+///
+/// If the output is shorter than the base, the rest of the base is left at the end of `file`,
+/// so truncate it to the returned length, e.g. with [File::set_len](std::fs::File::set_len).
+///
+/// The delta is checked as with [validate_delta()](crate::validate_delta) before `file` is
+/// modified, and its compressed literals are decompressed, so they are held in memory until
+/// they are written. Applying it fails with [ApplyError::OutputLimit] before that as well if the
+/// output would be longer than `limit` bytes. But if it fails after that, because of an IO error
+/// or a checksum mismatch, `file` holds neither the base nor the output. So this is only for
+/// files which can be restored some other way if that happens.
+pub fn apply_in_place<F: Read + Write + Seek>(
+    file: &mut F,
+    delta: &[u8],
+    limit: usize,
+) -> Result<u64, ApplyError> {
+    let base_len = file.seek(SeekFrom::End(0))?;
+    let mut reader = DeltaReader::new(delta)?.with_base_len(base_len);
+    #[cfg(feature = "zstd")]
+    let compressed = reader.has_compressed_literals();
+    let mut moves = Vec::new();
+    let mut literals = Vec::new();
+    let mut self_copies = Vec::new();
+    let mut target = 0;
+    while let Some(command) = reader.next() {
+        match command? {
+            DeltaCommand::Copy { offset, len } if offset != target => moves.push(Move {
+                source: offset,
+                target,
+                len,
+            }),
+            DeltaCommand::Copy { .. } | DeltaCommand::End => {}
+            // decompressed now, so that the targets after it don't rely on the size it claims
+            #[cfg(feature = "zstd")]
+            DeltaCommand::Literal(literal) if compressed => {
+                let size = compressed_len(literal)?;
+                if size > limit as u64 - target {
+                    return Err(ApplyError::OutputLimit {
+                        what: "literal",
+                        wanted: size.min(usize::MAX as u64) as usize,
+                        available: (limit as u64 - target) as usize,
+                    });
+                }
+                literals.push((target, Cow::Owned(decompress(literal, size)?)))
+            }
+            DeltaCommand::Literal(literal) => literals.push((target, Cow::Borrowed(literal))),
+            DeltaCommand::SelfCopy { offset, len } => self_copies.push(Move {
+                source: offset,
+                target,
+                len,
+            }),
+        }
+        target = reader.output_len();
+        if target > limit as u64 {
+            return Err(ApplyError::OutputLimit {
+                what: "output",
+                wanted: target.min(usize::MAX as u64) as usize,
+                available: limit,
+            });
+        }
+    }
+
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut saved = Vec::new();
+    for step in schedule(&moves) {
+        match step {
+            Step::Move(i) => move_within(file, moves[i], &mut buffer)?,
+            Step::Save(i) => {
+                let mut data = vec![0; moves[i].len as usize];
+                file.seek(SeekFrom::Start(moves[i].source))?;
+                file.read_exact(&mut data)?;
+                saved.push((moves[i].target, data));
+            }
+        }
+    }
+    for (target, data) in &saved {
+        file.seek(SeekFrom::Start(*target))?;
+        file.write_all(data)?;
+    }
+    for (target, literal) in &literals {
+        file.seek(SeekFrom::Start(*target))?;
+        file.write_all(literal)?;
+    }
+    // Each one only reads output from before its target, which is all written by now, or by the
+    // self-copies before it.
+    for self_copy in self_copies {
+        move_within(file, self_copy, &mut buffer)?;
+    }
+
+    let output_len = reader.output_len();
+    if let Some(checksum) = reader.checksum() {
+        file.seek(SeekFrom::Start(0))?;
+        let mut hasher = Blake2Hasher::default();
+        let mut rest = output_len;
+        while rest > 0 {
+            let chunk = &mut buffer[..rest.min(CHUNK_SIZE as u64) as usize];
+            file.read_exact(chunk)?;
+            hasher.update(chunk);
+            rest -= chunk.len() as u64;
+        }
+        if hasher.finalize()[..] != *checksum {
+            return Err(ApplyError::ChecksumMismatch);
+        }
+    }
+    file.flush()?;
+    Ok(output_len)
+}
+
+/// Order `moves`, which are sorted by target, so that none of them overwrites the source of
+/// another before it is read.
+///
+/// Where the remaining moves all wait for each other, the source of the shortest one is saved
+/// in memory instead, which lets the moves which overwrite it go ahead.
+fn schedule(moves: &[Move]) -> Vec<Step> {
+    // `blocks[y]` lists the moves which overwrite the source of move `y`, and `waiting[x]`
+    // counts the moves whose sources move `x` overwrites.
+    let mut blocks = vec![Vec::new(); moves.len()];
+    let mut waiting = vec![0usize; moves.len()];
+    for (y, source) in moves.iter().enumerate() {
+        let end = source.source + source.len;
+        // the targets don't overlap, so those which overlap the source are all in a row
+        let first = moves.partition_point(|x| x.target + x.len <= source.source);
+        for (x, target) in moves.iter().enumerate().skip(first) {
+            if target.target >= end {
+                break;
+            }
+            if x != y {
+                blocks[y].push(x);
+                waiting[x] += 1;
+            }
+        }
+    }
+
+    let mut ready: Vec<usize> = (0..moves.len()).filter(|&x| waiting[x] == 0).collect();
+    let mut by_len: Vec<usize> = (0..moves.len()).collect();
+    by_len.sort_unstable_by_key(|&x| moves[x].len);
+    let mut by_len = by_len.into_iter();
+    let mut done = vec![false; moves.len()];
+    let mut steps = Vec::with_capacity(moves.len());
+    while steps.len() < moves.len() {
+        let step = match ready.pop() {
+            Some(x) => Step::Move(x),
+            None => Step::Save(by_len.find(|&x| !done[x]).expect("a move is left to do")),
+        };
+        let y = match step {
+            Step::Move(y) | Step::Save(y) => y,
+        };
+        done[y] = true;
+        steps.push(step);
+        for &x in &blocks[y] {
+            waiting[x] -= 1;
+            if waiting[x] == 0 && !done[x] {
+                ready.push(x);
+            }
+        }
+    }
+    steps
+}
+
+/// Copy data within `file` a chunk at a time, in whichever direction works if the source and
+/// the target overlap, like `memmove`.
+fn move_within(
+    file: &mut (impl Read + Write + Seek),
+    m: Move,
+    buffer: &mut [u8],
+) -> io::Result<()> {
+    let mut done = 0;
+    while done < m.len {
+        let n = (m.len - done).min(buffer.len() as u64);
+        let from = if m.target > m.source {
+            m.len - done - n
+        } else {
+            done
+        };
+        let chunk = &mut buffer[..n as usize];
+        file.seek(SeekFrom::Start(m.source + from))?;
+        file.read_exact(chunk)?;
+        file.seek(SeekFrom::Start(m.target + from))?;
+        file.write_all(chunk)?;
+        done += n;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_in_place, schedule, Move, Step};
+    use crate::{diff_with_options, DeltaWriter, DiffOptions, Signature, SignatureOptions};
+    use rand::Rng;
+    use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+    /// Counts how many bytes are written through it.
+    struct Counting(Cursor<Vec<u8>>, u64);
+
+    impl Read for Counting {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = self.0.write(buf)?;
+            self.1 += n as u64;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Counting {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    fn patch(base: &[u8], delta: &[u8]) -> (Vec<u8>, u64) {
+        let mut file = Counting(Cursor::new(base.to_vec()), 0);
+        let len = apply_in_place(&mut file, delta, usize::MAX).expect("apply error");
+        let mut out = file.0.into_inner();
+        out.truncate(len as usize);
+        (out, file.1)
+    }
+
+    #[test]
+    fn in_place() {
+        let mut rng = rand::thread_rng();
+        let mut base = vec![0; 200_000];
+        rng.fill(&mut base[..]);
+        let mut data = base.clone();
+        // changed in place, moved, swapped with each other, and repeated
+        rng.fill(&mut data[5000..6000]);
+        data.copy_within(50_000..60_000, 52_000);
+        data[100_000..110_000].copy_from_slice(&base[120_000..130_000]);
+        data[120_000..130_000].copy_from_slice(&base[100_000..110_000]);
+        data.extend_from_slice(&base[100_000..101_000]);
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions {
+                block_size: 1000,
+                crypto_hash_size: 8,
+                ..Default::default()
+            },
+        );
+        for &(self_copies, checksum) in &[(false, false), (true, true)] {
+            let options = DiffOptions {
+                self_copies,
+                checksum,
+                ..Default::default()
+            };
+            let mut delta = vec![];
+            diff_with_options(&signature.index(), &data, &mut delta, &options).expect("diff error");
+            let (out, written) = patch(&base, &delta);
+            assert_eq!(out, data);
+            // most of the file stays where it was
+            assert!(written < 50_000, "{}", written);
+
+            // and shorter
+            let mut delta = vec![];
+            diff_with_options(
+                &signature.index(),
+                &data[1000..150_000],
+                &mut delta,
+                &options,
+            )
+            .expect("diff error");
+            assert_eq!(patch(&base, &delta).0, &data[1000..150_000]);
+
+            // a limit fails before anything is written
+            let mut file = Counting(Cursor::new(base.clone()), 0);
+            assert!(matches!(
+                apply_in_place(&mut file, &delta, 148_998),
+                Err(crate::ApplyError::OutputLimit { .. })
+            ));
+            assert_eq!(file.1, 0);
+        }
+    }
+
+    #[test]
+    fn cycles() {
+        // two ranges which trade places, and one which moves within itself
+        let base: Vec<u8> = (0..40).collect();
+        let mut delta = DeltaWriter::new(vec![]).unwrap();
+        for &(offset, len) in &[(10, 10), (20, 10), (0, 5), (5, 5), (32, 8)] {
+            delta.copy(offset, len).unwrap();
+        }
+        let delta = delta.finish().unwrap();
+        let data = [&base[10..30], &base[0..10], &base[32..40]].concat();
+        assert_eq!(patch(&base, &delta).0, data);
+
+        let moves = [
+            Move {
+                source: 10,
+                target: 0,
+                len: 20,
+            },
+            Move {
+                source: 0,
+                target: 20,
+                len: 10,
+            },
+        ];
+        assert_eq!(schedule(&moves), [Step::Save(1), Step::Move(0)]);
+    }
+}
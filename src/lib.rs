@@ -13,18 +13,90 @@
 #![allow(clippy::unreadable_literal)]
 #![deny(missing_docs)]
 
+mod atomic;
+mod blake2;
+mod bloom;
+mod cache;
+mod chain;
 mod consts;
 mod crc;
+mod describe;
 mod diff;
+mod disk_index;
+mod error;
+mod flat_index;
+#[cfg(feature = "fs")]
+pub mod fs;
 mod hasher;
 mod hashmap_variant;
+mod inplace;
 mod md4;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod optimize;
 mod patch;
+mod rabinkarp;
+mod rebase;
+mod seed;
 mod signature;
+#[cfg(all(feature = "sparse", unix))]
+mod sparse;
+#[cfg(feature = "tempfile")]
+mod spill;
+mod transcode;
+#[cfg(feature = "fs")]
+pub mod tree;
+mod vcdiff;
+mod windowed;
 
 #[cfg(test)]
 mod tests;
 
-pub use diff::{diff, DiffError};
-pub use patch::{apply, apply_limited, ApplyError};
-pub use signature::{IndexedSignature, Signature, SignatureOptions, SignatureParseError};
+pub use atomic::apply_to_path;
+pub use cache::SignatureCache;
+pub use chain::{apply_chain, compose};
+pub use describe::{describe_delta, DescribeOptions};
+#[cfg(feature = "tokio")]
+pub use diff::diff_async;
+#[cfg(feature = "rayon")]
+pub use diff::diff_parallel;
+#[cfg(feature = "digest")]
+pub use diff::diff_with_digest;
+pub use diff::{
+    diff, diff_commands, diff_from_reader, diff_limited, diff_with_base, diff_with_options,
+    diff_with_progress, estimate_delta_size, similarity, DeltaCommand, DeltaWriter, DiffCommand,
+    DiffError, DiffOptions, DiffProgress, DiffState, DuplicateBlocks,
+};
+pub use disk_index::DiskIndexedSignature;
+pub use error::ErrorKind;
+pub use flat_index::FlatIndexedSignature;
+pub use inplace::apply_in_place;
+#[cfg(feature = "md4-stats")]
+pub use md4::{md4_stats, reset_md4_stats, Md4Stats};
+#[cfg(feature = "mmap")]
+pub use mmap::{diff_files, diff_mapped};
+pub use optimize::optimize_delta;
+#[cfg(feature = "tokio")]
+pub use patch::apply_async;
+#[cfg(feature = "rayon")]
+pub use patch::apply_parallel;
+pub use patch::{
+    apply, apply_from_reader, apply_into, apply_limited, apply_prefix, apply_resume, apply_seek,
+    apply_seek_limited, apply_to_vec, apply_to_vec_limited, apply_verified, apply_with_options,
+    apply_with_provider, apply_with_stats, plan_apply, validate_delta, ApplyError, ApplyOptions,
+    ApplyPrefix, ApplyState, ApplyStats, BaseProvider, DeltaReader, DeltaSummary, OutputDigest,
+    ResumeToken,
+};
+pub use rebase::{rebase, RebaseError};
+pub use signature::{
+    BlockIndex, ConcatError, HashKey, IncompatibleSignatures, IndexOptions, IndexedSignature,
+    InvalidOptions, OwnedIndexedSignature, RollingHash, Signature, SignatureBuilder, SignatureHash,
+    SignatureOptions, SignatureOptionsBuilder, SignatureParseError, SignatureRef,
+};
+#[cfg(all(feature = "sparse", unix))]
+pub use sparse::apply_sparse;
+#[cfg(feature = "tempfile")]
+pub use spill::{apply_spilling, ApplyOutput};
+pub use transcode::{transcode_delta, DeltaFormat};
+pub use vcdiff::{apply_vcdiff, diff_vcdiff};
+pub use windowed::diff_windowed;
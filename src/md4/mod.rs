@@ -147,12 +147,16 @@ pub fn md4(data: &[u8]) -> [u8; 16] {
     for block in &mut chunks {
         state.process_block(&load_block(array_ref![block, 0, 64]));
     }
-    let remainder = chunks.remainder();
+    finish(state, chunks.remainder(), data.len() as u64)
+}
+
+/// Pad the last, partial block of the data, whose total length is `len`, and return the digest.
+fn finish(mut state: Md4State, remainder: &[u8], len: u64) -> [u8; 16] {
     let mut last_blocks = [0; 128];
     last_blocks[..remainder.len()].copy_from_slice(remainder);
     last_blocks[remainder.len()] = 0x80;
     let end = if remainder.len() >= 56 { 128 } else { 64 };
-    *array_mut_ref![&mut last_blocks, end - 8, 8] = (data.len() as u64 * 8).to_le_bytes();
+    *array_mut_ref![&mut last_blocks, end - 8, 8] = (len * 8).to_le_bytes();
     let (last_block_0, last_block_1) = array_refs![&last_blocks, 64, 64];
     state.process_block(&load_block(last_block_0));
     if end == 128 {
@@ -167,6 +171,52 @@ pub fn md4(data: &[u8]) -> [u8; 16] {
     digest
 }
 
+/// MD4 of data which is passed in a piece at a time.
+#[derive(Clone)]
+pub struct Md4Hasher {
+    state: Md4State,
+    buf: [u8; 64],
+    len: u64,
+}
+
+impl Default for Md4Hasher {
+    fn default() -> Self {
+        Md4Hasher {
+            state: Md4State { s: S },
+            buf: [0; 64],
+            len: 0,
+        }
+    }
+}
+
+impl Md4Hasher {
+    pub fn update(&mut self, mut data: &[u8]) {
+        let buffered = (self.len % 64) as usize;
+        self.len += data.len() as u64;
+        if buffered > 0 {
+            let n = (64 - buffered).min(data.len());
+            self.buf[buffered..buffered + n].copy_from_slice(&data[..n]);
+            data = &data[n..];
+            if buffered + n < 64 {
+                return;
+            }
+            self.state.process_block(&load_block(&self.buf));
+        }
+        let mut chunks = data.chunks_exact(64);
+        for block in &mut chunks {
+            self.state
+                .process_block(&load_block(array_ref![block, 0, 64]));
+        }
+        let remainder = chunks.remainder();
+        self.buf[..remainder.len()].copy_from_slice(remainder);
+    }
+
+    pub fn finalize(&self) -> [u8; 16] {
+        let buffered = (self.len % 64) as usize;
+        finish(self.state, &self.buf[..buffered], self.len)
+    }
+}
+
 mod simd {
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub const MAX_LANES: usize = 8;
@@ -432,6 +482,61 @@ mod simd {
     }
 }
 
+/// Statistics about how signature calculation, and diffs verifying runs of matching blocks, hashed
+/// MD4 blocks on the current thread.
+///
+/// Blocks are hashed in SIMD batches of [lanes](Md4Stats::lanes) blocks at a time; whatever doesn't
+/// fill a whole batch falls back to the much slower scalar implementation. A high proportion of
+/// `scalar_blocks` means the inputs are too small (in number of blocks) to benefit from SIMD.
+#[cfg(feature = "md4-stats")]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Md4Stats {
+    /// The number of blocks hashed in full SIMD batches.
+    pub simd_blocks: u64,
+    /// The number of blocks hashed one at a time.
+    pub scalar_blocks: u64,
+    /// The number of blocks in each SIMD batch, or 0 if no SIMD implementation is available.
+    pub lanes: usize,
+}
+
+#[cfg(feature = "md4-stats")]
+thread_local! {
+    static STATS: std::cell::Cell<(u64, u64)> = const { std::cell::Cell::new((0, 0)) };
+}
+
+/// Get the [Md4Stats] accumulated by the current thread since it started (or since the last call
+/// to [reset_md4_stats()]).
+#[cfg(feature = "md4-stats")]
+pub fn md4_stats() -> Md4Stats {
+    let (simd_blocks, scalar_blocks) = STATS.with(|stats| stats.get());
+    Md4Stats {
+        simd_blocks,
+        scalar_blocks,
+        lanes: simd::Md4xN::select().map_or(0, |simd_impl| simd_impl.lanes()),
+    }
+}
+
+/// Reset the current thread's [Md4Stats] counters to zero.
+#[cfg(feature = "md4-stats")]
+pub fn reset_md4_stats() {
+    STATS.with(|stats| stats.set((0, 0)));
+}
+
+macro_rules! count_blocks {
+    ($simd:expr, $scalar:expr) => {
+        #[cfg(feature = "md4-stats")]
+        STATS.with(|stats| {
+            let (simd_blocks, scalar_blocks) = stats.get();
+            stats.set((simd_blocks + $simd as u64, scalar_blocks + $scalar as u64));
+        });
+    };
+}
+
+/// The number of blocks [md4_many()] hashes at once, or 1 if it has no SIMD implementation.
+pub fn md4_many_lanes() -> usize {
+    simd::Md4xN::select().map_or(1, |simd_impl| simd_impl.lanes())
+}
+
 pub fn md4_many<'a>(
     datas: impl ExactSizeIterator<Item = &'a [u8]>,
 ) -> impl ExactSizeIterator<Item = (&'a [u8], [u8; 16])> {
@@ -456,6 +561,7 @@ pub fn md4_many<'a>(
                         datas[ix] = self.inner.next().unwrap();
                     }
                     self.len -= simd.simd_impl.lanes();
+                    count_blocks!(simd.simd_impl.lanes(), 0);
                     let digests = simd.simd_impl.md4(&datas);
                     simd.buf_len = simd.simd_impl.lanes();
                     for lane in 0..simd.simd_impl.lanes() {
@@ -470,6 +576,7 @@ pub fn md4_many<'a>(
             }
             self.inner.next().map(|data| {
                 self.len -= 1;
+                count_blocks!(0, 1);
                 (data, md4(data))
             })
         }
@@ -528,6 +635,13 @@ fn tests() {
 
     for &(msg, expected) in test_vectors {
         assert_eq!(md4(msg), expected);
+        for &piece in &[1, 7, 64, 100] {
+            let mut hasher = Md4Hasher::default();
+            for chunk in msg.chunks(piece) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finalize(), expected);
+        }
         if let Some(simd_impl) = simd::Md4xN::select() {
             assert_eq!(
                 simd_impl.md4(&vec![msg; simd_impl.lanes()])[..simd_impl.lanes()],
@@ -547,3 +661,25 @@ fn tests() {
         }
     }
 }
+
+#[cfg(feature = "md4-stats")]
+#[test]
+fn stats() {
+    let data = [0; 64 * 21];
+    reset_md4_stats();
+    assert_eq!(md4_many(data.chunks(64)).count(), 21);
+    let stats = md4_stats();
+    let expected_simd = 21usize
+        .checked_div(stats.lanes)
+        .map_or(0, |batches| batches * stats.lanes);
+    assert_eq!(
+        stats,
+        Md4Stats {
+            simd_blocks: expected_simd as u64,
+            scalar_blocks: 21 - expected_simd as u64,
+            lanes: stats.lanes,
+        }
+    );
+    reset_md4_stats();
+    assert_eq!(md4_stats().simd_blocks + md4_stats().scalar_blocks, 0);
+}
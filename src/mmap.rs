@@ -0,0 +1,218 @@
+//! Reading files by mapping them into memory, which is faster than copying them out with reads.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::ops::Deref;
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::diff::{diff_reader_with_options, diff_with_options, DiffError, DiffOptions};
+use crate::signature::{BlockIndex, IndexedSignature};
+
+/// Calculate a delta from the signature stored at `signature` to the contents of `new_file`, and
+/// write it to `out`.
+///
+/// Both files are mapped into memory rather than read, so they can be larger than the memory
+/// available, and the signature is indexed without being copied. A file which can't be mapped,
+/// like a pipe, is read instead. A signature which doesn't parse fails with
+/// [DiffError::InvalidSignature], and a file which can't be opened or read with
+/// [DiffError::Io], as does a file which changes size before the diff is done.
+///
+/// Neither file should be modified until the diff is done. If one is, the delta may be wrong,
+/// and truncating one can even crash the process on some platforms.
+///
+/// # Security
+/// The caveats for [diff()](crate::diff) apply here as well.
+pub fn diff_files(signature: &Path, new_file: &Path, out: impl Write) -> Result<(), DiffError> {
+    let file = File::open(signature)?;
+    let map = Mapped::new(&file)?;
+    let mut read = Vec::new();
+    let signature = match &map {
+        Some(map) => &map[..],
+        None => {
+            (&file).read_to_end(&mut read)?;
+            &read[..]
+        }
+    };
+    let index =
+        IndexedSignature::from_serialized(signature).map_err(|_| DiffError::InvalidSignature)?;
+    diff_mapped(&index, new_file, out, &DiffOptions::default())?;
+    if let Some(map) = map {
+        map.check_size(&file)?;
+    }
+    Ok(())
+}
+
+/// Like [diff_files()], but with a signature which is already in memory, and with additional
+/// control over the produced delta.
+///
+/// A file which can't be mapped is read a piece at a time as with [DiffState](crate::DiffState),
+/// whose delta can differ from that of [diff_with_options()](crate::diff_with_options).
+///
+/// # Security
+/// The caveats for [diff()](crate::diff) apply here as well.
+pub fn diff_mapped(
+    signature: &impl BlockIndex,
+    new_file: &Path,
+    out: impl Write,
+    options: &DiffOptions,
+) -> Result<(), DiffError> {
+    let file = File::open(new_file)?;
+    match Mapped::new(&file)? {
+        Some(data) => {
+            diff_with_options(signature, &data, out, options)?;
+            Ok(data.check_size(&file)?)
+        }
+        None => diff_reader_with_options(signature, &file, out, options),
+    }
+}
+
+/// A file mapped into memory.
+pub(crate) struct Mapped {
+    /// The map, or nothing if the file is empty, which not every platform can map.
+    map: Option<Mmap>,
+    len: u64,
+}
+
+impl Mapped {
+    /// Map `file`, or return `None` if it can't be, e.g. because it isn't a regular file, in which
+    /// case it should be read instead.
+    ///
+    /// Reading the map while the file is truncated can crash the process on some platforms, so
+    /// callers must document that the file shouldn't change while it is used, and should check
+    /// with [check_size()](Mapped::check_size) whether it did afterwards.
+    pub(crate) fn new(file: &File) -> io::Result<Option<Mapped>> {
+        let metadata = file.metadata()?;
+        let len = metadata.len();
+        if !metadata.is_file() || len > usize::MAX as u64 {
+            return Ok(None);
+        }
+        if len == 0 {
+            return Ok(Some(Mapped { map: None, len }));
+        }
+        // Safety: the callers are documented to require that the file doesn't change while mapped.
+        match unsafe { Mmap::map(file) } {
+            Ok(map) => Ok(Some(Mapped {
+                map: Some(map),
+                len,
+            })),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Whether `file`, which this is a map of, is no longer as long as it was when it was
+    /// mapped. If so, the map may hold a mix of its old and new contents.
+    pub(crate) fn resized(&self, file: &File) -> io::Result<bool> {
+        Ok(file.metadata()?.len() != self.len)
+    }
+
+    /// Fail if [resized()](Mapped::resized).
+    pub(crate) fn check_size(&self, file: &File) -> io::Result<()> {
+        if self.resized(file)? {
+            return Err(io::Error::other(
+                "the file changed size while it was being read",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Deref for Mapped {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.map.as_deref().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_files, diff_mapped, Mapped};
+    use crate::tests::Fixture;
+    use crate::{apply, DiffError, DiffOptions, Signature, SignatureOptions};
+    use std::io::Write;
+
+    #[test]
+    fn diff_files_matches_diff() {
+        let Fixture {
+            base,
+            data,
+            delta: expected,
+        } = Fixture::new(100_000, SignatureOptions::default());
+        let signature = Signature::calculate(&base, SignatureOptions::default());
+        let dir = tempfile::tempdir().unwrap();
+        let (signature_path, data_path) = (dir.path().join("sig"), dir.path().join("data"));
+        std::fs::write(&signature_path, signature.serialized()).unwrap();
+        std::fs::write(&data_path, &data).unwrap();
+
+        let mut delta = vec![];
+        diff_files(&signature_path, &data_path, &mut delta).unwrap();
+        assert_eq!(delta, expected);
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).unwrap();
+        assert_eq!(out, data);
+
+        // an empty file can't be mapped on every platform
+        let empty_path = dir.path().join("empty");
+        std::fs::write(&empty_path, b"").unwrap();
+        let mut delta = vec![];
+        diff_mapped(
+            &signature.index(),
+            &empty_path,
+            &mut delta,
+            &DiffOptions::default(),
+        )
+        .unwrap();
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).unwrap();
+        assert!(out.is_empty());
+
+        assert!(matches!(
+            diff_files(&data_path, &data_path, &mut vec![]),
+            Err(DiffError::InvalidSignature)
+        ));
+        assert!(matches!(
+            diff_files(&signature_path, &dir.path().join("missing"), &mut vec![]),
+            Err(DiffError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn mapped_notices_resize() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"some data").unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let map = Mapped::new(&file).unwrap().unwrap();
+        assert_eq!(&map[..], b"some data");
+        map.check_size(&file).unwrap();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b" and more")
+            .unwrap();
+        assert!(map.resized(&file).unwrap());
+        assert!(map.check_size(&file).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn diff_mapped_reads_what_cant_be_mapped() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        assert!(Mapped::new(&file).unwrap().is_none());
+        let signature = Signature::calculate(b"base", SignatureOptions::default());
+        let mut delta = vec![];
+        diff_mapped(
+            &signature.index(),
+            "/dev/null".as_ref(),
+            &mut delta,
+            &DiffOptions::default(),
+        )
+        .unwrap();
+        let mut out = vec![];
+        apply(b"base", &delta, &mut out).unwrap();
+        assert!(out.is_empty());
+    }
+}
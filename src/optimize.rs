@@ -0,0 +1,161 @@
+//! Re-encoding deltas as compactly as their commands allow.
+
+use std::io;
+
+use crate::consts::{RS_OP_COPY_N1_N1, RS_OP_END, RS_OP_SELF_COPY_N1_N1};
+use crate::diff::{copy_command, insert_command, DeltaCommand};
+use crate::patch::{ApplyError, DeltaReader};
+
+/// Re-encode `delta` with the same output in as few bytes as the format allows, without
+/// needing the base.
+///
+/// Copies of adjacent ranges of the base are merged into one, as are adjacent literals, and
+/// [self-copies](crate::DiffOptions::self_copies) of adjacent ranges of the output as long as
+/// they don't reach into what the first of them writes. Every offset and length is written in
+/// as few bytes as it fits in. [diff()](crate::diff) already writes deltas this way, but other
+/// encoders, e.g. ones which stream commands out without looking ahead, often don't.
+///
+/// The delta keeps its format and extensions, and its
+/// [checksum](crate::DiffOptions::checksum) if it has one. Compressed literals (see
+/// `DiffOptions::compress_literals`, with the `zstd` feature) are kept as they are, since
+/// merging them would mean compressing them again. The result is never longer than `delta`.
+///
+/// The delta is checked as with [DeltaReader].
+pub fn optimize_delta(delta: &[u8]) -> Result<Vec<u8>, ApplyError> {
+    let mut reader = DeltaReader::new(delta)?;
+    let mut encoder = Encoder {
+        out: delta[..4].to_vec(),
+        pending: Pending::None,
+        literal: Vec::new(),
+        compressed: reader.has_compressed_literals(),
+    };
+    loop {
+        let output_offset = reader.output_len();
+        match reader
+            .next()
+            .expect("a delta ends with an end command or an error")?
+        {
+            DeltaCommand::End => break,
+            command => encoder.command(command, output_offset)?,
+        }
+    }
+    encoder.flush()?;
+    let mut out = encoder.out;
+    out.push(RS_OP_END);
+    if let Some(checksum) = reader.checksum() {
+        out.extend_from_slice(checksum);
+    }
+    Ok(out)
+}
+
+/// The command which [Encoder] holds back in case the next one extends it.
+enum Pending {
+    None,
+    Copy {
+        offset: u64,
+        len: u64,
+    },
+    SelfCopy {
+        offset: u64,
+        len: u64,
+        /// Where its output goes, which the copy can't reach into.
+        output_offset: u64,
+    },
+    /// The data of [Encoder::literal].
+    Literal,
+}
+
+struct Encoder {
+    out: Vec<u8>,
+    pending: Pending,
+    literal: Vec<u8>,
+    compressed: bool,
+}
+
+impl Encoder {
+    fn command(&mut self, command: DeltaCommand<'_>, output_offset: u64) -> io::Result<()> {
+        let merged = match (&mut self.pending, command) {
+            (
+                Pending::Copy { offset, len },
+                DeltaCommand::Copy {
+                    offset: next,
+                    len: n,
+                },
+            ) => extend(*offset, len, next, n),
+            (
+                Pending::SelfCopy {
+                    offset,
+                    len,
+                    output_offset,
+                },
+                DeltaCommand::SelfCopy {
+                    offset: next,
+                    len: n,
+                },
+            ) => match next.checked_add(n) {
+                Some(end) if end <= *output_offset => extend(*offset, len, next, n),
+                _ => false,
+            },
+            (Pending::Literal, DeltaCommand::Literal(data)) if !self.compressed => {
+                self.literal.extend_from_slice(data);
+                true
+            }
+            _ => false,
+        };
+        if merged {
+            return Ok(());
+        }
+        self.flush()?;
+        self.pending = match command {
+            DeltaCommand::Copy { offset, len } => Pending::Copy { offset, len },
+            DeltaCommand::SelfCopy { offset, len } => Pending::SelfCopy {
+                offset,
+                len,
+                output_offset,
+            },
+            DeltaCommand::Literal(data) if !self.compressed => {
+                self.literal.extend_from_slice(data);
+                Pending::Literal
+            }
+            DeltaCommand::Literal(data) => {
+                insert_command(data.len() as u64, &mut self.out)?;
+                self.out.extend_from_slice(data);
+                Pending::None
+            }
+            DeltaCommand::End => unreachable!("the end isn't passed on"),
+        };
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match std::mem::replace(&mut self.pending, Pending::None) {
+            Pending::None => Ok(()),
+            Pending::Copy { offset, len } => {
+                copy_command(RS_OP_COPY_N1_N1, offset, len, &mut self.out)
+            }
+            Pending::SelfCopy { offset, len, .. } => {
+                copy_command(RS_OP_SELF_COPY_N1_N1, offset, len, &mut self.out)
+            }
+            // an empty literal is left out
+            Pending::Literal if self.literal.is_empty() => Ok(()),
+            Pending::Literal => {
+                insert_command(self.literal.len() as u64, &mut self.out)?;
+                self.out.extend_from_slice(&self.literal);
+                self.literal.clear();
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Extend the copy of `len` bytes from `offset` with the copy of `n` bytes from `next`, if it
+/// follows on from it.
+fn extend(offset: u64, len: &mut u64, next: u64, n: u64) -> bool {
+    if offset.checked_add(*len) == Some(next) {
+        if let Some(sum) = len.checked_add(n) {
+            *len = sum;
+            return true;
+        }
+    }
+    false
+}
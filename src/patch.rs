@@ -1,14 +1,29 @@
 use std::error::Error;
-use std::io::{self, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::{fmt, mem};
 
+use arrayref::array_ref;
+
+use crate::blake2::{Blake2Hasher, BLAKE2_SIZE};
 use crate::consts::{
-    DELTA_MAGIC, RS_OP_COPY_N1_N1, RS_OP_COPY_N8_N8, RS_OP_END, RS_OP_LITERAL_1, RS_OP_LITERAL_64,
-    RS_OP_LITERAL_N1, RS_OP_LITERAL_N8,
+    DELTA_CHECKSUM, DELTA_MAGIC, DELTA_SELF_COPIES, DELTA_ZSTD_LITERALS, EXTENDED_DELTA_MAGIC,
+    RS_OP_COPY_N1_N1, RS_OP_COPY_N8_N8, RS_OP_END, RS_OP_LITERAL_1, RS_OP_LITERAL_64,
+    RS_OP_LITERAL_N1, RS_OP_LITERAL_N8, RS_OP_SELF_COPY_N1_N1, RS_OP_SELF_COPY_N8_N8,
+    SELF_COPY_DELTA_MAGIC, SELF_COPY_WINDOW, ZSTD_DELTA_MAGIC, ZSTD_SELF_COPY_DELTA_MAGIC,
 };
+use crate::diff::DeltaCommand;
+#[cfg(feature = "tokio")]
+use crate::diff::YieldNow;
+use crate::error::ErrorKind;
+use crate::md4::Md4Hasher;
 
 /// Indicates that a delta could not be applied because it was invalid.
+///
+/// New variants may be added in any release: use [kind()](ApplyError::kind) to tell broad
+/// cases apart.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum ApplyError {
     /// The delta started with the wrong magic, perhaps because it is not really an rsync delta.
     WrongMagic {
@@ -23,6 +38,8 @@ pub enum ApplyError {
         expected: usize,
         /// The remaining length of the input.
         available: usize,
+        /// Where in the delta that item, or what is left of it, starts.
+        delta_offset: u64,
     },
     /// The resulting data would have exceeded the output limit given to [apply_limited()].
     OutputLimit {
@@ -41,20 +58,83 @@ pub enum ApplyError {
         len: u64,
         /// The length of the base data.
         data_len: usize,
+        /// The command byte of the copy.
+        command: u8,
+        /// Where in the delta the copy starts.
+        delta_offset: u64,
+    },
+    /// The delta contained a copy from the output which either isn't written yet or is too far
+    /// back to still be available.
+    SelfCopyOutOfBounds {
+        /// The copy offset.
+        offset: u64,
+        /// The copy length.
+        len: u64,
+        /// The length of the output so far.
+        written: u64,
+        /// The command byte of the copy.
+        command: u8,
+        /// Where in the delta the copy starts.
+        delta_offset: u64,
     },
     /// The delta contained a zero-length copy command.
-    CopyZero,
+    CopyZero {
+        /// The command byte of the copy.
+        command: u8,
+        /// Where in the delta the copy starts.
+        delta_offset: u64,
+    },
     /// The delta contained an unrecognized command.
     UnknownCommand {
         /// The command byte encountered.
         command: u8,
+        /// Where in the delta the command starts.
+        delta_offset: u64,
     },
+    /// A compressed literal in the delta could not be decompressed.
+    #[cfg(feature = "zstd")]
+    CorruptLiteral,
+    /// The output didn't match the checksum at the end of the delta (see
+    /// [DiffOptions::checksum](crate::DiffOptions::checksum)), or that of a window of a VCDIFF
+    /// delta, so it is not the data the delta was calculated from. It has already been written
+    /// by then, and should be discarded.
+    ChecksumMismatch,
+    /// The output didn't match the digest given to [apply_verified()], so it is not the data
+    /// it was expected to be. It has already been written by then, and should be discarded.
+    DigestMismatch,
     /// The delta contained data after its end command.
     TrailingData {
         /// The length of the trailing data.
         length: usize,
+        /// Where in the delta the trailing data starts.
+        delta_offset: u64,
+    },
+    /// [transcode_delta()](crate::transcode_delta) was asked to add a checksum to a delta which
+    /// doesn't have one, but not given the base to work it out from.
+    MissingBase,
+    /// The delta, or the format [transcode_delta()](crate::transcode_delta) was asked for,
+    /// needs a feature of this crate that isn't enabled.
+    Unsupported {
+        /// The feature.
+        feature: &'static str,
     },
-    /// There was an IO error while writing the output
+    /// A VCDIFF delta given to [apply_vcdiff()](crate::apply_vcdiff) is malformed in a way
+    /// which the other variants don't cover.
+    InvalidVcdiff {
+        /// What is wrong with it.
+        reason: &'static str,
+        /// Where in the delta the problem was found.
+        delta_offset: u64,
+    },
+    /// A VCDIFF delta given to [apply_vcdiff()](crate::apply_vcdiff) uses a feature of the
+    /// format which isn't supported.
+    UnsupportedVcdiff {
+        /// The feature.
+        feature: &'static str,
+    },
+    /// There was an IO error while writing the output, while reading the delta in
+    /// [apply_from_reader()], or while reading the base in [apply_seek()] or
+    /// [apply_with_provider()]
     Io(io::Error),
 }
 
@@ -66,10 +146,11 @@ impl fmt::Display for ApplyError {
                 reading,
                 expected,
                 available,
+                delta_offset,
             } => write!(
                 f,
-                "unexpected end of input when reading {} (expected={}, available={})",
-                reading, expected, available
+                "unexpected end of input when reading {} (expected={}, available={}, delta_offset={})",
+                reading, expected, available, delta_offset
             ),
             ApplyError::OutputLimit {
                 what,
@@ -84,149 +165,1573 @@ impl fmt::Display for ApplyError {
                 offset,
                 len,
                 data_len,
+                command,
+                delta_offset,
+            } => write!(
+                f,
+                "requested copy is out of bounds (offset={}, len={}, data_len={}, command=0x{:02x}, delta_offset={})",
+                offset, len, data_len, command, delta_offset
+            ),
+            ApplyError::SelfCopyOutOfBounds {
+                offset,
+                len,
+                written,
+                command,
+                delta_offset,
+            } => write!(
+                f,
+                "requested copy from the output is out of bounds (offset={}, len={}, written={}, command=0x{:02x}, delta_offset={})",
+                offset, len, written, command, delta_offset
+            ),
+            ApplyError::CopyZero {
+                command,
+                delta_offset,
+            } => write!(
+                f,
+                "copy length is empty (command=0x{:02x}, delta_offset={})",
+                command, delta_offset
+            ),
+            ApplyError::UnknownCommand {
+                command,
+                delta_offset,
+            } => write!(
+                f,
+                "unexpected command byte: 0x{:02x} (delta_offset={})",
+                command, delta_offset
+            ),
+            #[cfg(feature = "zstd")]
+            ApplyError::CorruptLiteral => f.write_str("compressed literal is corrupt"),
+            ApplyError::ChecksumMismatch => f.write_str("output doesn't match the checksum"),
+            ApplyError::DigestMismatch => f.write_str("output doesn't match the expected digest"),
+            ApplyError::TrailingData {
+                length,
+                delta_offset,
+            } => write!(
+                f,
+                "unexpected data after end command (len={}, delta_offset={})",
+                length, delta_offset
+            ),
+            ApplyError::MissingBase => {
+                f.write_str("the base is needed to add a checksum to the delta")
+            }
+            ApplyError::InvalidVcdiff {
+                reason,
+                delta_offset,
             } => write!(
                 f,
-                "requested copy is out of bounds (offset={}, len={}, data_len={})",
-                offset, len, data_len
+                "invalid VCDIFF delta: {} (delta_offset={})",
+                reason, delta_offset
             ),
-            ApplyError::CopyZero => f.write_str("copy length is empty"),
-            ApplyError::UnknownCommand { command } => {
-                write!(f, "unexpected command byte: 0x{:02x}", command)
+            ApplyError::UnsupportedVcdiff { feature } => {
+                write!(f, "unsupported VCDIFF feature: {}", feature)
             }
-            ApplyError::TrailingData { length } => {
-                write!(f, "unexpected data after end command (len={})", length)
+            ApplyError::Unsupported { feature } => {
+                write!(f, "the format needs the {} feature, which isn't enabled", feature)
             }
-            Self::Io(source) => write!(f, "io error while writing the output (source={})", source),
+            Self::Io(source) => write!(f, "io error (source={})", source),
         }
     }
 }
 
 impl Error for ApplyError {}
 
+impl ApplyError {
+    /// What kind of failure this is.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            // an extended delta, with extensions this build doesn't know or have enabled
+            ApplyError::WrongMagic { magic } if magic & !0x3f == EXTENDED_DELTA_MAGIC => {
+                ErrorKind::Unsupported
+            }
+            ApplyError::WrongMagic { .. }
+            | ApplyError::UnexpectedEof { .. }
+            | ApplyError::CopyOutOfBounds { .. }
+            | ApplyError::SelfCopyOutOfBounds { .. }
+            | ApplyError::CopyZero { .. }
+            | ApplyError::UnknownCommand { .. }
+            | ApplyError::TrailingData { .. }
+            | ApplyError::InvalidVcdiff { .. } => ErrorKind::InvalidData,
+            ApplyError::UnsupportedVcdiff { .. } | ApplyError::Unsupported { .. } => {
+                ErrorKind::Unsupported
+            }
+            ApplyError::MissingBase => ErrorKind::InvalidArgument,
+            #[cfg(feature = "zstd")]
+            ApplyError::CorruptLiteral => ErrorKind::InvalidData,
+            ApplyError::OutputLimit { .. } => ErrorKind::LimitExceeded,
+            ApplyError::ChecksumMismatch | ApplyError::DigestMismatch => {
+                ErrorKind::VerificationFailed
+            }
+            ApplyError::Io(_) => ErrorKind::Io,
+        }
+    }
+
+    /// Set where in the delta the command which caused this starts, and its command byte, for
+    /// the errors which come from checking a command without knowing where it is.
+    fn located(mut self, start: u64, cmd: u8) -> Self {
+        match &mut self {
+            ApplyError::CopyOutOfBounds {
+                command,
+                delta_offset,
+                ..
+            }
+            | ApplyError::SelfCopyOutOfBounds {
+                command,
+                delta_offset,
+                ..
+            }
+            | ApplyError::CopyZero {
+                command,
+                delta_offset,
+            }
+            | ApplyError::UnknownCommand {
+                command,
+                delta_offset,
+            } => {
+                *command = cmd;
+                *delta_offset = start;
+            }
+            _ => {}
+        }
+        self
+    }
+}
+
 impl From<io::Error> for ApplyError {
     fn from(source: io::Error) -> Self {
         Self::Io(source)
     }
 }
 
+/// Where the copies of a delta are read from.
+trait Base {
+    /// The length of the base.
+    fn len(&self) -> u64;
+    /// Pass the `len` bytes from `offset` on to `f`, in one or more pieces. The range is within
+    /// the base.
+    fn copy(
+        &mut self,
+        offset: u64,
+        len: u64,
+        f: impl FnMut(&[u8]) -> Result<(), ApplyError>,
+    ) -> Result<(), ApplyError>;
+}
+
+impl Base for &[u8] {
+    fn len(&self) -> u64 {
+        <[u8]>::len(self) as u64
+    }
+
+    fn copy(
+        &mut self,
+        offset: u64,
+        len: u64,
+        mut f: impl FnMut(&[u8]) -> Result<(), ApplyError>,
+    ) -> Result<(), ApplyError> {
+        f(&self[offset as usize..(offset + len) as usize])
+    }
+}
+
+/// A base which is read as needed, in pieces of at most [SeekBase::BUFFER_SIZE].
+struct SeekBase<R> {
+    inner: R,
+    len: u64,
+    /// Where `inner` is positioned, if that is known.
+    position: Option<u64>,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read + Seek> SeekBase<R> {
+    const BUFFER_SIZE: usize = 1 << 16;
+
+    fn new(mut inner: R) -> io::Result<Self> {
+        let len = inner.seek(SeekFrom::End(0))?;
+        Ok(SeekBase {
+            inner,
+            len,
+            position: None,
+            buffer: Vec::new(),
+        })
+    }
+}
+
+impl<R: Read + Seek> Base for SeekBase<R> {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn copy(
+        &mut self,
+        mut offset: u64,
+        mut len: u64,
+        mut f: impl FnMut(&[u8]) -> Result<(), ApplyError>,
+    ) -> Result<(), ApplyError> {
+        if self.position != Some(offset) {
+            // don't trust the position after a failed read
+            self.position = None;
+            self.inner.seek(SeekFrom::Start(offset))?;
+        }
+        while len > 0 {
+            let n = len.min(Self::BUFFER_SIZE as u64) as usize;
+            self.buffer.resize(n, 0);
+            self.position = None;
+            self.inner.read_exact(&mut self.buffer)?;
+            offset += n as u64;
+            len -= n as u64;
+            self.position = Some(offset);
+            f(&self.buffer)?;
+        }
+        Ok(())
+    }
+}
+
+/// Random access to base data which isn't in memory, e.g. in a file or in object storage, for
+/// [apply_with_provider()].
+pub trait BaseProvider {
+    /// The size of the base in bytes. This is only asked for once per delta.
+    fn size(&self) -> io::Result<u64>;
+    /// Fill `buf` with the base from `offset` on. The range is always within the base.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+}
+
+impl BaseProvider for [u8] {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let offset = offset as usize;
+        buf.copy_from_slice(&self[offset..offset + buf.len()]);
+        Ok(())
+    }
+}
+
+/// Reads with `pread`, so the file can be shared between threads.
+#[cfg(unix)]
+impl BaseProvider for std::fs::File {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buf, offset)
+    }
+}
+
+/// A [BaseProvider] as a [Base], which reads copies in pieces of at most
+/// [ProvidedBase::MAX_READ].
+struct ProvidedBase<'a, P: ?Sized> {
+    provider: &'a P,
+    len: u64,
+    buffer: Vec<u8>,
+}
+
+impl<P: BaseProvider + ?Sized> ProvidedBase<'_, P> {
+    /// Large enough that a provider which fetches ranges over the network makes few requests.
+    const MAX_READ: usize = 1 << 22;
+}
+
+impl<P: BaseProvider + ?Sized> Base for ProvidedBase<'_, P> {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn copy(
+        &mut self,
+        mut offset: u64,
+        mut len: u64,
+        mut f: impl FnMut(&[u8]) -> Result<(), ApplyError>,
+    ) -> Result<(), ApplyError> {
+        while len > 0 {
+            let n = len.min(Self::MAX_READ as u64) as usize;
+            self.buffer.resize(n, 0);
+            self.provider.read_at(offset, &mut self.buffer)?;
+            f(&self.buffer)?;
+            offset += n as u64;
+            len -= n as u64;
+        }
+        Ok(())
+    }
+}
+
 /// Apply `delta` to the base data `base`, writing the result to `out`.
 /// Errors if more than `limit` bytes would be written to `out`.
+///
+/// If applying the delta fails, the output up to that point has already been written. Apply it
+/// with [ApplyState] instead to find out how much that was, e.g. to clean up a partly written
+/// file.
 pub fn apply_limited(
     base: &[u8],
-    mut delta: &[u8],
+    delta: &[u8],
     out: &mut impl Write,
-    mut limit: usize,
+    limit: usize,
 ) -> Result<(), ApplyError> {
-    macro_rules! read_n {
-        ($n:expr, $what:expr) => {{
-            let n = $n;
-            if delta.len() < n {
-                return Err(ApplyError::UnexpectedEof {
-                    reading: $what,
-                    expected: n,
-                    available: delta.len(),
-                });
+    apply_base(base, delta, out, limit)
+}
+
+/// Options for [apply_with_options()] and [ApplyState::with_options()].
+#[derive(Clone, Debug)]
+pub struct ApplyOptions {
+    /// Fail with [ApplyError::OutputLimit] rather than write more than this many bytes of output.
+    /// No limit by default.
+    pub limit: usize,
+    /// Stop at the end of the delta, ignoring whatever follows it rather than failing with
+    /// [ApplyError::TrailingData], as librsync does. This is for deltas embedded in a larger
+    /// stream, where the data after the delta belongs to something else: [apply_with_options()]
+    /// and [ApplyState::consumed()] tell where the delta ended.
+    pub allow_trailing: bool,
+}
+
+impl Default for ApplyOptions {
+    fn default() -> Self {
+        ApplyOptions {
+            limit: usize::max_value(),
+            allow_trailing: false,
+        }
+    }
+}
+
+/// Like [apply_limited()], but with [ApplyOptions], and returning how long the delta was, which
+/// is all of `delta` unless [allow_trailing](ApplyOptions::allow_trailing) is set.
+pub fn apply_with_options(
+    base: &[u8],
+    delta: &[u8],
+    out: &mut impl Write,
+    options: &ApplyOptions,
+) -> Result<usize, ApplyError> {
+    let mut state = ApplyState::with_options(base, options);
+    state.feed(delta, out)?;
+    let consumed = state.consumed();
+    state.finish()?;
+    Ok(consumed as usize)
+}
+
+/// How far [apply_prefix()] or [apply_resume()] got.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ApplyPrefix {
+    /// How many bytes of output were written by this call.
+    pub written: u64,
+    /// Where to pick up the rest of the output, or `None` if it was all written.
+    pub resume: Option<ResumeToken>,
+}
+
+/// Where [apply_resume()] is to pick up a delta which [apply_prefix()] stopped partway through.
+///
+/// A token only makes sense for the base and delta it came from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ResumeToken {
+    /// How much output there has been in all.
+    written: u64,
+    /// Where in the delta and in the output the command which was cut off starts.
+    delta_offset: u64,
+    output_offset: u64,
+}
+
+impl ResumeToken {
+    /// How much output there has been in all, which is where the output picks up.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+/// Apply the start of `delta` to `base`, writing the first `limit` bytes of its output to `out`,
+/// or all of it if it is shorter. Unlike with [apply_limited()], a longer output isn't an error:
+/// it is cut off exactly at `limit`, and a [ResumeToken] returned to write the rest of it with
+/// [apply_resume()]. This is for previews, which shouldn't have to apply the whole delta.
+///
+/// Only the commands needed for the prefix are checked, so a delta which is cut off or
+/// invalid past that point still yields a prefix, but a
+/// [checksum](crate::DiffOptions::checksum) can only be checked once the whole output is written.
+pub fn apply_prefix(
+    base: &[u8],
+    delta: &[u8],
+    out: &mut impl Write,
+    limit: usize,
+) -> Result<ApplyPrefix, ApplyError> {
+    let mut applier = Applier::new(base, limit);
+    applier.sink.truncate = true;
+    prefix(applier, delta, out, 0)
+}
+
+/// Carry on applying `delta` to `base` from where `token` says [apply_prefix()] or a previous
+/// `apply_resume()` left off, writing at most `limit` more bytes of the output to `out`.
+///
+/// Where a delta has commands after the point they left off, only those are applied, except with
+/// deltas which have [self-copies](crate::DiffOptions::self_copies) or a
+/// [checksum](crate::DiffOptions::checksum), which need all the output before it. Those are
+/// applied from the start again, throwing away the output which has already been written.
+pub fn apply_resume(
+    base: &[u8],
+    delta: &[u8],
+    token: &ResumeToken,
+    out: &mut impl Write,
+    limit: usize,
+) -> Result<ApplyPrefix, ApplyError> {
+    let extensions = DeltaReader::new(delta)?.extensions;
+    let replay = extensions & (DELTA_SELF_COPIES | DELTA_CHECKSUM) != 0;
+    let (delta_offset, output_offset) = match replay {
+        true => (0, 0),
+        false => (token.delta_offset, token.output_offset),
+    };
+    let skip = token.written - output_offset;
+    let mut applier = Applier::new(
+        base,
+        (skip.min(usize::max_value() as u64) as usize).saturating_add(limit),
+    );
+    applier.sink.truncate = true;
+    if !replay {
+        applier.phase = Phase::Commands;
+        applier.extensions = extensions;
+        applier.consumed = delta_offset;
+        applier.sink.written = output_offset;
+    }
+    let delta = delta.get(delta_offset as usize..).unwrap_or_default();
+    let mut out = SkipWriter { skip, out };
+    prefix(applier, delta, &mut out, token.written)
+}
+
+/// Apply `delta` as far as the limit of `applier` allows, and report how far it got, counting
+/// the output from `written` on.
+fn prefix(
+    mut applier: Applier<&[u8]>,
+    delta: &[u8],
+    out: &mut impl Write,
+    written: u64,
+) -> Result<ApplyPrefix, ApplyError> {
+    let result = applier.feed(delta, out).and_then(|()| applier.finish());
+    let resume = match result {
+        Ok(()) => None,
+        Err(ApplyError::OutputLimit { .. }) => Some(ResumeToken {
+            written: applier.sink.written,
+            delta_offset: applier.command_start.0,
+            output_offset: applier.command_start.1,
+        }),
+        Err(e) => return Err(e),
+    };
+    Ok(ApplyPrefix {
+        written: applier.sink.written.max(written) - written,
+        resume,
+    })
+}
+
+/// Throws away the first `skip` bytes written to it.
+struct SkipWriter<W> {
+    skip: u64,
+    out: W,
+}
+
+impl<W: Write> Write for SkipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.skip >= buf.len() as u64 {
+            self.skip -= buf.len() as u64;
+            return Ok(buf.len());
+        }
+        let skipped = self.skip as usize;
+        let n = self.out.write(&buf[skipped..])?;
+        self.skip = 0;
+        Ok(skipped + n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Like [apply()], but with the base read from `base` as needed rather than held in memory, so
+/// it can be a file of any size.
+///
+/// Each copy is read with a seek to where it starts, unless it follows on from the previous one,
+/// so `base` should be buffered only if copies tend to be short.
+pub fn apply_seek(
+    base: impl Read + Seek,
+    delta: &[u8],
+    out: &mut impl Write,
+) -> Result<(), ApplyError> {
+    apply_seek_limited(base, delta, out, usize::max_value())
+}
+
+/// Like [apply_seek()], but errors if more than `limit` bytes would be written to `out`, as with
+/// [apply_limited()].
+pub fn apply_seek_limited(
+    base: impl Read + Seek,
+    delta: &[u8],
+    out: &mut impl Write,
+    limit: usize,
+) -> Result<(), ApplyError> {
+    apply_base(SeekBase::new(base)?, delta, out, limit)
+}
+
+/// Like [apply_limited()], but with the base read through `base`, and only the parts of it
+/// which the delta copies.
+///
+/// Each copy is read with one call to [BaseProvider::read_at], unless it is longer than 4 MiB,
+/// in which case it is read in pieces of that size.
+pub fn apply_with_provider(
+    base: &(impl BaseProvider + ?Sized),
+    delta: &[u8],
+    out: &mut impl Write,
+    limit: usize,
+) -> Result<(), ApplyError> {
+    let base = ProvidedBase {
+        provider: base,
+        len: base.size()?,
+        buffer: Vec::new(),
+    };
+    apply_base(base, delta, out, limit)
+}
+
+/// How much of a delta [apply_from_reader()] and `apply_async` read at a time.
+const DELTA_READ_SIZE: usize = 1 << 16;
+
+/// Like [apply_limited()], but reads the delta from `delta` rather than needing it all in
+/// memory.
+///
+/// The delta is read 64 KiB at a time and applied as it is read, with [ApplyState], so only
+/// that much of it is held at once, or a compressed literal if it is longer.
+pub fn apply_from_reader(
+    base: &[u8],
+    mut delta: impl Read,
+    out: &mut impl Write,
+    limit: usize,
+) -> Result<(), ApplyError> {
+    let mut state = ApplyState::new(base, limit);
+    let mut buf = vec![0; DELTA_READ_SIZE];
+    loop {
+        let n = match delta.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        state.feed(&buf[..n], out)?;
+    }
+    state.finish()
+}
+
+/// Like [apply_limited()], but reading `delta` and writing the output asynchronously.
+///
+/// The delta is applied a piece at a time as it is read, and the output of each piece is written
+/// before the next one is read. Between pieces, this yields to the executor, so that a large
+/// delta doesn't hold up other tasks on the same thread even if `delta` is always ready.
+#[cfg(feature = "tokio")]
+pub async fn apply_async<R, W>(
+    base: &[u8],
+    mut delta: R,
+    mut out: W,
+    limit: usize,
+) -> Result<(), ApplyError>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut state = ApplyState::new(base, limit);
+    let mut buf = vec![0; DELTA_READ_SIZE];
+    let mut output = Vec::new();
+    loop {
+        let n = delta.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        state.feed(&buf[..n], &mut output)?;
+        out.write_all(&output).await?;
+        output.clear();
+        YieldNow(false).await;
+    }
+    state.finish()?;
+    out.flush().await?;
+    Ok(())
+}
+
+fn apply_base(
+    base: impl Base,
+    delta: &[u8],
+    out: &mut impl Write,
+    limit: usize,
+) -> Result<(), ApplyError> {
+    let mut applier = Applier::new(base, limit);
+    applier.feed(delta, out)?;
+    applier.finish()
+}
+
+/// What the commands of a delta add up to, as counted by [apply_with_stats()] or
+/// [ApplyState::stats()].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ApplyStats {
+    /// How many copies from the base there were.
+    pub copies: u64,
+    /// How many bytes of output the copies from the base made.
+    pub copied_bytes: u64,
+    /// How many [self-copies](crate::DiffOptions::self_copies) there were.
+    pub self_copies: u64,
+    /// How many bytes of output the self-copies made.
+    pub self_copied_bytes: u64,
+    /// How many literals there were.
+    pub literals: u64,
+    /// How many bytes of output the literals made, once decompressed if they were compressed.
+    pub literal_bytes: u64,
+    /// The length of the longest copy, from the base or the output.
+    pub max_copy_len: u64,
+}
+
+impl ApplyStats {
+    /// How many commands there were, not counting the end.
+    pub fn commands(&self) -> u64 {
+        self.copies + self.self_copies + self.literals
+    }
+
+    /// How much of the output was copied rather than sent as literals, from 0 to 1, or 1 if
+    /// there was no output.
+    pub fn copied_ratio(&self) -> f64 {
+        let copied = self.copied_bytes + self.self_copied_bytes;
+        match copied + self.literal_bytes {
+            0 => 1.0,
+            total => copied as f64 / total as f64,
+        }
+    }
+}
+
+/// Like [apply()], but also return what the commands of the delta add up to, e.g. to see how
+/// much of the output had to be sent as literals.
+///
+/// # Security
+/// As with [apply()], a delta may create an arbitrarily large output. Use [ApplyState], whose
+/// [stats()](ApplyState::stats) are the same, to set an upper bound on its size.
+pub fn apply_with_stats(
+    base: &[u8],
+    delta: &[u8],
+    out: &mut impl Write,
+) -> Result<ApplyStats, ApplyError> {
+    let mut applier = Applier::new(base, usize::max_value());
+    applier.feed(delta, out)?;
+    applier.finish()?;
+    Ok(applier.stats)
+}
+
+/// An incremental version of [apply_limited()], for when the delta arrives in pieces and
+/// shouldn't have to be buffered before it can be applied.
+///
+/// An `ApplyState` doesn't do any IO itself: each piece of the delta is passed to
+/// [feed()](ApplyState::feed), which writes whatever output it completes to `out`, and
+/// [finish()](ApplyState::finish) then checks that the delta was complete. The pieces can be
+/// split anywhere, even in the middle of a command. Literals are written out as they arrive, so
+/// only the start of a command, or a compressed literal, is held back until the rest of it is
+/// fed.
+///
+/// The output is the same as that of [apply_limited()], and so are the errors, except that
+/// [ApplyError::UnexpectedEof] can only come from [finish()](ApplyState::finish). Once
+/// [feed()](ApplyState::feed) fails, the state shouldn't be fed any more, but
+/// [written()](ApplyState::written) and [consumed()](ApplyState::consumed) still tell how far it
+/// got.
+///
+/// ```
+/// use fast_rsync::{diff, ApplyState, Signature, SignatureOptions};
+///
+/// let base = b"hello world";
+/// let signature = Signature::calculate(base, SignatureOptions::default());
+/// let mut delta = vec![];
+/// diff(&signature.index(), b"hello there", &mut delta).unwrap();
+///
+/// let mut state = ApplyState::new(base, usize::MAX);
+/// let mut out = vec![];
+/// for piece in delta.chunks(3) {
+///     state.feed(piece, &mut out).unwrap();
+/// }
+/// state.finish().unwrap();
+/// assert_eq!(out, b"hello there");
+/// ```
+pub struct ApplyState<'a> {
+    applier: Applier<&'a [u8]>,
+}
+
+impl<'a> ApplyState<'a> {
+    /// Start applying a delta to `base`, failing if more than `limit` bytes would be written.
+    pub fn new(base: &'a [u8], limit: usize) -> Self {
+        ApplyState {
+            applier: Applier::new(base, limit),
+        }
+    }
+
+    /// Start applying a delta to `base`, with `options`.
+    pub fn with_options(base: &'a [u8], options: &ApplyOptions) -> Self {
+        let mut applier = Applier::new(base, options.limit);
+        applier.allow_trailing = options.allow_trailing;
+        ApplyState { applier }
+    }
+
+    /// Apply the next piece of the delta, writing whatever output it completes to `out`.
+    pub fn feed(&mut self, delta: &[u8], out: &mut impl Write) -> Result<(), ApplyError> {
+        self.applier.feed(delta, out)
+    }
+
+    /// Check that all of the delta has been fed.
+    pub fn finish(self) -> Result<(), ApplyError> {
+        self.applier.finish()
+    }
+
+    /// How many bytes of output have been written so far.
+    ///
+    /// After an error, this includes the output of the failed command up to the write which
+    /// failed, if any. A write which fails may still have written part of its data, which isn't
+    /// counted.
+    pub fn written(&self) -> u64 {
+        self.applier.sink.written
+    }
+
+    /// What the commands applied so far add up to. Each command is counted once it is written,
+    /// apart from an uncompressed literal, which is counted as soon as it starts.
+    pub fn stats(&self) -> ApplyStats {
+        self.applier.stats
+    }
+
+    /// How many bytes of the delta have been applied so far.
+    ///
+    /// This is where in the delta the command which is cut off at the end of what has been fed,
+    /// or the one which failed, starts. The rest of a literal which is cut off is counted as it
+    /// is written, though.
+    pub fn consumed(&self) -> u64 {
+        self.applier.consumed
+    }
+}
+
+/// Where an [Applier] is in the delta.
+#[derive(Clone, Copy, Debug)]
+enum Phase {
+    Magic,
+    Commands,
+    /// In the middle of an uncompressed literal, with this much of it left.
+    Literal(usize),
+    Checksum,
+    Done,
+}
+
+/// What [Applier::step] made of the start of its input.
+enum Step {
+    /// The first item was applied, and was this long.
+    Consumed(usize),
+    /// The first item is cut off, and needs this much input in all.
+    Incomplete {
+        reading: &'static str,
+        expected: usize,
+    },
+}
+
+/// Where the output of a delta goes, and what is kept of it.
+struct Sink {
+    /// The remaining output limit.
+    limit: usize,
+    /// How much output there has been.
+    written: u64,
+    /// The recent output, if the delta has self-copies to read from it.
+    history: Option<History>,
+    /// The hash of the output, if the delta ends with one.
+    checksum: Option<Blake2Hasher>,
+    /// Whether to write as much as fits before failing with [ApplyError::OutputLimit], rather
+    /// than nothing of the item which doesn't fit.
+    truncate: bool,
+}
+
+impl Sink {
+    fn write(
+        &mut self,
+        data: &[u8],
+        what: &'static str,
+        out: &mut impl Write,
+    ) -> Result<(), ApplyError> {
+        if data.len() > self.limit {
+            let available = self.limit;
+            if self.truncate {
+                self.write(&data[..available], what, out)?;
             }
-            let (prefix, rest) = delta.split_at(n);
-            delta = rest;
-            prefix
-        }};
+            return Err(ApplyError::OutputLimit {
+                what,
+                wanted: data.len(),
+                available,
+            });
+        }
+        self.limit -= data.len();
+        out.write_all(data)?;
+        self.written += data.len() as u64;
+        if let Some(history) = &mut self.history {
+            history.push(data);
+        }
+        if let Some(checksum) = &mut self.checksum {
+            checksum.update(data);
+        }
+        Ok(())
     }
-    macro_rules! read_int {
-        ($ty:ty, $what:expr) => {{
-            let mut b = [0; mem::size_of::<$ty>()];
-            b.copy_from_slice(read_n!(mem::size_of::<$ty>(), $what));
-            <$ty>::from_be_bytes(b)
-        }};
+}
+
+/// The workings of [ApplyState], for any kind of base.
+struct Applier<B> {
+    base: B,
+    sink: Sink,
+    phase: Phase,
+    /// The extension flags of the delta, once its magic is read.
+    extensions: u32,
+    /// How much of the delta has been applied.
+    consumed: u64,
+    stats: ApplyStats,
+    /// Whether to ignore whatever follows the end of the delta.
+    allow_trailing: bool,
+    /// Where in the delta and in the output the last command started.
+    command_start: (u64, u64),
+    /// The start of an item which was cut off at the end of the input so far.
+    pending: Vec<u8>,
+    /// The item which is cut off, and how long it is going to be.
+    reading: &'static str,
+    expected: usize,
+}
+
+impl<B: Base> Applier<B> {
+    fn new(base: B, limit: usize) -> Self {
+        Applier {
+            base,
+            sink: Sink {
+                limit,
+                written: 0,
+                history: None,
+                checksum: None,
+                truncate: false,
+            },
+            phase: Phase::Magic,
+            extensions: 0,
+            consumed: 0,
+            stats: ApplyStats::default(),
+            allow_trailing: false,
+            command_start: (0, 0),
+            pending: Vec::new(),
+            reading: "magic",
+            expected: 4,
+        }
     }
+
+    fn feed(&mut self, mut delta: &[u8], out: &mut impl Write) -> Result<(), ApplyError> {
+        while !self.pending.is_empty() {
+            let take = self
+                .expected
+                .saturating_sub(self.pending.len())
+                .min(delta.len());
+            self.pending.extend_from_slice(&delta[..take]);
+            delta = &delta[take..];
+            if self.pending.len() < self.expected {
+                return Ok(());
+            }
+            let pending = mem::take(&mut self.pending);
+            let consumed = self.run(&pending, out)?;
+            self.pending = pending;
+            self.pending.drain(..consumed);
+            if self.ignores_rest() {
+                self.pending.clear();
+            }
+        }
+        if self.ignores_rest() {
+            return Ok(());
+        }
+        let consumed = self.run(delta, out)?;
+        if !self.ignores_rest() {
+            self.pending.extend_from_slice(&delta[consumed..]);
+        }
+        Ok(())
+    }
+
+    /// Whether the delta has ended, and anything after it is to be ignored.
+    fn ignores_rest(&self) -> bool {
+        self.allow_trailing && matches!(self.phase, Phase::Done)
+    }
+
+    fn finish(&self) -> Result<(), ApplyError> {
+        match self.phase {
+            Phase::Done => Ok(()),
+            _ => Err(ApplyError::UnexpectedEof {
+                reading: self.reading,
+                expected: self.expected,
+                available: self.pending.len(),
+                delta_offset: self.consumed,
+            }),
+        }
+    }
+
+    /// Apply the items at the start of `input` as far as they are complete, and return how long
+    /// they were.
+    fn run(&mut self, input: &[u8], out: &mut impl Write) -> Result<usize, ApplyError> {
+        let mut pos = 0;
+        loop {
+            if let Phase::Done = self.phase {
+                if pos < input.len() && !self.allow_trailing {
+                    // extra content after EOF
+                    return Err(ApplyError::TrailingData {
+                        length: input.len() - pos,
+                        delta_offset: self.consumed,
+                    });
+                }
+                return Ok(pos);
+            }
+            match self.step(&input[pos..], out)? {
+                Step::Consumed(n) => {
+                    pos += n;
+                    self.consumed += n as u64;
+                }
+                Step::Incomplete { reading, expected } => {
+                    self.reading = reading;
+                    self.expected = expected;
+                    return Ok(pos);
+                }
+            }
+        }
+    }
+
+    /// Apply the item at the start of `input`, if it is all there.
+    fn step(&mut self, input: &[u8], out: &mut impl Write) -> Result<Step, ApplyError> {
+        let mut pos = 0;
+        macro_rules! read_n {
+            ($n:expr, $what:expr) => {{
+                let n: usize = $n;
+                if input.len() - pos < n {
+                    return Ok(Step::Incomplete {
+                        reading: $what,
+                        expected: pos.saturating_add(n),
+                    });
+                }
+                pos += n;
+                &input[pos - n..pos]
+            }};
+        }
+        match self.phase {
+            Phase::Magic => {
+                let magic = u32::from_be_bytes(*array_ref!(read_n!(4, "magic"), 0, 4));
+                self.extensions = extensions(magic)?;
+                if self.extensions & DELTA_SELF_COPIES != 0 {
+                    self.sink.history = Some(History::default());
+                }
+                if self.extensions & DELTA_CHECKSUM != 0 {
+                    self.sink.checksum = Some(Blake2Hasher::default());
+                }
+                self.phase = Phase::Commands;
+            }
+            Phase::Literal(remaining) => {
+                if input.is_empty() {
+                    return Ok(Step::Incomplete {
+                        reading: "literal",
+                        expected: remaining,
+                    });
+                }
+                let n = remaining.min(input.len());
+                self.sink.write(&input[..n], "literal", out)?;
+                pos = n;
+                self.phase = match remaining - n {
+                    0 => Phase::Commands,
+                    left => Phase::Literal(left),
+                };
+            }
+            Phase::Checksum => {
+                let expected = read_n!(BLAKE2_SIZE, "checksum");
+                let checksum = self
+                    .sink
+                    .checksum
+                    .as_ref()
+                    .expect("the delta has a checksum");
+                if expected != checksum.finalize() {
+                    return Err(ApplyError::ChecksumMismatch);
+                }
+                self.phase = Phase::Done;
+            }
+            Phase::Commands => {
+                self.command_start = (self.consumed, self.sink.written);
+                let located = |e: ApplyError| e.located(self.consumed, input[0]);
+                let header = match parse_header(input, self.extensions).map_err(located)? {
+                    Parsed::Header(header, len) => {
+                        pos = len;
+                        header
+                    }
+                    Parsed::Incomplete { reading, expected } => {
+                        return Ok(Step::Incomplete { reading, expected })
+                    }
+                };
+                match header {
+                    Header::End => {
+                        self.phase = if self.sink.checksum.is_some() {
+                            Phase::Checksum
+                        } else {
+                            Phase::Done
+                        };
+                    }
+                    Header::Literal(n) => {
+                        #[cfg(feature = "zstd")]
+                        if self.extensions & DELTA_ZSTD_LITERALS != 0 {
+                            let n = n.min(usize::max_value() as u64) as usize;
+                            let literal = read_n!(n, "literal");
+                            let size = compressed_len(literal)?;
+                            if size > self.sink.limit as u64 {
+                                let available = self.sink.limit;
+                                if self.sink.truncate {
+                                    // only decompress as much as fits
+                                    let part = decompress_prefix(literal, available)?;
+                                    self.sink.write(&part, "literal", out)?;
+                                }
+                                return Err(ApplyError::OutputLimit {
+                                    what: "literal",
+                                    wanted: size.min(usize::MAX as u64) as usize,
+                                    available,
+                                });
+                            }
+                            let literal = decompress(literal, size)?;
+                            self.stats.literals += 1;
+                            self.stats.literal_bytes += size;
+                            self.sink.write(&literal, "literal", out)?;
+                            return Ok(Step::Consumed(pos));
+                        }
+                        if n > self.sink.limit as u64 && !self.sink.truncate {
+                            return Err(ApplyError::OutputLimit {
+                                what: "literal",
+                                wanted: n.min(usize::max_value() as u64) as usize,
+                                available: self.sink.limit,
+                            });
+                        }
+                        self.stats.literals += 1;
+                        self.stats.literal_bytes += n;
+                        if n > 0 {
+                            self.phase = Phase::Literal(n as usize);
+                        }
+                    }
+                    Header::Copy { offset, len } => {
+                        let base_len = self.base.len();
+                        check_copy(offset, len, base_len).map_err(located)?;
+                        if len > self.sink.limit as u64 && !self.sink.truncate {
+                            return Err(ApplyError::OutputLimit {
+                                what: "copy",
+                                wanted: len as usize,
+                                available: self.sink.limit,
+                            });
+                        }
+                        let sink = &mut self.sink;
+                        self.base
+                            .copy(offset, len, |piece| sink.write(piece, "copy", out))?;
+                        self.stats.copies += 1;
+                        self.stats.copied_bytes += len;
+                        self.stats.max_copy_len = self.stats.max_copy_len.max(len);
+                    }
+                    Header::SelfCopy { offset, len } => {
+                        let kept = self.sink.history.as_ref();
+                        let kept = kept.expect("self-copies keep the output");
+                        let copied = match kept.get(offset, len) {
+                            Some(copied) => copied.to_vec(),
+                            None => {
+                                return Err(located(ApplyError::SelfCopyOutOfBounds {
+                                    offset,
+                                    len,
+                                    written: kept.written(),
+                                    command: 0,
+                                    delta_offset: 0,
+                                }))
+                            }
+                        };
+                        self.sink.write(&copied, "copy", out)?;
+                        self.stats.self_copies += 1;
+                        self.stats.self_copied_bytes += len;
+                        self.stats.max_copy_len = self.stats.max_copy_len.max(len);
+                    }
+                }
+            }
+            Phase::Done => unreachable!("there is nothing to apply after the end"),
+        }
+        Ok(Step::Consumed(pos))
+    }
+}
+
+/// The extension flags of a delta which starts with `magic`.
+fn extensions(magic: u32) -> Result<u32, ApplyError> {
+    let known_extensions = DELTA_SELF_COPIES | DELTA_CHECKSUM;
+    #[cfg(feature = "zstd")]
+    let known_extensions = known_extensions | DELTA_ZSTD_LITERALS;
+    match magic {
+        DELTA_MAGIC => Ok(0),
+        SELF_COPY_DELTA_MAGIC => Ok(DELTA_SELF_COPIES),
+        #[cfg(feature = "zstd")]
+        ZSTD_DELTA_MAGIC => Ok(DELTA_ZSTD_LITERALS),
+        #[cfg(feature = "zstd")]
+        ZSTD_SELF_COPY_DELTA_MAGIC => Ok(DELTA_ZSTD_LITERALS | DELTA_SELF_COPIES),
+        #[cfg(not(feature = "zstd"))]
+        ZSTD_DELTA_MAGIC | ZSTD_SELF_COPY_DELTA_MAGIC => {
+            Err(ApplyError::Unsupported { feature: "zstd" })
+        }
+        _ if magic != EXTENDED_DELTA_MAGIC && magic & !known_extensions == EXTENDED_DELTA_MAGIC => {
+            Ok(magic & known_extensions)
+        }
+        #[cfg(not(feature = "zstd"))]
+        _ if magic & !(known_extensions | DELTA_ZSTD_LITERALS) == EXTENDED_DELTA_MAGIC => {
+            Err(ApplyError::Unsupported { feature: "zstd" })
+        }
+        _ => Err(ApplyError::WrongMagic { magic }),
+    }
+}
+
+/// A command of a delta, apart from the data of a literal.
+enum Header {
+    End,
+    /// A literal of this length follows.
+    Literal(u64),
+    Copy {
+        offset: u64,
+        len: u64,
+    },
+    SelfCopy {
+        offset: u64,
+        len: u64,
+    },
+}
+
+enum Parsed {
+    /// The header, and its length.
+    Header(Header, usize),
+    /// The header is cut off, and needs this much input in all.
+    Incomplete {
+        reading: &'static str,
+        expected: usize,
+    },
+}
+
+/// Read the header of the command at the start of `input`, in a delta with `extensions`. The
+/// errors have to be [located](ApplyError::located).
+#[inline]
+fn parse_header(input: &[u8], extensions: u32) -> Result<Parsed, ApplyError> {
+    let mut pos = 0;
     macro_rules! read_varint {
         ($len:expr, $what:expr) => {{
-            let len = $len;
+            let len: usize = $len;
+            if input.len() - pos < len {
+                return Ok(Parsed::Incomplete {
+                    reading: $what,
+                    expected: pos + len,
+                });
+            }
             let mut b = [0; 8];
-            b[8 - len..8].copy_from_slice(read_n!(len, $what));
+            b[8 - len..8].copy_from_slice(&input[pos..pos + len]);
+            pos += len;
             u64::from_be_bytes(b)
         }};
     }
-    macro_rules! safe_cast {
-        ($val:expr, $ty:ty, $err:expr) => {{
-            let val = $val;
-            if val as u64 > <$ty>::max_value() as u64 {
-                return Err($err);
-            }
-            val as $ty
-        }};
-    }
-    macro_rules! safe_extend {
-        ($slice:expr, $what:expr) => {{
-            let slice: &[u8] = $slice;
-            if slice.len() > limit {
-                return Err(ApplyError::OutputLimit {
-                    what: $what,
-                    wanted: slice.len(),
-                    available: limit,
+    let cmd = read_varint!(1, "cmd") as u8;
+    let header = match cmd {
+        RS_OP_END => Header::End,
+        RS_OP_LITERAL_1..=RS_OP_LITERAL_64 => {
+            // <=64, length is encoded in `cmd`
+            Header::Literal((1 + cmd - RS_OP_LITERAL_1) as u64)
+        }
+        RS_OP_LITERAL_N1..=RS_OP_LITERAL_N8 => Header::Literal(read_varint!(
+            1 << (cmd - RS_OP_LITERAL_N1) as usize,
+            "literal length"
+        )),
+        RS_OP_COPY_N1_N1..=RS_OP_COPY_N8_N8 => {
+            let mode = cmd - RS_OP_COPY_N1_N1;
+            let offset = read_varint!(1 << (mode / 4) as usize, "copy offset");
+            let len = read_varint!(1 << (mode % 4) as usize, "copy length");
+            Header::Copy { offset, len }
+        }
+        RS_OP_SELF_COPY_N1_N1..=RS_OP_SELF_COPY_N8_N8 if extensions & DELTA_SELF_COPIES != 0 => {
+            let mode = cmd - RS_OP_SELF_COPY_N1_N1;
+            let offset = read_varint!(1 << (mode / 4) as usize, "copy offset");
+            let len = read_varint!(1 << (mode % 4) as usize, "copy length");
+            if len == 0 {
+                return Err(ApplyError::CopyZero {
+                    command: cmd,
+                    delta_offset: 0,
                 });
             }
-            limit -= slice.len();
-            out.write_all(slice)?;
-        }};
+            Header::SelfCopy { offset, len }
+        }
+        _ => {
+            return Err(ApplyError::UnknownCommand {
+                command: cmd,
+                delta_offset: 0,
+            })
+        }
+    };
+    Ok(Parsed::Header(header, pos))
+}
+
+/// Check that a copy is within a base of length `base_len`. The errors have to be
+/// [located](ApplyError::located).
+#[inline]
+fn check_copy(offset: u64, len: u64, base_len: u64) -> Result<(), ApplyError> {
+    let oob_error = || ApplyError::CopyOutOfBounds {
+        offset,
+        len,
+        data_len: base_len.min(usize::max_value() as u64) as usize,
+        command: 0,
+        delta_offset: 0,
+    };
+    if offset > usize::max_value() as u64 || len > usize::max_value() as u64 {
+        return Err(oob_error());
     }
-    let magic = read_int!(u32, "magic");
-    if magic != DELTA_MAGIC {
-        return Err(ApplyError::WrongMagic { magic });
+    if len == 0 {
+        return Err(ApplyError::CopyZero {
+            command: 0,
+            delta_offset: 0,
+        });
     }
-    loop {
-        let cmd = read_int!(u8, "cmd");
-        match cmd {
-            RS_OP_END => {
-                break;
+    match offset.checked_add(len) {
+        Some(end) if end <= base_len => Ok(()),
+        _ => Err(oob_error()),
+    }
+}
+
+/// How many times longer than itself a compressed literal can claim to be. zstd can't do better
+/// than a 4-byte RLE block for each 128 KiB, so a literal which claims more is corrupt.
+#[cfg(feature = "zstd")]
+const MAX_COMPRESSION_RATIO: u64 = 1 << 15;
+
+/// How much of a compressed literal's claimed length to allocate up front: the claim comes from
+/// the delta, so the rest is only allocated as the data turns out to be there.
+#[cfg(feature = "zstd")]
+const DECOMPRESS_PREALLOCATE: usize = 1 << 20;
+
+/// The length of a compressed literal once it is decompressed, as its header claims.
+///
+/// Fails with [ApplyError::CorruptLiteral] if the claim is more than zstd could compress the
+/// literal from, so the length is at most [MAX_COMPRESSION_RATIO] times that of the literal.
+#[cfg(feature = "zstd")]
+pub(crate) fn compressed_len(literal: &[u8]) -> Result<u64, ApplyError> {
+    match zstd::zstd_safe::get_frame_content_size(literal) {
+        Ok(Some(size)) if size <= (literal.len() as u64).saturating_mul(MAX_COMPRESSION_RATIO) => {
+            Ok(size)
+        }
+        _ => Err(ApplyError::CorruptLiteral),
+    }
+}
+
+/// Decompress a compressed literal, which [compressed_len()] says is `size` bytes long.
+///
+/// The literal is decompressed as a stream, so no more than it really holds is allocated, and
+/// decompression stops once it has produced more than `size` bytes.
+#[cfg(feature = "zstd")]
+pub(crate) fn decompress(literal: &[u8], size: u64) -> Result<Vec<u8>, ApplyError> {
+    let mut out = Vec::with_capacity(size.min(DECOMPRESS_PREALLOCATE as u64) as usize);
+    zstd::stream::read::Decoder::with_buffer(literal)
+        .and_then(|decoder| decoder.take(size.saturating_add(1)).read_to_end(&mut out))
+        .map_err(|_| ApplyError::CorruptLiteral)?;
+    if out.len() as u64 != size {
+        return Err(ApplyError::CorruptLiteral);
+    }
+    Ok(out)
+}
+
+/// Decompress the first `len` bytes of a compressed literal, which is longer than that.
+#[cfg(feature = "zstd")]
+fn decompress_prefix(literal: &[u8], len: usize) -> Result<Vec<u8>, ApplyError> {
+    let mut part = Vec::with_capacity(len.min(DECOMPRESS_PREALLOCATE));
+    zstd::stream::read::Decoder::with_buffer(literal)
+        .and_then(|decoder| decoder.take(len as u64).read_to_end(&mut part))
+        .map_err(|_| ApplyError::CorruptLiteral)?;
+    if part.len() != len {
+        return Err(ApplyError::CorruptLiteral);
+    }
+    Ok(part)
+}
+
+/// Parses a delta into its commands, without applying it.
+///
+/// This checks the delta as far as [apply()] would without the base or the output: its magic,
+/// that every command is well-formed and complete, that self-copies only reach back into the
+/// output so far, and that nothing follows the end. Copies are also checked against the length
+/// of the base if it is given with [with_base_len()](DeltaReader::with_base_len). The commands
+/// are yielded as they are checked, ending with [DeltaCommand::End], and the first error ends
+/// the iteration.
+///
+/// If the delta has compressed literals (see `DiffOptions::compress_literals`, with the `zstd`
+/// feature), they are yielded still compressed, and only checked for their length, not
+/// decompressed.
+///
+/// ```
+/// use fast_rsync::{diff, DeltaCommand, DeltaReader, Signature, SignatureOptions};
+///
+/// let base = b"hello world";
+/// let signature = Signature::calculate(base, SignatureOptions::default());
+/// let mut delta = vec![];
+/// diff(&signature.index(), b"hello there", &mut delta).unwrap();
+///
+/// let commands = DeltaReader::new(&delta)
+///     .unwrap()
+///     .collect::<Result<Vec<_>, _>>()
+///     .unwrap();
+/// assert_eq!(
+///     commands,
+///     [DeltaCommand::Literal(b"hello there"), DeltaCommand::End]
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct DeltaReader<'a> {
+    /// The rest of the delta, or `None` once the iteration has ended.
+    delta: Option<&'a [u8]>,
+    /// Where in the delta the rest of it starts.
+    pos: u64,
+    extensions: u32,
+    base_len: Option<u64>,
+    output_len: u64,
+    checksum: Option<&'a [u8]>,
+}
+
+impl<'a> DeltaReader<'a> {
+    /// Start reading `delta`, checking its magic.
+    pub fn new(delta: &'a [u8]) -> Result<Self, ApplyError> {
+        if delta.len() < 4 {
+            return Err(ApplyError::UnexpectedEof {
+                reading: "magic",
+                expected: 4,
+                available: delta.len(),
+                delta_offset: 0,
+            });
+        }
+        let magic = u32::from_be_bytes(*array_ref!(delta, 0, 4));
+        Ok(DeltaReader {
+            delta: Some(&delta[4..]),
+            pos: 4,
+            extensions: extensions(magic)?,
+            base_len: None,
+            output_len: 0,
+            checksum: None,
+        })
+    }
+
+    /// Also check that copies are within a base of length `base_len`.
+    pub fn with_base_len(mut self, base_len: u64) -> Self {
+        self.base_len = Some(base_len);
+        self
+    }
+
+    /// Whether the delta has [self-copies](crate::DiffOptions::self_copies).
+    pub fn has_self_copies(&self) -> bool {
+        self.extensions & DELTA_SELF_COPIES != 0
+    }
+
+    /// Whether the end of the delta has a [checksum](crate::DiffOptions::checksum).
+    pub(crate) fn has_checksum(&self) -> bool {
+        self.extensions & DELTA_CHECKSUM != 0
+    }
+
+    /// Whether the literals of the delta are compressed (see `DiffOptions::compress_literals`,
+    /// with the `zstd` feature).
+    pub fn has_compressed_literals(&self) -> bool {
+        #[cfg(feature = "zstd")]
+        if self.extensions & DELTA_ZSTD_LITERALS != 0 {
+            return true;
+        }
+        false
+    }
+
+    /// Where in the delta the next command starts.
+    pub fn delta_offset(&self) -> u64 {
+        self.pos
+    }
+
+    /// The length of the output of the commands read so far.
+    pub fn output_len(&self) -> u64 {
+        self.output_len
+    }
+
+    /// The [checksum](crate::DiffOptions::checksum) of the output which follows the end of the
+    /// delta, once [DeltaCommand::End] has been read, if the delta has one.
+    pub fn checksum(&self) -> Option<&'a [u8]> {
+        self.checksum
+    }
+
+    fn read(&mut self, delta: &'a [u8]) -> Result<(DeltaCommand<'a>, &'a [u8]), ApplyError> {
+        let start = self.pos;
+        let eof = |reading, expected, available, delta_offset| ApplyError::UnexpectedEof {
+            reading,
+            expected,
+            available,
+            delta_offset,
+        };
+        let (header, len) = match parse_header(delta, self.extensions) {
+            Ok(Parsed::Header(header, len)) => (header, len),
+            Ok(Parsed::Incomplete { reading, expected }) => {
+                return Err(eof(reading, expected, delta.len(), start))
             }
-            RS_OP_LITERAL_1..=RS_OP_LITERAL_N8 => {
-                let n = if cmd <= RS_OP_LITERAL_64 {
-                    // <=64, length is encoded in `cmd`
-                    (1 + cmd - RS_OP_LITERAL_1) as usize
-                } else {
-                    safe_cast!(
-                        read_varint!(1 << (cmd - RS_OP_LITERAL_N1) as usize, "literal length"),
-                        usize,
-                        ApplyError::OutputLimit {
-                            what: "literal",
-                            wanted: usize::max_value(),
-                            available: limit,
-                        }
-                    )
-                };
-                safe_extend!(read_n!(n, "literal"), "literal");
-            }
-            RS_OP_COPY_N1_N1..=RS_OP_COPY_N8_N8 => {
-                let mode = cmd - RS_OP_COPY_N1_N1;
-                let offset_len = 1 << (mode / 4) as usize;
-                let len_len = 1 << (mode % 4) as usize;
-                let offset = read_varint!(offset_len, "copy offset");
-                let len = read_varint!(len_len, "copy length");
-                let make_oob_error = || ApplyError::CopyOutOfBounds {
-                    offset,
-                    len,
-                    data_len: base.len(),
+            Err(e) => return Err(e.located(start, delta[0])),
+        };
+        let located = |e: ApplyError| e.located(start, delta[0]);
+        let rest = &delta[len..];
+        let rest_offset = start + len as u64;
+        let command = match header {
+            Header::End => {
+                let mut rest = rest;
+                if self.extensions & DELTA_CHECKSUM != 0 {
+                    if rest.len() < BLAKE2_SIZE {
+                        return Err(eof("checksum", BLAKE2_SIZE, rest.len(), rest_offset));
+                    }
+                    let (checksum, after) = rest.split_at(BLAKE2_SIZE);
+                    self.checksum = Some(checksum);
+                    rest = after;
+                }
+                if !rest.is_empty() {
+                    return Err(ApplyError::TrailingData {
+                        length: rest.len(),
+                        delta_offset: self.pos + (delta.len() - rest.len()) as u64,
+                    });
+                }
+                return Ok((DeltaCommand::End, rest));
+            }
+            Header::Literal(n) => {
+                if n > rest.len() as u64 {
+                    let expected = n.min(usize::max_value() as u64) as usize;
+                    return Err(eof("literal", expected, rest.len(), rest_offset));
+                }
+                let (literal, rest) = rest.split_at(n as usize);
+                #[cfg(feature = "zstd")]
+                let n = match self.has_compressed_literals() {
+                    true => compressed_len(literal)?,
+                    false => n,
                 };
-                let offset = safe_cast!(offset, usize, make_oob_error());
-                let len = safe_cast!(len, usize, make_oob_error());
-                if len == 0 {
-                    return Err(ApplyError::CopyZero);
+                self.output_len = self.output_len.saturating_add(n);
+                return Ok((DeltaCommand::Literal(literal), rest));
+            }
+            Header::Copy { offset, len } => {
+                check_copy(offset, len, self.base_len.unwrap_or(u64::max_value()))
+                    .map_err(located)?;
+                DeltaCommand::Copy { offset, len }
+            }
+            Header::SelfCopy { offset, len } => {
+                let reachable = self.output_len.saturating_sub(SELF_COPY_WINDOW);
+                match offset.checked_add(len) {
+                    Some(end) if offset >= reachable && end <= self.output_len => {}
+                    _ => {
+                        return Err(located(ApplyError::SelfCopyOutOfBounds {
+                            offset,
+                            len,
+                            written: self.output_len,
+                            command: 0,
+                            delta_offset: 0,
+                        }))
+                    }
                 }
-                let end = offset.checked_add(len).ok_or_else(make_oob_error)?;
-                let subslice = base.get(offset..end).ok_or_else(make_oob_error)?;
-                safe_extend!(subslice, "copy");
+                DeltaCommand::SelfCopy { offset, len }
             }
-            _ => return Err(ApplyError::UnknownCommand { command: cmd }),
+        };
+        if let DeltaCommand::Copy { len, .. } | DeltaCommand::SelfCopy { len, .. } = command {
+            self.output_len = self.output_len.saturating_add(len);
         }
+        Ok((command, rest))
     }
-    if delta.is_empty() {
-        Ok(())
-    } else {
-        // extra content after EOF
-        Err(ApplyError::TrailingData {
-            length: delta.len(),
-        })
+}
+
+impl<'a> Iterator for DeltaReader<'a> {
+    type Item = Result<DeltaCommand<'a>, ApplyError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let delta = self.delta.take()?;
+        match self.read(delta) {
+            Ok((DeltaCommand::End, _)) => Some(Ok(DeltaCommand::End)),
+            Ok((command, rest)) => {
+                self.pos += (delta.len() - rest.len()) as u64;
+                self.delta = Some(rest);
+                Some(Ok(command))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// What [validate_delta()] found out about a delta.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DeltaSummary {
+    /// The length of the output of the delta.
+    pub output_len: u64,
+    /// How many commands the delta has, not counting its end.
+    pub commands: u64,
+    /// Whether the delta ends with a [checksum](crate::DiffOptions::checksum) of its output,
+    /// which can only be checked by applying it.
+    pub has_checksum: bool,
+}
+
+/// Check that `delta` is well-formed and only copies from within a base of length `base_len`,
+/// without applying it, and return its output length.
+///
+/// These are all the checks of [DeltaReader], so once a delta passes them, applying it to such
+/// a base can only fail because of the output: if it exceeds the limit, if writing it fails, or
+/// if it doesn't match the checksum of the delta. The output can then be allocated up front,
+/// e.g. to apply the delta with [apply_into()].
+///
+/// The one exception is a compressed literal (see `DiffOptions::compress_literals`, with the
+/// `zstd` feature), whose length is taken from its header, but which is only found to be
+/// corrupt when it is decompressed.
+pub fn validate_delta(delta: &[u8], base_len: u64) -> Result<DeltaSummary, ApplyError> {
+    let mut reader = DeltaReader::new(delta)?.with_base_len(base_len);
+    let mut commands = 0;
+    for command in &mut reader {
+        if command? != DeltaCommand::End {
+            commands += 1;
+        }
+    }
+    Ok(DeltaSummary {
+        output_len: reader.output_len(),
+        commands,
+        has_checksum: reader.checksum().is_some(),
+    })
+}
+
+/// List the ranges of the base which `delta` copies from, sorted, and with overlapping or
+/// adjacent ranges merged, e.g. to fetch just those before applying the delta with
+/// [apply_with_provider()].
+///
+/// The delta is checked as with [DeltaReader].
+pub fn plan_apply(delta: &[u8]) -> Result<Vec<Range<u64>>, ApplyError> {
+    let mut ranges = Vec::new();
+    for command in DeltaReader::new(delta)? {
+        if let DeltaCommand::Copy { offset, len } = command? {
+            ranges.push(offset..offset + len);
+        }
+    }
+    ranges.sort_unstable_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    Ok(merged)
+}
+
+/// The output of a delta with self-copies, as far back as they can reach.
+#[derive(Default)]
+struct History {
+    buffer: Vec<u8>,
+    /// How much output came before `buffer`.
+    dropped: u64,
+}
+
+impl History {
+    fn push(&mut self, data: &[u8]) {
+        let window = SELF_COPY_WINDOW as usize;
+        if self.buffer.len() + data.len() > 2 * window {
+            // keep the last `window` bytes, counting `data`, only moving them every so often
+            let keep = window.saturating_sub(data.len()).min(self.buffer.len());
+            let drop = self.buffer.len() - keep;
+            self.buffer.drain(..drop);
+            self.dropped += drop as u64;
+        }
+        let skip = data.len().saturating_sub(window);
+        self.dropped += skip as u64;
+        self.buffer.extend_from_slice(&data[skip..]);
+    }
+
+    /// How much output there has been.
+    fn written(&self) -> u64 {
+        self.dropped + self.buffer.len() as u64
+    }
+
+    /// The `len` bytes of output from `offset` on, if they are still kept.
+    fn get(&self, offset: u64, len: u64) -> Option<&[u8]> {
+        if offset < self.written().saturating_sub(SELF_COPY_WINDOW) {
+            return None;
+        }
+        let start = offset.checked_sub(self.dropped)?;
+        let end = start.checked_add(len)?;
+        if end > self.buffer.len() as u64 {
+            return None;
+        }
+        Some(&self.buffer[start as usize..end as usize])
     }
 }
 
@@ -239,3 +1744,303 @@ pub fn apply_limited(
 pub fn apply(base: &[u8], delta: &[u8], out: &mut impl Write) -> Result<(), ApplyError> {
     apply_limited(base, delta, out, usize::max_value())
 }
+
+/// The expected hash of the output of a delta, for [apply_verified()].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OutputDigest {
+    /// BLAKE2b, unkeyed and configured for a 32-byte digest (which differs from a truncated
+    /// 64-byte one), as in librsync signatures.
+    Blake2([u8; 32]),
+    /// BLAKE3, with its default 32-byte output.
+    #[cfg(feature = "blake3")]
+    Blake3([u8; 32]),
+    /// SHA-256.
+    #[cfg(feature = "sha2")]
+    Sha256([u8; 32]),
+    /// The whole-file checksum of rsync: MD4 of `seed`, as 4 little-endian bytes, followed by
+    /// the data. This is what rsync sends after each file with protocol versions 27 to 29, with
+    /// the checksum seed it negotiated; later versions use MD5 or xxHash instead.
+    RsyncMd4 {
+        /// The checksum seed.
+        seed: u32,
+        /// The MD4 digest.
+        digest: [u8; 16],
+    },
+}
+
+/// Hashes output as it is written, for [apply_verified()].
+struct DigestWriter<'a, W> {
+    out: &'a mut W,
+    hasher: OutputHasher,
+}
+
+enum OutputHasher {
+    Blake2(Blake2Hasher),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+    #[cfg(feature = "sha2")]
+    Sha256(sha2::Sha256),
+    Md4(Md4Hasher),
+}
+
+impl<W: Write> Write for DigestWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.out.write(buf)?;
+        match &mut self.hasher {
+            OutputHasher::Blake2(hasher) => hasher.update(&buf[..n]),
+            #[cfg(feature = "blake3")]
+            OutputHasher::Blake3(hasher) => {
+                hasher.update(&buf[..n]);
+            }
+            #[cfg(feature = "sha2")]
+            OutputHasher::Sha256(hasher) => sha2::Digest::update(hasher, &buf[..n]),
+            OutputHasher::Md4(hasher) => hasher.update(&buf[..n]),
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Like [apply()], but also hash the output as it is written, and fail with
+/// [ApplyError::DigestMismatch] if it doesn't match `expected`.
+///
+/// Since a delta can't be trusted to reconstruct the data it was calculated from (see
+/// [diff()](crate::diff)), the sender can pass along a hash of that data to check the output
+/// against, without reading it again.
+///
+/// # Security
+/// As with [apply()], a delta may create an arbitrarily large output, and the digest is only
+/// checked at the end. Check the length of the delta's output with [validate_delta()] first if
+/// that matters.
+pub fn apply_verified(
+    base: &[u8],
+    delta: &[u8],
+    out: &mut impl Write,
+    expected: &OutputDigest,
+) -> Result<(), ApplyError> {
+    let hasher = match expected {
+        OutputDigest::Blake2(_) => OutputHasher::Blake2(Blake2Hasher::default()),
+        #[cfg(feature = "blake3")]
+        OutputDigest::Blake3(_) => OutputHasher::Blake3(Box::default()),
+        #[cfg(feature = "sha2")]
+        OutputDigest::Sha256(_) => OutputHasher::Sha256(sha2::Digest::new()),
+        OutputDigest::RsyncMd4 { seed, .. } => {
+            let mut hasher = Md4Hasher::default();
+            hasher.update(&seed.to_le_bytes());
+            OutputHasher::Md4(hasher)
+        }
+    };
+    let mut writer = DigestWriter { out, hasher };
+    apply(base, delta, &mut writer)?;
+    let matches = match (writer.hasher, expected) {
+        (OutputHasher::Blake2(hasher), OutputDigest::Blake2(expected)) => {
+            hasher.finalize() == *expected
+        }
+        #[cfg(feature = "blake3")]
+        (OutputHasher::Blake3(hasher), OutputDigest::Blake3(expected)) => {
+            hasher.finalize() == *expected
+        }
+        #[cfg(feature = "sha2")]
+        (OutputHasher::Sha256(hasher), OutputDigest::Sha256(expected)) => {
+            sha2::Digest::finalize(hasher)[..] == expected[..]
+        }
+        (OutputHasher::Md4(hasher), OutputDigest::RsyncMd4 { digest, .. }) => {
+            hasher.finalize() == *digest
+        }
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("the hasher is chosen by the digest"),
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(ApplyError::DigestMismatch)
+    }
+}
+
+/// Apply `delta` to the base data `base`, and return the result.
+///
+/// The output is allocated up front, at the length the commands of the delta add up to, so it
+/// isn't reallocated as it grows.
+///
+/// # Security
+/// As with [apply()], a delta may create an arbitrarily large output. Use
+/// [apply_to_vec_limited()] instead to set an upper bound on its size.
+pub fn apply_to_vec(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, ApplyError> {
+    apply_to_vec_limited(base, delta, usize::max_value())
+}
+
+/// Like [apply_to_vec()], but errors if the output would be longer than `limit` bytes. No more
+/// than `limit` bytes are allocated up front either, whatever the delta claims.
+pub fn apply_to_vec_limited(
+    base: &[u8],
+    delta: &[u8],
+    limit: usize,
+) -> Result<Vec<u8>, ApplyError> {
+    let mut out = Vec::with_capacity(output_len_hint(base.len(), delta).min(limit));
+    apply_limited(base, delta, &mut out, limit)?;
+    Ok(out)
+}
+
+/// Apply `delta` to the base data `base`, writing the result to the start of `out`, and return
+/// its length. This allocates nothing unless the delta has
+/// [self-copies](crate::DiffOptions::self_copies) or compressed literals.
+///
+/// Fails with [ApplyError::OutputLimit] if the output doesn't fit in `out`, in which case `out`
+/// holds as much of it as was written by then.
+pub fn apply_into(base: &[u8], delta: &[u8], out: &mut [u8]) -> Result<usize, ApplyError> {
+    let len = out.len();
+    let mut rest = out;
+    apply_limited(base, delta, &mut rest, len)?;
+    Ok(len - rest.len())
+}
+
+/// How much of a copy or literal is written by one task in [apply_parallel()].
+#[cfg(feature = "rayon")]
+const PARALLEL_CHUNK: usize = 1 << 20;
+
+/// Like [apply_into()], but copying the data of the commands into `out` on the rayon thread pool.
+///
+/// The delta is [validated](validate_delta()) against `base` first, which tells where the
+/// output of each command goes, so they can all be written at once, split into pieces of at
+/// most a MiB. Only [self-copies](crate::DiffOptions::self_copies) are left for afterwards,
+/// since they read the output, and a checksum is checked once the whole output is there.
+///
+/// Fails with [ApplyError::OutputLimit] without writing anything if the output doesn't fit in
+/// `out`.
+#[cfg(feature = "rayon")]
+pub fn apply_parallel(base: &[u8], delta: &[u8], out: &mut [u8]) -> Result<usize, ApplyError> {
+    use rayon::prelude::*;
+
+    let mut reader = DeltaReader::new(delta)?.with_base_len(base.len() as u64);
+    let mut commands = Vec::new();
+    for command in &mut reader {
+        commands.push(command?);
+    }
+    let output_len = reader.output_len();
+    if output_len > out.len() as u64 {
+        return Err(ApplyError::OutputLimit {
+            what: "output",
+            wanted: output_len.min(usize::MAX as u64) as usize,
+            available: out.len(),
+        });
+    }
+
+    let (mut output, _) = out.split_at_mut(output_len as usize);
+    let mut pieces = Vec::new();
+    #[cfg(feature = "zstd")]
+    let mut compressed_pieces = Vec::new();
+    let mut self_copies = Vec::new();
+    for command in commands {
+        let data = match command {
+            DeltaCommand::Copy { offset, len } => &base[offset as usize..(offset + len) as usize],
+            DeltaCommand::Literal(literal) => {
+                #[cfg(feature = "zstd")]
+                if reader.has_compressed_literals() {
+                    let len = compressed_len(literal)? as usize;
+                    let (piece, rest) = mem::take(&mut output).split_at_mut(len);
+                    compressed_pieces.push((piece, literal));
+                    output = rest;
+                    continue;
+                }
+                literal
+            }
+            DeltaCommand::SelfCopy { offset, len } => {
+                let target = output_len as usize - output.len();
+                self_copies.push((offset as usize..(offset + len) as usize, target));
+                output = &mut mem::take(&mut output)[len as usize..];
+                continue;
+            }
+            DeltaCommand::End => break,
+        };
+        for data in data.chunks(PARALLEL_CHUNK) {
+            let (piece, rest) = mem::take(&mut output).split_at_mut(data.len());
+            pieces.push((piece, data));
+            output = rest;
+        }
+    }
+
+    pieces
+        .into_par_iter()
+        .for_each(|(piece, data)| piece.copy_from_slice(data));
+    #[cfg(feature = "zstd")]
+    compressed_pieces.into_par_iter().try_for_each(
+        |(piece, literal)| -> Result<(), ApplyError> {
+            piece.copy_from_slice(&decompress(literal, piece.len() as u64)?);
+            Ok(())
+        },
+    )?;
+    // each one only reads output from before its target, which is all written by now
+    for (source, target) in self_copies {
+        out.copy_within(source, target);
+    }
+    if let Some(checksum) = reader.checksum() {
+        let mut hasher = Blake2Hasher::default();
+        hasher.update(&out[..output_len as usize]);
+        if hasher.finalize()[..] != *checksum {
+            return Err(ApplyError::ChecksumMismatch);
+        }
+    }
+    Ok(output_len as usize)
+}
+
+/// How long the output of `delta` is going to be, as far as the lengths of its commands tell
+/// without applying it. Commands are only read as far as they make sense, and lengths which
+/// can't be right are capped: a literal can't be longer than the rest of the delta, nor a copy
+/// longer than the base or the output so far.
+fn output_len_hint(base_len: usize, mut delta: &[u8]) -> usize {
+    fn read_varint(delta: &mut &[u8], len: usize) -> Option<u64> {
+        if delta.len() < len {
+            return None;
+        }
+        let mut b = [0; 8];
+        b[8 - len..].copy_from_slice(&delta[..len]);
+        *delta = &delta[len..];
+        Some(u64::from_be_bytes(b))
+    }
+
+    let magic = match read_varint(&mut delta, 4) {
+        Some(magic) => magic as u32,
+        None => return 0,
+    };
+    // Compressed literals are counted at their compressed length, which is an underestimate.
+    let self_copies = extensions(magic).map_or(false, |e| e & DELTA_SELF_COPIES != 0);
+    let mut total = 0usize;
+    while let Some((&cmd, rest)) = delta.split_first() {
+        delta = rest;
+        let len = match cmd {
+            RS_OP_LITERAL_1..=RS_OP_LITERAL_N8 => {
+                let n = if cmd <= RS_OP_LITERAL_64 {
+                    (1 + cmd - RS_OP_LITERAL_1) as u64
+                } else {
+                    match read_varint(&mut delta, 1 << (cmd - RS_OP_LITERAL_N1)) {
+                        Some(n) => n,
+                        None => break,
+                    }
+                };
+                let n = n.min(delta.len() as u64) as usize;
+                delta = &delta[n..];
+                n
+            }
+            RS_OP_COPY_N1_N1..=RS_OP_COPY_N8_N8 | RS_OP_SELF_COPY_N1_N1..=RS_OP_SELF_COPY_N8_N8 => {
+                let (first, max) = match cmd {
+                    RS_OP_COPY_N1_N1..=RS_OP_COPY_N8_N8 => (RS_OP_COPY_N1_N1, base_len),
+                    _ if self_copies => (RS_OP_SELF_COPY_N1_N1, total),
+                    _ => break,
+                };
+                let mode = cmd - first;
+                let len = read_varint(&mut delta, 1 << (mode / 4))
+                    .and_then(|_| read_varint(&mut delta, 1 << (mode % 4)));
+                match len {
+                    Some(len) => len.min(max as u64) as usize,
+                    None => break,
+                }
+            }
+            _ => break,
+        };
+        total = total.saturating_add(len);
+    }
+    total
+}
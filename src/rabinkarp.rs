@@ -0,0 +1,162 @@
+use crate::seed::SeedTable;
+
+/// The initial hash value, as used by librsync.
+const RABINKARP_SEED: u32 = 1;
+/// The multiplier used for each byte.
+const RABINKARP_MULT: u32 = 0x08104225;
+/// The multiplicative inverse of `RABINKARP_MULT` modulo 2^32.
+const RABINKARP_INVM: u32 = 0x98f009ad;
+/// Equal to `RABINKARP_SEED * (RABINKARP_MULT - 1)`; accounts for the seed when removing a byte.
+const RABINKARP_ADJ: u32 = 0x08104224;
+
+/// The RabinKarp rolling checksum introduced in librsync 2.2.
+///
+/// Unlike [Crc][crate::crc::Crc], this carries `RABINKARP_MULT` raised to the window length, so it
+/// doesn't need the window size to be passed in when rolling.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RabinKarp {
+    hash: u32,
+    mult: u32,
+}
+
+impl RabinKarp {
+    #[inline]
+    pub fn new() -> RabinKarp {
+        RabinKarp {
+            hash: RABINKARP_SEED,
+            mult: 1,
+        }
+    }
+
+    #[inline]
+    pub fn digest(self) -> u32 {
+        self.hash
+    }
+
+    pub fn update(self, buf: &[u8]) -> RabinKarp {
+        let mut hash = self.hash;
+        let mut mult = self.mult;
+        for &byte in buf {
+            hash = hash.wrapping_mul(RABINKARP_MULT).wrapping_add(byte as u32);
+            mult = mult.wrapping_mul(RABINKARP_MULT);
+        }
+        RabinKarp { hash, mult }
+    }
+
+    /// Like `RabinKarp::update`, but with every byte first mapped through `seed`. The result can
+    /// be rolled with `RabinKarp::rotate`, as long as the bytes passed to it are mapped too.
+    pub fn update_seeded(self, seed: &SeedTable, buf: &[u8]) -> RabinKarp {
+        buf.iter()
+            .fold(self, |sum, &byte| sum.rollin(seed.map(byte)))
+    }
+
+    #[inline]
+    pub fn rotate(self, old_byte: u8, new_byte: u8) -> RabinKarp {
+        RabinKarp {
+            hash: self
+                .hash
+                .wrapping_mul(RABINKARP_MULT)
+                .wrapping_add(new_byte as u32)
+                .wrapping_sub(
+                    self.mult
+                        .wrapping_mul((old_byte as u32).wrapping_add(RABINKARP_ADJ)),
+                ),
+            mult: self.mult,
+        }
+    }
+
+    #[inline]
+    pub fn rollin(self, new_byte: u8) -> RabinKarp {
+        RabinKarp {
+            hash: self
+                .hash
+                .wrapping_mul(RABINKARP_MULT)
+                .wrapping_add(new_byte as u32),
+            mult: self.mult.wrapping_mul(RABINKARP_MULT),
+        }
+    }
+
+    pub fn rollout(self, old_byte: u8) -> RabinKarp {
+        let mult = self.mult.wrapping_mul(RABINKARP_INVM);
+        RabinKarp {
+            hash: self
+                .hash
+                .wrapping_sub(mult.wrapping_mul((old_byte as u32).wrapping_add(RABINKARP_ADJ))),
+            mult,
+        }
+    }
+}
+
+impl Default for RabinKarp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RabinKarp, RABINKARP_INVM, RABINKARP_MULT};
+    use crate::seed::SeedTable;
+    use quickcheck_macros::quickcheck;
+
+    #[test]
+    fn constants() {
+        assert_eq!(RABINKARP_MULT.wrapping_mul(RABINKARP_INVM), 1);
+        // the empty sum is just the seed
+        assert_eq!(RabinKarp::new().digest(), 1);
+        assert_eq!(RabinKarp::new().update(&[0; 1]).digest(), RABINKARP_MULT);
+    }
+
+    #[quickcheck]
+    fn rollin_one(buf: Vec<u8>) -> bool {
+        let sum1 = RabinKarp::new().update(&buf);
+        let sum2 = buf
+            .iter()
+            .copied()
+            .fold(RabinKarp::new(), RabinKarp::rollin);
+        sum1 == sum2
+    }
+
+    #[quickcheck]
+    fn update_twice(mut buf1: Vec<u8>, buf2: Vec<u8>) -> bool {
+        let sum1 = RabinKarp::new().update(&buf1).update(&buf2);
+        buf1.extend(&buf2);
+        let sum2 = RabinKarp::new().update(&buf1);
+        sum1 == sum2
+    }
+
+    #[quickcheck]
+    fn rotate_one(mut buf: Vec<u8>, byte: u8) -> bool {
+        if buf.is_empty() {
+            return true;
+        }
+        let sum1 = RabinKarp::new().update(&buf).rotate(buf[0], byte);
+        buf.push(byte);
+        let sum2 = RabinKarp::new().update(&buf[1..]);
+        sum1 == sum2
+    }
+
+    #[quickcheck]
+    fn rollout_one(buf: Vec<u8>) -> bool {
+        if buf.is_empty() {
+            return true;
+        }
+        let sum1 = RabinKarp::new().update(&buf).rollout(buf[0]);
+        let sum2 = RabinKarp::new().update(&buf[1..]);
+        sum1 == sum2
+    }
+
+    #[quickcheck]
+    fn rotate_seeded(seed: u32, mut buf: Vec<u8>, byte: u8) -> bool {
+        if buf.is_empty() {
+            return true;
+        }
+        let table = SeedTable::new(seed);
+        let sum1 = RabinKarp::new()
+            .update_seeded(&table, &buf)
+            .rotate(table.map(buf[0]), table.map(byte));
+        buf.push(byte);
+        let sum2 = RabinKarp::new().update_seeded(&table, &buf[1..]);
+        sum1 == sum2
+    }
+}
@@ -0,0 +1,119 @@
+//! Moving a delta onto a different base.
+
+use std::error::Error;
+use std::fmt;
+
+#[cfg(feature = "zstd")]
+use crate::chain::decompress_all;
+use crate::chain::{DeltaBuilder, Source, Version};
+use crate::diff::{diff_commands, DiffCommand, DiffError, DiffOptions};
+use crate::error::ErrorKind;
+use crate::patch::ApplyError;
+use crate::signature::BlockIndex;
+
+/// Indicates that a delta couldn't be [rebased](rebase()).
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RebaseError {
+    /// Indicates the delta is invalid, or doesn't apply to the old base.
+    InvalidDelta(ApplyError),
+    /// Indicates the data copied from the old base couldn't be diffed against the new one.
+    Diff(DiffError),
+}
+
+impl RebaseError {
+    /// What kind of failure this is, which is that of the error it wraps.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::InvalidDelta(source) => source.kind(),
+            Self::Diff(source) => source.kind(),
+        }
+    }
+}
+
+impl fmt::Display for RebaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidDelta(source) => write!(f, "invalid delta: {}", source),
+            Self::Diff(source) => write!(f, "couldn't rebase delta: {}", source),
+        }
+    }
+}
+
+impl Error for RebaseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::InvalidDelta(source) => Some(source),
+            Self::Diff(source) => Some(source),
+        }
+    }
+}
+
+impl From<ApplyError> for RebaseError {
+    fn from(source: ApplyError) -> Self {
+        Self::InvalidDelta(source)
+    }
+}
+
+impl From<DiffError> for RebaseError {
+    fn from(source: DiffError) -> Self {
+        Self::Diff(source)
+    }
+}
+
+/// Turn `delta`, which applies to `old_base`, into a delta with the same output which applies
+/// to the base `new_base` was calculated from, and return it.
+///
+/// Only the data which `delta` copies from `old_base` is diffed against `new_base`, so the
+/// parts of it which are still there, say because the base only changed a little, are still
+/// copied, just from where they are now, and the rest is sent as literals. The literals of
+/// `delta` are kept as they are. This saves diffing the whole output again, but may miss some
+/// matches that would find: data copied from less than a block of `old_base` at a time, and
+/// literals of `delta` which have since turned up in the base.
+///
+/// The rebased delta is in the librsync format, with uncompressed literals and no self-copies,
+/// except that it keeps the [checksum](DiffOptions::checksum) of `delta` if it has one. Of
+/// `options`, only those which affect matching apply, as with [diff_commands()].
+///
+/// Fails with [ApplyError::OutputLimit] if the output of `delta` would be longer than `limit`
+/// bytes, or its compressed literals would take more than that decompressed, as with
+/// [apply_limited()](crate::apply_limited).
+///
+/// # Security
+/// The caveats for [diff()](crate::diff) apply here as well, and unless `limit` is bounded,
+/// `delta` may create an arbitrarily large output, much of which may end up in the rebased
+/// delta as literals.
+pub fn rebase(
+    old_base: &[u8],
+    delta: &[u8],
+    new_base: &impl BlockIndex,
+    options: &DiffOptions,
+    limit: usize,
+) -> Result<Vec<u8>, RebaseError> {
+    #[cfg(feature = "zstd")]
+    let decompressed = decompress_all(&[delta], limit)?;
+    #[cfg(feature = "zstd")]
+    let mut decompressed = decompressed.iter().map(|literal| &literal[..]);
+    #[cfg(not(feature = "zstd"))]
+    let mut decompressed = std::iter::empty::<&[u8]>();
+
+    let (output, checksum) =
+        Version::base(old_base.len() as u64).apply(delta, &mut decompressed, limit as u64)?;
+    let mut builder = DeltaBuilder::new().map_err(ApplyError::from)?;
+    for segment in &output.segments {
+        match segment.source {
+            Source::Base(offset) => {
+                let data = &old_base[offset as usize..(offset + segment.len) as usize];
+                diff_commands(new_base, data, options, |command| match command {
+                    DiffCommand::Copy { offset, len } => builder.copy(offset, len),
+                    DiffCommand::Literal(literal) => {
+                        builder.literal(literal);
+                        Ok(())
+                    }
+                })?;
+            }
+            Source::Data(data) => builder.literal(data),
+        }
+    }
+    Ok(builder.finish(checksum).map_err(ApplyError::from)?)
+}
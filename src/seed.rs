@@ -0,0 +1,62 @@
+//! Seeding for the rolling checksums.
+//!
+//! Neither rolling checksum can simply start from a seeded state: over a fixed-size window, that
+//! only adds the same constant to every sum, so colliding windows still collide. Instead, the seed
+//! selects a permutation which is applied to every byte before it is summed, which changes which
+//! windows collide.
+
+/// A permutation of the byte values, derived from a rolling checksum seed.
+#[derive(Clone)]
+pub struct SeedTable([u8; 256]);
+
+impl SeedTable {
+    pub fn new(seed: u32) -> SeedTable {
+        // splitmix64, which is plenty to shuffle a table (the seed itself is what must be
+        // unpredictable)
+        let mut state = seed as u64;
+        let mut next = move || {
+            state = state.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^ (z >> 31)
+        };
+        let mut table = [0; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            *entry = i as u8;
+        }
+        // Fisher-Yates
+        for i in (1..table.len()).rev() {
+            let j = (next() % (i as u64 + 1)) as usize;
+            table.swap(i, j);
+        }
+        SeedTable(table)
+    }
+
+    #[inline]
+    pub fn map(&self, byte: u8) -> u8 {
+        self.0[byte as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SeedTable;
+    use quickcheck_macros::quickcheck;
+
+    #[quickcheck]
+    fn permutation(seed: u32) -> bool {
+        let table = SeedTable::new(seed);
+        let mut seen = [false; 256];
+        for byte in 0..=255 {
+            seen[table.map(byte) as usize] = true;
+        }
+        seen.iter().all(|&seen| seen)
+    }
+
+    #[test]
+    fn depends_on_seed() {
+        let (a, b) = (SeedTable::new(1), SeedTable::new(2));
+        assert!((0..=255).any(|byte| a.map(byte) != b.map(byte)));
+    }
+}
@@ -1,14 +1,40 @@
-use std::collections::HashMap;
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
 use std::error::Error;
 use std::fmt;
+use std::hash::Hash;
+#[cfg(feature = "tokio")]
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Range;
 
 use arrayref::array_ref;
 
-use crate::consts::{BLAKE2_MAGIC, MD4_MAGIC};
+use crate::blake2::{blake2, blake2_many, BLAKE2_SIZE};
+use crate::bloom::WeakSumFilter;
+use crate::consts::{
+    BLAKE2_MAGIC, CUSTOM_MAGIC, EXTENDED_MAGIC, KEYED_BLAKE2_MAGIC, MD4_MAGIC, RK_BLAKE2_MAGIC,
+    RK_CUSTOM_MAGIC, RK_KEYED_BLAKE2_MAGIC, RK_MD4_MAGIC,
+};
+#[cfg(feature = "blake3")]
+use crate::consts::{BLAKE3_MAGIC, RK_BLAKE3_MAGIC};
+#[cfg(feature = "xxhash")]
+use crate::consts::{RK_XXH3_MAGIC, XXH3_MAGIC};
 use crate::crc::Crc;
+use crate::error::ErrorKind;
+use crate::flat_index::FlatIndexedSignature;
 use crate::hasher::BuildCrcHasher;
 use crate::hashmap_variant::SecondLayerMap;
 use crate::md4::{md4, md4_many, MD4_SIZE};
+use crate::rabinkarp::RabinKarp;
+use crate::seed::SeedTable;
+#[cfg(feature = "xxhash")]
+use xxhash_rust::xxh3::xxh3_128;
+
+#[cfg(feature = "xxhash")]
+const XXH3_SIZE: usize = 16;
 
 /// An rsync signature.
 ///
@@ -24,124 +50,1241 @@ pub struct Signature {
     signature: Vec<u8>,
 }
 
+/// A signature which borrows its serialized form, e.g. from a memory map or a network buffer.
+///
+/// This is obtained from [Signature::deserialize_ref], and can be indexed just like a [Signature].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SignatureRef<'a> {
+    signature_type: SignatureType,
+    pub(crate) block_size: u32,
+    crypto_hash_size: u32,
+    // Set for deduplicated signatures, whose table holds this many distinct blocks, followed by
+    // the index of each block among them.
+    unique_blocks: Option<u32>,
+    // Like `Signature::signature`, this is always a valid serialized signature.
+    signature: &'a [u8],
+}
+
+/// The blocks of a signature, as pairs of rolling checksum and strong hash.
+struct Blocks<'a> {
+    entries: &'a [u8],
+    entry_size: usize,
+    // For deduplicated signatures, the big-endian u32 index into `entries` of every block.
+    order: Option<&'a [u8]>,
+    next: usize,
+    len: usize,
+}
+
+impl<'a> Iterator for Blocks<'a> {
+    type Item = (u32, &'a [u8]);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == self.len {
+            return None;
+        }
+        let entry = match self.order {
+            Some(order) => u32::from_be_bytes(*array_ref![order, self.next * 4, 4]) as usize,
+            None => self.next,
+        };
+        self.next += 1;
+        let b = &self.entries[entry * self.entry_size..(entry + 1) * self.entry_size];
+        Some((
+            u32::from_be_bytes(*array_ref!(b, 0, 4)),
+            &b[Signature::WEAK_SUM_SIZE..],
+        ))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len - self.next, Some(self.len - self.next))
+    }
+}
+
+impl ExactSizeIterator for Blocks<'_> {}
+
+/// Calculates a [Signature] incrementally, for data which is not available all at once.
+///
+/// This produces the same signature as [Signature::calculate] would for the concatenation of
+/// all the data passed to [update](SignatureBuilder::update), while buffering at most one block.
+#[derive(Clone, Debug)]
+pub struct SignatureBuilder {
+    signature_type: SignatureType,
+    options: SignatureOptions,
+    signature: Vec<u8>,
+    // A partial block which hasn't been hashed yet. Always shorter than `block_size`.
+    pending: Vec<u8>,
+}
+
 /// A signature with a block index, suitable for calculating deltas.
+///
+/// By default the index borrows the strong hashes from the signature it was built from. Use
+/// [into_owned](IndexedSignature::into_owned) to get an [OwnedIndexedSignature] which doesn't.
+///
+/// To stay compact, the index stores 32-bit block indices, so only the first 2^32 blocks of a
+/// signature are indexed, and diffs against an index of a larger signature fail with
+/// [DiffError::IncompleteIndex](crate::DiffError::IncompleteIndex). Use a
+/// [FlatIndexedSignature](crate::FlatIndexedSignature) or
+/// [DiskIndexedSignature](crate::DiskIndexedSignature) for larger signatures.
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub struct IndexedSignature<'a> {
+pub struct IndexedSignature<'a, K: Eq + Hash = &'a [u8]> {
     pub(crate) signature_type: SignatureType,
     pub(crate) block_size: u32,
     pub(crate) crypto_hash_size: u32,
-    /// crc -> crypto hash -> block index
-    pub(crate) blocks: HashMap<Crc, SecondLayerMap<&'a [u8], u32>, BuildCrcHasher>,
+    /// The number of blocks in the signature, including duplicates.
+    pub(crate) block_count: usize,
+    /// rolling checksum -> crypto hash -> block index
+    pub(crate) blocks: HashMap<u32, SecondLayerMap<K, u32>, BuildCrcHasher>,
+    /// block index in `blocks` -> the indices of all identical blocks, in ascending order; only
+    /// kept with [IndexOptions::keep_duplicates]
+    pub(crate) duplicates: HashMap<u32, Vec<u32>>,
+    /// only kept with [IndexOptions::weak_sum_filter]
+    pub(crate) filter: Option<WeakSumFilter>,
+    pub(crate) marker: PhantomData<&'a [u8]>,
+}
+
+/// A searchable collection of the blocks of a signature, used by [diff()](crate::diff()) to find
+/// data which can be copied from the base.
+///
+/// This is implemented by [IndexedSignature], which keeps the index in a hash map, by
+/// [FlatIndexedSignature], which keeps it in a sorted array, and by
+/// [DiskIndexedSignature](crate::DiskIndexedSignature), which reads it from a serialized table.
+pub trait BlockIndex {
+    /// The options the signature was calculated with.
+    fn options(&self) -> SignatureOptions;
+    /// The number of blocks in the signature, including duplicates.
+    fn block_count(&self) -> u64;
+    /// Whether any block has the rolling checksum `weak_sum`.
+    fn contains_weak_sum(&self, weak_sum: u32) -> bool;
+    /// Find a block with the rolling checksum `weak_sum` and the strong hash `crypto_hash`
+    /// (truncated to `crypto_hash_size` bytes), and return its index.
+    fn find_block(&self, weak_sum: u32, crypto_hash: &[u8]) -> Option<u64>;
+    /// Like [find_block](BlockIndex::find_block), but if several identical blocks match, return
+    /// the one whose index is closest to `near`.
+    ///
+    /// Only an [IndexedSignature] built with [IndexOptions::keep_duplicates] keeps track of
+    /// identical blocks; by default, this is the same as `find_block`.
+    fn find_block_near(&self, weak_sum: u32, crypto_hash: &[u8], near: u64) -> Option<u64> {
+        let _ = near;
+        self.find_block(weak_sum, crypto_hash)
+    }
+    /// Whether every block of the signature can be found. Diffs against an index which leaves
+    /// some blocks out fail with [DiffError::IncompleteIndex](crate::DiffError::IncompleteIndex)
+    /// rather than quietly sending the data matching them as literals.
+    fn is_complete(&self) -> bool {
+        true
+    }
+}
+
+impl<K: Borrow<[u8]> + Eq + Hash> BlockIndex for IndexedSignature<'_, K> {
+    fn options(&self) -> SignatureOptions {
+        self.signature_type
+            .options(self.block_size, self.crypto_hash_size)
+    }
+    fn block_count(&self) -> u64 {
+        self.block_count as u64
+    }
+    #[inline]
+    fn contains_weak_sum(&self, weak_sum: u32) -> bool {
+        if let Some(filter) = &self.filter {
+            if !filter.may_contain(weak_sum) {
+                return false;
+            }
+        }
+        self.blocks.contains_key(&weak_sum)
+    }
+    #[inline]
+    fn find_block(&self, weak_sum: u32, crypto_hash: &[u8]) -> Option<u64> {
+        self.blocks
+            .get(&weak_sum)?
+            .get(crypto_hash)
+            .map(|&idx| idx as u64)
+    }
+    fn find_block_near(&self, weak_sum: u32, crypto_hash: &[u8], near: u64) -> Option<u64> {
+        let idx = *self.blocks.get(&weak_sum)?.get(crypto_hash)?;
+        let duplicates = match self.duplicates.get(&idx) {
+            Some(duplicates) => duplicates,
+            None => return Some(idx as u64),
+        };
+        // the closest index is on one side or the other of where `near` would go
+        let i = duplicates.partition_point(|&idx| (idx as u64) < near);
+        let before = i.checked_sub(1).map(|i| duplicates[i] as u64);
+        let after = duplicates.get(i).map(|&idx| idx as u64);
+        match (before, after) {
+            (Some(before), Some(after)) if near - before <= after - near => Some(before),
+            (_, Some(after)) => Some(after),
+            (before, None) => before,
+        }
+    }
+    fn is_complete(&self) -> bool {
+        self.block_count as u64 <= u32::MAX as u64 + 1
+    }
+}
+
+/// Options for [Signature::index_with].
+///
+/// The [Default] options match [Signature::index].
+#[derive(Clone, Debug)]
+pub struct IndexOptions {
+    /// Release the capacity the index doesn't need once it is built. This takes about as long as
+    /// building the index did, so it is only worth it for indexes which are kept around.
+    pub shrink_to_fit: bool,
+    /// The number of distinct rolling checksums to allocate room for up front. By default, room is
+    /// reserved for every block, which is too much if many blocks are identical.
+    pub expected_weak_sums: Option<usize>,
+    /// Remember where every copy of a repeated block is, rather than just one of them, so that
+    /// [BlockIndex::find_block_near] can choose between them. This costs memory in proportion to
+    /// the number of repeated blocks.
+    pub keep_duplicates: bool,
+    /// Also build a small bloom filter of the rolling checksums, which is checked before the
+    /// index itself. Most windows of the new data don't match any block, and the filter rules
+    /// out most of those without the cache misses of probing a large hash map, which speeds up
+    /// diffing against big signatures. It takes about two bytes per block.
+    pub weak_sum_filter: bool,
+}
+
+impl Default for IndexOptions {
+    fn default() -> Self {
+        IndexOptions {
+            shrink_to_fit: true,
+            expected_weak_sums: None,
+            keep_duplicates: false,
+            weak_sum_filter: false,
+        }
+    }
+}
+
+/// An [IndexedSignature] which owns its strong hashes, so it can outlive the [Signature] it was
+/// built from.
+pub type OwnedIndexedSignature = IndexedSignature<'static, Box<[u8]>>;
+
+impl<'a> IndexedSignature<'a> {
+    /// Read a binary signature and index it directly, without copying it into a [Signature] first.
+    pub fn from_serialized(signature: &'a [u8]) -> Result<Self, SignatureParseError> {
+        Ok(Signature::deserialize_ref(signature)?.index())
+    }
+
+    /// Copy the strong hashes into the index, so that it no longer borrows from the signature.
+    pub fn into_owned(self) -> OwnedIndexedSignature {
+        IndexedSignature {
+            signature_type: self.signature_type,
+            block_size: self.block_size,
+            crypto_hash_size: self.crypto_hash_size,
+            block_count: self.block_count,
+            blocks: self
+                .blocks
+                .into_iter()
+                .map(|(weak_sum, hashes)| (weak_sum, hashes.map_keys(Box::from)))
+                .collect(),
+            duplicates: self.duplicates,
+            filter: self.filter,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a> IndexedSignature<'a> {
+    /// The approximate number of bytes of heap memory used by the index, not counting the
+    /// signature it borrows from. This is meant for budgeting caches of indexes.
+    pub fn memory_usage(&self) -> usize {
+        self.heap_size(|_| 0)
+    }
+}
+
+impl OwnedIndexedSignature {
+    /// The approximate number of bytes of heap memory used by the index, including its copies
+    /// of the strong hashes. This is meant for budgeting caches of indexes.
+    pub fn memory_usage(&self) -> usize {
+        self.heap_size(|crypto_hash| crypto_hash.len())
+    }
 }
 
-/// The hash type used with within the signature.
-/// Note that this library generally only supports MD4 signatures.
+impl<K: Eq + Hash> IndexedSignature<'_, K> {
+    /// Estimate the heap memory used by the maps, plus `key_size` for each key.
+    fn heap_size(&self, key_size: impl Fn(&K) -> usize) -> usize {
+        // The standard hash tables store one control byte along with each slot, and keep a
+        // fraction of the slots empty; the capacity doesn't count those, so this is a lower bound.
+        fn table_size<T>(capacity: usize) -> usize {
+            capacity * (mem::size_of::<T>() + 1)
+        }
+        let mut size = table_size::<(u32, SecondLayerMap<K, u32>)>(self.blocks.capacity());
+        for hashes in self.blocks.values() {
+            if let SecondLayerMap::TwoOrMore(map) = hashes {
+                size += mem::size_of::<HashMap<K, u32>>() + table_size::<(K, u32)>(map.capacity());
+            }
+            size += hashes.iter().map(|(key, _)| key_size(key)).sum::<usize>();
+        }
+        size += table_size::<(u32, Vec<u32>)>(self.duplicates.capacity());
+        for duplicates in self.duplicates.values() {
+            size += duplicates.capacity() * mem::size_of::<u32>();
+        }
+        size += self.filter.as_ref().map_or(0, WeakSumFilter::heap_size);
+        size
+    }
+}
+
+/// The hash types used with within the signature.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-pub(crate) enum SignatureType {
-    Md4,
-    Blake2,
+pub(crate) struct SignatureType {
+    rolling_hash: RollingHash,
+    hash: SignatureHash,
+    rolling_seed: Option<u32>,
 }
 
 impl SignatureType {
     const SIZE: usize = 4;
-    fn from_magic(bytes: [u8; Self::SIZE]) -> Option<Self> {
-        match u32::from_be_bytes(bytes) {
-            BLAKE2_MAGIC => Some(SignatureType::Blake2),
-            MD4_MAGIC => Some(SignatureType::Md4),
-            _ => None,
+    /// Every supported combination of hashes, with its magic number.
+    const MAGICS: &'static [(u32, RollingHash, SignatureHash)] = &[
+        (MD4_MAGIC, RollingHash::Rollsum, SignatureHash::Md4),
+        (BLAKE2_MAGIC, RollingHash::Rollsum, SignatureHash::Blake2),
+        (RK_MD4_MAGIC, RollingHash::RabinKarp, SignatureHash::Md4),
+        (
+            RK_BLAKE2_MAGIC,
+            RollingHash::RabinKarp,
+            SignatureHash::Blake2,
+        ),
+        (CUSTOM_MAGIC, RollingHash::Rollsum, SignatureHash::Custom),
+        (
+            RK_CUSTOM_MAGIC,
+            RollingHash::RabinKarp,
+            SignatureHash::Custom,
+        ),
+        #[cfg(feature = "blake3")]
+        (BLAKE3_MAGIC, RollingHash::Rollsum, SignatureHash::Blake3),
+        #[cfg(feature = "blake3")]
+        (
+            RK_BLAKE3_MAGIC,
+            RollingHash::RabinKarp,
+            SignatureHash::Blake3,
+        ),
+        #[cfg(feature = "xxhash")]
+        (XXH3_MAGIC, RollingHash::Rollsum, SignatureHash::Xxh3),
+        #[cfg(feature = "xxhash")]
+        (RK_XXH3_MAGIC, RollingHash::RabinKarp, SignatureHash::Xxh3),
+        (
+            KEYED_BLAKE2_MAGIC,
+            RollingHash::Rollsum,
+            SignatureHash::KeyedBlake2,
+        ),
+        (
+            RK_KEYED_BLAKE2_MAGIC,
+            RollingHash::RabinKarp,
+            SignatureHash::KeyedBlake2,
+        ),
+    ];
+    pub(crate) fn new(rolling_hash: RollingHash, hash: SignatureHash) -> Self {
+        SignatureType {
+            rolling_hash,
+            hash,
+            rolling_seed: None,
         }
     }
-    fn to_magic(self) -> [u8; Self::SIZE] {
-        match self {
-            SignatureType::Md4 => MD4_MAGIC,
-            SignatureType::Blake2 => BLAKE2_MAGIC,
+    pub(crate) fn with_rolling_seed(self, rolling_seed: Option<u32>) -> Self {
+        SignatureType {
+            rolling_seed,
+            ..self
+        }
+    }
+    pub(crate) fn rolling_hash(self) -> RollingHash {
+        self.rolling_hash
+    }
+    pub(crate) fn hash(self) -> SignatureHash {
+        self.hash
+    }
+    pub(crate) fn rolling_seed(self) -> Option<u32> {
+        self.rolling_seed
+    }
+    /// The byte permutation for the rolling seed, if there is one. This is worth building once
+    /// and reusing for every block.
+    pub(crate) fn seed_table(self) -> Option<SeedTable> {
+        self.rolling_seed.map(SeedTable::new)
+    }
+    pub(crate) fn options(self, block_size: u32, crypto_hash_size: u32) -> SignatureOptions {
+        SignatureOptions {
+            block_size,
+            crypto_hash_size,
+            hash: self.hash(),
+            rolling_hash: self.rolling_hash(),
+            hash_key: None,
+            rolling_seed: self.rolling_seed(),
+        }
+    }
+    /// The largest supported `crypto_hash_size` for this signature type, or `None` if it depends
+    /// on the caller's hash.
+    pub(crate) fn max_crypto_hash_size(self) -> Option<usize> {
+        match self.hash() {
+            SignatureHash::Md4 => Some(MD4_SIZE),
+            SignatureHash::Blake2 | SignatureHash::KeyedBlake2 => Some(BLAKE2_SIZE),
+            #[cfg(feature = "blake3")]
+            SignatureHash::Blake3 => Some(blake3::OUT_LEN),
+            #[cfg(feature = "xxhash")]
+            SignatureHash::Xxh3 => Some(XXH3_SIZE),
+            SignatureHash::Custom => None,
+        }
+    }
+    /// Compute the strong hash of `data`. Only the first `max_crypto_hash_size()` bytes are
+    /// meaningful.
+    ///
+    /// Panics for [SignatureHash::Custom], which can't be computed here, or if `key` is missing
+    /// for [SignatureHash::KeyedBlake2].
+    pub(crate) fn crypto_hash(self, key: Option<&HashKey>, data: &[u8]) -> [u8; BLAKE2_SIZE] {
+        match self.hash() {
+            SignatureHash::Md4 => {
+                let mut hash = [0; BLAKE2_SIZE];
+                hash[..MD4_SIZE].copy_from_slice(&md4(data));
+                hash
+            }
+            SignatureHash::Blake2 => blake2(None, data),
+            SignatureHash::KeyedBlake2 => {
+                let key = key.expect("keyed hash requires a key");
+                blake2(Some(&key.0), data)
+            }
+            #[cfg(feature = "blake3")]
+            SignatureHash::Blake3 => *blake3::hash(data).as_bytes(),
+            #[cfg(feature = "xxhash")]
+            SignatureHash::Xxh3 => {
+                let mut hash = [0; BLAKE2_SIZE];
+                hash[..XXH3_SIZE].copy_from_slice(&xxh3_128(data).to_be_bytes());
+                hash
+            }
+            SignatureHash::Custom => panic!("custom hashes must be computed by the caller"),
+        }
+    }
+    /// Compute the rolling checksum of a single block. `seed` must be this type's
+    /// [seed_table](SignatureType::seed_table).
+    pub(crate) fn weak_sum(self, seed: Option<&SeedTable>, block: &[u8]) -> u32 {
+        match (self.rolling_hash(), seed) {
+            (RollingHash::Rollsum, None) => Crc::new().update(block).0,
+            (RollingHash::Rollsum, Some(seed)) => Crc::new().update_seeded(seed, block).0,
+            (RollingHash::RabinKarp, None) => RabinKarp::new().update(block).digest(),
+            (RollingHash::RabinKarp, Some(seed)) => {
+                RabinKarp::new().update_seeded(seed, block).digest()
+            }
         }
-        .to_be_bytes()
+    }
+    /// Look up the type for a magic number, telling apart signatures which might have been
+    /// written by another version of librsync or fast_rsync from data which isn't a signature.
+    pub(crate) fn from_magic(bytes: [u8; Self::SIZE]) -> Result<Self, SignatureParseError> {
+        let magic = u32::from_be_bytes(bytes);
+        Self::MAGICS
+            .iter()
+            .find(|&&(m, _, _)| m == magic)
+            .map(|&(_, rolling_hash, hash)| SignatureType::new(rolling_hash, hash))
+            .ok_or(match bytes {
+                // librsync's and fast_rsync's signature magics
+                [0x72, 0x73, 0x01, _] | [0x66, 0x72, 0x01, _] => {
+                    SignatureParseError::UnsupportedVersion
+                }
+                _ => SignatureParseError::Corrupt,
+            })
+    }
+    pub(crate) fn to_magic(self) -> [u8; Self::SIZE] {
+        let &(magic, _, _) = Self::MAGICS
+            .iter()
+            .find(|&&(_, rolling_hash, hash)| {
+                (rolling_hash, hash) == (self.rolling_hash, self.hash)
+            })
+            .expect("every signature type has a magic");
+        magic.to_be_bytes()
     }
 }
 
 /// Indicates that a signature was not valid.
-#[derive(Debug)]
-pub struct SignatureParseError(());
+///
+/// New variants may be added in any release: use [kind()](SignatureParseError::kind) to tell
+/// broad cases apart.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum SignatureParseError {
+    /// The data is truncated, or is not a signature at all.
+    Corrupt,
+    /// The signature uses a hash, format version, or extension which this build doesn't support.
+    /// It may have been written by a newer version of fast_rsync, or need a crate feature which
+    /// isn't enabled.
+    UnsupportedVersion,
+    /// The signature is deduplicated, and would expand to more than the limit given to
+    /// [deserialize_limited](Signature::deserialize_limited).
+    TooLarge,
+}
+
+impl SignatureParseError {
+    /// What kind of failure this is.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            SignatureParseError::Corrupt => ErrorKind::InvalidData,
+            SignatureParseError::UnsupportedVersion => ErrorKind::Unsupported,
+            SignatureParseError::TooLarge => ErrorKind::LimitExceeded,
+        }
+    }
+}
 
 impl fmt::Display for SignatureParseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str("invalid or unsupported signature")
+        match self {
+            SignatureParseError::Corrupt => f.write_str("invalid signature"),
+            SignatureParseError::UnsupportedVersion => {
+                f.write_str("unsupported signature format version")
+            }
+            SignatureParseError::TooLarge => {
+                f.write_str("signature would exceed the size limit once expanded")
+            }
+        }
     }
 }
 
 impl Error for SignatureParseError {}
 
+/// Indicates that two signatures could not be compared because they were calculated with
+/// different hash types, block sizes, or hash sizes.
+#[derive(Debug)]
+pub struct IncompatibleSignatures(());
+
+impl fmt::Display for IncompatibleSignatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("signatures were calculated with different options")
+    }
+}
+
+impl Error for IncompatibleSignatures {}
+
+/// Indicates that signatures could not be combined by [Signature::concat].
+///
+/// New variants may be added in any release: use [kind()](ConcatError::kind) to tell broad cases
+/// apart.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ConcatError {
+    /// There were no signatures to combine.
+    Empty,
+    /// The signatures were calculated with different hash types, block sizes, or hash sizes.
+    Incompatible,
+    /// The length given for a part doesn't fit it: every part but the last must cover a
+    /// multiple of the block size, and each must have the number of blocks its length implies.
+    Length {
+        /// The position of the part in the slice given to [Signature::concat].
+        part: usize,
+    },
+}
+
+impl ConcatError {
+    /// What kind of failure this is.
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::InvalidArgument
+    }
+}
+
+impl fmt::Display for ConcatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConcatError::Empty => f.write_str("no signatures to concatenate"),
+            ConcatError::Incompatible => {
+                f.write_str("signatures were calculated with different options")
+            }
+            ConcatError::Length { part } => write!(
+                f,
+                "the length of part {} doesn't match its signature or the block size",
+                part
+            ),
+        }
+    }
+}
+
+impl Error for ConcatError {}
+
+/// Indicates that [SignatureOptions] were not valid.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum InvalidOptions {
+    /// `block_size` was zero.
+    ZeroBlockSize,
+    /// `crypto_hash_size` was zero. Only reported by [Signature::validate], since librsync won't
+    /// read such signatures.
+    ZeroCryptoHashSize,
+    /// `crypto_hash_size` was larger than the hash used by the signature.
+    CryptoHashSizeTooLarge {
+        /// The requested hash size.
+        crypto_hash_size: u32,
+        /// The largest supported hash size.
+        max: u32,
+    },
+    /// `hash` was [SignatureHash::Custom], which can only be calculated with a caller-supplied
+    /// hash function.
+    CustomHash,
+    /// `hash_key` was missing for a keyed hash, or set for a hash which doesn't take a key.
+    HashKeyMismatch,
+}
+
+impl fmt::Display for InvalidOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidOptions::ZeroBlockSize => f.write_str("block size must be greater than zero"),
+            InvalidOptions::ZeroCryptoHashSize => {
+                f.write_str("crypto hash size must be greater than zero")
+            }
+            InvalidOptions::CryptoHashSizeTooLarge {
+                crypto_hash_size,
+                max,
+            } => write!(
+                f,
+                "crypto hash size is too large (crypto_hash_size={}, max={})",
+                crypto_hash_size, max
+            ),
+            InvalidOptions::CustomHash => {
+                f.write_str("custom hashes require a caller-supplied hash function")
+            }
+            InvalidOptions::HashKeyMismatch => {
+                f.write_str("a hash key must be given exactly when using a keyed hash")
+            }
+        }
+    }
+}
+
+impl Error for InvalidOptions {}
+
+/// The strong hash used to identify blocks within a signature.
+///
+/// Some hashes are only available with a crate feature, and more may be added in any release,
+/// so matches on this need a wildcard arm.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum SignatureHash {
+    /// MD4, the legacy librsync hash. This is the fastest option, but MD4 is not collision resistant.
+    Md4,
+    /// BLAKE2b with a 32-byte digest, the default hash of librsync 1.0 and later.
+    Blake2,
+    /// BLAKE2b keyed with [SignatureOptions::hash_key], so that an adversary who doesn't know the
+    /// key can't predict the hashes, or craft data which collides with a block of the signature.
+    ///
+    /// These signatures can't be read by librsync.
+    KeyedBlake2,
+    /// BLAKE3, which is cryptographically strong and faster than BLAKE2, with a 32-byte digest.
+    ///
+    /// These signatures can't be read by librsync.
+    #[cfg(feature = "blake3")]
+    Blake3,
+    /// The 128-bit XXH3 hash, which is much faster than MD4 on large blocks. It is not a
+    /// cryptographic hash, so it is only suitable when the data can't be chosen by an adversary.
+    ///
+    /// These signatures can't be read by librsync.
+    #[cfg(feature = "xxhash")]
+    Xxh3,
+    /// A hash chosen by the caller, through `Signature::calculate_with_digest` and
+    /// `diff_with_digest` (with the `digest` feature). The signature only records that a custom
+    /// hash was used, so both ends must agree on which one.
+    ///
+    /// These signatures can't be read by librsync.
+    Custom,
+}
+
+/// The rolling checksum used to find candidate blocks.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RollingHash {
+    /// The original rsync rolling checksum, used by all librsync versions.
+    Rollsum,
+    /// The RabinKarp rolling hash introduced in librsync 2.2, which has far fewer collisions.
+    /// Signatures using it can only be read by librsync 2.2 and later.
+    RabinKarp,
+}
+
 /// Options for [Signature::calculate].
-#[derive(Copy, Clone, Debug)]
+///
+/// The [Default] options produce a legacy (rollsum and MD4) signature with 2 KiB blocks
+/// (librsync's default block size) and 8-byte hashes.
+///
+/// Fields may be added in future major versions, as `hash`, `rolling_hash`, `hash_key` and
+/// `rolling_seed` were in 0.3.0. Set only the ones you need and fill in the rest with
+/// `..Default::default()`, or use [SignatureOptions::builder()], which also checks them.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct SignatureOptions {
     /// The granularity of the signature.
     /// Smaller block sizes yield larger, but more precise, signatures.
     pub block_size: u32,
-    /// The number of bytes to use from the MD4 hash. Must be at most 16.
-    /// The larger this is, the less likely that a delta will be mis-applied.
+    /// The number of bytes to use from the strong hash. Must be at most 16 for MD4 and XXH3, or 32
+    /// for BLAKE2 and BLAKE3. The larger this is, the less likely that a delta will be mis-applied.
     pub crypto_hash_size: u32,
+    /// The strong hash to use.
+    pub hash: SignatureHash,
+    /// The rolling checksum to use.
+    pub rolling_hash: RollingHash,
+    /// The secret key for [SignatureHash::KeyedBlake2]. Must be set exactly when that hash is used.
+    ///
+    /// The key is not part of the serialized signature; whoever calculates deltas against it must
+    /// be given the same key through [DiffOptions::hash_key](crate::DiffOptions::hash_key).
+    pub hash_key: Option<HashKey>,
+    /// If set, this seed is mixed into the rolling checksum, like rsync's `checksum_seed`. Without
+    /// a seed, anyone can precompute data whose blocks all have the same rolling checksum, and
+    /// make [diff()](crate::diff()) compute a strong hash at almost every byte. A fresh random
+    /// seed for each signature prevents this.
+    ///
+    /// The seed is not secret, and is stored in the signature. Seeded signatures can't be read
+    /// by librsync.
+    pub rolling_seed: Option<u32>,
+}
+
+/// Builds [SignatureOptions], checking that they are valid.
+///
+/// ```
+/// use fast_rsync::{SignatureHash, SignatureOptions};
+///
+/// let options = SignatureOptions::builder()
+///     .block_size(4096)
+///     .crypto_hash_size(16)
+///     .hash(SignatureHash::Blake2)
+///     .build()
+///     .unwrap();
+/// assert_eq!(options.block_size, 4096);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SignatureOptionsBuilder {
+    options: SignatureOptions,
+}
+
+impl SignatureOptions {
+    /// Start building options from the [Default] ones.
+    pub fn builder() -> SignatureOptionsBuilder {
+        SignatureOptionsBuilder::default()
+    }
+}
+
+impl SignatureOptionsBuilder {
+    /// Set [SignatureOptions::block_size].
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.options.block_size = block_size;
+        self
+    }
+
+    /// Set [SignatureOptions::crypto_hash_size].
+    pub fn crypto_hash_size(mut self, crypto_hash_size: u32) -> Self {
+        self.options.crypto_hash_size = crypto_hash_size;
+        self
+    }
+
+    /// Set [SignatureOptions::hash].
+    pub fn hash(mut self, hash: SignatureHash) -> Self {
+        self.options.hash = hash;
+        self
+    }
+
+    /// Set [SignatureOptions::rolling_hash].
+    pub fn rolling_hash(mut self, rolling_hash: RollingHash) -> Self {
+        self.options.rolling_hash = rolling_hash;
+        self
+    }
+
+    /// Set [SignatureOptions::hash_key], which is required by [SignatureHash::KeyedBlake2].
+    pub fn hash_key(mut self, hash_key: HashKey) -> Self {
+        self.options.hash_key = Some(hash_key);
+        self
+    }
+
+    /// Set [SignatureOptions::rolling_seed].
+    pub fn rolling_seed(mut self, rolling_seed: u32) -> Self {
+        self.options.rolling_seed = Some(rolling_seed);
+        self
+    }
+
+    /// Check the options, returning the same errors as [Signature::try_calculate] would.
+    ///
+    /// Options for [SignatureHash::Custom] are always rejected, since they can only be used
+    /// through `Signature::calculate_with_digest`, which checks them itself.
+    pub fn build(self) -> Result<SignatureOptions, InvalidOptions> {
+        Signature::check_options(&self.options)?;
+        Ok(self.options)
+    }
+}
+
+/// A secret key for keyed strong hashes. Its [Debug] output doesn't reveal the key.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct HashKey(pub(crate) [u8; 32]);
+
+impl HashKey {
+    /// Create a key from 32 secret bytes.
+    pub fn new(key: [u8; 32]) -> HashKey {
+        HashKey(key)
+    }
+}
+
+impl fmt::Debug for HashKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HashKey(..)")
+    }
+}
+
+impl Default for SignatureOptions {
+    fn default() -> Self {
+        SignatureOptions {
+            block_size: 2048,
+            crypto_hash_size: 8,
+            hash: SignatureHash::Md4,
+            rolling_hash: RollingHash::Rollsum,
+            hash_key: None,
+            rolling_seed: None,
+        }
+    }
+}
+
+impl SignatureBuilder {
+    /// Start calculating a signature with the given options.
+    ///
+    /// Panics if the provided options are invalid; see [Signature::calculate].
+    pub fn new(options: SignatureOptions) -> SignatureBuilder {
+        match Self::try_new(options) {
+            Ok(builder) => builder,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Start calculating a signature with the given options, returning an error if they are
+    /// invalid.
+    pub fn try_new(options: SignatureOptions) -> Result<SignatureBuilder, InvalidOptions> {
+        let signature_type = Signature::check_options(&options)?;
+        let mut signature = Vec::new();
+        Signature::write_header(signature_type, &options, &mut signature);
+        Ok(SignatureBuilder {
+            signature_type,
+            options,
+            signature,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Add more data to the signature.
+    pub fn update(&mut self, mut buf: &[u8]) {
+        let block_size = self.options.block_size as usize;
+        if !self.pending.is_empty() {
+            let needed = block_size - self.pending.len();
+            if buf.len() < needed {
+                self.pending.extend_from_slice(buf);
+                return;
+            }
+            let (head, tail) = buf.split_at(needed);
+            self.pending.extend_from_slice(head);
+            Signature::hash_blocks(
+                self.signature_type,
+                &self.options,
+                &self.pending,
+                &mut self.signature,
+            );
+            self.pending.clear();
+            buf = tail;
+        }
+        // Hash whole blocks straight from `buf`, keeping only the partial block at the end.
+        let whole_len = buf.len() - buf.len() % block_size;
+        let (whole, rest) = buf.split_at(whole_len);
+        Signature::hash_blocks(
+            self.signature_type,
+            &self.options,
+            whole,
+            &mut self.signature,
+        );
+        self.pending.extend_from_slice(rest);
+    }
+
+    /// Finish calculating the signature.
+    pub fn finish(mut self) -> Signature {
+        Signature::hash_blocks(
+            self.signature_type,
+            &self.options,
+            &self.pending,
+            &mut self.signature,
+        );
+        Signature {
+            signature_type: self.signature_type,
+            block_size: self.options.block_size,
+            crypto_hash_size: self.options.crypto_hash_size,
+            signature: self.signature,
+        }
+    }
 }
 
 impl Signature {
     const HEADER_SIZE: usize = SignatureType::SIZE + 2 * 4; // magic, block_size, then crypto_hash_size
+    /// Extension fields are a tag, the length of the value, then the value.
+    const EXTENSION_PREFIX_SIZE: usize = 2 * 4;
+    const ROLLING_SEED_TAG: u32 = 1;
+    const DEDUPLICATED_TAG: u32 = 2;
+    /// The version of the extended header written by this version of fast_rsync. Readers reject
+    /// later versions, as well as extension fields they don't know.
+    const EXTENDED_VERSION: u32 = 1;
+    const WEAK_SUM_SIZE: usize = 4;
+    /// The largest hash size of a deduplicated signature with a custom hash, whose size isn't
+    /// recorded: that of SHA-512 and BLAKE2b-512. Together with the limits of the built-in hashes,
+    /// this keeps a deduplicated signature from expanding to more than 17 times its size.
+    const MAX_DEDUPLICATED_CUSTOM_HASH_SIZE: usize = 64;
 
-    /// Compute an MD4 signature for the given data.
+    /// Compute a signature for the given data.
     ///
-    /// `options.block_size` must be greater than zero. `options.crypto_hash_size` must be at most 16, the length of an MD4 hash.
+    /// `options.block_size` must be greater than zero. `options.crypto_hash_size` must be at most
+    /// the length of the hash selected by `options.hash` (16 for MD4, 32 for BLAKE2 and BLAKE3).
     /// Panics if the provided options are invalid.
     pub fn calculate(buf: &[u8], options: SignatureOptions) -> Signature {
-        assert!(options.block_size > 0);
-        assert!(options.crypto_hash_size <= MD4_SIZE as u32);
+        match Self::try_calculate(buf, options) {
+            Ok(signature) => signature,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Compute a signature for the given data, returning an error instead of panicking if the
+    /// provided options are invalid.
+    pub fn try_calculate(
+        buf: &[u8],
+        options: SignatureOptions,
+    ) -> Result<Signature, InvalidOptions> {
+        let signature_type = Self::check_options(&options)?;
         let num_blocks = buf.chunks(options.block_size as usize).len();
 
-        let signature_type = SignatureType::Md4;
+        let mut signature = Vec::with_capacity(
+            Self::header_size(signature_type, None)
+                + num_blocks * (Self::WEAK_SUM_SIZE + options.crypto_hash_size as usize),
+        );
+        Self::write_header(signature_type, &options, &mut signature);
+        Self::hash_blocks(signature_type, &options, buf, &mut signature);
+        Ok(Signature {
+            signature_type,
+            block_size: options.block_size,
+            crypto_hash_size: options.crypto_hash_size,
+            signature,
+        })
+    }
+
+    /// Compute a signature for the given data using the strong hash `D` instead of one of the
+    /// built-in hashes. Deltas against it must be calculated with
+    /// [diff_with_digest()](crate::diff_with_digest()) using the same hash.
+    ///
+    /// `options.hash` is ignored, and recorded as [SignatureHash::Custom]. `options.block_size`
+    /// must be greater than zero, and `options.crypto_hash_size` must be at most the output size
+    /// of `D`. Panics if the provided options are invalid.
+    #[cfg(feature = "digest")]
+    pub fn calculate_with_digest<D: digest::Digest>(
+        buf: &[u8],
+        options: SignatureOptions,
+    ) -> Signature {
+        let options = SignatureOptions {
+            hash: SignatureHash::Custom,
+            ..options
+        };
+        if options.block_size == 0 {
+            panic!("{}", InvalidOptions::ZeroBlockSize);
+        }
+        let max = <D as digest::Digest>::output_size() as u32;
+        if options.crypto_hash_size > max {
+            panic!(
+                "{}",
+                InvalidOptions::CryptoHashSizeTooLarge {
+                    crypto_hash_size: options.crypto_hash_size,
+                    max,
+                }
+            );
+        }
+        let signature_type = SignatureType::new(options.rolling_hash, options.hash)
+            .with_rolling_seed(options.rolling_seed);
+        let seed = signature_type.seed_table();
+        let crypto_hash_size = options.crypto_hash_size as usize;
+        let mut signature = Vec::new();
+        Self::write_header(signature_type, &options, &mut signature);
+        for block in buf.chunks(options.block_size as usize) {
+            let weak_sum = signature_type.weak_sum(seed.as_ref(), block);
+            Self::push_block(
+                weak_sum,
+                &D::digest(block)[..crypto_hash_size],
+                &mut signature,
+            );
+        }
+        Signature {
+            signature_type,
+            block_size: options.block_size,
+            crypto_hash_size: options.crypto_hash_size,
+            signature,
+        }
+    }
+
+    /// Compute a signature for the given data, hashing it on the rayon thread pool.
+    ///
+    /// This produces the same signature as [Signature::calculate], but is faster for large inputs
+    /// on machines with several cores.
+    /// Panics if the provided options are invalid; see [Signature::calculate].
+    #[cfg(feature = "rayon")]
+    pub fn calculate_parallel(buf: &[u8], options: SignatureOptions) -> Signature {
+        use rayon::prelude::*;
+
+        const PARALLEL_CHUNK_SIZE: usize = 1 << 20;
+        let signature_type = match Self::check_options(&options) {
+            Ok(signature_type) => signature_type,
+            Err(e) => panic!("{}", e),
+        };
+        // Chunks must hold whole blocks, so that only the last one can end in a partial block.
+        let block_size = options.block_size as usize;
+        let chunk_size = (PARALLEL_CHUNK_SIZE / block_size).max(1) * block_size;
+        let parts: Vec<Vec<u8>> = buf
+            .par_chunks(chunk_size)
+            .map(|chunk| {
+                let mut part = Vec::new();
+                Self::hash_blocks(signature_type, &options, chunk, &mut part);
+                part
+            })
+            .collect();
 
         let mut signature = Vec::with_capacity(
-            Self::HEADER_SIZE + num_blocks * (Crc::SIZE + options.crypto_hash_size as usize),
+            Self::header_size(signature_type, None) + parts.iter().map(Vec::len).sum::<usize>(),
         );
+        Self::write_header(signature_type, &options, &mut signature);
+        for part in parts {
+            signature.extend_from_slice(&part);
+        }
+        Signature {
+            signature_type,
+            block_size: options.block_size,
+            crypto_hash_size: options.crypto_hash_size,
+            signature,
+        }
+    }
+
+    /// Compute a signature for everything read from `reader`.
+    ///
+    /// The data is read and hashed at most 64 KiB at a time, so that large inputs
+    /// don't monopolize the executor thread between reads.
+    /// Fails with an [io::ErrorKind::InvalidInput] error, before reading anything, if the
+    /// provided options are invalid; see [Signature::try_calculate].
+    #[cfg(feature = "tokio")]
+    pub async fn calculate_from_async_reader<R>(
+        mut reader: R,
+        options: SignatureOptions,
+    ) -> io::Result<Signature>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        use tokio::io::AsyncReadExt;
+
+        const ASYNC_CHUNK_SIZE: usize = 64 * 1024;
+        let mut builder = SignatureBuilder::try_new(options)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut buf = vec![0; ASYNC_CHUNK_SIZE];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(builder.finish());
+            }
+            builder.update(&buf[..n]);
+        }
+    }
 
+    pub(crate) fn check_options(
+        options: &SignatureOptions,
+    ) -> Result<SignatureType, InvalidOptions> {
+        if options.block_size == 0 {
+            return Err(InvalidOptions::ZeroBlockSize);
+        }
+        let signature_type = SignatureType::new(options.rolling_hash, options.hash)
+            .with_rolling_seed(options.rolling_seed);
+        if options.hash_key.is_some() != (options.hash == SignatureHash::KeyedBlake2) {
+            return Err(InvalidOptions::HashKeyMismatch);
+        }
+        let max_crypto_hash_size = match signature_type.max_crypto_hash_size() {
+            Some(max) => max as u32,
+            None => return Err(InvalidOptions::CustomHash),
+        };
+        if options.crypto_hash_size > max_crypto_hash_size {
+            return Err(InvalidOptions::CryptoHashSizeTooLarge {
+                crypto_hash_size: options.crypto_hash_size,
+                max: max_crypto_hash_size,
+            });
+        }
+        Ok(signature_type)
+    }
+
+    /// The size of the header of signatures of this type, which only depends on which extension
+    /// fields it needs. `unique_blocks` is set for deduplicated signatures.
+    fn header_size(signature_type: SignatureType, unique_blocks: Option<u32>) -> usize {
+        let extensions = Self::extensions_size(signature_type, unique_blocks);
+        if extensions == 0 {
+            Self::HEADER_SIZE
+        } else {
+            4 + 4 + Self::HEADER_SIZE + 4 + extensions
+        }
+    }
+
+    fn extensions_size(signature_type: SignatureType, unique_blocks: Option<u32>) -> usize {
+        let field_size = |present: bool| {
+            if present {
+                Self::EXTENSION_PREFIX_SIZE + 4
+            } else {
+                0
+            }
+        };
+        field_size(signature_type.rolling_seed().is_some()) + field_size(unique_blocks.is_some())
+    }
+
+    fn write_header(
+        signature_type: SignatureType,
+        options: &SignatureOptions,
+        signature: &mut Vec<u8>,
+    ) {
+        Self::write_extended_header(signature_type, options, None, signature)
+    }
+
+    /// Signatures which need extension fields are prefixed with `EXTENDED_MAGIC` and the version
+    /// of the extended header, followed by the usual librsync header, the total length of the
+    /// extension fields, and the fields.
+    fn write_extended_header(
+        signature_type: SignatureType,
+        options: &SignatureOptions,
+        unique_blocks: Option<u32>,
+        signature: &mut Vec<u8>,
+    ) {
+        let extensions = Self::extensions_size(signature_type, unique_blocks);
+        if extensions != 0 {
+            signature.extend_from_slice(&EXTENDED_MAGIC.to_be_bytes());
+            signature.extend_from_slice(&Self::EXTENDED_VERSION.to_be_bytes());
+        }
         signature.extend_from_slice(&signature_type.to_magic());
         signature.extend_from_slice(&options.block_size.to_be_bytes());
         signature.extend_from_slice(&options.crypto_hash_size.to_be_bytes());
+        if extensions != 0 {
+            signature.extend_from_slice(&(extensions as u32).to_be_bytes());
+        }
+        let fields = [
+            (Self::ROLLING_SEED_TAG, signature_type.rolling_seed()),
+            (Self::DEDUPLICATED_TAG, unique_blocks),
+        ];
+        for &(tag, value) in &fields {
+            if let Some(value) = value {
+                signature.extend_from_slice(&tag.to_be_bytes());
+                signature.extend_from_slice(&4u32.to_be_bytes());
+                signature.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+    }
+
+    /// Hash all the blocks of `buf` (with the rolling checksum as well as the strong hash) and
+    /// append them to `signature`. Only the last block may be shorter than `block_size`.
+    fn hash_blocks(
+        signature_type: SignatureType,
+        options: &SignatureOptions,
+        buf: &[u8],
+        signature: &mut Vec<u8>,
+    ) {
+        let crypto_hash_size = options.crypto_hash_size as usize;
+        let seed = signature_type.seed_table();
+        match signature_type.hash() {
+            SignatureHash::Md4 => {
+                match (signature_type.rolling_hash(), options.block_size) {
+                    // Common block sizes get a monomorphized fast path
+                    (RollingHash::Rollsum, 2048) if seed.is_none() => {
+                        Self::hash_md4_fixed::<2048>(buf, crypto_hash_size, signature)
+                    }
+                    (RollingHash::Rollsum, 4096) if seed.is_none() => {
+                        Self::hash_md4_fixed::<4096>(buf, crypto_hash_size, signature)
+                    }
+                    (RollingHash::Rollsum, 8192) if seed.is_none() => {
+                        Self::hash_md4_fixed::<8192>(buf, crypto_hash_size, signature)
+                    }
+                    (_, block_size) => {
+                        let chunks = buf.chunks_exact(block_size as usize);
+                        let remainder = chunks.remainder();
+                        for (block, md4_hash) in
+                            md4_many(chunks).chain(Self::md4_remainder(remainder))
+                        {
+                            let weak_sum = signature_type.weak_sum(seed.as_ref(), block);
+                            Self::push_block(weak_sum, &md4_hash[..crypto_hash_size], signature);
+                        }
+                    }
+                }
+            }
+            SignatureHash::Blake2 | SignatureHash::KeyedBlake2 => {
+                let key = options.hash_key.as_ref().map(|key| &key.0[..]);
+                for (block, blake2_hash) in blake2_many(key, buf, options.block_size as usize) {
+                    let weak_sum = signature_type.weak_sum(seed.as_ref(), block);
+                    Self::push_block(weak_sum, &blake2_hash[..crypto_hash_size], signature);
+                }
+            }
+            #[cfg(feature = "blake3")]
+            SignatureHash::Blake3 => {
+                for block in buf.chunks(options.block_size as usize) {
+                    let weak_sum = signature_type.weak_sum(seed.as_ref(), block);
+                    let blake3_hash = blake3::hash(block);
+                    Self::push_block(
+                        weak_sum,
+                        &blake3_hash.as_bytes()[..crypto_hash_size],
+                        signature,
+                    );
+                }
+            }
+            #[cfg(feature = "xxhash")]
+            SignatureHash::Xxh3 => {
+                for block in buf.chunks(options.block_size as usize) {
+                    let weak_sum = signature_type.weak_sum(seed.as_ref(), block);
+                    let xxh3_hash = xxh3_128(block).to_be_bytes();
+                    Self::push_block(weak_sum, &xxh3_hash[..crypto_hash_size], signature);
+                }
+            }
+            SignatureHash::Custom => unreachable!("rejected by check_options"),
+        }
+    }
 
-        // Hash all the blocks (with the CRC as well as MD4)
-        let chunks = buf.chunks_exact(options.block_size as usize);
+    fn hash_md4_fixed<const N: usize>(
+        buf: &[u8],
+        crypto_hash_size: usize,
+        signature: &mut Vec<u8>,
+    ) {
+        let chunks = buf.chunks_exact(N);
         let remainder = chunks.remainder();
-        for (block, md4_hash) in md4_many(chunks).chain(if remainder.is_empty() {
+        for (block, md4_hash) in md4_many(chunks) {
+            // `chunks_exact` guarantees the length, so this conversion can't fail
+            let crc = Crc::new().update_fixed::<N>(block.try_into().unwrap());
+            Self::push_block(crc.0, &md4_hash[..crypto_hash_size], signature);
+        }
+        if let Some((block, md4_hash)) = Self::md4_remainder(remainder) {
+            let crc = Crc::new().update(block);
+            Self::push_block(crc.0, &md4_hash[..crypto_hash_size], signature);
+        }
+    }
+
+    // Manually tack on the last block if necessary, since `md4_many` requires every block to be
+    // identical in size
+    fn md4_remainder(remainder: &[u8]) -> Option<(&[u8], [u8; MD4_SIZE])> {
+        if remainder.is_empty() {
             None
         } else {
-            // Manually tack on the last block if necessary, since `md4_many`
-            // requires every block to be identical in size
             Some((remainder, md4(remainder)))
-        }) {
-            // would be nice to use `chunks_exact_mut`, but it doesn't work for zero sizes
-            let crc = Crc::new().update(block);
-            let crypto_hash = &md4_hash[..options.crypto_hash_size as usize];
-            signature.extend_from_slice(&crc.to_bytes());
-            signature.extend_from_slice(crypto_hash);
-        }
-        Signature {
-            signature_type: SignatureType::Md4,
-            block_size: options.block_size,
-            crypto_hash_size: options.crypto_hash_size,
-            signature,
         }
     }
 
+    #[inline]
+    fn push_block(weak_sum: u32, crypto_hash: &[u8], signature: &mut Vec<u8>) {
+        // would be nice to use `chunks_exact_mut`, but it doesn't work for zero sizes
+        signature.extend_from_slice(&weak_sum.to_be_bytes());
+        signature.extend_from_slice(crypto_hash);
+    }
+
     /// Read a binary signature.
+    ///
+    /// [Deduplicated](Signature::serialize_deduplicated) signatures are expanded, to at most 17
+    /// times their size. Use [deserialize_limited](Signature::deserialize_limited) to bound that
+    /// further.
     pub fn deserialize(signature: Vec<u8>) -> Result<Signature, SignatureParseError> {
-        if signature.len() < Self::HEADER_SIZE {
-            return Err(SignatureParseError(()));
-        }
-        let signature_type = SignatureType::from_magic(*array_ref![signature, 0, 4])
-            .ok_or(SignatureParseError(()))?;
-        let block_size = u32::from_be_bytes(*array_ref![signature, 4, 4]);
-        let crypto_hash_size = u32::from_be_bytes(*array_ref![signature, 8, 4]);
-        let block_signature_size = Crc::SIZE + crypto_hash_size as usize;
-        if (signature.len() - Self::HEADER_SIZE) % block_signature_size != 0 {
-            return Err(SignatureParseError(()));
+        Self::deserialize_limited(signature, usize::MAX)
+    }
+
+    /// Like [deserialize](Signature::deserialize), but fail with [SignatureParseError::TooLarge]
+    /// rather than expand a deduplicated signature to more than `limit` bytes. Other signatures
+    /// are kept as they are, whatever their size, since they are already in memory.
+    ///
+    /// A `limit` of `signature.len()` rejects every deduplicated signature which would be any
+    /// larger expanded, e.g. for a format which never stores them deduplicated.
+    pub fn deserialize_limited(
+        signature: Vec<u8>,
+        limit: usize,
+    ) -> Result<Signature, SignatureParseError> {
+        let signature_ref = Self::deserialize_ref(&signature)?;
+        if signature_ref.unique_blocks.is_some() {
+            if signature_ref.expanded_len() > limit {
+                return Err(SignatureParseError::TooLarge);
+            }
+            return Ok(signature_ref.to_signature());
         }
+        let (signature_type, block_size, crypto_hash_size) = (
+            signature_ref.signature_type,
+            signature_ref.block_size,
+            signature_ref.crypto_hash_size,
+        );
         Ok(Signature {
             signature_type,
             block_size,
@@ -150,49 +1293,607 @@ impl Signature {
         })
     }
 
+    /// Read a binary signature without copying it.
+    ///
+    /// This checks that `signature` is well-formed. That takes constant time, except for
+    /// deduplicated signatures, whose list of blocks is checked as well. Their hash size must
+    /// also be within that of their hash, or 64 bytes for a custom hash, since each block they
+    /// list expands to a copy of its hash.
+    pub fn deserialize_ref(signature: &[u8]) -> Result<SignatureRef<'_>, SignatureParseError> {
+        let extended = signature.len() >= 4
+            && u32::from_be_bytes(*array_ref![signature, 0, 4]) == EXTENDED_MAGIC;
+        let header = if extended {
+            if signature.len() < 8 {
+                return Err(SignatureParseError::Corrupt);
+            }
+            if u32::from_be_bytes(*array_ref![signature, 4, 4]) > Self::EXTENDED_VERSION {
+                return Err(SignatureParseError::UnsupportedVersion);
+            }
+            &signature[8..]
+        } else {
+            signature
+        };
+        if header.len() < Self::HEADER_SIZE {
+            return Err(SignatureParseError::Corrupt);
+        }
+        let mut signature_type = SignatureType::from_magic(*array_ref![header, 0, 4])?;
+        let block_size = u32::from_be_bytes(*array_ref![header, 4, 4]);
+        let crypto_hash_size = u32::from_be_bytes(*array_ref![header, 8, 4]);
+        let mut unique_blocks = None;
+        if extended {
+            (signature_type, unique_blocks) =
+                Self::parse_extensions(signature_type, &header[Self::HEADER_SIZE..])?;
+        }
+        let header_size = Self::header_size(signature_type, unique_blocks);
+        let block_signature_size = Self::WEAK_SUM_SIZE + crypto_hash_size as usize;
+        let table = signature
+            .get(header_size..)
+            .ok_or(SignatureParseError::Corrupt)?;
+        match unique_blocks {
+            None => {
+                if table.len() % block_signature_size != 0 {
+                    return Err(SignatureParseError::Corrupt);
+                }
+            }
+            Some(unique_blocks) => {
+                if crypto_hash_size as usize > Self::max_deduplicated_hash_size(signature_type) {
+                    return Err(SignatureParseError::Corrupt);
+                }
+                let order = (unique_blocks as usize)
+                    .checked_mul(block_signature_size)
+                    .and_then(|entries_size| table.get(entries_size..))
+                    .ok_or(SignatureParseError::Corrupt)?;
+                if order.len() % 4 != 0
+                    || order
+                        .chunks_exact(4)
+                        .any(|idx| u32::from_be_bytes(*array_ref![idx, 0, 4]) >= unique_blocks)
+                {
+                    return Err(SignatureParseError::Corrupt);
+                }
+            }
+        }
+        Ok(SignatureRef {
+            signature_type,
+            block_size,
+            crypto_hash_size,
+            unique_blocks,
+            signature,
+        })
+    }
+
+    /// The largest hash size which a deduplicated signature of this type may have.
+    fn max_deduplicated_hash_size(signature_type: SignatureType) -> usize {
+        signature_type
+            .max_crypto_hash_size()
+            .unwrap_or(Self::MAX_DEDUPLICATED_CUSTOM_HASH_SIZE)
+    }
+
+    /// Apply the extension fields at the start of `extensions` to `signature_type`, and return
+    /// it along with the number of unique blocks if the signature is deduplicated. The fields
+    /// must be exactly the ones `write_extended_header` would have written.
+    fn parse_extensions(
+        mut signature_type: SignatureType,
+        extensions: &[u8],
+    ) -> Result<(SignatureType, Option<u32>), SignatureParseError> {
+        if extensions.len() < 4 {
+            return Err(SignatureParseError::Corrupt);
+        }
+        let len = u32::from_be_bytes(*array_ref![extensions, 0, 4]) as usize;
+        let mut fields = extensions[4..]
+            .get(..len)
+            .ok_or(SignatureParseError::Corrupt)?;
+        let mut unique_blocks = None;
+        while !fields.is_empty() {
+            if fields.len() < Self::EXTENSION_PREFIX_SIZE {
+                return Err(SignatureParseError::Corrupt);
+            }
+            let tag = u32::from_be_bytes(*array_ref![fields, 0, 4]);
+            let value_len = u32::from_be_bytes(*array_ref![fields, 4, 4]) as usize;
+            let value = fields[Self::EXTENSION_PREFIX_SIZE..]
+                .get(..value_len)
+                .ok_or(SignatureParseError::Corrupt)?;
+            let field = match tag {
+                Self::ROLLING_SEED_TAG if signature_type.rolling_seed().is_none() => {
+                    &mut signature_type.rolling_seed
+                }
+                Self::DEDUPLICATED_TAG if unique_blocks.is_none() => &mut unique_blocks,
+                Self::ROLLING_SEED_TAG | Self::DEDUPLICATED_TAG => {
+                    return Err(SignatureParseError::Corrupt)
+                }
+                _ => return Err(SignatureParseError::UnsupportedVersion),
+            };
+            if value.len() != 4 {
+                return Err(SignatureParseError::Corrupt);
+            }
+            *field = Some(u32::from_be_bytes(*array_ref![value, 0, 4]));
+            fields = &fields[Self::EXTENSION_PREFIX_SIZE + value_len..];
+        }
+        if Self::extensions_size(signature_type, unique_blocks) != len {
+            return Err(SignatureParseError::Corrupt);
+        }
+        Ok((signature_type, unique_blocks))
+    }
+
+    pub(crate) fn options(&self) -> SignatureOptions {
+        self.signature_type
+            .options(self.block_size, self.crypto_hash_size)
+    }
+
+    /// The offset in the signed data where the last block of the signature starts, or zero if the
+    /// signature has no blocks. This is where the input to [append](Signature::append) must start.
+    pub fn append_offset(&self) -> u64 {
+        self.blocks().len().saturating_sub(1) as u64 * self.block_size as u64
+    }
+
+    /// Extend the signature to cover data appended to the original data.
+    ///
+    /// `tail` must hold the data from [append_offset](Signature::append_offset) to the new end,
+    /// so that the last block (which may have been partial) is hashed again along with the new
+    /// blocks. The result is the same as recalculating the signature over all the data.
+    ///
+    /// A signature with [SignatureHash::KeyedBlake2] needs the key it was calculated with as
+    /// `hash_key`, since the signature doesn't record it; others take `None`. Fails, leaving the
+    /// signature as it was, if the key doesn't fit the hash, if the hash is
+    /// [SignatureHash::Custom], which can't be calculated here, or if the signature has invalid
+    /// options, which can only happen if it was deserialized.
+    pub fn append(&mut self, tail: &[u8], hash_key: Option<HashKey>) -> Result<(), InvalidOptions> {
+        let options = SignatureOptions {
+            hash_key,
+            ..self.options()
+        };
+        Self::check_options(&options)?;
+        if self.signature.len() > Self::header_size(self.signature_type, None) {
+            let last_block = Self::WEAK_SUM_SIZE + self.crypto_hash_size as usize;
+            self.signature.truncate(self.signature.len() - last_block);
+        }
+        Self::hash_blocks(self.signature_type, &options, tail, &mut self.signature);
+        Ok(())
+    }
+
     /// Get the serialized form of this signature.
     pub fn serialized(&self) -> &[u8] {
         &self.signature
     }
 
+    /// Check that the header of a deserialized signature is usable: the block size and hash size
+    /// must be non-zero, and the hash size at most the size of its hash. The length of the block
+    /// table was already checked by [deserialize](Signature::deserialize).
+    ///
+    /// [index](Signature::index) and [diff()](crate::diff()) don't check this themselves, and
+    /// may fail late (or, for a zero hash size, match every block with the right rolling
+    /// checksum) on a signature which doesn't pass.
+    pub fn validate(&self) -> Result<(), InvalidOptions> {
+        SignatureRef::from(self).validate()
+    }
+
     /// Get ownership of the serialized form of this signature.
     pub fn into_serialized(self) -> Vec<u8> {
         self.signature
     }
 
-    fn blocks(&self) -> impl ExactSizeIterator<Item = (Crc, &[u8])> {
-        self.signature[Self::HEADER_SIZE..]
-            .chunks(Crc::SIZE + self.crypto_hash_size as usize)
-            .map(|b| {
-                (
-                    Crc::from_bytes(*array_ref!(b, 0, Crc::SIZE)),
-                    &b[Crc::SIZE..],
-                )
-            })
+    /// Serialize this signature with every distinct block stored only once, followed by a list
+    /// of which of them each block is. This is much smaller for data with many identical blocks,
+    /// like the zeroes in a disk image, but costs 4 more bytes for every block otherwise.
+    ///
+    /// The result can be read by [deserialize](Signature::deserialize) (which expands it again)
+    /// and [deserialize_ref](Signature::deserialize_ref), but not by librsync. Signatures with
+    /// more than 2^32 distinct blocks, or with a custom hash longer than 64 bytes, are not
+    /// deduplicated.
+    pub fn serialize_deduplicated(&self) -> Vec<u8> {
+        if self.crypto_hash_size as usize > Self::max_deduplicated_hash_size(self.signature_type) {
+            return self.signature.clone();
+        }
+        let blocks = self.blocks();
+        let mut entries: HashMap<(u32, &[u8]), u32> = HashMap::new();
+        let mut unique = Vec::new();
+        let mut order = Vec::with_capacity(blocks.len() * 4);
+        for block in blocks {
+            let next = unique.len();
+            let entry = *entries.entry(block).or_insert_with(|| {
+                unique.push(block);
+                next as u32
+            });
+            if unique.len() > u32::MAX as usize {
+                return self.signature.clone();
+            }
+            order.extend_from_slice(&entry.to_be_bytes());
+        }
+        let unique_blocks = unique.len() as u32;
+        let mut signature = Vec::with_capacity(
+            Self::header_size(self.signature_type, Some(unique_blocks))
+                + unique.len() * (Self::WEAK_SUM_SIZE + self.crypto_hash_size as usize)
+                + order.len(),
+        );
+        Self::write_extended_header(
+            self.signature_type,
+            &self.options(),
+            Some(unique_blocks),
+            &mut signature,
+        );
+        for (weak_sum, crypto_hash) in unique {
+            Self::push_block(weak_sum, crypto_hash, &mut signature);
+        }
+        signature.extend_from_slice(&order);
+        signature
+    }
+
+    /// Get the signature of just the blocks overlapping the byte range `range` of the signed data.
+    ///
+    /// The sliced signature describes the data from the start of the first of those blocks, i.e.
+    /// from `range.start` rounded down to a multiple of the block size, so deltas against it must
+    /// be applied to the base data from that offset. Ranges past the end of the data are clipped.
+    ///
+    /// Panics if the block size is zero, which can only happen if the signature was deserialized;
+    /// see [validate](Signature::validate).
+    pub fn slice(&self, range: Range<u64>) -> Signature {
+        let block_size = self.block_size as u64;
+        let blocks = self.blocks().len() as u64;
+        let (start, end) = if range.start < range.end {
+            (
+                (range.start / block_size).min(blocks),
+                // rounded up, since the last block only has to overlap the range
+                (range.end / block_size + (range.end % block_size != 0) as u64).min(blocks),
+            )
+        } else {
+            (0, 0)
+        };
+        let header_size = Self::header_size(self.signature_type, None);
+        let entry_size = (Self::WEAK_SUM_SIZE + self.crypto_hash_size as usize) as u64;
+        let mut signature = self.signature[..header_size].to_vec();
+        signature.extend_from_slice(
+            &self.signature[header_size + (start * entry_size) as usize
+                ..header_size + (end * entry_size) as usize],
+        );
+        Signature {
+            signature_type: self.signature_type,
+            block_size: self.block_size,
+            crypto_hash_size: self.crypto_hash_size,
+            signature,
+        }
+    }
+
+    /// Find the indexes of the blocks that differ between two signatures of successive versions of
+    /// the same data. Blocks which are only present in one of the signatures (because the data
+    /// grew or shrank) are reported as changed as well.
+    ///
+    /// Both signatures must have been calculated with the same options.
+    pub fn diff_blocks(
+        old: &Signature,
+        new: &Signature,
+    ) -> Result<Vec<usize>, IncompatibleSignatures> {
+        old.check_compatible(new)?;
+        let old_blocks = old.blocks();
+        let new_blocks = new.blocks();
+        let common = old_blocks.len().min(new_blocks.len());
+        let total = old_blocks.len().max(new_blocks.len());
+        let mut changed: Vec<usize> = old_blocks
+            .zip(new_blocks)
+            .enumerate()
+            .filter(|(_, (old_block, new_block))| old_block != new_block)
+            .map(|(idx, _)| idx)
+            .collect();
+        changed.extend(common..total);
+        Ok(changed)
+    }
+
+    /// Estimate how similar the data behind two signatures is, without access to either.
+    ///
+    /// This is the fraction of the blocks of `self` which also appear somewhere in `other`, i.e.
+    /// roughly how much of a delta from `other` to `self` would consist of copies rather than
+    /// literals. It is 1.0 if `self` has no blocks.
+    ///
+    /// Both signatures must have been calculated with the same options.
+    pub fn estimate_similarity(&self, other: &Signature) -> Result<f64, IncompatibleSignatures> {
+        self.check_compatible(other)?;
+        let blocks = self.blocks();
+        if blocks.len() == 0 {
+            return Ok(1.0);
+        }
+        let total = blocks.len();
+        let other_blocks: HashSet<(u32, &[u8])> = other.blocks().collect();
+        let shared = blocks.filter(|block| other_blocks.contains(block)).count();
+        Ok(shared as f64 / total as f64)
+    }
+
+    /// Combine signatures of consecutive pieces of data into the signature of all of it, as if it
+    /// had been calculated in one go.
+    ///
+    /// Each part is given with the length of the data it was calculated from, since signatures
+    /// don't record it. All the parts must have been calculated with the same options, and every
+    /// part except the last must cover a multiple of the block size, since otherwise its final
+    /// block would be partial.
+    pub fn concat(parts: &[(Signature, u64)]) -> Result<Signature, ConcatError> {
+        let ((first, _), _) = parts.split_first().ok_or(ConcatError::Empty)?;
+        let block_size = u64::from(first.block_size);
+        let header_size = Self::header_size(first.signature_type, None);
+        let mut signature = Vec::with_capacity(
+            header_size
+                + parts
+                    .iter()
+                    .map(|(part, _)| part.signature.len().saturating_sub(header_size))
+                    .sum::<usize>(),
+        );
+        signature.extend_from_slice(&first.signature[..header_size]);
+        for (i, (part, len)) in parts.iter().enumerate() {
+            first
+                .check_compatible(part)
+                .map_err(|_| ConcatError::Incompatible)?;
+            let last = i + 1 == parts.len();
+            if (!last && len % block_size != 0)
+                || part.blocks().len() as u64
+                    != (len / block_size) + u64::from(len % block_size != 0)
+            {
+                return Err(ConcatError::Length { part: i });
+            }
+            signature.extend_from_slice(&part.signature[header_size..]);
+        }
+        Ok(Signature {
+            signature_type: first.signature_type,
+            block_size: first.block_size,
+            crypto_hash_size: first.crypto_hash_size,
+            signature,
+        })
+    }
+
+    fn check_compatible(&self, other: &Signature) -> Result<(), IncompatibleSignatures> {
+        if self.signature_type != other.signature_type
+            || self.block_size != other.block_size
+            || self.crypto_hash_size != other.crypto_hash_size
+        {
+            return Err(IncompatibleSignatures(()));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn blocks(&self) -> impl ExactSizeIterator<Item = (u32, &[u8])> {
+        SignatureRef::from(self).blocks()
     }
 
     /// Convert a signature to a form suitable for computing deltas.
     pub fn index(&self) -> IndexedSignature<'_> {
+        SignatureRef::from(self).index()
+    }
+
+    /// Like [index](Signature::index), with control over how the index is allocated.
+    pub fn index_with(&self, options: &IndexOptions) -> IndexedSignature<'_> {
+        SignatureRef::from(self).index_with(options)
+    }
+
+    /// Convert a signature to a [FlatIndexedSignature], a more compact alternative to
+    /// [index](Signature::index).
+    pub fn index_flat(&self) -> FlatIndexedSignature<'_> {
+        SignatureRef::from(self).index_flat()
+    }
+}
+
+impl<'a> SignatureRef<'a> {
+    /// Get the serialized form of this signature.
+    pub fn serialized(&self) -> &'a [u8] {
+        self.signature
+    }
+
+    /// Check that the header of the signature is usable; see [Signature::validate].
+    pub fn validate(&self) -> Result<(), InvalidOptions> {
+        if self.block_size == 0 {
+            return Err(InvalidOptions::ZeroBlockSize);
+        }
+        if self.crypto_hash_size == 0 {
+            return Err(InvalidOptions::ZeroCryptoHashSize);
+        }
+        // The size of a custom hash isn't recorded, so it can't be checked here.
+        if let Some(max) = self.signature_type.max_crypto_hash_size() {
+            if self.crypto_hash_size as usize > max {
+                return Err(InvalidOptions::CryptoHashSizeTooLarge {
+                    crypto_hash_size: self.crypto_hash_size,
+                    max: max as u32,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy the blocks overlapping the byte range `range` of the signed data into an owned
+    /// [Signature]; see [Signature::slice]. Only those blocks are read, so this is cheap even for
+    /// a huge signature which is mapped into memory.
+    ///
+    /// Panics if the block size is zero; see [validate](SignatureRef::validate).
+    pub fn slice(&self, range: Range<u64>) -> Signature {
+        let block_size = self.block_size as u64;
+        let mut blocks = self.blocks();
+        let count = blocks.len() as u64;
+        let (start, end) = if range.start < range.end {
+            (
+                (range.start / block_size).min(count),
+                (range.end / block_size + (range.end % block_size != 0) as u64).min(count),
+            )
+        } else {
+            (0, 0)
+        };
+        blocks.next = start as usize;
+        blocks.len = end as usize;
+        let mut signature = Vec::with_capacity(
+            Signature::header_size(self.signature_type, None) + blocks.len() * blocks.entry_size,
+        );
+        Signature::write_header(
+            self.signature_type,
+            &self
+                .signature_type
+                .options(self.block_size, self.crypto_hash_size),
+            &mut signature,
+        );
+        for (weak_sum, crypto_hash) in blocks {
+            Signature::push_block(weak_sum, crypto_hash, &mut signature);
+        }
+        Signature {
+            signature_type: self.signature_type,
+            block_size: self.block_size,
+            crypto_hash_size: self.crypto_hash_size,
+            signature,
+        }
+    }
+
+    /// The length of this signature once expanded, if it is deduplicated.
+    fn expanded_len(&self) -> usize {
         let blocks = self.blocks();
-        let mut block_index: HashMap<Crc, SecondLayerMap<&[u8], u32>, BuildCrcHasher> =
-            HashMap::with_capacity_and_hasher(blocks.len(), BuildCrcHasher::default());
-        for (idx, (crc, crypto_hash)) in blocks.enumerate() {
-            block_index
-                .entry(crc)
+        Signature::header_size(self.signature_type, None)
+            .saturating_add(blocks.len().saturating_mul(blocks.entry_size))
+    }
+
+    /// Copy this signature into an owned [Signature]. Deduplicated signatures are expanded, to
+    /// at most 17 times their size.
+    pub fn to_signature(&self) -> Signature {
+        let signature = match self.unique_blocks {
+            None => self.signature.to_vec(),
+            Some(_) => {
+                let blocks = self.blocks();
+                let mut signature = Vec::with_capacity(
+                    Signature::header_size(self.signature_type, None)
+                        + blocks.len() * blocks.entry_size,
+                );
+                Signature::write_header(
+                    self.signature_type,
+                    &self
+                        .signature_type
+                        .options(self.block_size, self.crypto_hash_size),
+                    &mut signature,
+                );
+                for (weak_sum, crypto_hash) in blocks {
+                    Signature::push_block(weak_sum, crypto_hash, &mut signature);
+                }
+                signature
+            }
+        };
+        Signature {
+            signature_type: self.signature_type,
+            block_size: self.block_size,
+            crypto_hash_size: self.crypto_hash_size,
+            signature,
+        }
+    }
+
+    fn blocks(&self) -> Blocks<'a> {
+        let table =
+            &self.signature[Signature::header_size(self.signature_type, self.unique_blocks)..];
+        let entry_size = Signature::WEAK_SUM_SIZE + self.crypto_hash_size as usize;
+        match self.unique_blocks {
+            None => Blocks {
+                entries: table,
+                entry_size,
+                order: None,
+                next: 0,
+                len: table.len() / entry_size,
+            },
+            Some(unique_blocks) => {
+                let (entries, order) = table.split_at(unique_blocks as usize * entry_size);
+                Blocks {
+                    entries,
+                    entry_size,
+                    order: Some(order),
+                    next: 0,
+                    len: order.len() / 4,
+                }
+            }
+        }
+    }
+
+    /// Convert a signature to a form suitable for computing deltas.
+    pub fn index(&self) -> IndexedSignature<'a> {
+        self.index_with(&IndexOptions::default())
+    }
+
+    /// Convert a signature to a [FlatIndexedSignature]; see [Signature::index_flat].
+    pub fn index_flat(&self) -> FlatIndexedSignature<'a> {
+        FlatIndexedSignature::new(
+            self.signature_type,
+            self.block_size,
+            self.crypto_hash_size,
+            self.blocks(),
+        )
+    }
+
+    /// Like [index](SignatureRef::index), with control over how the index is allocated.
+    pub fn index_with(&self, options: &IndexOptions) -> IndexedSignature<'a> {
+        let blocks = self.blocks();
+        let block_count = blocks.len();
+        // Identical blocks share an entry, so deduplicated signatures need less capacity.
+        let capacity = options.expected_weak_sums.unwrap_or_else(|| {
+            self.unique_blocks
+                .map_or(block_count, |unique_blocks| unique_blocks as usize)
+        });
+        let mut block_index: HashMap<u32, SecondLayerMap<&'a [u8], u32>, BuildCrcHasher> =
+            HashMap::with_capacity_and_hasher(capacity, BuildCrcHasher::default());
+        let mut duplicates: HashMap<u32, Vec<u32>> = HashMap::new();
+        // `zip` stops at the last index which fits in a u32, rather than wrapping around.
+        for (idx, (weak_sum, crypto_hash)) in (0..=u32::MAX).zip(blocks) {
+            let previous = block_index
+                .entry(weak_sum)
                 .or_default()
-                .insert(crypto_hash, idx as u32);
+                .insert(crypto_hash, idx);
+            if let (Some(previous), true) = (previous, options.keep_duplicates) {
+                // the list moves along with the index stored in `block_index`
+                let mut indices = duplicates
+                    .remove(&previous)
+                    .unwrap_or_else(|| vec![previous]);
+                indices.push(idx);
+                duplicates.insert(idx, indices);
+            }
         }
 
-        // Multiple blocks having the same `Crc` value means that the hashmap will reserve more
+        // Multiple blocks having the same rolling checksum means that the hashmap will reserve more
         // capacity than needed. This is particularly noticable when `self.blocks` contains a very
         // large number of values
-        block_index.shrink_to_fit();
+        if options.shrink_to_fit {
+            block_index.shrink_to_fit();
+        }
+        let filter = if options.weak_sum_filter {
+            Some(WeakSumFilter::new(block_index.keys().copied()))
+        } else {
+            None
+        };
 
         IndexedSignature {
             signature_type: self.signature_type,
             block_size: self.block_size,
             crypto_hash_size: self.crypto_hash_size,
+            block_count,
             blocks: block_index,
+            duplicates,
+            filter,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl TryFrom<Vec<u8>> for Signature {
+    type Error = SignatureParseError;
+
+    fn try_from(signature: Vec<u8>) -> Result<Self, Self::Error> {
+        Signature::deserialize(signature)
+    }
+}
+
+impl TryFrom<&[u8]> for Signature {
+    type Error = SignatureParseError;
+
+    fn try_from(signature: &[u8]) -> Result<Self, Self::Error> {
+        Ok(Signature::deserialize_ref(signature)?.to_signature())
+    }
+}
+
+impl AsRef<[u8]> for Signature {
+    fn as_ref(&self) -> &[u8] {
+        self.serialized()
+    }
+}
+
+impl<'a> From<&'a Signature> for SignatureRef<'a> {
+    fn from(signature: &'a Signature) -> Self {
+        SignatureRef {
+            signature_type: signature.signature_type,
+            block_size: signature.block_size,
+            crypto_hash_size: signature.crypto_hash_size,
+            unique_blocks: None,
+            signature: &signature.signature,
         }
     }
 }
@@ -0,0 +1,105 @@
+//! Applying deltas to sparse files, such as disk images.
+
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+
+use crate::patch::{apply, ApplyError};
+
+/// Runs of zeros are only left out a whole block at a time, since that's what a filesystem can
+/// leave unallocated.
+const HOLE_BLOCK: u64 = 4096;
+
+/// Apply `delta` to `base` like [apply()], writing the output to `out` in place of whatever it
+/// held, and leaving every block of zeros in the output as a hole rather than writing it.
+///
+/// Zeros are detected in the output itself, so runs of zeros in literals and in copies from the
+/// base both end up as holes, whether or not `base` was itself sparse. `out` is truncated to
+/// nothing first, so holes in its old contents don't survive either, and then its blocks of
+/// zeros are skipped with a seek, so the filesystem doesn't allocate them, and it's extended to
+/// its full length at the end. On filesystems which don't support sparse files, the skipped
+/// blocks are simply filled with zeros.
+///
+/// Blocks are 4 KiB and aligned to the start of `out`, which matches most filesystems.
+pub fn apply_sparse(base: &[u8], delta: &[u8], out: &mut File) -> Result<(), ApplyError> {
+    out.set_len(0)?;
+    out.seek(SeekFrom::Start(0))?;
+    let mut writer = SparseWriter {
+        file: out,
+        pos: 0,
+        seeked: false,
+    };
+    apply(base, delta, &mut writer)?;
+    writer.file.set_len(writer.pos)?;
+    Ok(())
+}
+
+/// Writes to a file, skipping over the blocks which are all zeros.
+struct SparseWriter<'a> {
+    file: &'a mut File,
+    /// Where the output written so far ends.
+    pos: u64,
+    /// Whether the file's position is behind `pos` because the last block was skipped.
+    seeked: bool,
+}
+
+impl Write for SparseWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            // up to the end of the current block
+            let n = ((HOLE_BLOCK - self.pos % HOLE_BLOCK) as usize).min(rest.len());
+            let (block, tail) = rest.split_at(n);
+            if block.iter().all(|&b| b == 0) {
+                self.seeked = true;
+            } else {
+                if self.seeked {
+                    self.file.seek(SeekFrom::Start(self.pos))?;
+                    self.seeked = false;
+                }
+                self.file.write_all(block)?;
+            }
+            self.pos += n as u64;
+            rest = tail;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::apply_sparse;
+    use crate::{diff, Signature, SignatureOptions};
+    use rand::Rng;
+    use std::fs::{self, OpenOptions};
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn holes() {
+        let mut rng = rand::thread_rng();
+        let mut base = vec![0; 1 << 20];
+        rng.fill(&mut base[..1 << 19]);
+        // zeros copied from the base, zeros in a literal, and data which isn't block aligned
+        let mut data = base.clone();
+        data.extend(vec![0; 1 << 20]);
+        rng.fill(&mut data[(3 << 19) + 100..(3 << 19) + 200]);
+        data.extend_from_slice(&base[1000..2000]);
+        let signature = Signature::calculate(&base, SignatureOptions::default());
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).unwrap();
+
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        // none of the old contents are left behind
+        fs::write(&path, vec![1; 3 << 20]).unwrap();
+        let mut out = OpenOptions::new().write(true).open(&path).unwrap();
+        apply_sparse(&base, &delta, &mut out).unwrap();
+        drop(out);
+        assert!(fs::read(&path).unwrap() == data);
+        // holes are only a hint to the filesystem, but most of the zeros shouldn't take up space
+        let blocks = fs::metadata(&path).unwrap().blocks();
+        assert!(blocks * 512 < 1 << 20, "{} blocks", blocks);
+    }
+}
@@ -0,0 +1,197 @@
+//! Applying deltas whose output may not fit in memory.
+
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use tempfile::NamedTempFile;
+
+use crate::patch::{apply_limited, ApplyError};
+
+/// The output of [apply_spilling()]: either an in-memory buffer or a temporary file, depending on
+/// how large the output turned out to be.
+///
+/// Reading from an `ApplyOutput` yields the output from the beginning. A spilled temporary file is
+/// deleted when the `ApplyOutput` is dropped, unless it is [persisted](ApplyOutput::persist).
+#[derive(Debug)]
+pub struct ApplyOutput {
+    inner: Spill,
+}
+
+#[derive(Debug)]
+enum Spill {
+    Memory(Cursor<Vec<u8>>),
+    File { file: NamedTempFile, len: u64 },
+}
+
+impl ApplyOutput {
+    /// The length of the output.
+    pub fn len(&self) -> u64 {
+        match &self.inner {
+            Spill::Memory(buf) => buf.get_ref().len() as u64,
+            Spill::File { len, .. } => *len,
+        }
+    }
+
+    /// Whether the output is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the output exceeded the spill threshold and was written to a temporary file.
+    pub fn is_spilled(&self) -> bool {
+        matches!(self.inner, Spill::File { .. })
+    }
+
+    /// Get the output as a `Vec<u8>`, reading it back from disk if it was spilled.
+    pub fn into_vec(self) -> io::Result<Vec<u8>> {
+        match self.inner {
+            Spill::Memory(buf) => Ok(buf.into_inner()),
+            Spill::File { mut file, len } => {
+                let mut buf = Vec::with_capacity(len as usize);
+                file.seek(SeekFrom::Start(0))?;
+                file.read_to_end(&mut buf)?;
+                Ok(buf)
+            }
+        }
+    }
+
+    /// Store the output at `path`, replacing any existing file. A spilled output is renamed into
+    /// place when possible, and copied otherwise.
+    pub fn persist(self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        match self.inner {
+            Spill::Memory(buf) => std::fs::write(path, buf.into_inner()),
+            Spill::File { file, .. } => match file.persist(path) {
+                Ok(_) => Ok(()),
+                // most likely `path` is on another filesystem
+                Err(e) => {
+                    let mut file = e.file;
+                    file.seek(SeekFrom::Start(0))?;
+                    io::copy(&mut file, &mut File::create(path)?)?;
+                    Ok(())
+                }
+            },
+        }
+    }
+}
+
+impl Read for ApplyOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.inner {
+            Spill::Memory(cursor) => cursor.read(buf),
+            Spill::File { file, .. } => file.read(buf),
+        }
+    }
+}
+
+/// A writer which buffers in memory until `threshold` bytes have been written, then moves
+/// everything to a temporary file.
+struct SpillWriter {
+    buf: Vec<u8>,
+    file: Option<io::BufWriter<NamedTempFile>>,
+    len: u64,
+    threshold: usize,
+}
+
+impl Write for SpillWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.file.is_none() && self.buf.len() + data.len() > self.threshold {
+            let mut file = io::BufWriter::new(NamedTempFile::new()?);
+            file.write_all(&self.buf)?;
+            self.buf = Vec::new();
+            self.file = Some(file);
+        }
+        match &mut self.file {
+            Some(file) => file.write_all(data)?,
+            None => self.buf.extend_from_slice(data),
+        }
+        self.len += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Apply `delta` to the base data `base`, keeping the result in memory unless it grows beyond
+/// `spill_threshold` bytes, in which case it is moved to a temporary file.
+/// Errors if the result would be longer than `limit` bytes.
+///
+/// This degrades gracefully when a delta turns out to produce far more data than expected, instead
+/// of exhausting memory.
+pub fn apply_spilling(
+    base: &[u8],
+    delta: &[u8],
+    limit: usize,
+    spill_threshold: usize,
+) -> Result<ApplyOutput, ApplyError> {
+    let mut writer = SpillWriter {
+        buf: Vec::new(),
+        file: None,
+        len: 0,
+        threshold: spill_threshold,
+    };
+    apply_limited(base, delta, &mut writer, limit)?;
+    let inner = match writer.file {
+        None => Spill::Memory(Cursor::new(writer.buf)),
+        Some(file) => {
+            let mut file = file.into_inner().map_err(|e| e.into_error())?;
+            file.seek(SeekFrom::Start(0))?;
+            Spill::File {
+                file,
+                len: writer.len,
+            }
+        }
+    };
+    Ok(ApplyOutput { inner })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::apply_spilling;
+    use crate::{diff, Signature, SignatureOptions};
+    use std::io::Read;
+
+    #[test]
+    fn spill() {
+        let base = b"the quick brown fox jumps over the lazy dog".repeat(100);
+        let mut data = base.clone();
+        data.extend_from_slice(b"and then some more");
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions {
+                block_size: 16,
+                crypto_hash_size: 8,
+                ..Default::default()
+            },
+        );
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).unwrap();
+
+        let out = apply_spilling(&base, &delta, usize::MAX, data.len()).unwrap();
+        assert!(!out.is_spilled());
+        assert_eq!(out.into_vec().unwrap(), data);
+
+        let mut out = apply_spilling(&base, &delta, usize::MAX, 100).unwrap();
+        assert!(out.is_spilled());
+        assert_eq!(out.len(), data.len() as u64);
+        let mut read = vec![];
+        out.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        apply_spilling(&base, &delta, usize::MAX, 100)
+            .unwrap()
+            .persist(&path)
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        assert!(apply_spilling(&base, &delta, 100, 10).is_err());
+    }
+}
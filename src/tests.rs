@@ -1,139 +1,4278 @@
 use quickcheck_macros::quickcheck;
 use std::io::Cursor;
 
-use crate::{apply, diff, Signature, SignatureOptions};
+use crate::{
+    apply, diff, diff_from_reader, diff_with_base, diff_with_options, ConcatError, DiffOptions,
+    DiffState, DiskIndexedSignature, IndexedSignature, OwnedIndexedSignature, RollingHash,
+    Signature, SignatureBuilder, SignatureHash, SignatureOptions, SignatureRef,
+};
+
+/// A base of `len` bytes, the data it becomes with a few bytes inserted into it, and a delta
+/// to that from the signature of the base with `options`, for the tests of the helpers which
+/// work on files.
+pub(crate) struct Fixture {
+    pub base: Vec<u8>,
+    pub data: Vec<u8>,
+    pub delta: Vec<u8>,
+}
+
+impl Fixture {
+    pub fn new(len: u32, options: SignatureOptions) -> Fixture {
+        let base: Vec<u8> = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+        let middle = base.len() / 3;
+        let data = [&base[..middle], b"inserted", &base[middle..]].concat();
+        let signature = Signature::calculate(&base, options);
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).expect("diff error");
+        Fixture { base, data, delta }
+    }
+}
 
 #[quickcheck]
 fn test_signature_creation(data: Vec<u8>, block_size: u32, crypto_hash_size: u32) {
     let signature = Signature::calculate(
-        &data,
+        &data,
+        SignatureOptions {
+            block_size: block_size.saturating_add(1),
+            crypto_hash_size: crypto_hash_size % 16,
+            ..Default::default()
+        },
+    );
+    let serialized = signature.serialized().to_vec();
+    let deserialized = Signature::deserialize(serialized).expect("deserialization error");
+    assert_eq!(signature, deserialized);
+}
+
+#[quickcheck]
+fn test_signature_deserialize_ref(data: Vec<u8>, block_size: u32, crypto_hash_size: u32) {
+    let signature = Signature::calculate(
+        &data,
+        SignatureOptions {
+            block_size: block_size.saturating_add(1),
+            crypto_hash_size: crypto_hash_size % 16,
+            ..Default::default()
+        },
+    );
+    let borrowed =
+        Signature::deserialize_ref(signature.serialized()).expect("deserialization error");
+    assert_eq!(borrowed, SignatureRef::from(&signature));
+    assert_eq!(borrowed.to_signature(), signature);
+}
+
+#[test]
+fn test_owned_index() {
+    use rand::Rng;
+    let mut base = vec![0; 10000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base.clone();
+    data[5000..5100].copy_from_slice(&[0; 100]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 64,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let mut delta = vec![];
+    diff(&signature.index(), &data, &mut delta).expect("diff error");
+    let owned: OwnedIndexedSignature = signature.index().into_owned();
+    drop(signature);
+    let owned_delta = std::thread::spawn(move || {
+        let mut owned_delta = vec![];
+        diff(&owned, &data, &mut owned_delta).expect("diff error");
+        owned_delta
+    })
+    .join()
+    .unwrap();
+    assert_eq!(delta, owned_delta);
+}
+
+#[test]
+fn test_owned_index_memory_usage() {
+    use rand::Rng;
+    let mut base = vec![0; 10000];
+    rand::thread_rng().fill(&mut base[..]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 64,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let owned = signature.index().into_owned();
+    // the owned index also holds the 8-byte hashes of all (distinct) blocks
+    let borrowed_usage = signature.index().memory_usage();
+    assert!(borrowed_usage > signature.blocks().len() * 8);
+    assert!(owned.memory_usage() >= borrowed_usage + signature.blocks().len() * 8);
+}
+
+#[test]
+fn test_flat_index() {
+    use rand::Rng;
+    let mut base = vec![0; 10000];
+    rand::thread_rng().fill(&mut base[..]);
+    base[2000..4000].copy_from_slice(&[0; 2000]);
+    let mut data = base.clone();
+    data[5000..5100].copy_from_slice(&[0; 100]);
+    data.extend_from_slice(&base[..3000]);
+    for &rolling_hash in &[RollingHash::Rollsum, RollingHash::RabinKarp] {
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions {
+                block_size: 64,
+                crypto_hash_size: 8,
+                rolling_hash,
+                ..Default::default()
+            },
+        );
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).expect("diff error");
+        let mut flat_delta = vec![];
+        diff(&signature.index_flat(), &data, &mut flat_delta).expect("diff error");
+        assert_eq!(delta, flat_delta);
+    }
+}
+
+#[test]
+fn test_index_options() {
+    use crate::IndexOptions;
+    let mut base = vec![0; 10000];
+    base[..5000]
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, x)| *x = i as u8);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 100,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let index = signature.index();
+    for options in &[
+        IndexOptions {
+            shrink_to_fit: false,
+            ..Default::default()
+        },
+        IndexOptions {
+            shrink_to_fit: false,
+            expected_weak_sums: Some(4),
+            ..Default::default()
+        },
+        IndexOptions {
+            expected_weak_sums: Some(1000),
+            ..Default::default()
+        },
+    ] {
+        assert_eq!(signature.index_with(options), index);
+    }
+}
+
+#[test]
+fn test_signature_cache() {
+    use crate::SignatureCache;
+    use std::sync::Arc;
+    let options = SignatureOptions {
+        block_size: 64,
+        crypto_hash_size: 8,
+        ..Default::default()
+    };
+    let signatures: Vec<Signature> = (0..4u8)
+        .map(|i| Signature::calculate(&[i; 640], options))
+        .collect();
+    let size = signatures[0].serialized().len();
+    let cache = SignatureCache::new(3 * size);
+    for (i, signature) in signatures.iter().enumerate() {
+        cache.insert(i, signature.clone());
+        // keep the first one in use
+        assert!(cache.get(&0).is_some());
+    }
+    assert_eq!(cache.len(), 3);
+    assert_eq!(cache.memory_usage(), 3 * size);
+    assert!(cache.get(&1).is_none());
+    assert_eq!(*cache.get(&3).unwrap(), signatures[3]);
+
+    // building an index makes room for it, starting with the least recently used signature
+    let index = cache.get_index(&3).unwrap();
+    assert!(Arc::ptr_eq(&index, &cache.get_index(&3).unwrap()));
+    assert!(cache.get(&2).is_none());
+    assert!(cache.memory_usage() <= 3 * size);
+    assert_eq!(
+        cache.memory_usage(),
+        cache.len() * size + index.memory_usage()
+    );
+    assert!(cache.get_index(&1).is_none());
+
+    cache.remove(&0);
+    assert_eq!(*cache.remove(&3).unwrap(), signatures[3]);
+    assert_eq!(cache.memory_usage(), 0);
+    cache.insert(0, signatures[0].clone());
+    // a signature larger than the whole cache isn't kept, and doesn't evict anything else
+    let large = Signature::calculate(&[9; 6400], options);
+    assert_eq!(*cache.insert(9, large.clone()), large);
+    assert!(cache.get(&9).is_none());
+    assert_eq!(*cache.get(&0).unwrap(), signatures[0]);
+    assert_eq!(cache.memory_usage(), size);
+    // nor is an index which doesn't fit along with its signature
+    let cache = SignatureCache::new(size);
+    cache.insert(0, signatures[0].clone());
+    let index = cache.get_index(&0).unwrap();
+    assert!(!Arc::ptr_eq(&index, &cache.get_index(&0).unwrap()));
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.memory_usage(), size);
+
+    let cache = SignatureCache::new(usize::MAX);
+    cache.insert("shared", signatures[0].clone());
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                let index = cache.get_index(&"shared").unwrap();
+                assert_eq!(*index, signatures[0].index().into_owned());
+            });
+        }
+    });
+    assert_eq!(
+        cache.memory_usage(),
+        size + cache.get_index(&"shared").unwrap().memory_usage()
+    );
+}
+
+#[test]
+fn test_disk_index() {
+    use rand::Rng;
+    let mut base = vec![0; 10000];
+    rand::thread_rng().fill(&mut base[..]);
+    // some duplicate blocks, which must resolve to the same block as the in-memory index
+    base[2000..4000].copy_from_slice(&[0; 2000]);
+    let mut data = base.clone();
+    data[5000..5100].copy_from_slice(&[0; 100]);
+    data.extend_from_slice(&base[..3000]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 64,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let mut table = vec![];
+    DiskIndexedSignature::write(&signature, &mut table).expect("write error");
+    let disk_index = DiskIndexedSignature::new(&table[..]).expect("parse error");
+    let mut delta = vec![];
+    diff(&signature.index(), &data, &mut delta).expect("diff error");
+    let mut disk_delta = vec![];
+    diff(&disk_index, &data, &mut disk_delta).expect("diff error");
+    assert_eq!(delta, disk_delta);
+
+    // persisting the in-memory index gives the same table
+    let mut index_table = vec![];
+    DiskIndexedSignature::write_index(&signature.index(), &mut index_table).expect("write error");
+    assert_eq!(table, index_table);
+    let mut owned_table = vec![];
+    DiskIndexedSignature::write_index(&signature.index().into_owned(), &mut owned_table)
+        .expect("write error");
+    assert_eq!(table, owned_table);
+
+    assert!(DiskIndexedSignature::new(&table[..table.len() - 1]).is_err());
+    assert!(DiskIndexedSignature::new(signature.serialized()).is_err());
+    // the fanout table must count up to the number of entries
+    let mut bad_fanout = table.clone();
+    bad_fanout[32..40].copy_from_slice(&u64::MAX.to_be_bytes());
+    assert!(DiskIndexedSignature::new(&bad_fanout[..]).is_err());
+
+    // a block index whose offset overflows must be rejected rather than wrapped
+    let mut table = vec![];
+    DiskIndexedSignature::write(
+        &Signature::calculate(&base[..64], signature.options()),
+        &mut table,
+    )
+    .expect("write error");
+    table[2084..2092].copy_from_slice(&u64::MAX.to_be_bytes());
+    let disk_index = DiskIndexedSignature::new(&table[..]).expect("parse error");
+    assert!(diff(&disk_index, &base[..64], &mut vec![]).is_err());
+}
+
+#[test]
+fn test_signature_conversions() {
+    use std::convert::TryFrom;
+    let signature = Signature::calculate(&[1; 1000], SignatureOptions::default());
+    let serialized: &[u8] = signature.as_ref();
+    assert_eq!(serialized, signature.serialized());
+    assert_eq!(Signature::try_from(serialized).unwrap(), signature);
+    assert_eq!(Signature::try_from(serialized.to_vec()).unwrap(), signature);
+    assert!(Signature::try_from(&serialized[1..]).is_err());
+    assert!(Signature::try_from(serialized[1..].to_vec()).is_err());
+}
+
+#[test]
+fn test_signature_ref() {
+    let base = b"the quick brown fox jumps over the lazy dog".repeat(10);
+    let data = b"the quick brown fox jumps over the lazy cat".repeat(10);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 16,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let borrowed =
+        Signature::deserialize_ref(signature.serialized()).expect("deserialization error");
+    let mut delta = vec![];
+    diff(&signature.index(), &data, &mut delta).expect("diff error");
+    let mut borrowed_delta = vec![];
+    diff(&borrowed.index(), &data, &mut borrowed_delta).expect("diff error");
+    assert_eq!(delta, borrowed_delta);
+
+    assert!(Signature::deserialize_ref(&signature.serialized()[..5]).is_err());
+    assert!(Signature::deserialize_ref(&signature.serialized()[1..]).is_err());
+}
+
+#[test]
+fn test_index_from_serialized() {
+    let base = b"the quick brown fox jumps over the lazy dog".repeat(10);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 16,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        IndexedSignature::from_serialized(signature.serialized()).expect("deserialization error"),
+        signature.index()
+    );
+    assert!(IndexedSignature::from_serialized(&signature.serialized()[1..]).is_err());
+}
+
+#[test]
+fn test_signature_concat() {
+    use rand::Rng;
+    let mut data = vec![0; 10000];
+    rand::thread_rng().fill(&mut data[..]);
+    let options = SignatureOptions {
+        block_size: 100,
+        crypto_hash_size: 8,
+        ..Default::default()
+    };
+    let parts: Vec<(Signature, u64)> = data
+        .chunks(3000)
+        .map(|shard| (Signature::calculate(shard, options), shard.len() as u64))
+        .collect();
+    assert_eq!(
+        Signature::concat(&parts).unwrap(),
+        Signature::calculate(&data, options)
+    );
+    let mismatched = [
+        parts[0].clone(),
+        (
+            Signature::calculate(
+                &data,
+                SignatureOptions {
+                    crypto_hash_size: 4,
+                    ..options
+                },
+            ),
+            data.len() as u64,
+        ),
+    ];
+    assert_eq!(
+        Signature::concat(&mismatched),
+        Err(ConcatError::Incompatible)
+    );
+    assert_eq!(Signature::concat(&[]), Err(ConcatError::Empty));
+
+    // a part before the last which ends in a partial block
+    let uneven: Vec<(Signature, u64)> = data
+        .chunks(3050)
+        .map(|shard| (Signature::calculate(shard, options), shard.len() as u64))
+        .collect();
+    assert_eq!(
+        Signature::concat(&uneven),
+        Err(ConcatError::Length { part: 0 })
+    );
+    assert!(Signature::concat(&uneven[3..]).is_ok());
+    // a length which doesn't match the part's blocks
+    let mut wrong = parts.clone();
+    wrong[1].1 += 100;
+    assert_eq!(
+        Signature::concat(&wrong),
+        Err(ConcatError::Length { part: 1 })
+    );
+    wrong[1].1 -= 100;
+    wrong[3].1 = 5000;
+    assert_eq!(
+        Signature::concat(&wrong),
+        Err(ConcatError::Length { part: 3 })
+    );
+}
+
+#[test]
+fn test_signature_slice() {
+    use rand::Rng;
+    let mut base = vec![0; 10000];
+    rand::thread_rng().fill(&mut base[..]);
+    let options = SignatureOptions {
+        block_size: 100,
+        crypto_hash_size: 8,
+        ..Default::default()
+    };
+    let signature = Signature::calculate(&base, options);
+    assert_eq!(
+        signature.slice(250..1001),
+        Signature::calculate(&base[200..1100], options)
+    );
+    assert_eq!(
+        signature.slice(9950..20000),
+        Signature::calculate(&base[9900..], options)
+    );
+    assert_eq!(signature.slice(0..u64::MAX), signature);
+    let deduplicated = signature.serialize_deduplicated();
+    for range in [250..1001, 9950..20000, 0..u64::MAX, 500..500] {
+        let expected = signature.slice(range.clone());
+        assert_eq!(
+            SignatureRef::from(&signature).slice(range.clone()),
+            expected
+        );
+        let deduplicated = Signature::deserialize_ref(&deduplicated).unwrap();
+        assert_eq!(deduplicated.slice(range), expected);
+    }
+    assert_eq!(
+        signature.slice(500..500),
+        Signature::calculate(&[], options)
+    );
+    assert_eq!(
+        signature.slice(20000..30000),
+        Signature::calculate(&[], options)
+    );
+
+    // a delta against a slice applies to the base from the start of its first block
+    let data = base[300..2000].to_vec();
+    let mut patch = vec![];
+    diff(&signature.slice(350..2000).index(), &data, &mut patch).expect("diff error");
+    assert!(patch.len() < 200);
+    let mut out = vec![];
+    apply(&base[300..], &patch, &mut out).expect("apply error");
+    assert_eq!(data, out);
+}
+
+#[test]
+fn test_estimate_similarity() {
+    let options = SignatureOptions {
+        block_size: 4,
+        crypto_hash_size: 8,
+        ..Default::default()
+    };
+    let base = Signature::calculate(b"aaaabbbbccccdddd", options);
+    let same = Signature::calculate(b"ddddccccbbbbaaaa", options);
+    let half = Signature::calculate(b"xxxxbbbbyyyyaaaa", options);
+    let none = Signature::calculate(b"wwwwxxxxyyyyzzzz", options);
+    assert_eq!(same.estimate_similarity(&base).unwrap(), 1.0);
+    assert_eq!(half.estimate_similarity(&base).unwrap(), 0.5);
+    assert_eq!(none.estimate_similarity(&base).unwrap(), 0.0);
+    assert_eq!(
+        Signature::calculate(b"", options)
+            .estimate_similarity(&base)
+            .unwrap(),
+        1.0
+    );
+    let other_options = Signature::calculate(
+        b"aaaabbbbccccdddd",
+        SignatureOptions {
+            block_size: 8,
+            ..options
+        },
+    );
+    assert!(other_options.estimate_similarity(&base).is_err());
+}
+
+#[test]
+fn test_fixed_block_sizes() {
+    use rand::Rng;
+    // These block sizes take a specialized path; make sure it agrees with the generic one.
+    let mut data = vec![0; 50000];
+    rand::thread_rng().fill(&mut data[..]);
+    for &block_size in &[2048, 4096, 8192] {
+        for &len in &[0, 1, block_size, block_size + 1, data.len()] {
+            let signature = Signature::calculate(
+                &data[..len],
+                SignatureOptions {
+                    block_size: block_size as u32,
+                    crypto_hash_size: 16,
+                    ..Default::default()
+                },
+            );
+            let mut expected = signature.serialized()[..12].to_vec();
+            for block in data[..len].chunks(block_size) {
+                expected
+                    .extend_from_slice(&crate::crc::Crc::new().basic_update(block).0.to_be_bytes());
+                expected.extend_from_slice(&crate::md4::md4(block));
+            }
+            assert_eq!(signature.serialized(), &expected[..]);
+        }
+    }
+}
+
+#[quickcheck]
+fn test_signature_builder(data: Vec<u8>, splits: Vec<usize>, block_size: u8, blake2: bool) {
+    let options = SignatureOptions {
+        block_size: block_size as u32 + 1,
+        crypto_hash_size: 8,
+        hash: if blake2 {
+            SignatureHash::Blake2
+        } else {
+            SignatureHash::Md4
+        },
+        ..Default::default()
+    };
+    let mut builder = SignatureBuilder::new(options);
+    let mut rest = &data[..];
+    for split in splits {
+        let (head, tail) = rest.split_at(split % (rest.len() + 1));
+        builder.update(head);
+        rest = tail;
+    }
+    builder.update(rest);
+    assert_eq!(builder.finish(), Signature::calculate(&data, options));
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_signature_parallel() {
+    use rand::Rng;
+    let mut data = vec![0; (3 << 20) + 123];
+    rand::thread_rng().fill(&mut data[..]);
+    for &block_size in &[1000, 2048, 3 << 20] {
+        for &hash in &[SignatureHash::Md4, SignatureHash::Blake2] {
+            let options = SignatureOptions {
+                block_size,
+                crypto_hash_size: 8,
+                hash,
+                ..Default::default()
+            };
+            assert_eq!(
+                Signature::calculate_parallel(&data, options),
+                Signature::calculate(&data, options)
+            );
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn test_signature_from_async_reader() {
+    use rand::Rng;
+    use tokio::io::AsyncReadExt;
+    let mut data = vec![0; 200_000];
+    rand::thread_rng().fill(&mut data[..]);
+    let options = SignatureOptions {
+        block_size: 1000,
+        crypto_hash_size: 8,
+        ..Default::default()
+    };
+    // split the input at an odd offset so reads don't line up with blocks
+    let reader = (&data[..12345]).chain(&data[12345..]);
+    let signature = Signature::calculate_from_async_reader(reader, options)
+        .await
+        .expect("read error");
+    assert_eq!(signature, Signature::calculate(&data, options));
+    let options = SignatureOptions {
+        block_size: 0,
+        ..options
+    };
+    let err = Signature::calculate_from_async_reader(&data[..], options)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[quickcheck]
+fn test_signature_append(data: Vec<u8>, splits: Vec<usize>, block_size: u8) {
+    let options = SignatureOptions {
+        block_size: block_size as u32 + 1,
+        crypto_hash_size: 8,
+        ..Default::default()
+    };
+    let mut len = 0;
+    let mut signature = Signature::calculate(&[], options);
+    for split in splits {
+        len += split % (data.len() - len + 1);
+        signature
+            .append(&data[signature.append_offset() as usize..len], None)
+            .unwrap();
+        assert_eq!(signature, Signature::calculate(&data[..len], options));
+    }
+    signature
+        .append(&data[signature.append_offset() as usize..], None)
+        .unwrap();
+    assert_eq!(signature, Signature::calculate(&data, options));
+}
+
+#[test]
+fn test_signature_append_keyed() {
+    use crate::{HashKey, InvalidOptions};
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let key = HashKey::new([42; 32]);
+    let options = SignatureOptions {
+        block_size: 1000,
+        crypto_hash_size: 16,
+        hash: SignatureHash::KeyedBlake2,
+        hash_key: Some(key),
+        ..Default::default()
+    };
+    let mut signature = Signature::calculate(&data[..4500], options);
+    let before = signature.clone();
+    let tail = &data[signature.append_offset() as usize..];
+    assert_eq!(
+        signature.append(tail, None),
+        Err(InvalidOptions::HashKeyMismatch)
+    );
+    assert_eq!(signature, before);
+    signature.append(tail, Some(key)).unwrap();
+    assert_eq!(signature, Signature::calculate(&data, options));
+
+    // an unkeyed signature takes no key
+    let options = SignatureOptions {
+        hash: SignatureHash::Blake2,
+        hash_key: None,
+        ..options
+    };
+    let mut signature = Signature::calculate(&data[..4500], options);
+    assert_eq!(
+        signature.append(tail, Some(key)),
+        Err(InvalidOptions::HashKeyMismatch)
+    );
+}
+
+#[cfg(feature = "digest")]
+#[test]
+fn test_signature_append_custom() {
+    use crate::InvalidOptions;
+    let options = SignatureOptions {
+        block_size: 100,
+        crypto_hash_size: 8,
+        ..Default::default()
+    };
+    let mut signature = Signature::calculate_with_digest::<sha2::Sha256>(b"data", options);
+    assert_eq!(
+        signature.append(b"data and more", None),
+        Err(InvalidOptions::CustomHash)
+    );
+}
+
+#[test]
+fn test_invalid_signature_options() {
+    assert_eq!(
+        Signature::try_calculate(
+            b"potato",
+            SignatureOptions {
+                block_size: 0,
+                crypto_hash_size: 8,
+                ..Default::default()
+            },
+        )
+        .unwrap_err()
+        .to_string(),
+        "block size must be greater than zero",
+    );
+    assert_eq!(
+        Signature::try_calculate(
+            b"potato",
+            SignatureOptions {
+                block_size: 4,
+                crypto_hash_size: 17,
+                ..Default::default()
+            },
+        )
+        .unwrap_err()
+        .to_string(),
+        "crypto hash size is too large (crypto_hash_size=17, max=16)",
+    );
+}
+
+#[test]
+fn test_diff_blocks() {
+    let options = SignatureOptions {
+        block_size: 4,
+        crypto_hash_size: 8,
+        ..Default::default()
+    };
+    let old = Signature::calculate(b"aaaabbbbccccdd", options);
+    let new = Signature::calculate(b"aaaaBBBBccccddddeeee", options);
+    // block 1 was edited, block 3 grew from "dd" and block 4 is new
+    assert_eq!(Signature::diff_blocks(&old, &new).unwrap(), [1, 3, 4]);
+    assert_eq!(Signature::diff_blocks(&new, &old).unwrap(), [1, 3, 4]);
+    assert!(Signature::diff_blocks(&old, &old).unwrap().is_empty());
+    let other = Signature::calculate(
+        b"aaaabbbbccccdd",
+        SignatureOptions {
+            block_size: 8,
+            ..options
+        },
+    );
+    assert!(Signature::diff_blocks(&old, &other).is_err());
+}
+
+#[test]
+fn test_trivial() {
+    let data = vec![0; 100000];
+    let signature = Signature::calculate(
+        &data,
+        SignatureOptions {
+            block_size: 64,
+            crypto_hash_size: 5,
+            ..Default::default()
+        },
+    );
+    let indexed = signature.index();
+    let mut patch = vec![];
+    diff(&indexed, &data, &mut patch).expect("diff error");
+    let mut out = vec![];
+    assert!(patch.len() < 10000);
+    apply(&data, &patch, &mut out).expect("apply error");
+    assert_eq!(data, out);
+}
+
+#[test]
+fn test_delta_size() {
+    let mut data1 = b"hello".to_vec();
+    let mut data2 = b"goodbye".to_vec();
+    data1.resize(1 << 20, 0);
+    data2.resize(1 << 20, 0);
+
+    let signature = Signature::calculate(
+        &data1,
+        SignatureOptions {
+            block_size: 4096,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let mut patch = vec![];
+    diff(&signature.index(), &data2, &mut patch).expect("diff error");
+    let mut out = vec![];
+    assert!(patch.len() < 10000);
+    apply(&data1, &patch, &mut out).expect("apply error");
+    assert_eq!(data2, out);
+}
+
+#[test]
+fn test_random() {
+    use rand::Rng;
+    let mut base = vec![0; 100000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = vec![0; 100000];
+    rand::thread_rng().fill(&mut data[..]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 4,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let indexed = signature.index();
+    let mut patch = vec![];
+    diff(&indexed, &data, &mut patch).expect("diff error");
+    let mut out = vec![];
+    apply(&base, &patch, &mut out).expect("apply error");
+    assert_eq!(data, out);
+
+    // interoperability: we can apply patches generated by librsync
+    let serialized_signature = signature.into_serialized();
+    let mut librsync_patch = vec![];
+    librsync::whole::delta(
+        &mut &data[..],
+        &mut &serialized_signature[..],
+        &mut librsync_patch,
+    )
+    .unwrap();
+    out.clear();
+    apply(&base, &librsync_patch, &mut out).expect("apply error");
+    assert_eq!(data, out);
+
+    // interoperability: librsync can apply our patches
+    out.clear();
+    librsync::whole::patch(&mut Cursor::new(&base[..]), &mut &patch[..], &mut out).unwrap();
+    assert_eq!(data, out);
+}
+
+#[test]
+fn test_literal_segments() {
+    use rand::Rng;
+    use std::num::NonZeroUsize;
+    let mut base = vec![0; 10000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = vec![0; 10000];
+    rand::thread_rng().fill(&mut data[..]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 64,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let mut patch = vec![];
+    diff_with_options(
+        &signature.index(),
+        &data,
+        &mut patch,
+        &DiffOptions {
+            literal_segment_size: NonZeroUsize::new(1000),
+            ..Default::default()
+        },
+    )
+    .expect("diff error");
+    // magic, ten 1000-byte literals with a 3-byte header each, then the end command
+    assert_eq!(patch.len(), 4 + 10 * (3 + 1000) + 1);
+    let mut out = vec![];
+    apply(&base, &patch, &mut out).expect("apply error");
+    assert_eq!(data, out);
+}
+
+#[test]
+fn test_max_size_ratio() {
+    use std::num::NonZeroU64;
+    let base: Vec<u8> = (0..=255).collect();
+    let data = base.repeat(100);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 16,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let diff_ratio = |ratio| {
+        let mut patch = vec![];
+        diff_with_options(
+            &signature.index(),
+            &data,
+            &mut patch,
+            &DiffOptions {
+                max_size_ratio: NonZeroU64::new(ratio),
+                ..Default::default()
+            },
+        )
+        .expect("diff error");
+        let mut out = vec![];
+        apply(&base, &patch, &mut out).expect("apply error");
+        assert_eq!(data, out);
+        patch
+    };
+    // within the ratio, the repeated base is found
+    assert!(diff_ratio(100).len() < data.len() / 10);
+    // beyond it, the data is emitted as a single literal
+    assert_eq!(diff_ratio(99).len(), 4 + 3 + data.len() + 1);
+}
+
+#[test]
+fn test_diff_matching_options() {
+    use rand::Rng;
+    use std::num::{NonZeroU32, NonZeroUsize};
+    let mut base = vec![0; 10000];
+    rand::thread_rng().fill(&mut base[..]);
+    // one byte inserted at the start, then a short run which matches a single block
+    let mut data = vec![1];
+    data.extend_from_slice(&base[..5000]);
+    data.extend_from_slice(&[2; 100]);
+    data.extend_from_slice(&base[6016..6080]);
+    data.extend_from_slice(&[3; 100]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 64,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let diff_options = |options: DiffOptions| {
+        let mut patch = vec![];
+        diff_with_options(&signature.index(), &data, &mut patch, &options).expect("diff error");
+        let mut out = vec![];
+        apply(&base, &patch, &mut out).expect("apply error");
+        assert_eq!(data, out);
+        patch
+    };
+    let default = diff_options(DiffOptions::default());
+    assert!(default.len() < 500);
+    // the matches are all at odd offsets
+    assert!(
+        diff_options(DiffOptions {
+            match_alignment: NonZeroU32::new(2),
+            ..Default::default()
+        })
+        .len()
+            > data.len()
+    );
+    // only the single-block copy is dropped
+    let min_copy = diff_options(DiffOptions {
+        min_copy_len: NonZeroUsize::new(100),
+        ..Default::default()
+    });
+    assert!(min_copy.len() > default.len() + 50 && min_copy.len() < 500);
+    assert!(
+        diff_options(DiffOptions {
+            max_weak_sum_collisions: Some(0),
+            ..Default::default()
+        })
+        .len()
+            > data.len()
+    );
+}
+
+#[test]
+fn test_diff_state() {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 100000];
+    rng.fill(&mut base[..]);
+    let mut data = base[1000..40000].to_vec();
+    let mut new = vec![0; 70000];
+    rng.fill(&mut new[..]);
+    data.extend_from_slice(&new);
+    data.extend_from_slice(&base[50000..]);
+    data.extend_from_slice(&base[20000..20100]);
+    for &(rolling_hash, seed) in &[
+        (RollingHash::Rollsum, None),
+        (RollingHash::RabinKarp, None),
+        (RollingHash::RabinKarp, Some(7)),
+    ] {
+        let mut options = SignatureOptions::builder()
+            .block_size(64)
+            .crypto_hash_size(8)
+            .rolling_hash(rolling_hash);
+        if let Some(seed) = seed {
+            options = options.rolling_seed(seed);
+        }
+        let signature = Signature::calculate(&base, options.build().unwrap());
+        let index = signature.index();
+        let mut whole = vec![];
+        diff(&index, &data, &mut whole).unwrap();
+        for &chunk_size in &[1, 63, 64, 1000, data.len()] {
+            let mut state = DiffState::new(&index, &DiffOptions::default()).unwrap();
+            let mut patch = vec![];
+            for chunk in data.chunks(chunk_size) {
+                state.feed(chunk, &mut patch).unwrap();
+            }
+            state.finish(&mut patch).unwrap();
+            let mut out = vec![];
+            apply(&base, &patch, &mut out).unwrap();
+            assert_eq!(out, data);
+            // only literal framing differs
+            assert!(
+                patch.len() <= whole.len() + 8,
+                "{} {}",
+                patch.len(),
+                whole.len()
+            );
+        }
+    }
+
+    let signature = Signature::calculate(&base, SignatureOptions::default());
+    let index = signature.index();
+    let mut patch = vec![];
+    DiffState::new(&index, &DiffOptions::default())
+        .unwrap()
+        .finish(&mut patch)
+        .unwrap();
+    let mut out = vec![];
+    apply(&base, &patch, &mut out).unwrap();
+    assert!(out.is_empty());
+    assert!(DiffState::new(
+        &index,
+        &DiffOptions {
+            hash_key: Some(crate::HashKey::new([0; 32])),
+            ..Default::default()
+        }
+    )
+    .is_err());
+}
+
+#[test]
+fn test_diff_checkpoint() {
+    use crate::DiffError;
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 100000];
+    rng.fill(&mut base[..]);
+    let mut data = base[1000..40000].to_vec();
+    let mut new = vec![0; 30000];
+    rng.fill(&mut new[..]);
+    data.extend_from_slice(&new);
+    data.extend_from_slice(&new[..5000]);
+    data.extend_from_slice(&base[50000..]);
+    for &rolling_hash in &[RollingHash::Rollsum, RollingHash::RabinKarp] {
+        let signature_options = SignatureOptions::builder()
+            .block_size(64)
+            .crypto_hash_size(8)
+            .rolling_hash(rolling_hash)
+            .rolling_seed(3)
+            .build()
+            .unwrap();
+        let signature = Signature::calculate(&base, signature_options);
+        let index = signature.index();
+        for &self_copies in &[false, true] {
+            let options = DiffOptions {
+                self_copies,
+                ..Default::default()
+            };
+            let mut state = DiffState::new(&index, &options).unwrap();
+            let mut uninterrupted = vec![];
+            for chunk in data.chunks(1000) {
+                state.feed(chunk, &mut uninterrupted).unwrap();
+            }
+            state.finish(&mut uninterrupted).unwrap();
+
+            // save and resume between every piece
+            let mut state = DiffState::new(&index, &options).unwrap();
+            let mut patch = vec![];
+            let mut checkpoint = state.checkpoint().unwrap();
+            while state.consumed() < data.len() as u64 {
+                state = DiffState::resume(&index, &options, &checkpoint).unwrap();
+                let start = state.consumed() as usize;
+                let end = (start + 1000).min(data.len());
+                state.feed(&data[start..end], &mut patch).unwrap();
+                checkpoint = state.checkpoint().unwrap();
+            }
+            state.finish(&mut patch).unwrap();
+            assert_eq!(patch, uninterrupted);
+
+            assert!(matches!(
+                DiffState::resume(&index, &options, &checkpoint[..checkpoint.len() - 1]),
+                Err(DiffError::InvalidCheckpoint)
+            ));
+            let other_options = DiffOptions {
+                self_copies: !self_copies,
+                ..Default::default()
+            };
+            assert!(matches!(
+                DiffState::resume(&index, &other_options, &checkpoint),
+                Err(DiffError::InvalidCheckpoint)
+            ));
+        }
+        let other = Signature::calculate(&base[..50000], signature_options);
+        let checkpoint = DiffState::new(&index, &DiffOptions::default())
+            .unwrap()
+            .checkpoint()
+            .unwrap();
+        assert!(matches!(
+            DiffState::resume(&other.index(), &DiffOptions::default(), &checkpoint),
+            Err(DiffError::InvalidCheckpoint)
+        ));
+    }
+
+    let signature = Signature::calculate(&base, SignatureOptions::default());
+    let index = signature.index();
+    let options = DiffOptions {
+        checksum: true,
+        ..Default::default()
+    };
+    assert!(DiffState::new(&index, &options)
+        .unwrap()
+        .checkpoint()
+        .is_none());
+}
+
+#[test]
+fn test_diff_checkpoint_corrupt() {
+    use crate::blake2::{Blake2Hasher, BLAKE2_SIZE};
+    use crate::DiffError;
+    use std::num::NonZeroUsize;
+    let base: Vec<u8> = (0..50_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let data = [&base[10_000..20_000], &base[3000..3100], &base[..5000]].concat();
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 64,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let index = signature.index();
+    let options = DiffOptions {
+        self_copies: true,
+        min_copy_len: NonZeroUsize::new(200),
+        ..Default::default()
+    };
+    // forge the hash of a checkpoint after changing it, as an attacker could
+    let seal = |body: &[u8]| {
+        let mut hasher = Blake2Hasher::default();
+        hasher.update(body);
+        [body, &hasher.finalize()[..]].concat()
+    };
+    let mut state = DiffState::new(&index, &options).unwrap();
+    let mut patch = vec![];
+    for (i, chunk) in data.chunks(1000).enumerate().take(12) {
+        state.feed(chunk, &mut patch).unwrap();
+        if i % 4 != 2 {
+            continue;
+        }
+        let checkpoint = state.checkpoint().unwrap();
+        let rest = &data[state.consumed() as usize..];
+        for len in 0..checkpoint.len() {
+            assert!(matches!(
+                DiffState::resume(&index, &options, &checkpoint[..len]),
+                Err(DiffError::InvalidCheckpoint)
+            ));
+        }
+        let mut damaged = checkpoint.clone();
+        damaged[checkpoint.len() / 2] ^= 1;
+        assert!(DiffState::resume(&index, &options, &damaged).is_err());
+
+        // every field, but not every byte of the saved input or of the tables after it
+        let body = &checkpoint[..checkpoint.len() - BLAKE2_SIZE];
+        let buffer_len = u64::from_be_bytes(*arrayref::array_ref![body, 58, 8]) as usize;
+        let buffer_end = 66 + buffer_len;
+        let positions = (0..66)
+            .chain(buffer_end..buffer_end + 25)
+            .chain((buffer_end + 25..body.len()).step_by(13));
+        for position in positions {
+            let byte = body[position];
+            for &value in &[0, 0xff, byte ^ 1, byte ^ 0x80] {
+                let mut body = body.to_vec();
+                body[position] = value;
+                if let Ok(mut state) = DiffState::resume(&index, &options, &seal(&body)) {
+                    let mut out = vec![];
+                    let _ = state.feed(&rest[..rest.len().min(2000)], &mut out);
+                    let _ = state.finish(&mut out);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_diff_from_reader() {
+    use rand::Rng;
+    use std::io::Read;
+    let mut base = vec![0; 300_000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base[7..].to_vec();
+    data[150_000..150_100].fill(0);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 1000,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let index = signature.index();
+    // the reader hands out pieces which don't line up with blocks
+    let reader = (&data[..12345])
+        .chain(&data[12345..100_001])
+        .chain(&data[100_001..]);
+    let mut patch = vec![];
+    diff_from_reader(&index, reader, &mut patch).expect("diff error");
+    let mut out = vec![];
+    apply(&base, &patch, &mut out).expect("apply error");
+    assert_eq!(out, data);
+    assert!(patch.len() < 3000);
+
+    struct Failing;
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+    }
+    assert!(matches!(
+        diff_from_reader(&index, Failing, &mut vec![]),
+        Err(crate::DiffError::Io(_))
+    ));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn test_diff_async() {
+    use rand::Rng;
+    use tokio::io::AsyncReadExt;
+    let mut base = vec![0; 300_000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base[..200_000].to_vec();
+    data.extend_from_slice(b"inserted");
+    data.extend_from_slice(&base[200_000..]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 1000,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let index = signature.index();
+    let reader = (&data[..12345]).chain(&data[12345..]);
+    let mut patch = vec![];
+    crate::diff_async(&index, reader, &mut patch, &DiffOptions::default())
+        .await
+        .expect("diff error");
+    let mut sync_patch = vec![];
+    diff_from_reader(&index, &data[..], &mut sync_patch).expect("diff error");
+    assert_eq!(patch, sync_patch);
+    let mut out = vec![];
+    apply(&base, &patch, &mut out).expect("apply error");
+    assert_eq!(out, data);
+
+    // the options are passed on
+    let options = DiffOptions {
+        checksum: true,
+        ..Default::default()
+    };
+    let mut patch = vec![];
+    crate::diff_async(&index, &data[..], &mut patch, &options)
+        .await
+        .expect("diff error");
+    let mut sync_patch = vec![];
+    diff_with_options(&index, &data, &mut sync_patch, &options).expect("diff error");
+    assert_eq!(patch, sync_patch);
+    let options = DiffOptions {
+        max_delta_len: Some(10),
+        ..Default::default()
+    };
+    assert!(matches!(
+        crate::diff_async(&index, &data[..], vec![], &options).await,
+        Err(crate::DiffError::OutputLimit)
+    ));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn test_apply_async() {
+    use crate::ApplyError;
+    use rand::Rng;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, ReadBuf};
+
+    /// Reads a few bytes at a time, so that commands are split between reads.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl AsyncRead for Trickle<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let n = self.0.len().min(buf.remaining()).min(5);
+            buf.put_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    let mut base = vec![0; 100_000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base[..50_000].to_vec();
+    data.extend_from_slice(&[b'x'; 1000]);
+    data.extend_from_slice(&base[50_000..]);
+    let signature = Signature::calculate(&base, SignatureOptions::default());
+    let options = DiffOptions {
+        checksum: true,
+        ..Default::default()
+    };
+    let mut delta = vec![];
+    diff_with_options(&signature.index(), &data, &mut delta, &options).expect("diff error");
+    let mut out = vec![];
+    crate::apply_async(&base, &delta[..], &mut out, usize::MAX)
+        .await
+        .expect("apply error");
+    assert_eq!(out, data);
+    let mut out = vec![];
+    crate::apply_async(&base, Trickle(&delta), &mut out, usize::MAX)
+        .await
+        .expect("apply error");
+    assert_eq!(out, data);
+
+    assert!(matches!(
+        crate::apply_async(&base, &delta[..], &mut vec![], data.len() - 1).await,
+        Err(ApplyError::OutputLimit { .. })
+    ));
+    assert!(matches!(
+        crate::apply_async(&base, &delta[..delta.len() - 1], &mut vec![], usize::MAX).await,
+        Err(ApplyError::UnexpectedEof {
+            reading: "checksum",
+            ..
+        })
+    ));
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_diff_parallel() {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 9 << 20];
+    rng.fill(&mut base[..]);
+    // shift the data so that matches don't line up with the segments
+    let mut data = base[123..5_000_000].to_vec();
+    let mut new = vec![0; 10_000];
+    rng.fill(&mut new[..]);
+    data.extend_from_slice(&new);
+    data.extend_from_slice(&base[4_000_000..]);
+    for &rolling_hash in &[RollingHash::Rollsum, RollingHash::RabinKarp] {
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions {
+                block_size: 4096,
+                crypto_hash_size: 8,
+                rolling_hash,
+                ..Default::default()
+            },
+        );
+        let index = signature.index();
+        let mut patch = vec![];
+        crate::diff_parallel(&index, &data, &mut patch, &DiffOptions::default())
+            .expect("diff error");
+        let mut out = vec![];
+        apply(&base, &patch, &mut out).expect("apply error");
+        assert_eq!(out, data);
+        let mut sequential = vec![];
+        diff(&index, &data, &mut sequential).expect("diff error");
+        // each of the three seams can cost a block
+        assert!(patch.len() <= sequential.len() + 3 * 4200);
+    }
+    let signature = Signature::calculate(&base[..10], SignatureOptions::default());
+    let mut patch = vec![];
+    crate::diff_parallel(&signature.index(), &[], &mut patch, &DiffOptions::default())
+        .expect("diff error");
+    let mut out = vec![];
+    apply(&base[..10], &patch, &mut out).expect("apply error");
+    assert!(out.is_empty());
+
+    // the options are passed on
+    let key = crate::HashKey::new([42; 32]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 4096,
+            crypto_hash_size: 16,
+            hash: SignatureHash::KeyedBlake2,
+            hash_key: Some(key),
+            ..Default::default()
+        },
+    );
+    let index = signature.index();
+    let options = DiffOptions {
+        hash_key: Some(key),
+        checksum: true,
+        ..Default::default()
+    };
+    let mut patch = vec![];
+    crate::diff_parallel(&index, &data, &mut patch, &options).expect("diff error");
+    let mut out = vec![];
+    apply(&base, &patch, &mut out).expect("apply error");
+    assert_eq!(out, data);
+    assert!(patch.len() < 100_000);
+    assert!(matches!(
+        crate::diff_parallel(&index, &data, vec![], &DiffOptions::default()),
+        Err(crate::DiffError::InvalidSignature)
+    ));
+    let options = DiffOptions {
+        cancel: Some(std::sync::Arc::new(std::sync::atomic::AtomicBool::new(
+            true,
+        ))),
+        ..options
+    };
+    assert!(matches!(
+        crate::diff_parallel(&index, &data, vec![], &options),
+        Err(crate::DiffError::Cancelled)
+    ));
+    let options = DiffOptions {
+        cancel: None,
+        max_delta_len: Some(1000),
+        ..options
+    };
+    assert!(matches!(
+        crate::diff_parallel(&index, &data, vec![], &options),
+        Err(crate::DiffError::OutputLimit)
+    ));
+}
+
+#[test]
+fn test_diff_trailing_partial_block() {
+    use rand::Rng;
+    let mut base = vec![0; 10 * 1024 + 100];
+    rand::thread_rng().fill(&mut base[..]);
+    for &rolling_hash in &[RollingHash::Rollsum, RollingHash::RabinKarp] {
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions {
+                block_size: 1024,
+                crypto_hash_size: 8,
+                rolling_hash,
+                ..Default::default()
+            },
+        );
+        let index = signature.index();
+        // whole blocks followed by the short last block; and the short block on its own, after a
+        // literal
+        let mut after_literal = b"new".to_vec();
+        after_literal.extend_from_slice(&base[10 * 1024..]);
+        for data in &[&base[3 * 1024..], &after_literal[..]] {
+            let mut patch = vec![];
+            diff(&index, data, &mut patch).expect("diff error");
+            let mut state = DiffState::new(&index, &DiffOptions::default()).unwrap();
+            let mut streamed = vec![];
+            state.feed(data, &mut streamed).unwrap();
+            state.finish(&mut streamed).unwrap();
+            assert_eq!(patch, streamed);
+            // the tail is copied rather than sent as a literal
+            assert!(patch.len() < 30, "{}", patch.len());
+            let mut out = vec![];
+            apply(&base, &patch, &mut out).expect("apply error");
+            assert_eq!(&out, data);
+        }
+        // a tail which only partially matches the last block is sent as a literal
+        let mut patch = vec![];
+        diff(&index, &base[10 * 1024..base.len() - 1], &mut patch).expect("diff error");
+        assert!(patch.len() > 100);
+    }
+}
+
+#[test]
+fn test_diff_extend_matches() {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 64 * 1024];
+    rng.fill(&mut base[..]);
+    let mut data = base.clone();
+    // small edits which don't line up with blocks
+    for &at in &[1500, 5555, 20_001, 40_000] {
+        data[at] ^= 0xff;
+    }
+    data.splice(30_000..30_000, b"inserted".iter().copied());
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 1024,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let index = signature.index();
+    let mut plain = vec![];
+    diff(&index, &data, &mut plain).expect("diff error");
+    let mut unextended = vec![];
+    diff_with_base(
+        &index,
+        &base,
+        &data,
+        &mut unextended,
+        &DiffOptions::default(),
+    )
+    .expect("diff error");
+    assert_eq!(plain, unextended);
+    let mut extended = vec![];
+    let options = DiffOptions {
+        extend_matches: true,
+        ..Default::default()
+    };
+    diff_with_base(&index, &base, &data, &mut extended, &options).expect("diff error");
+    let mut out = vec![];
+    apply(&base, &extended, &mut out).expect("apply error");
+    assert_eq!(out, data);
+    // only the edited bytes are sent as literals
+    assert!(extended.len() < 100, "{}", extended.len());
+    assert!(plain.len() > 4 * 1024);
+}
+
+#[test]
+fn test_duplicate_blocks() {
+    use crate::{BlockIndex, DuplicateBlocks, IndexOptions};
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let (mut repeated, mut other) = (vec![0; 1024], vec![0; 1024]);
+    rng.fill(&mut repeated[..]);
+    rng.fill(&mut other[..]);
+    // blocks 0-3 and 5-8 are identical
+    let mut base = repeated.repeat(4);
+    base.extend_from_slice(&other);
+    base.extend_from_slice(&repeated.repeat(4));
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 1024,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let index = signature.index_with(&IndexOptions {
+        keep_duplicates: true,
+        ..Default::default()
+    });
+    let (weak_sum, crypto_hash) = signature.blocks().next().unwrap();
+    assert_eq!(index.find_block(weak_sum, crypto_hash), Some(8));
+    assert_eq!(index.find_block_near(weak_sum, crypto_hash, 0), Some(0));
+    assert_eq!(index.find_block_near(weak_sum, crypto_hash, 4), Some(3));
+    assert_eq!(index.find_block_near(weak_sum, crypto_hash, 6), Some(6));
+    assert_eq!(index.find_block_near(weak_sum, crypto_hash, 100), Some(8));
+    // without duplicates, there is nothing to choose from
+    assert_eq!(
+        signature.index().find_block_near(weak_sum, crypto_hash, 0),
+        Some(8)
+    );
+    assert!(index.memory_usage() > signature.index().memory_usage());
+    assert_eq!(
+        index
+            .clone()
+            .into_owned()
+            .find_block_near(weak_sum, crypto_hash, 0),
+        Some(0)
+    );
+
+    let diff_with = |duplicate_blocks| {
+        let mut patch = vec![];
+        let options = DiffOptions {
+            duplicate_blocks,
+            ..Default::default()
+        };
+        diff_with_options(&index, &base, &mut patch, &options).expect("diff error");
+        let mut out = vec![];
+        apply(&base, &patch, &mut out).expect("apply error");
+        assert_eq!(out, base);
+        patch
+    };
+    // every repeated block is copied from block 8
+    assert!(diff_with(DuplicateBlocks::Any).len() > 30);
+    // a single copy of the whole base
+    assert!(diff_with(DuplicateBlocks::NearPrevious).len() < 20);
+    assert!(diff_with(DuplicateBlocks::SamePosition).len() < 20);
+}
+
+#[test]
+fn test_sparse_search() {
+    use rand::Rng;
+    use std::num::NonZeroU64;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 50 * 1024];
+    rng.fill(&mut base[..]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 1024,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let index = signature.index();
+    let options = DiffOptions {
+        sparse_search_after: NonZeroU64::new(4096),
+        ..Default::default()
+    };
+    for &(unrelated_len, found) in &[(100 * 1024, true), (100 * 1024 + 1, false)] {
+        let mut data = vec![0; unrelated_len];
+        rng.fill(&mut data[..]);
+        data.extend_from_slice(&base);
+        let mut patch = vec![];
+        diff_with_options(&index, &data, &mut patch, &options).expect("diff error");
+        let mut out = vec![];
+        apply(&base, &patch, &mut out).expect("apply error");
+        assert_eq!(out, data);
+        // the base is only found where it lines up with the probes
+        assert_eq!(patch.len() < unrelated_len + 100, found);
+        let mut full = vec![];
+        diff(&index, &data, &mut full).expect("diff error");
+        assert!(full.len() < unrelated_len + 100);
+    }
+}
+
+#[test]
+fn test_search_stride() {
+    use rand::Rng;
+    use std::num::NonZeroU32;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 50 * 1024];
+    rng.fill(&mut base[..]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 1024,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let index = signature.index();
+    let options = DiffOptions {
+        max_search_stride: NonZeroU32::new(16),
+        ..Default::default()
+    };
+    for &unrelated_len in &[100 * 1024, 100 * 1024 + 1, 100 * 1024 + 7] {
+        let mut data = vec![0; unrelated_len];
+        rng.fill(&mut data[..]);
+        data.extend_from_slice(&base);
+        data.extend_from_slice(&base[..10 * 1024]);
+        let mut patch = vec![];
+        diff_with_options(&index, &data, &mut patch, &options).expect("diff error");
+        let mut out = vec![];
+        apply(&base, &patch, &mut out).expect("apply error");
+        assert_eq!(out, data);
+        // the base is found within 15 blocks, and the repeat right after it straight away
+        assert!(patch.len() < unrelated_len + 16 * 1024);
+        let mut full = vec![];
+        diff(&index, &data, &mut full).expect("diff error");
+        assert!(patch.len() < full.len() + 15 * 1024);
+    }
+}
+
+#[test]
+fn test_weak_sum_filter() {
+    use crate::IndexOptions;
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 100000];
+    rng.fill(&mut base[..]);
+    let mut data = base[5000..60000].to_vec();
+    let mut new = vec![0; 20000];
+    rng.fill(&mut new[..]);
+    data.extend_from_slice(&new);
+    data.extend_from_slice(&base[70000..]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 64,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let index = signature.index();
+    let filtered = signature.index_with(&IndexOptions {
+        weak_sum_filter: true,
+        ..Default::default()
+    });
+    assert!(filtered.memory_usage() > index.memory_usage());
+    let mut patch = vec![];
+    diff(&index, &data, &mut patch).expect("diff error");
+    let mut filtered_patch = vec![];
+    diff(&filtered, &data, &mut filtered_patch).expect("diff error");
+    assert_eq!(patch, filtered_patch);
+    let mut owned_patch = vec![];
+    diff(&filtered.into_owned(), &data, &mut owned_patch).expect("diff error");
+    assert_eq!(patch, owned_patch);
+}
+
+#[test]
+fn test_block_aligned() {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 256 * 1024];
+    rng.fill(&mut base[..]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 1024,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let index = signature.index();
+    let options = DiffOptions {
+        block_aligned: true,
+        ..Default::default()
+    };
+    // whole pages rewritten, and one added
+    let mut data = base.clone();
+    for &page in &[3, 4, 100, 255] {
+        rng.fill(&mut data[page * 1024..(page + 1) * 1024]);
+    }
+    data.extend_from_slice(&base[..1024]);
+    let mut patch = vec![];
+    diff_with_options(&index, &data, &mut patch, &options).expect("diff error");
+    let mut full = vec![];
+    diff(&index, &data, &mut full).expect("diff error");
+    assert_eq!(patch, full);
+    // shifted data doesn't line up with the blocks anymore
+    data.insert(0, 0);
+    let mut patch = vec![];
+    diff_with_options(&index, &data, &mut patch, &options).expect("diff error");
+    let mut out = vec![];
+    apply(&base, &patch, &mut out).expect("apply error");
+    assert_eq!(out, data);
+    assert!(patch.len() > data.len());
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_compress_literals() {
+    use crate::{apply_limited, ApplyError};
+    let base = b"the quick brown fox jumps over the lazy dog\n".repeat(100);
+    let mut data = base[..2000].to_vec();
+    data.extend_from_slice(&b"a new line which repeats itself\n".repeat(100));
+    data.extend_from_slice(&base[3000..]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 64,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let mut plain = vec![];
+    diff(&signature.index(), &data, &mut plain).expect("diff error");
+    let mut compressed = vec![];
+    let options = DiffOptions {
+        compress_literals: Some(3),
+        ..Default::default()
+    };
+    diff_with_options(&signature.index(), &data, &mut compressed, &options).expect("diff error");
+    assert!(compressed.len() < plain.len() / 4);
+    let mut out = vec![];
+    apply(&base, &compressed, &mut out).expect("apply error");
+    assert_eq!(out, data);
+    // the literal is checked against the limit before it is decompressed
+    assert!(matches!(
+        apply_limited(&base, &compressed, &mut vec![], 2500),
+        Err(ApplyError::OutputLimit {
+            what: "literal",
+            ..
+        })
+    ));
+    let mut corrupt = compressed.clone();
+    let len = corrupt.len();
+    corrupt[len - 20] ^= 0xff;
+    assert!(apply(&base, &corrupt, &mut vec![]).is_err());
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_compressed_literal_forged_size() {
+    use crate::ApplyError;
+    // a literal holding an empty zstd frame whose header claims `size` bytes
+    let delta = |size: u64| {
+        let mut delta = vec![0x66, 0x72, 0x02, 0x41, 0x41, 16];
+        delta.extend_from_slice(&[0x28, 0xb5, 0x2f, 0xfd, 0xe0]);
+        delta.extend_from_slice(&size.to_le_bytes());
+        delta.extend_from_slice(&[0x01, 0x00, 0x00, 0x00]);
+        delta
+    };
+    assert_eq!(delta(0).len(), 23);
+    let mut out = vec![];
+    apply(b"", &delta(0), &mut out).expect("apply error");
+    assert!(out.is_empty());
+    for size in [1, 100_000, 1 << 40, 1 << 62, u64::MAX - 1] {
+        assert!(matches!(
+            apply(b"", &delta(size), &mut vec![]),
+            Err(ApplyError::CorruptLiteral)
+        ));
+        // nor is the size trusted to place what comes after it
+        let mut file = std::io::Cursor::new(b"base".to_vec());
+        assert!(matches!(
+            crate::apply_in_place(&mut file, &delta(size), usize::MAX),
+            Err(ApplyError::CorruptLiteral)
+        ));
+        assert_eq!(file.into_inner(), b"base");
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+#[test]
+fn test_compress_literals_unsupported() {
+    use crate::DiffError;
+    let signature = Signature::calculate(b"base", SignatureOptions::default());
+    let options = DiffOptions {
+        compress_literals: Some(3),
+        ..Default::default()
+    };
+    assert!(matches!(
+        diff_with_options(&signature.index(), b"data", &mut vec![], &options),
+        Err(DiffError::Unsupported { feature: "zstd" })
+    ));
+}
+
+#[test]
+fn test_self_copies() {
+    use crate::consts::{DELTA_SELF_COPIES, EXTENDED_DELTA_MAGIC, SELF_COPY_DELTA_MAGIC};
+    use crate::ApplyError;
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand::rngs::StdRng::seed_from_u64(17);
+    let base: Vec<u8> = (0..20000).map(|_| rng.gen()).collect();
+    let new: Vec<u8> = (0..3000).map(|_| rng.gen()).collect();
+    let data = [
+        &base[..5000],
+        &new,
+        &base[5000..9000],
+        &new,
+        &new[..1234],
+        &base[9000..],
+    ]
+    .concat();
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 64,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let options = DiffOptions {
+        self_copies: true,
+        ..Default::default()
+    };
+    let mut plain = vec![];
+    diff(&signature.index(), &data, &mut plain).expect("diff error");
+    let mut delta = vec![];
+    diff_with_options(&signature.index(), &data, &mut delta, &options).expect("diff error");
+    // only the first copy of `new` is sent
+    assert!(delta.len() < 3500, "{}", delta.len());
+    assert!(plain.len() > 7000);
+    let mut out = vec![];
+    apply(&base, &delta, &mut out).expect("apply error");
+    assert_eq!(out, data);
+
+    let index = signature.index();
+    let mut state = DiffState::new(&index, &options).expect("diff error");
+    let mut streamed = vec![];
+    for piece in data.chunks(1000) {
+        state.feed(piece, &mut streamed).expect("diff error");
+    }
+    state.finish(&mut streamed).expect("diff error");
+    // which is dropped once it is written, but still repeats within a later literal are found
+    assert!(streamed.len() < plain.len() - 1000);
+    out.clear();
+    apply(&base, &streamed, &mut out).expect("apply error");
+    assert_eq!(out, data);
+
+    // a literal "abcd", then a copy of it, then a copy of output which isn't written yet
+    let mut delta = (EXTENDED_DELTA_MAGIC | DELTA_SELF_COPIES)
+        .to_be_bytes()
+        .to_vec();
+    delta.extend_from_slice(&[0x04, b'a', b'b', b'c', b'd', 0x55, 0, 4, 0]);
+    out.clear();
+    apply(&base, &delta, &mut out).expect("apply error");
+    assert_eq!(out, b"abcdabcd");
+    // the magic from before extensions had a bit each is still read
+    let mut legacy = SELF_COPY_DELTA_MAGIC.to_be_bytes().to_vec();
+    legacy.extend_from_slice(&delta[4..]);
+    out.clear();
+    apply(&base, &legacy, &mut out).expect("apply error");
+    assert_eq!(out, b"abcdabcd");
+    delta.pop();
+    delta.extend_from_slice(&[0x55, 6, 4, 0]);
+    assert!(matches!(
+        apply(&base, &delta, &mut vec![]),
+        Err(ApplyError::SelfCopyOutOfBounds {
+            offset: 6,
+            len: 4,
+            written: 8,
+            command: 0x55,
+            delta_offset: 12,
+        })
+    ));
+    // and at the same place in the delta when it's fed a byte at a time
+    let mut state = crate::ApplyState::new(&base, usize::MAX);
+    let error = delta
+        .chunks(1)
+        .find_map(|piece| state.feed(piece, &mut vec![]).err());
+    assert!(matches!(
+        error,
+        Some(ApplyError::SelfCopyOutOfBounds {
+            delta_offset: 12,
+            ..
+        })
+    ));
+    // which isn't allowed without the extension either
+    delta[..4].copy_from_slice(&0x72730236u32.to_be_bytes());
+    assert!(matches!(
+        apply(&base, &delta, &mut vec![]),
+        Err(ApplyError::UnknownCommand {
+            command: 0x55,
+            delta_offset: 9,
+        })
+    ));
+}
+
+#[quickcheck]
+fn test_apply_to_vec(base: Vec<u8>, data: Vec<u8>, garbage: Vec<u8>) -> bool {
+    use crate::{apply_limited, apply_to_vec, apply_to_vec_limited};
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 4,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let mut delta = vec![];
+    diff(&signature.index(), &data, &mut delta).expect("diff error");
+    let out = apply_to_vec(&base, &delta).expect("apply error");
+    // anything at all must fail the same way as with apply(), and not allocate too much
+    let garbage = [&delta[..4], &garbage].concat();
+    let limited = apply_to_vec_limited(&base, &garbage, 1000);
+    let mut expected = vec![];
+    let expected = apply_limited(&base, &garbage, &mut expected, 1000).map(|()| expected);
+    out == data
+        && out.capacity() == data.len()
+        && format!("{:?}", limited) == format!("{:?}", expected)
+        && limited.map_or(true, |out| out.capacity() <= 1000)
+}
+
+#[test]
+fn test_apply_into() {
+    use crate::{apply_into, ApplyError};
+    let base: Vec<u8> = (0..10000u32).map(|i| (i * 31 % 251) as u8).collect();
+    let data = [&base[..4000], b"some new data", &base[4000..]].concat();
+    let signature = Signature::calculate(&base, SignatureOptions::default());
+    let mut delta = vec![];
+    diff(&signature.index(), &data, &mut delta).expect("diff error");
+    let mut buffer = vec![0xff; data.len() + 10];
+    let len = apply_into(&base, &delta, &mut buffer).expect("apply error");
+    assert_eq!(&buffer[..len], &data[..]);
+    assert_eq!(buffer[len..], [0xff; 10]);
+    let len = apply_into(&base, &delta, &mut buffer[..data.len()]).expect("apply error");
+    assert_eq!(len, data.len());
+    assert!(matches!(
+        apply_into(&base, &delta, &mut buffer[..data.len() - 1]),
+        Err(ApplyError::OutputLimit { .. })
+    ));
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_apply_parallel() {
+    use crate::{apply_parallel, ApplyError};
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 3 << 20];
+    rng.fill(&mut base[..]);
+    let mut data = base[1000..].to_vec();
+    let mut new = vec![0; 100_000];
+    rng.fill(&mut new[..]);
+    data.extend_from_slice(&new);
+    data.extend_from_slice(&new);
+    data.extend_from_slice(&base[..5000]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 4096,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    for &(self_copies, checksum) in &[(false, false), (true, true)] {
+        let options = DiffOptions {
+            self_copies,
+            checksum,
+            #[cfg(feature = "zstd")]
+            compress_literals: if self_copies { Some(3) } else { None },
+            ..Default::default()
+        };
+        let mut delta = vec![];
+        diff_with_options(&signature.index(), &data, &mut delta, &options).expect("diff error");
+        let mut out = vec![0xff; data.len() + 10];
+        let len = apply_parallel(&base, &delta, &mut out).expect("apply error");
+        assert_eq!(len, data.len());
+        assert!(out[..len] == data[..]);
+        assert_eq!(out[len..], [0xff; 10]);
+        assert!(matches!(
+            apply_parallel(&base, &delta, &mut out[..data.len() - 1]),
+            Err(ApplyError::OutputLimit { .. })
+        ));
+    }
+}
+
+#[test]
+fn test_apply_chain() {
+    use crate::apply_chain;
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut versions = vec![vec![0; 50_000]];
+    rng.fill(&mut versions[0][..]);
+    for i in 0..4 {
+        let last = &versions[i];
+        let mut new = vec![0; 1000];
+        rng.fill(&mut new[..]);
+        let cut = rng.gen_range(0..last.len() - 5000);
+        let next = [&last[..cut], &new, &new, &last[cut + 5000..], &last[..3000]].concat();
+        versions.push(next);
+    }
+    let signature_options = SignatureOptions {
+        block_size: 64,
+        crypto_hash_size: 8,
+        ..Default::default()
+    };
+    for &(self_copies, checksum) in &[(false, false), (true, true)] {
+        let options = DiffOptions {
+            self_copies,
+            checksum,
+            #[cfg(feature = "zstd")]
+            compress_literals: if checksum { Some(3) } else { None },
+            ..Default::default()
+        };
+        let deltas: Vec<Vec<u8>> = versions
+            .windows(2)
+            .map(|pair| {
+                let signature = Signature::calculate(&pair[0], signature_options);
+                let mut delta = vec![];
+                diff_with_options(&signature.index(), &pair[1], &mut delta, &options)
+                    .expect("diff error");
+                delta
+            })
+            .collect();
+        let deltas: Vec<&[u8]> = deltas.iter().map(|delta| &delta[..]).collect();
+        for n in 0..=deltas.len() {
+            // the limit is on the versions the deltas create, not the base
+            let longest = versions[1..=n].iter().map(Vec::len).max().unwrap_or(0);
+            let mut out = vec![];
+            apply_chain(&versions[0], &deltas[..n], &mut out, longest).expect("apply error");
+            assert!(out == versions[n], "{} deltas", n);
+            if n > 0 {
+                let mut out = vec![];
+                assert!(matches!(
+                    apply_chain(&versions[0], &deltas[..n], &mut out, longest - 1),
+                    Err(crate::ApplyError::OutputLimit { .. })
+                ));
+                assert!(out.is_empty());
+            }
+        }
+        if checksum {
+            // the version in between is checked before anything is written
+            let mut bad = deltas[0].to_vec();
+            *bad.last_mut().unwrap() ^= 1;
+            let mut out = vec![];
+            assert!(matches!(
+                apply_chain(&versions[0], &[&bad, deltas[1]], &mut out, usize::MAX),
+                Err(crate::ApplyError::ChecksumMismatch)
+            ));
+            assert!(out.is_empty());
+        }
+    }
+}
+
+#[test]
+fn test_compose() {
+    use crate::compose;
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut a = vec![0; 50_000];
+    rng.fill(&mut a[..]);
+    let mut new = vec![0; 2000];
+    rng.fill(&mut new[..]);
+    let b = [&a[10_000..], &new[..1000], &a[..20_000]].concat();
+    let c = [
+        &b[..5000],
+        &new[1000..],
+        &b[40_000..],
+        &new,
+        &b[30_000..35_000],
+    ]
+    .concat();
+    let signature_options = SignatureOptions {
+        block_size: 64,
+        crypto_hash_size: 8,
+        ..Default::default()
+    };
+    let delta = |from: &[u8], to: &[u8], options: &DiffOptions| {
+        let signature = Signature::calculate(from, signature_options);
+        let mut delta = vec![];
+        diff_with_options(&signature.index(), to, &mut delta, options).expect("diff error");
+        delta
+    };
+    for &(self_copies, checksum) in &[(false, false), (true, true)] {
+        let options = DiffOptions {
+            self_copies,
+            checksum,
+            #[cfg(feature = "zstd")]
+            compress_literals: if checksum { Some(3) } else { None },
+            ..Default::default()
+        };
+        let ab = delta(&a, &b, &options);
+        let bc = delta(&b, &c, &options);
+        let ac = compose(&ab, &bc, usize::MAX).expect("compose error");
+        let mut out = vec![];
+        apply(&a, &ac, &mut out).expect("apply error");
+        assert!(out == c);
+        // only the new data is sent as literals
+        assert!(ac.len() < 6000, "{}", ac.len());
+        let summary = crate::validate_delta(&ac, a.len() as u64).unwrap();
+        assert_eq!(summary.has_checksum, checksum);
+        assert!(matches!(
+            compose(&ab, &bc, 100),
+            Err(crate::ApplyError::OutputLimit { .. })
+        ));
+    }
+    // copies of the second delta have to be within the output of the first
+    let short = delta(&a, &b[..1000], &DiffOptions::default());
+    let bc = delta(&b, &c, &DiffOptions::default());
+    assert!(matches!(
+        compose(&short, &bc, usize::MAX),
+        Err(crate::ApplyError::CopyOutOfBounds { .. })
+    ));
+
+    // self-copies which double B with every command, to 2^40 bytes in 40 pieces
+    use crate::consts::{DELTA_MAGIC, DELTA_SELF_COPIES, EXTENDED_DELTA_MAGIC};
+    let mut doubling = (EXTENDED_DELTA_MAGIC | DELTA_SELF_COPIES)
+        .to_be_bytes()
+        .to_vec();
+    doubling.extend_from_slice(&[0x01, b'a']);
+    for doubling_step in 0..40 {
+        doubling.push(0x64);
+        doubling.extend_from_slice(&0u64.to_be_bytes());
+        doubling.extend_from_slice(&(1u64 << doubling_step).to_be_bytes());
+    }
+    doubling.push(0);
+    let mut empty = DELTA_MAGIC.to_be_bytes().to_vec();
+    empty.push(0);
+    assert!(matches!(
+        compose(&doubling, &empty, 1 << 20),
+        Err(crate::ApplyError::OutputLimit { .. })
+    ));
+}
+
+#[test]
+fn test_rebase() {
+    use crate::{rebase, RebaseError};
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut old_base = vec![0; 100_000];
+    rng.fill(&mut old_base[..]);
+    let mut new = vec![0; 5000];
+    rng.fill(&mut new[..]);
+    // shifted, and with some of it changed
+    let new_base = [&new[..1000], &old_base[..60_000], &new[1000..]].concat();
+    let data = [&old_base[..50_000], &new[..2000], &old_base[70_000..]].concat();
+    let signature_options = SignatureOptions {
+        block_size: 256,
+        crypto_hash_size: 8,
+        ..Default::default()
+    };
+    let old_signature = Signature::calculate(&old_base, signature_options);
+    let new_signature = Signature::calculate(&new_base, signature_options);
+    let mut delta = vec![];
+    diff(&old_signature.index(), &data, &mut delta).expect("diff error");
+    assert!(matches!(
+        rebase(
+            &old_base[..1000],
+            &delta,
+            &new_signature.index(),
+            &DiffOptions::default(),
+            usize::MAX
+        ),
+        Err(RebaseError::InvalidDelta(
+            crate::ApplyError::CopyOutOfBounds { .. }
+        ))
+    ));
+    for &checksum in &[false, true] {
+        let options = DiffOptions {
+            checksum,
+            ..Default::default()
+        };
+        let mut delta = vec![];
+        diff_with_options(&old_signature.index(), &data, &mut delta, &options).expect("diff error");
+        let rebased = rebase(
+            &old_base,
+            &delta,
+            &new_signature.index(),
+            &options,
+            data.len(),
+        )
+        .expect("rebase error");
+        let mut out = vec![];
+        apply(&new_base, &rebased, &mut out).expect("apply error");
+        assert!(out == data);
+        // what's no longer in the base is sent as literals: the end of it, but not the start
+        assert!(
+            rebased.len() > 32_000 && rebased.len() < 33_000,
+            "{}",
+            rebased.len()
+        );
+        let summary = crate::validate_delta(&rebased, new_base.len() as u64).unwrap();
+        assert_eq!(summary.has_checksum, checksum);
+        assert!(matches!(
+            rebase(
+                &old_base,
+                &delta,
+                &new_signature.index(),
+                &options,
+                data.len() - 1
+            ),
+            Err(RebaseError::InvalidDelta(
+                crate::ApplyError::OutputLimit { .. }
+            ))
+        ));
+    }
+}
+
+#[test]
+fn test_apply_with_stats() {
+    use crate::{apply_with_stats, validate_delta, ApplyState};
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 100_000];
+    rng.fill(&mut base[..]);
+    let mut new = vec![0; 3000];
+    rng.fill(&mut new[..]);
+    let data = [&base[..40_000], &new, &new, &base[50_000..]].concat();
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 1000,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    for &self_copies in &[false, true] {
+        let options = DiffOptions {
+            self_copies,
+            ..Default::default()
+        };
+        let mut delta = vec![];
+        diff_with_options(&signature.index(), &data, &mut delta, &options).expect("diff error");
+        let mut out = vec![];
+        let stats = apply_with_stats(&base, &delta, &mut out).expect("apply error");
+        assert_eq!(out, data);
+        assert_eq!(stats.copies, 2);
+        assert_eq!(stats.copied_bytes, 90_000);
+        assert_eq!(stats.max_copy_len, 50_000);
+        assert_eq!(stats.self_copies, self_copies as u64);
+        assert_eq!(
+            stats.copied_bytes + stats.self_copied_bytes + stats.literal_bytes,
+            data.len() as u64
+        );
+        assert_eq!(
+            stats.commands(),
+            validate_delta(&delta, base.len() as u64).unwrap().commands
+        );
+        assert!(stats.copied_ratio() > 0.9);
+
+        let mut state = ApplyState::new(&base, usize::MAX);
+        for piece in delta.chunks(100) {
+            state.feed(piece, &mut vec![]).expect("apply error");
+        }
+        assert_eq!(state.stats(), stats);
+    }
+}
+
+#[test]
+fn test_apply_allow_trailing() {
+    use crate::{apply_with_options, ApplyError, ApplyOptions, ApplyState};
+    let base: Vec<u8> = (0..10000u32).map(|i| (i * 31 % 251) as u8).collect();
+    let data = [&base[..4000], b"some new data", &base[4000..]].concat();
+    let signature = Signature::calculate(&base, SignatureOptions::default());
+    for &checksum in &[false, true] {
+        let options = DiffOptions {
+            checksum,
+            ..Default::default()
+        };
+        let mut delta = vec![];
+        diff_with_options(&signature.index(), &data, &mut delta, &options).expect("diff error");
+        let framed = [&delta[..], b"the next frame"].concat();
+
+        let options = ApplyOptions::default();
+        assert!(matches!(
+            apply_with_options(&base, &framed, &mut vec![], &options),
+            Err(ApplyError::TrailingData { length: 14, .. })
+        ));
+        let options = ApplyOptions {
+            allow_trailing: true,
+            ..Default::default()
+        };
+        let mut out = vec![];
+        let len = apply_with_options(&base, &framed, &mut out, &options).expect("apply error");
+        assert_eq!(len, delta.len());
+        assert_eq!(out, data);
+
+        // and a byte at a time
+        let mut state = ApplyState::with_options(&base, &options);
+        out.clear();
+        for piece in framed.chunks(1) {
+            state.feed(piece, &mut out).expect("apply error");
+        }
+        assert_eq!(state.consumed(), delta.len() as u64);
+        state.finish().expect("apply error");
+        assert_eq!(out, data);
+
+        // but the delta still has to be complete
+        assert!(matches!(
+            apply_with_options(&base, &delta[..delta.len() - 1], &mut vec![], &options),
+            Err(ApplyError::UnexpectedEof { .. })
+        ));
+        let options = ApplyOptions {
+            limit: 100,
+            ..options
+        };
+        assert!(matches!(
+            apply_with_options(&base, &framed, &mut vec![], &options),
+            Err(ApplyError::OutputLimit { .. })
+        ));
+    }
+}
+
+#[test]
+fn test_apply_prefix() {
+    use crate::{apply_prefix, apply_resume};
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 50_000];
+    rng.fill(&mut base[..]);
+    let mut new = vec![0; 5000];
+    rng.fill(&mut new[..]);
+    let data = [&base[..20_000], &new, &new, &base[30_000..]].concat();
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 1000,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    for &(self_copies, checksum, compress) in &[
+        (false, false, false),
+        (true, true, false),
+        (false, false, true),
+    ] {
+        if compress && cfg!(not(feature = "zstd")) {
+            continue;
+        }
+        let options = DiffOptions {
+            self_copies,
+            checksum,
+            #[cfg(feature = "zstd")]
+            compress_literals: if compress { Some(3) } else { None },
+            ..Default::default()
+        };
+        let mut delta = vec![];
+        diff_with_options(&signature.index(), &data, &mut delta, &options).expect("diff error");
+
+        let mut out = vec![];
+        let prefix = apply_prefix(&base, &delta, &mut out, 22_222).expect("apply error");
+        assert_eq!(prefix.written, 22_222);
+        assert_eq!(out, &data[..22_222]);
+        let mut token = prefix.resume.expect("no resume token");
+        assert_eq!(token.written(), 22_222);
+
+        // the rest, a piece at a time, cutting off copies and literals alike
+        loop {
+            let before = out.len();
+            let prefix = apply_resume(&base, &delta, &token, &mut out, 7777).expect("apply error");
+            assert_eq!(prefix.written, (out.len() - before) as u64);
+            assert!(prefix.written <= 7777);
+            match prefix.resume {
+                Some(next) => {
+                    assert_eq!(next.written(), out.len() as u64);
+                    token = next
+                }
+                None => break,
+            }
+        }
+        assert_eq!(out, data);
+
+        let mut out = vec![];
+        let prefix = apply_prefix(&base, &delta, &mut out, data.len()).expect("apply error");
+        assert_eq!(prefix.written, data.len() as u64);
+        assert_eq!(prefix.resume, None);
+        assert_eq!(out, data);
+    }
+}
+
+#[quickcheck]
+fn test_apply_state(
+    base: Vec<u8>,
+    literal: Vec<u8>,
+    splits: Vec<usize>,
+    self_copies: bool,
+    checksum: bool,
+) {
+    use crate::{ApplyError, ApplyState};
+    let mut data = base[base.len() / 3..].to_vec();
+    data.extend_from_slice(&literal);
+    data.extend_from_slice(&base);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 4,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let options = DiffOptions {
+        self_copies,
+        checksum,
+        ..Default::default()
+    };
+    let mut delta = vec![];
+    diff_with_options(&signature.index(), &data, &mut delta, &options).expect("diff error");
+
+    let mut state = ApplyState::new(&base, usize::MAX);
+    let mut out = vec![];
+    let mut rest = &delta[..];
+    for split in splits {
+        let (head, tail) = rest.split_at(split % (rest.len() + 1));
+        state.feed(head, &mut out).expect("apply error");
+        rest = tail;
+    }
+    state.feed(rest, &mut out).expect("apply error");
+    state.finish().expect("apply error");
+    assert_eq!(out, data);
+
+    // only `finish` can tell that a delta is truncated
+    let mut state = ApplyState::new(&base, usize::MAX);
+    state
+        .feed(&delta[..delta.len() - 1], &mut vec![])
+        .expect("apply error");
+    assert!(matches!(
+        state.finish(),
+        Err(ApplyError::UnexpectedEof { .. })
+    ));
+
+    let mut state = ApplyState::new(&base, usize::MAX);
+    state.feed(&delta, &mut vec![]).expect("apply error");
+    assert_eq!(state.written(), data.len() as u64);
+    assert_eq!(state.consumed(), delta.len() as u64);
+    assert!(matches!(
+        state.feed(b"trailing", &mut vec![]),
+        Err(ApplyError::TrailingData {
+            length: 8,
+            delta_offset,
+        }) if delta_offset == delta.len() as u64
+    ));
+}
+
+#[test]
+fn test_apply_state_position() {
+    use crate::{ApplyError, ApplyState, DeltaWriter};
+    let base = b"0123456789";
+    let mut delta = DeltaWriter::new(vec![]).expect("write error");
+    delta.literal(b"abc").expect("write error");
+    delta.copy(2, 5).expect("write error");
+    delta.copy(8, 5).expect("write error");
+    let delta = delta.finish().expect("write error");
+    // magic, literal, first copy
+    let good = 4 + 4 + 3;
+
+    let mut state = ApplyState::new(base, usize::MAX);
+    let mut out = vec![];
+    state
+        .feed(&delta[..good + 1], &mut out)
+        .expect("apply error");
+    assert_eq!((state.written(), state.consumed()), (8, good as u64));
+    assert!(matches!(
+        state.feed(&delta[good + 1..], &mut out),
+        Err(ApplyError::CopyOutOfBounds { .. })
+    ));
+    assert_eq!(out, b"abc23456");
+    assert_eq!((state.written(), state.consumed()), (8, good as u64));
+
+    // a literal is counted as it arrives
+    let mut state = ApplyState::new(base, 5);
+    state.feed(&delta[..6], &mut vec![]).expect("apply error");
+    assert_eq!((state.written(), state.consumed()), (1, 6));
+    state.feed(&delta[6..], &mut vec![]).unwrap_err();
+    assert_eq!((state.written(), state.consumed()), (3, 8));
+}
+
+#[test]
+fn test_apply_from_reader() {
+    use crate::{apply_from_reader, ApplyError};
+    use rand::Rng;
+    use std::io::{self, Read};
+
+    /// Reads a few bytes at a time, and then fails if `fail` is set.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        fail: bool,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.is_empty() && self.fail {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            let n = self.data.len().min(buf.len()).min(7);
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    let mut base = vec![0; 100_000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base[20_000..].to_vec();
+    data.extend_from_slice(&[b'x'; 1000]);
+    data.extend_from_slice(&base[..20_000]);
+    let signature = Signature::calculate(&base, SignatureOptions::default());
+    let mut delta = vec![];
+    diff(&signature.index(), &data, &mut delta).expect("diff error");
+    let mut out = vec![];
+    apply_from_reader(&base, &delta[..], &mut out, usize::MAX).expect("apply error");
+    assert_eq!(out, data);
+    let mut out = vec![];
+    let reader = Trickle {
+        data: &delta,
+        fail: false,
+    };
+    apply_from_reader(&base, reader, &mut out, usize::MAX).expect("apply error");
+    assert_eq!(out, data);
+
+    assert!(matches!(
+        apply_from_reader(&base, &delta[..], &mut vec![], data.len() - 1),
+        Err(ApplyError::OutputLimit { .. })
+    ));
+    let reader = Trickle {
+        data: &delta[..delta.len() - 1],
+        fail: true,
+    };
+    assert!(matches!(
+        apply_from_reader(&base, reader, &mut vec![], usize::MAX),
+        Err(ApplyError::Io(_))
+    ));
+}
+
+#[quickcheck]
+fn test_delta_reader(base: Vec<u8>, literal: Vec<u8>, self_copies: bool, checksum: bool) {
+    use crate::{ApplyError, DeltaCommand, DeltaReader};
+    let mut data = base[base.len() / 2..].to_vec();
+    data.extend_from_slice(&literal);
+    data.extend_from_slice(&literal);
+    data.extend_from_slice(&base);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 4,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let options = DiffOptions {
+        self_copies,
+        checksum,
+        ..Default::default()
+    };
+    let mut delta = vec![];
+    diff_with_options(&signature.index(), &data, &mut delta, &options).expect("diff error");
+
+    let mut reader = DeltaReader::new(&delta)
+        .expect("read error")
+        .with_base_len(base.len() as u64);
+    assert_eq!(reader.has_self_copies(), self_copies);
+    let mut out = vec![];
+    while let Some(command) = reader.next() {
+        match command.expect("read error") {
+            DeltaCommand::Copy { offset, len } => {
+                out.extend_from_slice(&base[offset as usize..(offset + len) as usize])
+            }
+            DeltaCommand::Literal(literal) => out.extend_from_slice(literal),
+            DeltaCommand::SelfCopy { offset, len } => {
+                out.extend_from_within(offset as usize..(offset + len) as usize)
+            }
+            DeltaCommand::End => {}
+        }
+        assert_eq!(reader.output_len(), out.len() as u64);
+    }
+    assert_eq!(out, data);
+    assert_eq!(reader.checksum().is_some(), checksum);
+
+    fn last(delta: &[u8]) -> Result<DeltaCommand<'_>, ApplyError> {
+        DeltaReader::new(delta)
+            .expect("read error")
+            .last()
+            .expect("no commands")
+    }
+    assert!(matches!(
+        last(&delta[..delta.len() - 1]),
+        Err(ApplyError::UnexpectedEof { .. })
+    ));
+    let mut trailing = delta.clone();
+    trailing.push(0);
+    assert!(matches!(
+        last(&trailing),
+        Err(ApplyError::TrailingData {
+            length: 1,
+            delta_offset,
+        }) if delta_offset == delta.len() as u64
+    ));
+    if base.len() > 1 && data.len() > base.len() {
+        // there is a copy somewhere
+        let short = DeltaReader::new(&delta)
+            .expect("read error")
+            .with_base_len(1)
+            .find_map(Result::err);
+        assert!(matches!(short, Some(ApplyError::CopyOutOfBounds { .. })));
+    }
+}
+
+#[test]
+fn test_validate_delta() {
+    use crate::{validate_delta, ApplyError, DeltaReader};
+    use rand::Rng;
+    let mut base = vec![0; 100_000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base[20_000..].to_vec();
+    data.extend_from_slice(b"inserted");
+    data.extend_from_slice(&base[..10_000]);
+    let signature = Signature::calculate(&base, SignatureOptions::default());
+    let options = DiffOptions {
+        checksum: true,
+        ..Default::default()
+    };
+    let mut delta = vec![];
+    diff_with_options(&signature.index(), &data, &mut delta, &options).expect("diff error");
+    let summary = validate_delta(&delta, base.len() as u64).expect("invalid delta");
+    assert_eq!(summary.output_len, data.len() as u64);
+    assert!(summary.has_checksum);
+    assert_eq!(
+        summary.commands,
+        DeltaReader::new(&delta).expect("read error").count() as u64 - 1
+    );
+    assert!(matches!(
+        validate_delta(&delta, 50_000),
+        Err(ApplyError::CopyOutOfBounds {
+            data_len: 50_000,
+            ..
+        })
+    ));
+    assert!(matches!(
+        validate_delta(&delta[..delta.len() - 1], base.len() as u64),
+        Err(ApplyError::UnexpectedEof { .. })
+    ));
+}
+
+#[test]
+fn test_plan_apply() {
+    use crate::{plan_apply, DeltaWriter};
+    let mut delta = DeltaWriter::new(vec![]).expect("write error");
+    for &(offset, len) in &[(10, 5), (0, 5), (100, 1), (12, 10), (22, 3), (30, 1)] {
+        delta.copy(offset, len).expect("write error");
+        delta.literal(b"x").expect("write error");
+    }
+    let delta = delta.finish().expect("write error");
+    assert_eq!(
+        plan_apply(&delta).expect("invalid delta"),
+        [0..5, 10..25, 30..31, 100..101]
+    );
+    assert!(plan_apply(&delta[..delta.len() - 1]).is_err());
+}
+
+#[test]
+fn test_delta_reader_errors() {
+    use crate::consts::{DELTA_SELF_COPIES, EXTENDED_DELTA_MAGIC};
+    use crate::{ApplyError, DeltaReader};
+    assert!(matches!(
+        DeltaReader::new(b"rs"),
+        Err(ApplyError::UnexpectedEof {
+            reading: "magic",
+            ..
+        })
+    ));
+    assert!(matches!(
+        DeltaReader::new(b"not a delta"),
+        Err(ApplyError::WrongMagic { .. })
+    ));
+    // a self-copy, without the extension or from past the end of the output
+    let delta = [&0x72730236u32.to_be_bytes()[..], &[0x55, 0, 1, 0]].concat();
+    assert!(matches!(
+        DeltaReader::new(&delta).unwrap().next(),
+        Some(Err(ApplyError::UnknownCommand {
+            command: 0x55,
+            delta_offset: 4,
+        }))
+    ));
+    let magic = EXTENDED_DELTA_MAGIC | DELTA_SELF_COPIES;
+    let delta = [&magic.to_be_bytes()[..], &[0x01, 0, 0x55, 1, 1, 0]].concat();
+    let mut reader = DeltaReader::new(&delta).unwrap();
+    assert!(matches!(reader.next(), Some(Ok(_))));
+    assert!(matches!(
+        reader.next(),
+        Some(Err(ApplyError::SelfCopyOutOfBounds {
+            offset: 1,
+            len: 1,
+            written: 1,
+            command: 0x55,
+            delta_offset: 6,
+        }))
+    ));
+    assert!(reader.next().is_none());
+}
+
+#[test]
+fn test_transcode_delta() {
+    use crate::{apply, transcode_delta, ApplyError, DeltaFormat, DeltaReader};
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 20_000];
+    rng.fill(&mut base[..]);
+    let mut new = vec![0; 3000];
+    rng.fill(&mut new[..]);
+    let data = [&base[..8000], &new, &new, &base[9000..]].concat();
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 1000,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let options = DiffOptions {
+        self_copies: true,
+        checksum: true,
+        #[cfg(feature = "zstd")]
+        compress_literals: Some(3),
+        ..Default::default()
+    };
+    let mut delta = vec![];
+    diff_with_options(&signature.index(), &data, &mut delta, &options).expect("diff error");
+
+    // down to the librsync format
+    let plain = transcode_delta(&delta, None, &DeltaFormat::default(), data.len())
+        .expect("transcode error");
+    let reader = DeltaReader::new(&plain).unwrap();
+    assert!(!reader.has_self_copies() && !reader.has_compressed_literals());
+    assert_eq!(plain[..4], crate::consts::DELTA_MAGIC.to_be_bytes());
+    let mut out = vec![];
+    apply(&base, &plain, &mut out).expect("apply error");
+    assert_eq!(out, data);
+    assert!(plain.len() > new.len() * 2);
+    assert!(matches!(
+        transcode_delta(&delta, None, &DeltaFormat::default(), data.len() - 1),
+        Err(ApplyError::OutputLimit { .. })
+    ));
+
+    // and back up again, which needs the base for the checksum
+    let format = DeltaFormat {
+        self_copies: true,
+        checksum: true,
+        compress_literals: if cfg!(feature = "zstd") {
+            Some(3)
+        } else {
+            None
+        },
+    };
+    assert!(matches!(
+        transcode_delta(&plain, None, &format, usize::MAX),
+        Err(ApplyError::MissingBase)
+    ));
+    assert!(matches!(
+        transcode_delta(&plain, Some(&base), &format, data.len() - 1),
+        Err(ApplyError::OutputLimit { .. })
+    ));
+    let extended =
+        transcode_delta(&plain, Some(&base), &format, data.len()).expect("transcode error");
+    let summary = crate::validate_delta(&extended, base.len() as u64).unwrap();
+    assert!(summary.has_checksum);
+    let mut out = vec![];
+    apply(&base, &extended, &mut out).expect("apply error");
+    assert_eq!(out, data);
+    let mut wrong_base = base.clone();
+    wrong_base[0] ^= 1;
+    assert!(matches!(
+        apply(&wrong_base, &extended, &mut vec![]),
+        Err(ApplyError::ChecksumMismatch)
+    ));
+
+    // keeping the self-copies and the checksum of the delta
+    let format = DeltaFormat {
+        self_copies: true,
+        checksum: true,
+        ..Default::default()
+    };
+    let kept = transcode_delta(&delta, None, &format, data.len()).expect("transcode error");
+    if cfg!(feature = "zstd") {
+        assert!(kept.len() < plain.len());
+    } else {
+        assert_eq!(kept, delta);
+    }
+    let mut out = vec![];
+    apply(&base, &kept, &mut out).expect("apply error");
+    assert_eq!(out, data);
+    assert!(matches!(
+        transcode_delta(&delta, None, &format, data.len() - 1),
+        Err(ApplyError::OutputLimit { .. })
+    ));
+
+    #[cfg(not(feature = "zstd"))]
+    assert!(matches!(
+        transcode_delta(
+            &delta,
+            None,
+            &DeltaFormat {
+                compress_literals: Some(3),
+                ..Default::default()
+            },
+            usize::MAX
+        ),
+        Err(ApplyError::Unsupported { feature: "zstd" })
+    ));
+}
+
+#[test]
+fn test_optimize_delta() {
+    use crate::consts::{
+        DELTA_CHECKSUM, DELTA_SELF_COPIES, EXTENDED_DELTA_MAGIC, RS_OP_COPY_N8_N8, RS_OP_END,
+        RS_OP_LITERAL_1, RS_OP_LITERAL_N8, RS_OP_SELF_COPY_N8_N8,
+    };
+    use crate::{apply, optimize_delta, validate_delta};
+    let base: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 253) as u8).collect();
+    // a delta written without looking ahead: a copy and a literal in pieces, and every length
+    // in 8 bytes
+    let mut delta = (EXTENDED_DELTA_MAGIC | DELTA_SELF_COPIES | DELTA_CHECKSUM)
+        .to_be_bytes()
+        .to_vec();
+    let copy = |delta: &mut Vec<u8>, op, offset: u64, len: u64| {
+        delta.push(op);
+        delta.extend_from_slice(&offset.to_be_bytes());
+        delta.extend_from_slice(&len.to_be_bytes());
+    };
+    for i in 0..10 {
+        copy(&mut delta, RS_OP_COPY_N8_N8, 1000 + i * 100, 100);
+    }
+    for &piece in &[&b"some "[..], b"new ", b"data"] {
+        delta.push(RS_OP_LITERAL_N8);
+        delta.extend_from_slice(&(piece.len() as u64).to_be_bytes());
+        delta.extend_from_slice(piece);
+    }
+    // self-copies which can be merged, then one which reads what the one before it writes
+    copy(&mut delta, RS_OP_SELF_COPY_N8_N8, 0, 10);
+    copy(&mut delta, RS_OP_SELF_COPY_N8_N8, 10, 10);
+    copy(&mut delta, RS_OP_SELF_COPY_N8_N8, 1013, 20);
+    copy(&mut delta, RS_OP_SELF_COPY_N8_N8, 1033, 5);
+    delta.extend_from_slice(&[RS_OP_LITERAL_1, b'!', RS_OP_END]);
+    let mut expected = base[1000..2000].to_vec();
+    expected.extend_from_slice(b"some new data");
+    let from = expected[..20].to_vec();
+    expected.extend_from_slice(&from);
+    for i in 0..25 {
+        expected.push(expected[1013 + i]);
+    }
+    expected.push(b'!');
+    delta.extend_from_slice(&crate::blake2::blake2(None, &expected));
+
+    let mut out = vec![];
+    apply(&base, &delta, &mut out).expect("apply error");
+    assert_eq!(out, expected);
+    let optimized = optimize_delta(&delta).expect("optimize error");
+    let mut out = vec![];
+    apply(&base, &optimized, &mut out).expect("apply error");
+    assert_eq!(out, expected);
+    let summary = validate_delta(&optimized, base.len() as u64).unwrap();
+    assert!(summary.has_checksum);
+    // the copy, the literals and the first two self-copies are merged
+    assert_eq!(summary.commands, 6);
+    assert!(optimized.len() < delta.len() / 3);
+    assert_eq!(optimize_delta(&optimized).unwrap(), optimized);
+
+    // which diff() does already
+    let signature = Signature::calculate(&base, SignatureOptions::default());
+    let mut delta = vec![];
+    diff(&signature.index(), &expected, &mut delta).expect("diff error");
+    assert_eq!(optimize_delta(&delta).unwrap(), delta);
+}
+
+#[test]
+fn test_describe_delta() {
+    use crate::{describe_delta, ApplyError, DescribeOptions};
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 10000];
+    rng.fill(&mut base[..]);
+    let mut literal = b"a literal which is longer than the preview\n".to_vec();
+    literal.resize(2000, 0);
+    rng.fill(&mut literal[100..]);
+    let data = [&base[..4000], &literal, &literal, &base[4000..]].concat();
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 1000,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let options = DiffOptions {
+        self_copies: true,
+        checksum: true,
+        ..Default::default()
+    };
+    let mut delta = vec![];
+    diff_with_options(&signature.index(), &data, &mut delta, &options).expect("diff error");
+
+    let mut text = vec![];
+    describe_delta(&delta, &mut text, &DescribeOptions::default()).expect("describe error");
+    let text = String::from_utf8(text).unwrap();
+    let lines: Vec<_> = text.lines().collect();
+    assert_eq!(lines[0], "extended delta: self-copies, checksum");
+    assert!(lines[2].ends_with(&format!(
+        "output_offset=4000 literal len={} data=\"a literal which is longer than t\"...",
+        literal.len()
+    )));
+    assert!(lines[3].contains(&format!("self-copy offset=4000 len={}", literal.len())));
+    let checksum = crate::blake2::blake2(None, &data);
+    let hex: String = checksum.iter().map(|b| format!("{:02x}", b)).collect();
+    assert!(lines
+        .last()
+        .unwrap()
+        .ends_with(&format!("end checksum={}", hex)));
+
+    let options = DescribeOptions {
+        json: true,
+        preview_len: 10,
+    };
+    let mut json = vec![];
+    describe_delta(&delta, &mut json, &options).expect("describe error");
+    let json = String::from_utf8(json).unwrap();
+    let lines: Vec<_> = json.lines().collect();
+    assert_eq!(lines.len(), text.lines().count());
+    assert_eq!(
+        lines[0],
+        r#"{"format":"extended","extensions":["self-copies","checksum"]}"#
+    );
+    assert_eq!(
+        lines[1],
+        r#"{"delta_offset":4,"output_offset":0,"command":"copy","offset":0,"len":4000}"#
+    );
+    let hex: String = literal[..10].iter().map(|b| format!("{:02x}", b)).collect();
+    assert!(lines[2].ends_with(&format!(
+        r#""command":"literal","len":{},"data":"{}"}}"#,
+        literal.len(),
+        hex
+    )));
+
+    // the commands before a problem are still written
+    let mut out = vec![];
+    assert!(matches!(
+        describe_delta(&delta[..delta.len() - 1], &mut out, &options),
+        Err(ApplyError::UnexpectedEof { .. })
+    ));
+    assert_eq!(
+        String::from_utf8(out).unwrap().lines().count(),
+        lines.len() - 1
+    );
+}
+
+#[test]
+fn test_apply_verified() {
+    use crate::{apply_verified, ApplyError, OutputDigest};
+    use rand::Rng;
+    let mut base = vec![0; 100_000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base[..50_000].to_vec();
+    data.extend_from_slice(b"inserted");
+    data.extend_from_slice(&base[50_000..]);
+    let signature = Signature::calculate(&base, SignatureOptions::default());
+    let mut delta = vec![];
+    diff(&signature.index(), &data, &mut delta).expect("diff error");
+
+    let digests = [
+        OutputDigest::Blake2(crate::blake2::blake2(None, &data)),
+        #[cfg(feature = "blake3")]
+        OutputDigest::Blake3(*blake3::hash(&data).as_bytes()),
+        #[cfg(feature = "sha2")]
+        OutputDigest::Sha256(<sha2::Sha256 as sha2::Digest>::digest(&data).into()),
+        OutputDigest::RsyncMd4 {
+            seed: 0x12345678,
+            digest: crate::md4::md4(&[&[0x78, 0x56, 0x34, 0x12], &data[..]].concat()),
+        },
+    ];
+    for digest in &digests {
+        let mut out = vec![];
+        apply_verified(&base, &delta, &mut out, digest).expect("apply error");
+        assert_eq!(out, data);
+        let mut wrong_base = base.clone();
+        wrong_base[0] ^= 1;
+        assert!(matches!(
+            apply_verified(&wrong_base, &delta, &mut vec![], digest),
+            Err(ApplyError::DigestMismatch)
+        ));
+    }
+    // the seed is part of the checksum
+    let digest = OutputDigest::RsyncMd4 {
+        seed: 0,
+        digest: crate::md4::md4(&[&[0x78, 0x56, 0x34, 0x12], &data[..]].concat()),
+    };
+    assert!(matches!(
+        apply_verified(&base, &delta, &mut vec![], &digest),
+        Err(ApplyError::DigestMismatch)
+    ));
+}
+
+#[test]
+fn test_apply_seek() {
+    use crate::{apply_seek, apply_seek_limited, ApplyError};
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 300_000];
+    rng.fill(&mut base[..]);
+    let mut data = base[100_000..].to_vec();
+    data.extend_from_slice(b"new data");
+    data.extend_from_slice(&base[..150_000]);
+    let signature = Signature::calculate(&base, SignatureOptions::default());
+    let mut delta = vec![];
+    diff(&signature.index(), &data, &mut delta).expect("diff error");
+    let mut out = vec![];
+    apply_seek(Cursor::new(&base), &delta, &mut out).expect("apply error");
+    assert_eq!(out, data);
+
+    assert!(matches!(
+        apply_seek_limited(Cursor::new(&base), &delta, &mut vec![], data.len() - 1),
+        Err(ApplyError::OutputLimit { .. })
+    ));
+    assert!(matches!(
+        apply_seek(Cursor::new(&base[..200_000]), &delta, &mut vec![]),
+        Err(ApplyError::CopyOutOfBounds {
+            data_len: 200_000,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_apply_with_provider() {
+    use crate::{apply_with_provider, BaseProvider};
+    use rand::Rng;
+    use std::cell::RefCell;
+    use std::io;
+
+    /// Records the ranges which were read.
+    struct Ranges<'a>(&'a [u8], RefCell<Vec<(u64, usize)>>);
+
+    impl BaseProvider for Ranges<'_> {
+        fn size(&self) -> io::Result<u64> {
+            Ok(self.0.len() as u64)
+        }
+
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+            self.1.borrow_mut().push((offset, buf.len()));
+            self.0.read_at(offset, buf)
+        }
+    }
+
+    let mut base = vec![0; 100_000];
+    rand::thread_rng().fill(&mut base[..]);
+    let data = [&base[50_000..60_000], b"new data", &base[..1000]].concat();
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 1000,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let mut delta = vec![];
+    diff(&signature.index(), &data, &mut delta).expect("diff error");
+    let provider = Ranges(&base, RefCell::default());
+    let mut out = vec![];
+    apply_with_provider(&provider, &delta, &mut out, usize::MAX).expect("apply error");
+    assert_eq!(out, data);
+    assert_eq!(provider.1.into_inner(), [(50_000, 10_000), (0, 1000)]);
+
+    let mut out = vec![];
+    apply_with_provider(&base[..], &delta, &mut out, usize::MAX).expect("apply error");
+    assert_eq!(out, data);
+}
+
+#[test]
+fn test_checksum() {
+    use crate::{apply_limited, ApplyError};
+    let base: Vec<u8> = (0..10000u32).map(|i| (i * 31 % 251) as u8).collect();
+    let data = [&base[..4000], b"some new data", &base[4000..]].concat();
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 64,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let options = DiffOptions {
+        checksum: true,
+        ..Default::default()
+    };
+    let mut delta = vec![];
+    diff_with_options(&signature.index(), &data, &mut delta, &options).expect("diff error");
+    let mut out = vec![];
+    apply(&base, &delta, &mut out).expect("apply error");
+    assert_eq!(out, data);
+
+    // fed in pieces, the checksum is the same
+    let index = signature.index();
+    let mut state = DiffState::new(&index, &options).expect("diff error");
+    let mut streamed = vec![];
+    for piece in data.chunks(1000) {
+        state.feed(piece, &mut streamed).expect("diff error");
+    }
+    state.finish(&mut streamed).expect("diff error");
+    assert_eq!(streamed[streamed.len() - 32..], delta[delta.len() - 32..]);
+
+    // as if a block of the base had matched by mistake
+    let mut wrong_base = base.clone();
+    wrong_base[100] ^= 1;
+    assert!(matches!(
+        apply(&wrong_base, &delta, &mut vec![]),
+        Err(ApplyError::ChecksumMismatch)
+    ));
+    assert!(matches!(
+        apply(&base, &delta[..delta.len() - 1], &mut vec![]),
+        Err(ApplyError::UnexpectedEof {
+            reading: "checksum",
+            ..
+        })
+    ));
+    assert!(apply_limited(&base, &delta, &mut vec![], data.len()).is_ok());
+}
+
+#[cfg(target_pointer_width = "64")]
+#[test]
+fn test_incomplete_index() {
+    use crate::{similarity, BlockIndex, DiffError};
+    let signature = Signature::calculate(b"hello world", SignatureOptions::default());
+    let mut index = signature.index();
+    // pretend the signature has as many blocks as the index can hold, and then one more
+    index.block_count = u32::MAX as usize + 1;
+    assert!(index.is_complete());
+    diff(&index, b"hello world", &mut vec![]).expect("diff error");
+    index.block_count += 1;
+    assert!(!index.is_complete());
+    assert!(matches!(
+        diff(&index, b"hello world", &mut vec![]),
+        Err(DiffError::IncompleteIndex)
+    ));
+    assert!(matches!(
+        DiffState::new(&index, &DiffOptions::default()),
+        Err(DiffError::IncompleteIndex)
+    ));
+    assert!(matches!(
+        similarity(&index, b"hello world", &DiffOptions::default()),
+        Err(DiffError::IncompleteIndex)
+    ));
+    assert!(signature.index_flat().is_complete());
+}
+
+#[quickcheck]
+fn test_diff_commands(base: Vec<u8>, data: Vec<u8>, block_size: u8) -> bool {
+    use crate::{diff_commands, DiffCommand};
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: block_size as u32 + 1,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let mut out = vec![];
+    let mut commands = 0;
+    diff_commands(
+        &signature.index(),
+        &data,
+        &DiffOptions::default(),
+        |command| {
+            commands += 1;
+            match command {
+                DiffCommand::Copy { offset, len } => {
+                    out.extend_from_slice(&base[offset as usize..(offset + len) as usize])
+                }
+                DiffCommand::Literal(literal) => out.extend_from_slice(literal),
+            }
+            Ok(())
+        },
+    )
+    .expect("diff error");
+    // the commands are the same as in the serialized delta, which has a byte for each besides
+    // those in literals
+    let mut delta = vec![];
+    diff(&signature.index(), &data, &mut delta).expect("diff error");
+    out == data && commands <= delta.len() - 5
+}
+
+#[quickcheck]
+fn test_delta_writer(base: Vec<u8>, data: Vec<u8>, block_size: u8) -> bool {
+    use crate::{diff_commands, DeltaWriter, DiffCommand};
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: block_size as u32 + 1,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let mut writer = DeltaWriter::new(vec![]).unwrap();
+    diff_commands(
+        &signature.index(),
+        &data,
+        &DiffOptions::default(),
+        |command| match command {
+            DiffCommand::Copy { offset, len } => writer.copy(offset, len),
+            DiffCommand::Literal(literal) => writer.literal(literal),
+        },
+    )
+    .expect("diff error");
+    let mut delta = vec![];
+    diff(&signature.index(), &data, &mut delta).expect("diff error");
+    writer.finish().unwrap() == delta
+}
+
+#[test]
+fn test_delta_writer_coalescing() {
+    use crate::DeltaWriter;
+    let base: Vec<u8> = (0..=255).collect();
+    let mut writer = DeltaWriter::new(vec![]).unwrap();
+    writer.copy(10, 20).unwrap();
+    writer.copy(30, 5).unwrap();
+    writer.copy(100, 0).unwrap();
+    writer.literal(b"").unwrap();
+    writer.copy(35, 1).unwrap();
+    writer.literal(b"x").unwrap();
+    writer.copy(36, 4).unwrap();
+    writer.copy(0, 1).unwrap();
+    let delta = writer.finish().unwrap();
+    let mut single = DeltaWriter::new(vec![]).unwrap();
+    single.copy(10, 26).unwrap();
+    single.literal(b"x").unwrap();
+    single.copy(36, 4).unwrap();
+    single.copy(0, 1).unwrap();
+    assert_eq!(delta, single.finish().unwrap());
+    let mut out = vec![];
+    apply(&base, &delta, &mut out).expect("apply error");
+    assert_eq!(
+        out,
+        [&base[10..36], b"x", &base[36..40], &base[..1]].concat()
+    );
+    // the header, three copies, a literal and the end
+    assert_eq!(delta.len(), 4 + 3 + 2 + 3 + 3 + 1);
+}
+
+#[test]
+fn test_appended() {
+    use crate::{diff_commands, DiffCommand, DuplicateBlocks, IndexOptions};
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; (3 << 20) + 100];
+    rng.fill(&mut base[..]);
+    // some repeated blocks, which the index may find elsewhere
+    base.copy_within(..4096, 8192);
+    let mut data = base.clone();
+    data.extend_from_slice(b"appended");
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 1024,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    for &(duplicate_blocks, keep_duplicates) in &[
+        (DuplicateBlocks::Any, false),
+        (DuplicateBlocks::NearPrevious, true),
+        (DuplicateBlocks::SamePosition, true),
+    ] {
+        let index = signature.index_with(&IndexOptions {
+            keep_duplicates,
+            ..Default::default()
+        });
+        let options = DiffOptions {
+            duplicate_blocks,
+            ..Default::default()
+        };
+        let mut delta = vec![];
+        diff_with_options(&index, &data, &mut delta, &options).expect("diff error");
+        // the same as without looking for an appended-to base first
+        let mut state = DiffState::new(&index, &options).expect("diff error");
+        let mut streamed = vec![];
+        state.feed(&data, &mut streamed).expect("diff error");
+        state.finish(&mut streamed).expect("diff error");
+        assert_eq!(delta, streamed);
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).expect("apply error");
+        assert_eq!(out, data);
+    }
+
+    let options = DiffOptions {
+        duplicate_blocks: DuplicateBlocks::NearPrevious,
+        ..Default::default()
+    };
+    let index = signature.index_with(&IndexOptions {
+        keep_duplicates: true,
+        ..Default::default()
+    });
+    let mut commands = vec![];
+    diff_commands(&index, &data, &options, |command| {
+        commands.push(match command {
+            DiffCommand::Copy { offset, len } => (offset, len, vec![]),
+            DiffCommand::Literal(literal) => (0, 0, literal.to_vec()),
+        });
+        Ok(())
+    })
+    .expect("diff error");
+    assert_eq!(
+        commands,
+        [
+            (0, 3 << 20, vec![]),
+            (0, 0, [&base[3 << 20..], b"appended"].concat())
+        ]
+    );
+}
+
+#[test]
+fn test_diff_commands_error() {
+    use crate::{diff_commands, DiffError};
+    let signature = Signature::calculate(b"hello world", SignatureOptions::default());
+    let result = diff_commands(
+        &signature.index(),
+        b"hello there",
+        &DiffOptions::default(),
+        |_| Err(std::io::Error::other("stop")),
+    );
+    assert!(matches!(result, Err(DiffError::Io(_))));
+}
+
+#[quickcheck]
+fn test_estimate_delta_size(base: Vec<u8>, data: Vec<u8>, block_size: u8) -> bool {
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: block_size as u32 + 1,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let mut patch = vec![];
+    diff(&signature.index(), &data, &mut patch).expect("diff error");
+    crate::estimate_delta_size(&signature.index(), &data).expect("diff error") == patch.len() as u64
+}
+
+#[test]
+fn test_similarity() {
+    use crate::similarity;
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 1 << 20];
+    rng.fill(&mut base[..]);
+    let mut unrelated = vec![0; 1 << 20];
+    rng.fill(&mut unrelated[..]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 64,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let index = signature.index();
+    let options = DiffOptions::default();
+    // large enough to be sampled, and small enough to be searched exhaustively
+    for &len in &[1 << 20, 50_000] {
+        let mut half = base[3..3 + len / 2].to_vec();
+        half.extend_from_slice(&unrelated[..len / 2]);
+        let similar = similarity(&index, &base[base.len() - len..], &options).unwrap();
+        let half = similarity(&index, &half, &options).unwrap();
+        let dissimilar = similarity(&index, &unrelated[..len], &options).unwrap();
+        assert!(similar > 0.95, "{}", similar);
+        assert!((half - 0.5).abs() < 0.1, "{}", half);
+        assert!(dissimilar < 0.05, "{}", dissimilar);
+    }
+    assert_eq!(similarity(&index, &[], &options).unwrap(), 1.0);
+
+    // the final, partial block of the base, matched as diff() would
+    let short = &base[..100];
+    let signature = Signature::calculate(
+        short,
+        SignatureOptions {
+            block_size: 64,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let index = signature.index();
+    assert_eq!(similarity(&index, &short[64..], &options).unwrap(), 1.0);
+    assert_eq!(similarity(&index, short, &options).unwrap(), 1.0);
+    assert_eq!(similarity(&index, &unrelated[..36], &options).unwrap(), 0.0);
+
+    // keyed signatures need the key
+    let key = crate::HashKey::new([42; 32]);
+    let signature = Signature::calculate(
+        short,
         SignatureOptions {
-            block_size: block_size.saturating_add(1),
-            crypto_hash_size: crypto_hash_size % 16,
+            block_size: 64,
+            crypto_hash_size: 16,
+            hash: SignatureHash::KeyedBlake2,
+            hash_key: Some(key),
+            ..Default::default()
         },
     );
-    let serialized = signature.serialized().to_vec();
-    let deserialized = Signature::deserialize(serialized).expect("deserialization error");
-    assert_eq!(signature, deserialized);
+    let index = signature.index();
+    assert!(matches!(
+        similarity(&index, short, &options),
+        Err(crate::DiffError::InvalidSignature)
+    ));
+    let options = DiffOptions {
+        hash_key: Some(key),
+        ..Default::default()
+    };
+    assert_eq!(similarity(&index, short, &options).unwrap(), 1.0);
 }
 
 #[test]
-fn test_trivial() {
-    let data = vec![0; 100000];
+fn test_flush_interval() {
+    use rand::Rng;
+    use std::io::{self, Write};
+    use std::num::{NonZeroU64, NonZeroUsize};
+
+    /// Records how much had been written at each flush.
+    #[derive(Default)]
+    struct Flushes {
+        written: Vec<u8>,
+        flushed_at: Vec<usize>,
+    }
+
+    impl Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushed_at.push(self.written.len());
+            Ok(())
+        }
+    }
+
+    let mut rng = rand::thread_rng();
+    let mut data = vec![0; 100_000];
+    rng.fill(&mut data[..]);
+    let signature = Signature::calculate(&data[..1000], SignatureOptions::default());
+    let mut out = Flushes::default();
+    diff(&signature.index(), &data, &mut out).unwrap();
+    assert_eq!(out.flushed_at, [out.written.len()]);
+
+    let options = DiffOptions {
+        literal_segment_size: NonZeroUsize::new(1000),
+        flush_interval: NonZeroU64::new(4096),
+        ..Default::default()
+    };
+    let mut out = Flushes::default();
+    diff_with_options(&signature.index(), &data, &mut out, &options).unwrap();
+    assert_eq!(out.flushed_at.last(), Some(&out.written.len()));
+    let mut flushed = 0;
+    for &at in &out.flushed_at[..out.flushed_at.len() - 1] {
+        assert!(at - flushed >= 4096 && at - flushed < 4096 + 1010);
+        flushed = at;
+    }
+    assert!(out.written.len() - flushed < 4096 + 1010);
+}
+
+#[test]
+fn test_diff_progress() {
+    use crate::{diff_with_progress, DiffProgress};
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; (3 << 20) + 500_000];
+    rng.fill(&mut base[..]);
+    let mut data = base[1000..].to_vec();
+    rng.fill(&mut data[2_000_000..2_100_000]);
     let signature = Signature::calculate(
-        &data,
+        &base,
         SignatureOptions {
-            block_size: 64,
-            crypto_hash_size: 5,
+            block_size: 4096,
+            crypto_hash_size: 8,
+            ..Default::default()
         },
     );
-    let indexed = signature.index();
+    let index = signature.index();
+    let mut reports: Vec<DiffProgress> = vec![];
     let mut patch = vec![];
-    diff(&indexed, &data, &mut patch).expect("diff error");
-    let mut out = vec![];
-    assert!(patch.len() < 10000);
-    apply(&data, &patch, &mut out).expect("apply error");
-    assert_eq!(data, out);
+    diff_with_progress(
+        &index,
+        &data,
+        &mut patch,
+        &DiffOptions::default(),
+        |progress| reports.push(progress),
+    )
+    .expect("diff error");
+    let mut expected = vec![];
+    diff(&index, &data, &mut expected).expect("diff error");
+    assert_eq!(patch, expected);
+    assert!(reports.len() >= 4);
+    assert!(reports
+        .windows(2)
+        .all(|w| w[0].consumed <= w[1].consumed && w[0].emitted <= w[1].emitted));
+    assert_eq!(
+        reports.last(),
+        Some(&DiffProgress {
+            consumed: data.len() as u64,
+            emitted: patch.len() as u64,
+        })
+    );
 }
 
 #[test]
-fn test_delta_size() {
-    let mut data1 = b"hello".to_vec();
-    let mut data2 = b"goodbye".to_vec();
-    data1.resize(1 << 20, 0);
-    data2.resize(1 << 20, 0);
-
+fn test_diff_cancel() {
+    use crate::{diff_with_progress, DiffError};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    let base = vec![7; 4 << 20];
     let signature = Signature::calculate(
-        &data1,
+        &base,
         SignatureOptions {
             block_size: 4096,
             crypto_hash_size: 8,
+            ..Default::default()
         },
     );
+    let index = signature.index();
+    let options = DiffOptions {
+        cancel: Some(Arc::new(AtomicBool::new(false))),
+        ..Default::default()
+    };
+    let cancel = options.cancel.clone().unwrap();
+    let mut reports = 0;
+    let result = diff_with_progress(&index, &base, vec![], &options, |_| {
+        reports += 1;
+        cancel.store(true, Ordering::Relaxed);
+    });
+    assert!(matches!(result, Err(DiffError::Cancelled)));
+    assert_eq!(reports, 1);
+    // a flag which isn't set doesn't get in the way
+    cancel.store(false, Ordering::Relaxed);
     let mut patch = vec![];
-    diff(&signature.index(), &data2, &mut patch).expect("diff error");
+    diff_with_options(&index, &base, &mut patch, &options).expect("diff error");
     let mut out = vec![];
-    assert!(patch.len() < 10000);
-    apply(&data1, &patch, &mut out).expect("apply error");
-    assert_eq!(data2, out);
+    apply(&base, &patch, &mut out).expect("apply error");
+    assert_eq!(out, base);
+
+    // a DiffState checks the flag too, and can carry on once it is cleared
+    let mut expected = vec![];
+    let mut state = DiffState::new(&index, &options).expect("diff error");
+    state.feed(&base, &mut expected).expect("diff error");
+    state.finish(&mut expected).expect("diff error");
+    let mut state = DiffState::new(&index, &options).expect("diff error");
+    let mut streamed = vec![];
+    cancel.store(true, Ordering::Relaxed);
+    assert!(matches!(
+        state.feed(&base, &mut streamed),
+        Err(DiffError::Cancelled)
+    ));
+    cancel.store(false, Ordering::Relaxed);
+    state.finish(&mut streamed).expect("diff error");
+    assert_eq!(streamed, expected);
+    let state = DiffState::new(&index, &options).expect("diff error");
+    cancel.store(true, Ordering::Relaxed);
+    assert!(matches!(
+        state.finish(&mut vec![]),
+        Err(DiffError::Cancelled)
+    ));
 }
 
 #[test]
-fn test_random() {
+fn test_diff_limited() {
+    use crate::{diff_limited, DiffError};
     use rand::Rng;
+    let mut rng = rand::thread_rng();
     let mut base = vec![0; 100000];
-    rand::thread_rng().fill(&mut base[..]);
-    let mut data = vec![0; 100000];
-    rand::thread_rng().fill(&mut data[..]);
+    rng.fill(&mut base[..]);
+    let mut data = base[..50000].to_vec();
+    data.extend_from_slice(&[1; 1000]);
     let signature = Signature::calculate(
         &base,
         SignatureOptions {
-            block_size: 4,
+            block_size: 64,
             crypto_hash_size: 8,
+            ..Default::default()
         },
     );
-    let indexed = signature.index();
+    let index = signature.index();
     let mut patch = vec![];
-    diff(&indexed, &data, &mut patch).expect("diff error");
+    diff(&index, &data, &mut patch).expect("diff error");
+    let mut limited = vec![];
+    diff_limited(&index, &data, &mut limited, patch.len()).expect("diff error");
+    assert_eq!(limited, patch);
+    let mut limited = vec![];
+    let result = diff_limited(&index, &data, &mut limited, patch.len() - 1);
+    assert!(matches!(result, Err(DiffError::OutputLimit)));
+    assert!(limited.len() < patch.len());
+
+    // unrelated data is given up on long before it is all searched
+    let mut data = vec![0; 8 << 20];
+    rng.fill(&mut data[..]);
+    let mut consumed = 0;
+    let options = DiffOptions {
+        max_delta_len: Some(data.len() as u64 / 2),
+        ..Default::default()
+    };
+    let result = crate::diff_with_progress(&index, &data, vec![], &options, |progress| {
+        consumed = progress.consumed
+    });
+    assert!(matches!(result, Err(DiffError::OutputLimit)));
+    assert!(consumed < data.len() as u64);
+}
+
+#[test]
+fn test_diff_deterministic() {
+    use crate::{DuplicateBlocks, IndexOptions};
+    // fixed pseudo-random data, so the delta can be compared against a known one
+    let mut state = 1u32;
+    let mut base: Vec<u8> = (0..2048)
+        .map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect();
+    // blocks 2 and 20 are identical
+    base.copy_within(128..192, 1280);
+    let mut data = base[64..192].to_vec();
+    data.extend_from_slice(b"some new data");
+    data.extend_from_slice(&base[1000..1100]);
+    data.extend_from_slice(&base[1984..]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 64,
+            crypto_hash_size: 8,
+            ..Default::default()
+        },
+    );
+    let options = DiffOptions {
+        deterministic: true,
+        duplicate_blocks: DuplicateBlocks::NearPrevious,
+        ..Default::default()
+    };
+    #[rustfmt::skip]
+    let mut expected = vec![
+        0x72, 0x73, 0x02, 0x36,
+        // blocks 1 and 20, rather than the identical block 2 which would continue the copy
+        0x45, 0x40, 0x40,
+        0x49, 0x05, 0x00, 0x40,
+        0x25, b's', b'o', b'm', b'e', b' ', b'n', b'e', b'w', b' ', b'd', b'a', b't', b'a',
+    ];
+    expected.extend_from_slice(&base[1000..1024]);
+    expected.extend_from_slice(&[0x49, 0x04, 0x00, 0x40, 0x0c]);
+    expected.extend_from_slice(&base[1088..1100]);
+    expected.extend_from_slice(&[0x49, 0x07, 0xc0, 0x40, 0x00]);
+    let check = |index: &dyn Fn(&mut Vec<u8>, &DiffOptions)| {
+        let mut delta = vec![];
+        index(&mut delta, &options);
+        assert_eq!(delta, expected);
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).expect("apply error");
+        assert_eq!(out, data);
+        delta
+    };
+    let delta = check(&|delta, options| {
+        diff_with_options(&signature.index(), &data, delta, options).expect("diff error")
+    });
+    let indexed = signature.index_with(&IndexOptions {
+        keep_duplicates: true,
+        ..Default::default()
+    });
+    for other in &[
+        check(&|delta, options| {
+            diff_with_options(&indexed, &data, delta, options).expect("diff error")
+        }),
+        check(&|delta, options| {
+            diff_with_options(&signature.index_flat(), &data, delta, options).expect("diff error")
+        }),
+        check(&|delta, options| {
+            diff_with_options(&signature.index().into_owned(), &data, delta, options)
+                .expect("diff error")
+        }),
+        check(&|delta, options| {
+            diff_with_base(&signature.index(), &base, &data, delta, options).expect("diff error")
+        }),
+    ] {
+        assert_eq!(other, &delta);
+    }
+    // without the guarantee, the index which knows about identical blocks does better
+    let mut near = vec![];
+    diff_with_options(
+        &indexed,
+        &data,
+        &mut near,
+        &DiffOptions {
+            deterministic: false,
+            ..options.clone()
+        },
+    )
+    .expect("diff error");
+    assert!(near.len() < delta.len());
+}
+
+#[test]
+fn test_signature_interoperability() {
+    // interoperability: we generate identical signatures to librsync
+    use rand::Rng;
+    for &(hash, librsync_hash, strong_lens) in &[
+        (
+            SignatureHash::Md4,
+            librsync::SignatureType::MD4,
+            &[1, 8, 16][..],
+        ),
+        (
+            SignatureHash::Blake2,
+            librsync::SignatureType::Blake2,
+            &[1, 8, 16, 32][..],
+        ),
+    ] {
+        for &block_len in &[10, 1024] {
+            for &strong_len in strong_lens {
+                for &len in &[0, 1, 2, 10, 128, 500, 1111, 2000, 2048] {
+                    let mut data = vec![0; len];
+                    rand::thread_rng().fill(&mut data[..]);
+                    let mut librsync_out = vec![];
+                    librsync::whole::signature_with_options(
+                        &mut &data[..],
+                        &mut librsync_out,
+                        block_len,
+                        strong_len,
+                        librsync_hash,
+                    )
+                    .unwrap();
+                    let signature = Signature::calculate(
+                        &data,
+                        SignatureOptions {
+                            block_size: block_len as u32,
+                            crypto_hash_size: strong_len as u32,
+                            hash,
+                            ..Default::default()
+                        },
+                    );
+                    let serialized = signature.into_serialized();
+                    assert_eq!(
+                        librsync_out, serialized,
+                        "hash={:?}, block_len={}, strong_len={}, len={}",
+                        hash, block_len, strong_len, len
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_blake2() {
+    use rand::Rng;
+    let mut base = vec![0; 100000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base.clone();
+    data[50000..51000].copy_from_slice(&[7; 1000]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions {
+            block_size: 1024,
+            crypto_hash_size: 32,
+            hash: SignatureHash::Blake2,
+            ..Default::default()
+        },
+    );
+    let deserialized =
+        Signature::deserialize(signature.serialized().to_vec()).expect("deserialization error");
+    assert_eq!(signature, deserialized);
+    let mut patch = vec![];
+    diff(&signature.index(), &data, &mut patch).expect("diff error");
+    assert!(patch.len() < 5000);
     let mut out = vec![];
     apply(&base, &patch, &mut out).expect("apply error");
     assert_eq!(data, out);
+}
 
-    // interoperability: we can apply patches generated by librsync
-    let serialized_signature = signature.into_serialized();
-    let mut librsync_patch = vec![];
-    librsync::whole::delta(
-        &mut &data[..],
-        &mut &serialized_signature[..],
-        &mut librsync_patch,
-    )
-    .unwrap();
-    out.clear();
-    apply(&base, &librsync_patch, &mut out).expect("apply error");
-    assert_eq!(data, out);
+#[cfg(feature = "blake3")]
+#[test]
+fn test_blake3() {
+    use rand::Rng;
+    let mut base = vec![0; 100000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base.clone();
+    data[50000..51000].copy_from_slice(&[7; 1000]);
+    for &(rolling_hash, magic) in &[
+        (RollingHash::Rollsum, crate::consts::BLAKE3_MAGIC),
+        (RollingHash::RabinKarp, crate::consts::RK_BLAKE3_MAGIC),
+    ] {
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions {
+                block_size: 1024,
+                crypto_hash_size: 32,
+                hash: SignatureHash::Blake3,
+                rolling_hash,
+                ..Default::default()
+            },
+        );
+        assert_eq!(signature.serialized()[..4], magic.to_be_bytes());
+        let deserialized =
+            Signature::deserialize(signature.serialized().to_vec()).expect("deserialization error");
+        assert_eq!(signature, deserialized);
+        let mut patch = vec![];
+        diff(&signature.index(), &data, &mut patch).expect("diff error");
+        assert!(patch.len() < 5000);
+        let mut out = vec![];
+        apply(&base, &patch, &mut out).expect("apply error");
+        assert_eq!(data, out);
+    }
+}
 
-    // interoperability: librsync can apply our patches
-    out.clear();
-    librsync::whole::patch(&mut Cursor::new(&base[..]), &mut &patch[..], &mut out).unwrap();
-    assert_eq!(data, out);
+#[cfg(feature = "xxhash")]
+#[test]
+fn test_xxh3() {
+    use rand::Rng;
+    let mut base = vec![0; 100000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base.clone();
+    data[50000..51000].copy_from_slice(&[7; 1000]);
+    for &(rolling_hash, magic) in &[
+        (RollingHash::Rollsum, crate::consts::XXH3_MAGIC),
+        (RollingHash::RabinKarp, crate::consts::RK_XXH3_MAGIC),
+    ] {
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions {
+                block_size: 1024,
+                crypto_hash_size: 16,
+                hash: SignatureHash::Xxh3,
+                rolling_hash,
+                ..Default::default()
+            },
+        );
+        assert_eq!(signature.serialized()[..4], magic.to_be_bytes());
+        let deserialized =
+            Signature::deserialize(signature.serialized().to_vec()).expect("deserialization error");
+        assert_eq!(signature, deserialized);
+        let mut patch = vec![];
+        diff(&signature.index(), &data, &mut patch).expect("diff error");
+        assert!(patch.len() < 5000);
+        let mut out = vec![];
+        apply(&base, &patch, &mut out).expect("apply error");
+        assert_eq!(data, out);
+    }
 }
 
 #[test]
-fn test_signature_interoperability() {
-    // interoperability: we generate identical signatures to librsync
+fn test_keyed_blake2() {
+    use crate::HashKey;
     use rand::Rng;
-    for &block_len in &[10, 1024] {
-        for &strong_len in &[1, 8, 16] {
-            for &len in &[0, 1, 2, 10, 128, 500, 1111, 2000, 2048] {
-                let mut data = vec![0; len];
-                rand::thread_rng().fill(&mut data[..]);
-                let mut librsync_out = vec![];
-                librsync::whole::signature_with_options(
-                    &mut &data[..],
-                    &mut librsync_out,
-                    block_len,
-                    strong_len,
-                    librsync::SignatureType::MD4,
-                )
-                .unwrap();
-                let signature = Signature::calculate(
-                    &data,
-                    SignatureOptions {
-                        block_size: block_len as u32,
-                        crypto_hash_size: strong_len as u32,
-                    },
-                );
-                let serialized = signature.into_serialized();
-                assert_eq!(
-                    librsync_out, serialized,
-                    "block_len={}, strong_len={}, len={}",
-                    block_len, strong_len, len
-                );
+    let mut base = vec![0; 100000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base.clone();
+    data[50000..51000].copy_from_slice(&[7; 1000]);
+    let key = HashKey::new([42; 32]);
+    let options = SignatureOptions {
+        block_size: 1024,
+        crypto_hash_size: 16,
+        hash: SignatureHash::KeyedBlake2,
+        hash_key: Some(key),
+        ..Default::default()
+    };
+    let signature = Signature::calculate(&base, options);
+    assert_eq!(
+        signature.serialized()[..4],
+        crate::consts::KEYED_BLAKE2_MAGIC.to_be_bytes()
+    );
+    // the key is what makes the hashes unpredictable
+    let unkeyed = Signature::calculate(
+        &base,
+        SignatureOptions {
+            hash: SignatureHash::Blake2,
+            hash_key: None,
+            ..options
+        },
+    );
+    assert_ne!(signature.serialized()[4..], unkeyed.serialized()[4..],);
+    assert!(!format!("{:?}", options).contains("42"));
+
+    let diff_keyed = |hash_key| {
+        let mut patch = vec![];
+        diff_with_options(
+            &signature.index(),
+            &data,
+            &mut patch,
+            &DiffOptions {
+                hash_key,
+                ..Default::default()
+            },
+        )
+        .map(|()| patch)
+    };
+    let patch = diff_keyed(Some(key)).expect("diff error");
+    assert!(patch.len() < 5000);
+    let mut out = vec![];
+    apply(&base, &patch, &mut out).expect("apply error");
+    assert_eq!(data, out);
+    // with the wrong key nothing matches, but the delta is still correct
+    let patch = diff_keyed(Some(HashKey::new([0; 32]))).expect("diff error");
+    assert!(patch.len() > data.len());
+    assert!(diff_keyed(None).is_err());
+
+    assert_eq!(
+        Signature::try_calculate(
+            &base,
+            SignatureOptions {
+                hash_key: None,
+                ..options
+            }
+        ),
+        Err(crate::InvalidOptions::HashKeyMismatch)
+    );
+    assert_eq!(
+        Signature::try_calculate(
+            &base,
+            SignatureOptions {
+                hash: SignatureHash::Md4,
+                ..options
             }
+        ),
+        Err(crate::InvalidOptions::HashKeyMismatch)
+    );
+}
+
+#[test]
+fn test_rolling_seed() {
+    use rand::Rng;
+    let mut base = vec![0; 100000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base.clone();
+    data[50000..51000].copy_from_slice(&[7; 1000]);
+    data.drain(10000..10007);
+    for &rolling_hash in &[RollingHash::Rollsum, RollingHash::RabinKarp] {
+        let options = SignatureOptions {
+            block_size: 1024,
+            crypto_hash_size: 8,
+            rolling_hash,
+            rolling_seed: Some(0xdeadbeef),
+            ..Default::default()
+        };
+        let signature = Signature::calculate(&base, options);
+        assert_eq!(
+            signature.serialized()[..4],
+            crate::consts::EXTENDED_MAGIC.to_be_bytes()
+        );
+        let deserialized =
+            Signature::deserialize(signature.serialized().to_vec()).expect("deserialization error");
+        assert_eq!(signature, deserialized);
+        assert_eq!(signature.options().rolling_seed, Some(0xdeadbeef));
+
+        // the strong hashes are the same, but the rolling checksums depend on the seed
+        let unseeded = Signature::calculate(
+            &base,
+            SignatureOptions {
+                rolling_seed: None,
+                ..options
+            },
+        );
+        assert_eq!(signature.blocks().len(), unseeded.blocks().len());
+        for (seeded, unseeded) in signature.blocks().zip(unseeded.blocks()) {
+            assert_ne!(seeded.0, unseeded.0);
+            assert_eq!(seeded.1, unseeded.1);
+        }
+        let len = base.len() as u64;
+        assert!(Signature::concat(&[(signature.clone(), len), (unseeded, len)]).is_err());
+
+        let mut patch = vec![];
+        diff(&signature.index(), &data, &mut patch).expect("diff error");
+        assert!(patch.len() < 5000);
+        let mut out = vec![];
+        apply(&base, &patch, &mut out).expect("apply error");
+        assert_eq!(data, out);
+
+        let mut table = vec![];
+        DiskIndexedSignature::write(&signature, &mut table).expect("write error");
+        let disk_index = DiskIndexedSignature::new(&table[..]).expect("parse error");
+        let mut disk_patch = vec![];
+        diff(&disk_index, &data, &mut disk_patch).expect("diff error");
+        assert_eq!(patch, disk_patch);
+
+        // a truncated or padded extension header is rejected
+        let serialized = signature.serialized();
+        assert!(Signature::deserialize(serialized[..24].to_vec()).is_err());
+        let mut padded = serialized[..20].to_vec();
+        padded.extend_from_slice(&16u32.to_be_bytes());
+        padded.extend_from_slice(&serialized[24..36]);
+        padded.extend_from_slice(&[0; 4]);
+        assert!(Signature::deserialize(padded).is_err());
+    }
+}
+
+#[test]
+fn test_deduplicated_signature() {
+    use crate::SignatureParseError;
+    use rand::Rng;
+    let mut base = vec![0; 100000];
+    rand::thread_rng().fill(&mut base[..20000]);
+    let mut data = base.clone();
+    data[10000..11000].copy_from_slice(&[7; 1000]);
+    data[90000..91000].copy_from_slice(&[7; 1000]);
+    for &rolling_seed in &[None, Some(7)] {
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions {
+                block_size: 1024,
+                crypto_hash_size: 8,
+                rolling_seed,
+                ..Default::default()
+            },
+        );
+        let deduplicated = signature.serialize_deduplicated();
+        assert!(deduplicated.len() < signature.serialized().len() * 2 / 3);
+        assert_eq!(
+            Signature::deserialize(deduplicated.clone()).expect("deserialization error"),
+            signature
+        );
+        let signature_ref = Signature::deserialize_ref(&deduplicated).expect("parse error");
+        assert_eq!(signature_ref.serialized(), &deduplicated[..]);
+        assert_eq!(signature_ref.to_signature(), signature);
+
+        let mut patch = vec![];
+        diff(&signature.index(), &data, &mut patch).expect("diff error");
+        let mut dedup_patch = vec![];
+        diff(
+            &IndexedSignature::from_serialized(&deduplicated).expect("parse error"),
+            &data,
+            &mut dedup_patch,
+        )
+        .expect("diff error");
+        assert_eq!(patch, dedup_patch);
+        let mut out = vec![];
+        apply(&base, &dedup_patch, &mut out).expect("apply error");
+        assert_eq!(data, out);
+
+        // every block must refer to one of the distinct blocks
+        let mut corrupt = deduplicated.clone();
+        let len = corrupt.len();
+        corrupt[len - 4..].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(
+            Signature::deserialize(corrupt),
+            Err(SignatureParseError::Corrupt)
+        );
+        assert!(Signature::deserialize(deduplicated[..len - 1].to_vec()).is_err());
+
+        // the expanded size can be bounded
+        let expanded = signature.serialized().len();
+        assert_eq!(
+            Signature::deserialize_limited(deduplicated.clone(), expanded).unwrap(),
+            signature
+        );
+        assert_eq!(
+            Signature::deserialize_limited(deduplicated.clone(), expanded - 1),
+            Err(SignatureParseError::TooLarge)
+        );
+        // and a hash size beyond that of the hash would expand every block listed to a copy of
+        // a huge hash
+        let mut corrupt = deduplicated.clone();
+        corrupt[16..20].copy_from_slice(&(1u32 << 16).to_be_bytes());
+        assert_eq!(
+            Signature::deserialize_ref(&corrupt).err(),
+            Some(SignatureParseError::Corrupt)
+        );
+    }
+}
+
+#[test]
+fn test_signature_options_builder() {
+    use crate::{HashKey, InvalidOptions};
+    let options = SignatureOptions::builder()
+        .block_size(1024)
+        .crypto_hash_size(32)
+        .hash(SignatureHash::KeyedBlake2)
+        .hash_key(HashKey::new([1; 32]))
+        .rolling_hash(RollingHash::RabinKarp)
+        .rolling_seed(5)
+        .build()
+        .unwrap();
+    assert_eq!(options.block_size, 1024);
+    assert_eq!(options.crypto_hash_size, 32);
+    assert_eq!(options.hash, SignatureHash::KeyedBlake2);
+    assert_eq!(options.hash_key, Some(HashKey::new([1; 32])));
+    assert_eq!(options.rolling_hash, RollingHash::RabinKarp);
+    assert_eq!(options.rolling_seed, Some(5));
+
+    let default = SignatureOptions::builder().build().unwrap();
+    assert_eq!(
+        Signature::calculate(&[1; 10000], default),
+        Signature::calculate(&[1; 10000], SignatureOptions::default())
+    );
+    assert_eq!(
+        SignatureOptions::builder()
+            .block_size(0)
+            .build()
+            .unwrap_err(),
+        InvalidOptions::ZeroBlockSize
+    );
+    assert_eq!(
+        SignatureOptions::builder()
+            .crypto_hash_size(32)
+            .build()
+            .unwrap_err(),
+        InvalidOptions::CryptoHashSizeTooLarge {
+            crypto_hash_size: 32,
+            max: 16
         }
+    );
+    assert_eq!(
+        SignatureOptions::builder()
+            .hash(SignatureHash::KeyedBlake2)
+            .build()
+            .unwrap_err(),
+        InvalidOptions::HashKeyMismatch
+    );
+}
+
+#[test]
+fn test_signature_validate() {
+    use crate::InvalidOptions;
+    let signature = Signature::calculate(&[1; 100], SignatureOptions::default());
+    assert_eq!(signature.validate(), Ok(()));
+    let header = |block_size: u32, crypto_hash_size: u32| {
+        let mut serialized = crate::consts::MD4_MAGIC.to_be_bytes().to_vec();
+        serialized.extend_from_slice(&block_size.to_be_bytes());
+        serialized.extend_from_slice(&crypto_hash_size.to_be_bytes());
+        Signature::deserialize(serialized).expect("deserialization error")
+    };
+    assert_eq!(header(0, 8).validate(), Err(InvalidOptions::ZeroBlockSize));
+    assert_eq!(
+        header(16, 0).validate(),
+        Err(InvalidOptions::ZeroCryptoHashSize)
+    );
+    assert_eq!(
+        header(16, 17).validate(),
+        Err(InvalidOptions::CryptoHashSizeTooLarge {
+            crypto_hash_size: 17,
+            max: 16
+        })
+    );
+    let serialized = header(16, 17).into_serialized();
+    assert_eq!(
+        Signature::deserialize_ref(&serialized).unwrap().validate(),
+        header(16, 17).validate()
+    );
+}
+
+#[test]
+fn test_signature_versions() {
+    use crate::SignatureParseError;
+    let signature = Signature::calculate(
+        &[1; 100],
+        SignatureOptions {
+            block_size: 16,
+            rolling_seed: Some(1),
+            ..Default::default()
+        },
+    );
+    let parse = |f: &dyn Fn(&mut Vec<u8>)| {
+        let mut serialized = signature.serialized().to_vec();
+        f(&mut serialized);
+        Signature::deserialize(serialized).map(|_| ())
+    };
+    assert_eq!(parse(&|_| ()), Ok(()));
+    // a later version of the extended header
+    assert_eq!(
+        parse(&|s| s[4..8].copy_from_slice(&2u32.to_be_bytes())),
+        Err(SignatureParseError::UnsupportedVersion)
+    );
+    // an extension field which this version doesn't know
+    assert_eq!(
+        parse(&|s| s[24..28].copy_from_slice(&99u32.to_be_bytes())),
+        Err(SignatureParseError::UnsupportedVersion)
+    );
+    // a hash which this version doesn't know, with and without the extended header
+    assert_eq!(
+        parse(&|s| s[8..12].copy_from_slice(&0x667201ffu32.to_be_bytes())),
+        Err(SignatureParseError::UnsupportedVersion)
+    );
+    assert_eq!(
+        Signature::deserialize(0x727301ffu32.to_be_bytes().repeat(3)),
+        Err(SignatureParseError::UnsupportedVersion)
+    );
+
+    assert_eq!(
+        parse(&|s| s.truncate(30)),
+        Err(SignatureParseError::Corrupt)
+    );
+    assert_eq!(
+        parse(&|s| {
+            s.pop();
+        }),
+        Err(SignatureParseError::Corrupt)
+    );
+    assert_eq!(
+        parse(&|s| s[28..32].copy_from_slice(&8u32.to_be_bytes())),
+        Err(SignatureParseError::Corrupt)
+    );
+    assert_eq!(
+        Signature::deserialize(b"not a signature".to_vec()),
+        Err(SignatureParseError::Corrupt)
+    );
+}
+
+#[test]
+fn test_rabinkarp() {
+    use rand::Rng;
+    let mut base = vec![0; 100000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base.clone();
+    data[50000..51000].copy_from_slice(&[7; 1000]);
+    data.drain(10000..10007);
+    for &(hash, magic) in &[
+        (SignatureHash::Md4, crate::consts::RK_MD4_MAGIC),
+        (SignatureHash::Blake2, crate::consts::RK_BLAKE2_MAGIC),
+    ] {
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions {
+                block_size: 1024,
+                crypto_hash_size: 16,
+                hash,
+                rolling_hash: RollingHash::RabinKarp,
+                ..Default::default()
+            },
+        );
+        assert_eq!(signature.serialized()[..4], magic.to_be_bytes());
+        let deserialized =
+            Signature::deserialize(signature.serialized().to_vec()).expect("deserialization error");
+        assert_eq!(signature, deserialized);
+        let mut patch = vec![];
+        diff(&signature.index(), &data, &mut patch).expect("diff error");
+        assert!(patch.len() < 5000);
+        let mut out = vec![];
+        apply(&base, &patch, &mut out).expect("apply error");
+        assert_eq!(data, out);
     }
 }
 
+#[cfg(feature = "digest")]
+#[test]
+fn test_custom_digest() {
+    use crate::diff_with_digest;
+    use rand::Rng;
+    use sha2::Sha256;
+    let mut base = vec![0; 100000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base.clone();
+    data[50000..51000].copy_from_slice(&[7; 1000]);
+    let options = SignatureOptions {
+        block_size: 1024,
+        crypto_hash_size: 32,
+        ..Default::default()
+    };
+    let signature = Signature::calculate_with_digest::<Sha256>(&base, options);
+    let deserialized =
+        Signature::deserialize(signature.serialized().to_vec()).expect("deserialization error");
+    assert_eq!(signature, deserialized);
+
+    let mut patch = vec![];
+    diff_with_digest::<Sha256>(&signature.index(), &data, &mut patch, &Default::default())
+        .expect("diff error");
+    assert!(patch.len() < 5000);
+    let mut out = vec![];
+    apply(&base, &patch, &mut out).expect("apply error");
+    assert_eq!(data, out);
+
+    // the built-in hashes can't be used with a custom signature, or vice versa
+    assert!(diff(&signature.index(), &data, &mut vec![]).is_err());
+    let md4_signature = Signature::calculate(&base, SignatureOptions::default());
+    assert!(diff_with_digest::<Sha256>(
+        &md4_signature.index(),
+        &data,
+        &mut vec![],
+        &Default::default()
+    )
+    .is_err());
+    assert_eq!(
+        Signature::try_calculate(
+            &base,
+            SignatureOptions {
+                hash: SignatureHash::Custom,
+                ..options
+            }
+        ),
+        Err(crate::InvalidOptions::CustomHash)
+    );
+}
+
 #[test]
 fn test_apply_errors() {
     let base_data = b"potato";
@@ -144,7 +4283,7 @@ fn test_apply_errors() {
         apply(base_data, &[], &mut Vec::new())
             .unwrap_err()
             .to_string(),
-        "unexpected end of input when reading magic (expected=4, available=0)",
+        "unexpected end of input when reading magic (expected=4, available=0, delta_offset=0)",
     );
     // wrong magic
     assert_eq!(
@@ -162,7 +4301,7 @@ fn test_apply_errors() {
         )
         .unwrap_err()
         .to_string(),
-        "copy length is empty",
+        "copy length is empty (command=0x45, delta_offset=4)",
     );
     // copy start out of range
     assert_eq!(
@@ -173,7 +4312,7 @@ fn test_apply_errors() {
         )
         .unwrap_err()
         .to_string(),
-        "requested copy is out of bounds (offset=10, len=1, data_len=6)",
+        "requested copy is out of bounds (offset=10, len=1, data_len=6, command=0x45, delta_offset=4)",
     );
     // copy end out of range
     assert_eq!(
@@ -184,7 +4323,7 @@ fn test_apply_errors() {
         )
         .unwrap_err()
         .to_string(),
-        "requested copy is out of bounds (offset=0, len=10, data_len=6)",
+        "requested copy is out of bounds (offset=0, len=10, data_len=6, command=0x45, delta_offset=4)",
     );
     // copy end out of range
     assert_eq!(
@@ -195,20 +4334,60 @@ fn test_apply_errors() {
         )
         .unwrap_err()
         .to_string(),
-        "requested copy is out of bounds (offset=0, len=10, data_len=6)",
+        "requested copy is out of bounds (offset=0, len=10, data_len=6, command=0x45, delta_offset=4)",
     );
     // garbage
     assert_eq!(
         apply(base_data, &[114, 115, 2, 54, 0x55], &mut Vec::new(),)
             .unwrap_err()
             .to_string(),
-        "unexpected command byte: 0x55",
+        "unexpected command byte: 0x55 (delta_offset=4)",
     );
     // trailing garbage
     assert_eq!(
         apply(base_data, &[114, 115, 2, 54, 0, 1], &mut Vec::new(),)
             .unwrap_err()
             .to_string(),
-        "unexpected data after end command (len=1)",
+        "unexpected data after end command (len=1, delta_offset=5)",
+    );
+}
+
+#[test]
+fn test_error_kinds() {
+    use crate::{apply_limited, ApplyError, DiffError, ErrorKind};
+    let base_data = b"potato";
+    let kind = |delta: &[u8]| apply(base_data, delta, &mut Vec::new()).unwrap_err().kind();
+    assert_eq!(kind(&[114, 115, 2]), ErrorKind::InvalidData);
+    assert_eq!(kind(&[1, 2, 3, 4]), ErrorKind::InvalidData);
+    assert_eq!(kind(&[114, 115, 2, 54, 0x55]), ErrorKind::InvalidData);
+    // an extended delta with an extension this build doesn't have
+    assert_eq!(kind(&0x66720260u32.to_be_bytes()), ErrorKind::Unsupported);
+    #[cfg(not(feature = "zstd"))]
+    for &magic in &[0x66720236u32, 0x66720238, 0x66720241, 0x66720243] {
+        assert!(matches!(
+            apply(base_data, &magic.to_be_bytes(), &mut Vec::new()),
+            Err(ApplyError::Unsupported { feature: "zstd" })
+        ));
+    }
+    assert_eq!(
+        apply_limited(base_data, &[114, 115, 2, 54, 0x45, 0, 6, 0], &mut vec![], 5)
+            .unwrap_err()
+            .kind(),
+        ErrorKind::LimitExceeded
+    );
+    assert_eq!(
+        ApplyError::ChecksumMismatch.kind(),
+        ErrorKind::VerificationFailed
+    );
+    assert_eq!(DiffError::Cancelled.kind(), ErrorKind::Cancelled);
+    assert_eq!(
+        Signature::deserialize(vec![1, 2, 3]).unwrap_err().kind(),
+        ErrorKind::InvalidData
+    );
+    assert_eq!(
+        Signature::deserialize(0x727301ffu32.to_be_bytes().repeat(3))
+            .unwrap_err()
+            .kind(),
+        ErrorKind::Unsupported
     );
 }
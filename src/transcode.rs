@@ -0,0 +1,214 @@
+//! Converting deltas between the librsync format and its extensions.
+
+use std::io::{self, Write};
+
+use crate::blake2::{Blake2Hasher, BLAKE2_SIZE};
+#[cfg(feature = "zstd")]
+use crate::chain::decompress_all;
+use crate::chain::{Source, Version};
+#[cfg(feature = "zstd")]
+use crate::consts::DELTA_ZSTD_LITERALS;
+use crate::consts::{
+    DELTA_CHECKSUM, DELTA_MAGIC, DELTA_SELF_COPIES, EXTENDED_DELTA_MAGIC, RS_OP_COPY_N1_N1,
+    RS_OP_END, RS_OP_SELF_COPY_N1_N1,
+};
+use crate::diff::{copy_command, insert_command, DeltaCommand};
+use crate::patch::{apply_limited, validate_delta, ApplyError, DeltaReader};
+#[cfg(feature = "zstd")]
+use crate::patch::{compressed_len, decompress};
+
+/// The format to convert a delta to with [transcode_delta()], as the extensions of the librsync
+/// format it uses. The default is the librsync format itself, which librsync can apply.
+#[derive(Clone, Debug, Default)]
+pub struct DeltaFormat {
+    /// Keep the [self-copies](crate::DiffOptions::self_copies) of the delta. Without this, they
+    /// are replaced with the copies and literals they repeat.
+    pub self_copies: bool,
+    /// End the delta with a [checksum](crate::DiffOptions::checksum) of its output.
+    pub checksum: bool,
+    /// Compress each literal with zstd at this compression level, as with
+    /// [DiffOptions::compress_literals](crate::DiffOptions::compress_literals). This needs the
+    /// `zstd` feature, without which it fails with [ApplyError::Unsupported].
+    pub compress_literals: Option<i32>,
+}
+
+/// Convert `delta` to `format`, with the same output.
+///
+/// This downgrades a delta which uses extensions of the librsync format to one which librsync,
+/// or a reader which only knows some of the extensions, can apply, and adds extensions to a
+/// delta from elsewhere. Compressed literals are decompressed, and compressed again if `format`
+/// asks for it. Self-copies are resolved into the pieces of the base and of the literals of the
+/// delta which they repeat, which doesn't need the base, but can make the delta larger.
+///
+/// A checksum is kept if the delta has one. Adding one to a delta which doesn't means applying
+/// it, so that needs `base`, and fails with [ApplyError::MissingBase] without it. Otherwise
+/// `base` is only used to check the copies of the delta, which are checked as with
+/// [DeltaReader] in any case.
+///
+/// Literals aren't merged or split, so the delta keeps as many of them as it had, apart from
+/// those split up by resolving self-copies: see [optimize_delta()](crate::optimize_delta) to
+/// merge them afterwards.
+///
+/// Fails with [ApplyError::OutputLimit] if the output of the delta would be longer than `limit`
+/// bytes, or its compressed literals would take more than that decompressed. Only those are
+/// held in memory, besides the transcoded delta, but resolving self-copies can make the delta
+/// as large as the output, less what it copies from the base.
+pub fn transcode_delta(
+    delta: &[u8],
+    base: Option<&[u8]>,
+    format: &DeltaFormat,
+    limit: usize,
+) -> Result<Vec<u8>, ApplyError> {
+    #[cfg(not(feature = "zstd"))]
+    if format.compress_literals.is_some() {
+        return Err(ApplyError::Unsupported { feature: "zstd" });
+    }
+    let base_len = base.map_or(u64::MAX, |base| base.len() as u64);
+    let summary = validate_delta(delta, base_len)?;
+    let mut checksum = None;
+    if format.checksum && !summary.has_checksum {
+        let base = base.ok_or(ApplyError::MissingBase)?;
+        let mut hasher = HashWriter(Blake2Hasher::default());
+        apply_limited(base, delta, &mut hasher, limit)?;
+        checksum = Some(hasher.0.finalize());
+    }
+
+    let mut extensions = 0;
+    if format.self_copies {
+        extensions |= DELTA_SELF_COPIES;
+    }
+    if format.checksum {
+        extensions |= DELTA_CHECKSUM;
+    }
+    #[cfg(feature = "zstd")]
+    if format.compress_literals.is_some() {
+        extensions |= DELTA_ZSTD_LITERALS;
+    }
+    let magic = match extensions {
+        0 => DELTA_MAGIC,
+        _ => EXTENDED_DELTA_MAGIC | extensions,
+    };
+    let mut writer = Writer {
+        out: magic.to_be_bytes().to_vec(),
+        #[cfg(feature = "zstd")]
+        compress_literals: format.compress_literals,
+    };
+
+    let mut reader = DeltaReader::new(delta)?;
+    if reader.has_self_copies() && !format.self_copies {
+        #[cfg(feature = "zstd")]
+        let decompressed = decompress_all(&[delta], limit)?;
+        #[cfg(feature = "zstd")]
+        let mut decompressed = decompressed.iter().map(|literal| &literal[..]);
+        #[cfg(not(feature = "zstd"))]
+        let mut decompressed = std::iter::empty::<&[u8]>();
+        let (version, delta_checksum) =
+            Version::base(base_len).apply(delta, &mut decompressed, limit as u64)?;
+        for segment in &version.segments {
+            match segment.source {
+                Source::Base(offset) => writer.copy(RS_OP_COPY_N1_N1, offset, segment.len)?,
+                Source::Data(data) => writer.literal(data)?,
+            }
+        }
+        checksum = checksum.or_else(|| delta_checksum.map(to_checksum));
+    } else {
+        let compressed = reader.has_compressed_literals();
+        let mut written = 0u64;
+        for command in &mut reader {
+            match command? {
+                DeltaCommand::Copy { offset, len } => {
+                    check_limit(&mut written, len, limit)?;
+                    writer.copy(RS_OP_COPY_N1_N1, offset, len)?
+                }
+                DeltaCommand::SelfCopy { offset, len } => {
+                    check_limit(&mut written, len, limit)?;
+                    writer.copy(RS_OP_SELF_COPY_N1_N1, offset, len)?
+                }
+                #[cfg(feature = "zstd")]
+                DeltaCommand::Literal(literal) if compressed => {
+                    let size = compressed_len(literal)?;
+                    check_limit(&mut written, size, limit)?;
+                    writer.literal(&decompress(literal, size)?)?
+                }
+                DeltaCommand::Literal(literal) => {
+                    debug_assert!(!compressed);
+                    check_limit(&mut written, literal.len() as u64, limit)?;
+                    writer.literal(literal)?
+                }
+                DeltaCommand::End => {}
+            }
+        }
+        checksum = checksum.or_else(|| reader.checksum().map(to_checksum));
+    }
+
+    let mut out = writer.out;
+    out.push(RS_OP_END);
+    if format.checksum {
+        out.extend_from_slice(&checksum.expect("the checksum is kept or worked out"));
+    }
+    Ok(out)
+}
+
+/// Count `len` more bytes of output after `written`, failing if that is more than `limit`.
+fn check_limit(written: &mut u64, len: u64, limit: usize) -> Result<(), ApplyError> {
+    let available = (limit as u64).saturating_sub(*written);
+    if len > available {
+        return Err(ApplyError::OutputLimit {
+            what: "output",
+            wanted: len.min(usize::MAX as u64) as usize,
+            available: available as usize,
+        });
+    }
+    *written += len;
+    Ok(())
+}
+
+fn to_checksum(checksum: &[u8]) -> [u8; BLAKE2_SIZE] {
+    let mut array = [0; BLAKE2_SIZE];
+    array.copy_from_slice(checksum);
+    array
+}
+
+/// Writes the commands of a delta in memory, compressing literals if needed.
+struct Writer {
+    out: Vec<u8>,
+    #[cfg(feature = "zstd")]
+    compress_literals: Option<i32>,
+}
+
+impl Writer {
+    /// Write a copy command of the kind whose first opcode is `first_op`.
+    fn copy(&mut self, first_op: u8, offset: u64, len: u64) -> io::Result<()> {
+        copy_command(first_op, offset, len, &mut self.out)
+    }
+
+    fn literal(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        #[cfg(feature = "zstd")]
+        if let Some(level) = self.compress_literals {
+            let compressed = zstd::bulk::compress(data, level)?;
+            insert_command(compressed.len() as u64, &mut self.out)?;
+            self.out.extend_from_slice(&compressed);
+            return Ok(());
+        }
+        insert_command(data.len() as u64, &mut self.out)?;
+        self.out.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Hashes what is written to it.
+struct HashWriter(Blake2Hasher);
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
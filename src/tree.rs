@@ -0,0 +1,890 @@
+//! Syncing whole directories: a [Manifest] of the files under one, and a [TreeDelta] of the
+//! changes which turn it into another.
+//!
+//! This follows the same steps as syncing a single file. The side with the old copy of a
+//! directory sends its [Manifest], which has the signature of each file. The side with the new
+//! copy diffs it against the manifest with [diff_tree()], and sends back the [TreeDelta], which
+//! the old side applies with [apply_tree()]. Both serialize to bytes, so how they are sent is up
+//! to the caller.
+//!
+//! Only regular files are synced. Symlinks and other special files are skipped, and directories
+//! only exist as the parents of files, so empty ones aren't recorded. Paths are relative to the
+//! directory, with `/` between components, and a file whose path isn't UTF-8 fails with
+//! [TreeError::InvalidPath].
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use arrayref::array_ref;
+
+use crate::atomic::{apply_file_base, replace_file};
+use crate::consts::{MANIFEST_MAGIC, TREE_DELTA_MAGIC};
+use crate::diff::DiffError;
+use crate::error::ErrorKind;
+use crate::fs::{diff_file, sign_file};
+use crate::patch::ApplyError;
+use crate::signature::{InvalidOptions, Signature, SignatureOptions};
+
+/// One file of a [Manifest].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ManifestEntry {
+    /// The path of the file, relative to the directory.
+    pub path: String,
+    /// The length of the file.
+    pub size: u64,
+    /// When the file was last modified, unless the platform doesn't record it or it is before
+    /// 1970.
+    pub mtime: Option<SystemTime>,
+    /// The signature of the file.
+    pub signature: Signature,
+}
+
+/// The files under a directory, with their signatures, to diff another copy of the directory
+/// against with [diff_tree()].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Manifest {
+    /// The files, sorted by path.
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Calculate the manifest of the files under `root`, with the signature of each calculated
+    /// as with [sign_file()](crate::fs::sign_file).
+    ///
+    /// Fails with [TreeError::InvalidOptions] before reading anything if the provided options
+    /// are invalid; see [Signature::try_calculate()].
+    pub fn calculate(root: &Path, options: SignatureOptions) -> Result<Manifest, TreeError> {
+        Signature::check_options(&options)
+            .map_err(|source| TreeError::InvalidOptions { source })?;
+        let mut entries = Vec::new();
+        for (path, metadata) in walk(root)? {
+            let signature =
+                sign_file(&join(root, &path), options).map_err(|e| io_error(&path, e))?;
+            entries.push(ManifestEntry {
+                size: metadata.len(),
+                mtime: mtime(&metadata),
+                signature,
+                path,
+            });
+        }
+        Ok(Manifest { entries })
+    }
+
+    /// Write the manifest in a binary format, which [deserialize()](Manifest::deserialize) reads.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = MANIFEST_MAGIC.to_be_bytes().to_vec();
+        for entry in &self.entries {
+            put_bytes(&mut out, entry.path.as_bytes());
+            out.extend_from_slice(&entry.size.to_be_bytes());
+            put_mtime(&mut out, entry.mtime);
+            put_bytes(&mut out, entry.signature.serialized());
+        }
+        out
+    }
+
+    /// Read a manifest written by [serialize()](Manifest::serialize).
+    pub fn deserialize(data: &[u8]) -> Result<Manifest, TreeError> {
+        let mut reader = Reader::new(data, MANIFEST_MAGIC)?;
+        let mut entries = Vec::new();
+        while !reader.data.is_empty() {
+            let path = reader.path()?;
+            let size = reader.u64()?;
+            let mtime = reader.mtime()?;
+            // manifests store signatures as they are, never deduplicated, so none should grow
+            let bytes = reader.bytes()?;
+            let signature = Signature::deserialize_limited(bytes.to_vec(), bytes.len())
+                .map_err(|_| invalid("invalid signature"))?;
+            entries.push(ManifestEntry {
+                path,
+                size,
+                mtime,
+                signature,
+            });
+        }
+        Ok(Manifest { entries })
+    }
+}
+
+/// One change of a [TreeDelta].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TreeChange {
+    /// Apply `delta` to the file at `path`.
+    Patch {
+        /// The path of the file.
+        path: String,
+        /// The delta from the old contents of the file to the new ones.
+        delta: Vec<u8>,
+        /// The modification time to give the file.
+        mtime: Option<SystemTime>,
+    },
+    /// Apply `delta` to the file at `from`, store the output at `to`, and delete `from`.
+    ///
+    /// [diff_tree()] records this rather than deleting `from` and creating `to` when the new
+    /// file at `to` has the same signature as the old one at `from`, so `delta` is just a copy.
+    Rename {
+        /// The path of the old file.
+        from: String,
+        /// The path of the new file, which isn't in the old tree.
+        to: String,
+        /// The delta from the old file to the new one.
+        delta: Vec<u8>,
+        /// The modification time to give the new file.
+        mtime: Option<SystemTime>,
+    },
+    /// Create the file at `path`, which isn't in the old tree, with `data`.
+    Create {
+        /// The path of the file.
+        path: String,
+        /// The contents of the file.
+        data: Vec<u8>,
+        /// The modification time to give the file.
+        mtime: Option<SystemTime>,
+    },
+    /// Delete the file at `path`, which isn't in the new tree.
+    Delete {
+        /// The path of the file.
+        path: String,
+    },
+}
+
+/// The changes which turn the files under one directory into those under another, as
+/// calculated by [diff_tree()] and applied by [apply_tree()].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TreeDelta {
+    /// The changes. [apply_tree()] applies them in an order which can't clash, so they can be
+    /// in any order here.
+    pub changes: Vec<TreeChange>,
+}
+
+impl TreeDelta {
+    /// Write the tree delta in a binary format, which [deserialize()](TreeDelta::deserialize)
+    /// reads.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = TREE_DELTA_MAGIC.to_be_bytes().to_vec();
+        for change in &self.changes {
+            match change {
+                TreeChange::Patch { path, delta, mtime } => {
+                    out.push(0);
+                    put_bytes(&mut out, path.as_bytes());
+                    put_bytes(&mut out, delta);
+                    put_mtime(&mut out, *mtime);
+                }
+                TreeChange::Rename {
+                    from,
+                    to,
+                    delta,
+                    mtime,
+                } => {
+                    out.push(1);
+                    put_bytes(&mut out, from.as_bytes());
+                    put_bytes(&mut out, to.as_bytes());
+                    put_bytes(&mut out, delta);
+                    put_mtime(&mut out, *mtime);
+                }
+                TreeChange::Create { path, data, mtime } => {
+                    out.push(2);
+                    put_bytes(&mut out, path.as_bytes());
+                    put_bytes(&mut out, data);
+                    put_mtime(&mut out, *mtime);
+                }
+                TreeChange::Delete { path } => {
+                    out.push(3);
+                    put_bytes(&mut out, path.as_bytes());
+                }
+            }
+        }
+        out
+    }
+
+    /// Read a tree delta written by [serialize()](TreeDelta::serialize).
+    pub fn deserialize(data: &[u8]) -> Result<TreeDelta, TreeError> {
+        let mut reader = Reader::new(data, TREE_DELTA_MAGIC)?;
+        let mut changes = Vec::new();
+        while !reader.data.is_empty() {
+            let change = match reader.take(1)?[0] {
+                0 => TreeChange::Patch {
+                    path: reader.path()?,
+                    delta: reader.bytes()?.to_vec(),
+                    mtime: reader.mtime()?,
+                },
+                1 => TreeChange::Rename {
+                    from: reader.path()?,
+                    to: reader.path()?,
+                    delta: reader.bytes()?.to_vec(),
+                    mtime: reader.mtime()?,
+                },
+                2 => TreeChange::Create {
+                    path: reader.path()?,
+                    data: reader.bytes()?.to_vec(),
+                    mtime: reader.mtime()?,
+                },
+                3 => TreeChange::Delete {
+                    path: reader.path()?,
+                },
+                _ => return Err(invalid("unknown change")),
+            };
+            changes.push(change);
+        }
+        Ok(TreeDelta { changes })
+    }
+}
+
+/// Options for [diff_tree()].
+#[derive(Clone, Debug, Default)]
+pub struct TreeDiffOptions {
+    /// Diff every file, like rsync's `--ignore-times`. By default, a file with the same size and
+    /// modification time as in the manifest is taken to be unchanged, and left out of the delta.
+    pub ignore_times: bool,
+}
+
+/// Calculate the changes which turn the directory `manifest` was calculated for into the one at
+/// `root`.
+///
+/// Files in both are diffed against their signatures, unless they look unchanged (see
+/// [TreeDiffOptions::ignore_times]), and files which are only in the manifest are deleted. A file
+/// which is only under `root` is recorded as a rename of a deleted file with the same size and
+/// signature if there is one, and created from its whole contents otherwise. The delta holds
+/// the deltas and new files in memory.
+///
+/// Each file is diffed with [diff_file()](crate::fs::diff_file), and a file which fails to
+/// diff, e.g. because its signature uses a keyed hash, fails with [TreeError::Diff].
+///
+/// # Security
+/// The caveats for [diff()](crate::diff) apply here as well, including to renames, which are
+/// found by comparing signatures.
+pub fn diff_tree(
+    manifest: &Manifest,
+    root: &Path,
+    options: &TreeDiffOptions,
+) -> Result<TreeDelta, TreeError> {
+    let files = walk(root)?;
+    let old: BTreeMap<&str, &ManifestEntry> = manifest
+        .entries
+        .iter()
+        .map(|entry| (&entry.path[..], entry))
+        .collect();
+
+    let mut patches = Vec::new();
+    let mut added = Vec::new();
+    for (path, metadata) in &files {
+        let mtime = mtime(metadata);
+        match old.get(&path[..]) {
+            Some(entry) => {
+                let unchanged = entry.size == metadata.len() && mtime.is_some();
+                if unchanged && entry.mtime == mtime && !options.ignore_times {
+                    continue;
+                }
+                patches.push(TreeChange::Patch {
+                    path: path.clone(),
+                    delta: diff_path(root, path, &entry.signature)?,
+                    mtime,
+                });
+            }
+            None => added.push((path, metadata.len(), mtime)),
+        }
+    }
+
+    // files which are gone, and haven't been matched to a new file yet
+    let mut gone: Vec<Option<&ManifestEntry>> = old
+        .iter()
+        .filter(|(path, _)| !files.contains_key(**path))
+        .map(|(_, &entry)| Some(entry))
+        .collect();
+    let mut renames = Vec::new();
+    let mut creates = Vec::new();
+    for (path, size, mtime) in added {
+        match find_rename(root, path, size, &old, &mut gone)? {
+            Some(entry) => renames.push(TreeChange::Rename {
+                from: entry.path.clone(),
+                to: path.clone(),
+                delta: diff_path(root, path, &entry.signature)?,
+                mtime,
+            }),
+            None => creates.push(TreeChange::Create {
+                path: path.clone(),
+                data: fs::read(join(root, path)).map_err(|e| io_error(path, e))?,
+                mtime,
+            }),
+        }
+    }
+    let deletes = gone.into_iter().flatten().map(|entry| TreeChange::Delete {
+        path: entry.path.clone(),
+    });
+
+    let mut changes = patches;
+    changes.extend(renames);
+    changes.extend(creates);
+    changes.extend(deletes);
+    Ok(TreeDelta { changes })
+}
+
+/// Find a file among `gone` which the new file at `path` is a copy of, and take it out of
+/// `gone`.
+///
+/// Empty files aren't matched, since there is nothing to save by it, and neither are files
+/// whose path clashes with that of an old file, which [apply_tree()] can't write until it has
+/// deleted the old files.
+fn find_rename<'a>(
+    root: &Path,
+    path: &str,
+    size: u64,
+    old: &BTreeMap<&str, &ManifestEntry>,
+    gone: &mut [Option<&'a ManifestEntry>],
+) -> Result<Option<&'a ManifestEntry>, TreeError> {
+    let parent_clashes = path
+        .match_indices('/')
+        .any(|(i, _)| old.contains_key(&path[..i]));
+    let dir_prefix = format!("{}/", path);
+    let dir_clashes = old
+        .range(&dir_prefix[..]..)
+        .next()
+        .map_or(false, |(old_path, _)| old_path.starts_with(&dir_prefix));
+    if size == 0 || parent_clashes || dir_clashes {
+        return Ok(None);
+    }
+    // the signature of the new file with the options of each candidate, as they're needed
+    let mut signatures: Vec<(SignatureOptions, Signature)> = Vec::new();
+    for slot in gone.iter_mut() {
+        let entry = match slot {
+            Some(entry) if entry.size == size => *entry,
+            _ => continue,
+        };
+        let options = entry.signature.options();
+        let signature = match signatures.iter().position(|(o, _)| *o == options) {
+            Some(i) => &signatures[i].1,
+            None => {
+                let signature =
+                    sign_file(&join(root, path), options).map_err(|e| io_error(path, e))?;
+                signatures.push((options, signature));
+                &signatures[signatures.len() - 1].1
+            }
+        };
+        if *signature == entry.signature {
+            *slot = None;
+            return Ok(Some(entry));
+        }
+    }
+    Ok(None)
+}
+
+fn diff_path(root: &Path, path: &str, signature: &Signature) -> Result<Vec<u8>, TreeError> {
+    let mut delta = Vec::new();
+    diff_file(&signature.index(), &join(root, path), &mut delta).map_err(|e| match e {
+        DiffError::Io(source) => io_error(path, source),
+        source => TreeError::Diff {
+            path: path.to_string(),
+            source,
+        },
+    })?;
+    Ok(delta)
+}
+
+/// Apply `delta` to the directory at `root`.
+///
+/// Patches are applied first, then the new files of renames are written, then files are
+/// deleted, along with the directories which that leaves empty, and finally new files are
+/// created, along with their directories. Each file is replaced atomically, as with
+/// [apply_file()](crate::fs::apply_file), and gets the modification time of the change, but
+/// the directory as a whole isn't: if this fails part of the way through, the changes before
+/// the failure stay applied. Calculating a new manifest and diffing again picks up from there.
+///
+/// A path which isn't a relative path without `.` or `..` components, or which goes through a
+/// symlink under `root`, fails with [TreeError::InvalidPath], before that change is made, so
+/// the delta can't write outside of `root`.
+///
+/// # Security
+/// As with [apply()](crate::apply), the files should be verified with a cryptographic hash
+/// before they are trusted.
+pub fn apply_tree(root: &Path, delta: &TreeDelta) -> Result<(), TreeError> {
+    for change in &delta.changes {
+        if let TreeChange::Patch { path, delta, mtime } = change {
+            let file = resolve(root, path)?;
+            let base = File::open(&file).map_err(|e| io_error(path, e))?;
+            replace_file(&file, *mtime, |out| apply_file_base(&base, delta, out))
+                .map_err(|e| apply_error(path, e))?;
+        }
+    }
+    for change in &delta.changes {
+        if let TreeChange::Rename {
+            from,
+            to,
+            delta,
+            mtime,
+        } = change
+        {
+            let base = File::open(resolve(root, from)?).map_err(|e| io_error(from, e))?;
+            let file = resolve(root, to)?;
+            create_parents(root, to)?;
+            replace_file(&file, *mtime, |out| apply_file_base(&base, delta, out))
+                .map_err(|e| apply_error(to, e))?;
+        }
+    }
+    for change in &delta.changes {
+        match change {
+            TreeChange::Rename { from: path, .. } | TreeChange::Delete { path } => {
+                delete(root, path)?
+            }
+            _ => {}
+        }
+    }
+    for change in &delta.changes {
+        if let TreeChange::Create { path, data, mtime } = change {
+            let file = resolve(root, path)?;
+            create_parents(root, path)?;
+            replace_file(&file, *mtime, |out| Ok(out.write_all(data)?))
+                .map_err(|e| apply_error(path, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Delete the file at `path`, and then its parent directories up to `root` for as long as
+/// they are empty.
+fn delete(root: &Path, path: &str) -> Result<(), TreeError> {
+    let file = resolve(root, path)?;
+    match fs::remove_file(&file) {
+        Ok(()) => {}
+        // already gone, which is what was wanted
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(io_error(path, e)),
+    }
+    for (i, _) in path.rmatch_indices('/') {
+        // fails if the directory isn't empty, which ends the deletion
+        if fs::remove_dir(join(root, &path[..i])).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn create_parents(root: &Path, path: &str) -> Result<(), TreeError> {
+    if let Some(i) = path.rfind('/') {
+        fs::create_dir_all(join(root, &path[..i])).map_err(|e| io_error(path, e))?;
+    }
+    Ok(())
+}
+
+/// The file at `path` under `root`, after checking that `path` is relative, has no `.` or `..`
+/// components, and doesn't go through a symlink, so the file is really under `root`.
+fn resolve(root: &Path, path: &str) -> Result<PathBuf, TreeError> {
+    let mut file = root.to_path_buf();
+    for name in path.split('/') {
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(component)), None) if component == name => {}
+            _ => return Err(invalid_path(path)),
+        }
+        file.push(name);
+        if fs::symlink_metadata(&file).map_or(false, |metadata| metadata.file_type().is_symlink()) {
+            return Err(invalid_path(path));
+        }
+    }
+    Ok(file)
+}
+
+/// `path`, with `/` between components, under `root`.
+fn join(root: &Path, path: &str) -> PathBuf {
+    path.split('/')
+        .fold(root.to_path_buf(), |file, name| file.join(name))
+}
+
+/// The regular files under `root`, by path.
+fn walk(root: &Path) -> Result<BTreeMap<String, Metadata>, TreeError> {
+    let mut files = BTreeMap::new();
+    let mut dirs = vec![String::new()];
+    while let Some(dir) = dirs.pop() {
+        let entries = fs::read_dir(join(root, &dir)).map_err(|e| io_error(&dir, e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| io_error(&dir, e))?;
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(name) => name,
+                None => return Err(invalid_path(&format!("{}/{}", dir, name.to_string_lossy()))),
+            };
+            let path = match &dir[..] {
+                "" => name.to_string(),
+                _ => format!("{}/{}", dir, name),
+            };
+            // `DirEntry::file_type` doesn't follow symlinks, so they are neither
+            let file_type = entry.file_type().map_err(|e| io_error(&path, e))?;
+            if file_type.is_dir() {
+                dirs.push(path);
+            } else if file_type.is_file() {
+                let metadata = entry.metadata().map_err(|e| io_error(&path, e))?;
+                files.insert(path, metadata);
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn mtime(metadata: &Metadata) -> Option<SystemTime> {
+    metadata
+        .modified()
+        .ok()
+        .filter(|mtime| *mtime >= UNIX_EPOCH)
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// Write `mtime` as a flag, and if it is set, the seconds and nanoseconds since 1970.
+fn put_mtime(out: &mut Vec<u8>, mtime: Option<SystemTime>) {
+    match mtime.and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok()) {
+        Some(since_epoch) => {
+            out.push(1);
+            out.extend_from_slice(&since_epoch.as_secs().to_be_bytes());
+            out.extend_from_slice(&since_epoch.subsec_nanos().to_be_bytes());
+        }
+        None => out.push(0),
+    }
+}
+
+/// Reads the fields of a serialized manifest or tree delta.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], magic: u32) -> Result<Self, TreeError> {
+        let mut reader = Reader { data };
+        if reader.u32()? != magic {
+            return Err(invalid("wrong magic"));
+        }
+        Ok(reader)
+    }
+
+    fn take(&mut self, len: u64) -> Result<&'a [u8], TreeError> {
+        if len > self.data.len() as u64 {
+            return Err(invalid("truncated"));
+        }
+        let (taken, rest) = self.data.split_at(len as usize);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, TreeError> {
+        Ok(u32::from_be_bytes(*array_ref![self.take(4)?, 0, 4]))
+    }
+
+    fn u64(&mut self) -> Result<u64, TreeError> {
+        Ok(u64::from_be_bytes(*array_ref![self.take(8)?, 0, 8]))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], TreeError> {
+        let len = self.u64()?;
+        self.take(len)
+    }
+
+    fn path(&mut self) -> Result<String, TreeError> {
+        let path = self.bytes()?;
+        String::from_utf8(path.to_vec()).map_err(|_| invalid("path isn't UTF-8"))
+    }
+
+    fn mtime(&mut self) -> Result<Option<SystemTime>, TreeError> {
+        if self.take(1)?[0] == 0 {
+            return Ok(None);
+        }
+        let (secs, nanos) = (self.u64()?, self.u32()?);
+        if nanos >= 1_000_000_000 {
+            return Err(invalid("invalid modification time"));
+        }
+        UNIX_EPOCH
+            .checked_add(Duration::new(secs, nanos))
+            .map(Some)
+            .ok_or_else(|| invalid("invalid modification time"))
+    }
+}
+
+/// Indicates that a directory couldn't be synced.
+///
+/// New variants may be added in any release: use [kind()](TreeError::kind) to tell broad
+/// cases apart.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum TreeError {
+    /// A serialized manifest or tree delta is truncated or corrupt.
+    InvalidData {
+        /// What is wrong with it.
+        reason: &'static str,
+    },
+    /// A path of a tree delta isn't a relative path within the directory, or goes through a
+    /// symlink, or a file under the directory has a path which isn't UTF-8.
+    InvalidPath {
+        /// The path, with any parts which aren't UTF-8 replaced.
+        path: String,
+    },
+    /// Diffing a file failed.
+    Diff {
+        /// The path of the file.
+        path: String,
+        /// Why it failed.
+        source: DiffError,
+    },
+    /// Applying the delta of a file failed.
+    Apply {
+        /// The path of the file.
+        path: String,
+        /// Why it failed.
+        source: ApplyError,
+    },
+    /// Reading or writing a file or directory failed.
+    Io {
+        /// The path of the file or directory.
+        path: String,
+        /// The error.
+        source: io::Error,
+    },
+    /// The signature options given to [Manifest::calculate()] were invalid.
+    InvalidOptions {
+        /// Why they are invalid.
+        source: InvalidOptions,
+    },
+}
+
+impl fmt::Display for TreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeError::InvalidData { reason } => {
+                write!(f, "invalid manifest or tree delta: {}", reason)
+            }
+            TreeError::InvalidPath { path } => write!(f, "invalid path: {:?}", path),
+            TreeError::Diff { path, source } => {
+                write!(f, "failed to diff {:?} (source={})", path, source)
+            }
+            TreeError::Apply { path, source } => {
+                write!(
+                    f,
+                    "failed to apply the delta of {:?} (source={})",
+                    path, source
+                )
+            }
+            TreeError::Io { path, source } => {
+                write!(f, "io error on {:?} (source={})", path, source)
+            }
+            TreeError::InvalidOptions { source } => {
+                write!(f, "invalid signature options (source={})", source)
+            }
+        }
+    }
+}
+
+impl Error for TreeError {}
+
+impl TreeError {
+    /// What kind of failure this is.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            TreeError::InvalidData { .. } | TreeError::InvalidPath { .. } => ErrorKind::InvalidData,
+            TreeError::Diff { source, .. } => source.kind(),
+            TreeError::Apply { source, .. } => source.kind(),
+            TreeError::Io { .. } => ErrorKind::Io,
+            TreeError::InvalidOptions { .. } => ErrorKind::InvalidArgument,
+        }
+    }
+}
+
+fn invalid(reason: &'static str) -> TreeError {
+    TreeError::InvalidData { reason }
+}
+
+fn invalid_path(path: &str) -> TreeError {
+    TreeError::InvalidPath {
+        path: path.to_string(),
+    }
+}
+
+fn io_error(path: &str, source: io::Error) -> TreeError {
+    TreeError::Io {
+        path: path.to_string(),
+        source,
+    }
+}
+
+fn apply_error(path: &str, source: ApplyError) -> TreeError {
+    match source {
+        ApplyError::Io(source) => io_error(path, source),
+        source => TreeError::Apply {
+            path: path.to_string(),
+            source,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_tree, diff_tree, walk, Manifest, ManifestEntry, TreeChange, TreeDelta,
+        TreeDiffOptions,
+    };
+    use crate::tests::Fixture;
+    use crate::{ErrorKind, SignatureOptions};
+    use std::path::Path;
+
+    fn write(root: &Path, path: &str, data: &[u8]) {
+        let file = root.join(path);
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(file, data).unwrap();
+    }
+
+    /// The contents and modification times of the files under `root`.
+    fn contents(root: &Path) -> Vec<(String, Vec<u8>, std::time::SystemTime)> {
+        walk(root)
+            .unwrap()
+            .into_iter()
+            .map(|(path, metadata)| {
+                let data = std::fs::read(root.join(&path)).unwrap();
+                (path, data, metadata.modified().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tree_roundtrip() {
+        let options = SignatureOptions {
+            block_size: 1024,
+            crypto_hash_size: 8,
+            ..Default::default()
+        };
+        let Fixture { base, data, .. } = Fixture::new(50_000, options);
+        let moved: Vec<u8> = (0..10_000u32).map(|i| (i * 13 % 241) as u8).collect();
+        let (old, new) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (old, new) = (old.path(), new.path());
+        write(old, "a.txt", &base);
+        write(old, "sub/b.bin", b"unchanged");
+        write(old, "gone.txt", b"deleted");
+        write(old, "x/moved.bin", &moved);
+        write(new, "a.txt", &data);
+        write(new, "sub/b.bin", b"unchanged");
+        write(new, "new/c.txt", b"created");
+        write(new, "y/moved2.bin", &moved);
+        let mtime = std::fs::metadata(old.join("sub/b.bin"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(new.join("sub/b.bin"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        let manifest = Manifest::calculate(old, options).unwrap();
+        let paths: Vec<_> = manifest.entries.iter().map(|e| &e.path[..]).collect();
+        assert_eq!(paths, ["a.txt", "gone.txt", "sub/b.bin", "x/moved.bin"]);
+        let manifest = Manifest::deserialize(&manifest.serialize()).unwrap();
+
+        let delta = diff_tree(&manifest, new, &TreeDiffOptions::default()).unwrap();
+        let delta = TreeDelta::deserialize(&delta.serialize()).unwrap();
+        let kinds: Vec<_> = delta
+            .changes
+            .iter()
+            .map(|change| match change {
+                TreeChange::Patch { path, .. } => format!("patch {}", path),
+                TreeChange::Rename { from, to, .. } => format!("rename {} {}", from, to),
+                TreeChange::Create { path, .. } => format!("create {}", path),
+                TreeChange::Delete { path } => format!("delete {}", path),
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                "patch a.txt",
+                "rename x/moved.bin y/moved2.bin",
+                "create new/c.txt",
+                "delete gone.txt"
+            ]
+        );
+
+        apply_tree(old, &delta).unwrap();
+        assert_eq!(contents(old), contents(new));
+        assert!(!old.join("x").exists());
+
+        // with the modification times applied, nothing looks changed
+        let manifest = Manifest::calculate(old, options).unwrap();
+        let delta = diff_tree(&manifest, new, &TreeDiffOptions::default()).unwrap();
+        assert!(delta.changes.is_empty());
+        let options = TreeDiffOptions { ignore_times: true };
+        let delta = diff_tree(&manifest, new, &options).unwrap();
+        assert_eq!(delta.changes.len(), 4);
+    }
+
+    #[test]
+    fn apply_tree_rejects_escaping_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        write(&root, "file", b"data");
+        for path in ["../file", "/file", "a//b", "./file", ""] {
+            let delta = TreeDelta {
+                changes: vec![TreeChange::Create {
+                    path: path.to_string(),
+                    data: b"data".to_vec(),
+                    mtime: None,
+                }],
+            };
+            let err = apply_tree(&root, &delta).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", path);
+        }
+        assert!(!dir.path().join("file").exists());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.path(), root.join("link")).unwrap();
+            let delta = TreeDelta {
+                changes: vec![TreeChange::Delete {
+                    path: "link/root/file".to_string(),
+                }],
+            };
+            assert!(apply_tree(&root, &delta).is_err());
+            assert!(root.join("file").exists());
+        }
+
+        assert_eq!(
+            TreeDelta::deserialize(b"junk").unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        let mut manifest = Manifest::default().serialize();
+        manifest.push(0);
+        assert!(Manifest::deserialize(&manifest).is_err());
+
+        // a deduplicated signature would grow as it is read
+        let options = SignatureOptions {
+            block_size: 64,
+            crypto_hash_size: 8,
+            ..Default::default()
+        };
+        let signature = crate::Signature::calculate(&[0; 10_000], options);
+        let entry = ManifestEntry {
+            path: "zeroes".to_string(),
+            size: 10_000,
+            mtime: None,
+            signature: signature.clone(),
+        };
+        let manifest = Manifest {
+            entries: vec![entry],
+        }
+        .serialize();
+        let deduplicated = signature.serialize_deduplicated();
+        let prefix = manifest.len() - signature.serialized().len() - 8;
+        let mut forged = manifest[..prefix].to_vec();
+        forged.extend_from_slice(&(deduplicated.len() as u64).to_be_bytes());
+        forged.extend_from_slice(&deduplicated);
+        assert!(Manifest::deserialize(&manifest).is_ok());
+        assert!(Manifest::deserialize(&forged).is_err());
+
+        // invalid options are reported rather than panicking
+        let options = SignatureOptions {
+            block_size: 0,
+            ..Default::default()
+        };
+        let err = Manifest::calculate(&root, options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidArgument);
+    }
+}
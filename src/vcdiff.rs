@@ -0,0 +1,904 @@
+//! Deltas in the VCDIFF format of [RFC 3284](https://www.rfc-editor.org/rfc/rfc3284), as read
+//! by xdelta3 and open-vcdiff among others.
+//!
+//! The matcher only ever produces copies from the base and literals, which map directly onto
+//! VCDIFF's COPY and ADD instructions, so a VCDIFF delta is made by re-encoding the commands of a
+//! librsync delta. Decoding has to handle the rest of the format as well, as written by other
+//! encoders: RUN instructions, copies from the target window and the address cache.
+
+use std::io::Write;
+
+use arrayref::array_ref;
+
+use crate::consts::{
+    DELTA_MAGIC, RS_OP_COPY_N1_N1, RS_OP_COPY_N8_N8, RS_OP_END, RS_OP_LITERAL_1, RS_OP_LITERAL_64,
+    RS_OP_LITERAL_N1, RS_OP_LITERAL_N8,
+};
+use crate::diff::{diff_with_options, DiffError, DiffOptions};
+use crate::patch::ApplyError;
+use crate::signature::BlockIndex;
+
+/// The VCDIFF magic, followed by the version byte.
+const VCDIFF_HEADER: [u8; 4] = [0xd6, 0xc3, 0xc4, 0x00];
+/// `Hdr_Indicator` bits for secondary compression, a custom code table and application data.
+const VCD_DECOMPRESS: u8 = 0x01;
+const VCD_CODETABLE: u8 = 0x02;
+const VCD_APPHEADER: u8 = 0x04;
+/// `Win_Indicator` bit for a window which copies from a segment of the source (the base).
+const VCD_SOURCE: u8 = 0x01;
+/// `Win_Indicator` bit for a window which copies from a segment of the earlier target windows.
+const VCD_TARGET: u8 = 0x02;
+/// `Win_Indicator` bit, an extension of xdelta3's, for a window with the Adler-32 checksum of its
+/// target, as 4 big-endian bytes after the lengths of the sections.
+const VCD_ADLER32: u8 = 0x04;
+
+// Instruction codes of the default code table, which only ever encode a single instruction.
+/// ADD with its size in the instruction section.
+const ADD_0: u8 = 1;
+/// ADD of size 1, followed by sizes up to `ADD_MAX_INLINE`.
+const ADD_1: u8 = 2;
+const ADD_MAX_INLINE: u64 = 17;
+/// COPY in `VCD_SELF` address mode, with its size in the instruction section.
+const COPY_SELF_0: u8 = 19;
+/// COPY in `VCD_SELF` address mode of size 4, followed by sizes up to `COPY_MAX_INLINE`.
+const COPY_SELF_4: u8 = 20;
+const COPY_MAX_INLINE: u64 = 18;
+
+/// How much output each window reconstructs at most. Decoders keep a whole target window in
+/// memory and refuse ones larger than some limit, which is 8 MiB by default for xdelta3.
+const MAX_WINDOW_SIZE: u64 = 1 << 23;
+
+/// Like [diff_with_options()], but writes the delta in the VCDIFF format of
+/// [RFC 3284](https://www.rfc-editor.org/rfc/rfc3284) rather than librsync's, for consumers
+/// such as xdelta3 which expect that instead. It can't be read by [apply()](crate::apply), but
+/// [apply_vcdiff()] can.
+///
+/// The delta uses the default code table without secondary compression, and copies only from
+/// the base, whose length needn't be known to decode it. The options for the extensions of the
+/// librsync format, like [checksum](DiffOptions::checksum), are ignored.
+/// [max_delta_len](DiffOptions::max_delta_len) limits the VCDIFF delta, but is only checked once
+/// the matching is done.
+///
+/// # Security
+/// The caveats for [diff()](crate::diff) apply here as well.
+pub fn diff_vcdiff(
+    signature: &impl BlockIndex,
+    data: &[u8],
+    mut out: impl Write,
+    options: &DiffOptions,
+) -> Result<(), DiffError> {
+    let mut rsync_options = options.clone();
+    rsync_options.max_delta_len = None;
+    rsync_options.self_copies = false;
+    rsync_options.checksum = false;
+    rsync_options.compress_literals = None;
+    let mut delta = Vec::new();
+    diff_with_options(signature, data, &mut delta, &rsync_options)?;
+    let mut limited = LimitedWriter {
+        inner: &mut out,
+        available: options.max_delta_len.unwrap_or(u64::MAX),
+    };
+    encode(&delta, &mut limited, MAX_WINDOW_SIZE)?;
+    out.flush()?;
+    Ok(())
+}
+
+struct LimitedWriter<W> {
+    inner: W,
+    available: u64,
+}
+
+impl<W: Write> LimitedWriter<W> {
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), DiffError> {
+        if buf.len() as u64 > self.available {
+            return Err(DiffError::OutputLimit);
+        }
+        self.available -= buf.len() as u64;
+        self.inner.write_all(buf)?;
+        Ok(())
+    }
+}
+
+/// A command of a librsync delta.
+#[derive(Copy, Clone, Debug)]
+enum Command<'a> {
+    Literal(&'a [u8]),
+    Copy { offset: u64, len: u64 },
+}
+
+/// Call `f` with each command of `delta`, which must be a well-formed librsync delta.
+fn for_each_command<'a>(
+    mut delta: &'a [u8],
+    mut f: impl FnMut(Command<'a>) -> Result<(), DiffError>,
+) -> Result<(), DiffError> {
+    fn take<'a>(delta: &mut &'a [u8], n: usize) -> &'a [u8] {
+        let (prefix, rest) = delta.split_at(n);
+        *delta = rest;
+        prefix
+    }
+    fn take_int(delta: &mut &[u8], n: usize) -> u64 {
+        take(delta, n)
+            .iter()
+            .fold(0, |val, &byte| val << 8 | byte as u64)
+    }
+    assert_eq!(take_int(&mut delta, 4), DELTA_MAGIC as u64);
+    loop {
+        let cmd = take(&mut delta, 1)[0];
+        let command = match cmd {
+            RS_OP_END => return Ok(()),
+            RS_OP_LITERAL_1..=RS_OP_LITERAL_64 => {
+                Command::Literal(take(&mut delta, (1 + cmd - RS_OP_LITERAL_1) as usize))
+            }
+            RS_OP_LITERAL_N1..=RS_OP_LITERAL_N8 => {
+                let len = take_int(&mut delta, 1 << (cmd - RS_OP_LITERAL_N1));
+                Command::Literal(take(&mut delta, len as usize))
+            }
+            RS_OP_COPY_N1_N1..=RS_OP_COPY_N8_N8 => {
+                let mode = cmd - RS_OP_COPY_N1_N1;
+                let offset = take_int(&mut delta, 1 << (mode / 4));
+                let len = take_int(&mut delta, 1 << (mode % 4));
+                Command::Copy { offset, len }
+            }
+            _ => unreachable!("diff() wrote command 0x{:02x}", cmd),
+        };
+        f(command)?;
+    }
+}
+
+/// The commands which make up a target window, and the span of the base they copy from.
+#[derive(Default)]
+struct Window<'a> {
+    commands: Vec<Command<'a>>,
+    target_len: u64,
+    source: Option<(u64, u64)>,
+}
+
+impl<'a> Window<'a> {
+    fn push(&mut self, command: Command<'a>) {
+        match command {
+            Command::Literal(literal) => self.target_len += literal.len() as u64,
+            Command::Copy { offset, len } => {
+                self.target_len += len;
+                let (start, end) = self.source.get_or_insert((offset, offset + len));
+                *start = (*start).min(offset);
+                *end = (*end).max(offset + len);
+            }
+        }
+        self.commands.push(command);
+    }
+
+    fn write_to(&self, out: &mut LimitedWriter<impl Write>) -> Result<(), DiffError> {
+        let (source_start, source_end) = self.source.unwrap_or((0, 0));
+        let mut data = Vec::new();
+        let mut instructions = Vec::new();
+        let mut addresses = Vec::new();
+        for command in &self.commands {
+            match *command {
+                Command::Literal(literal) => {
+                    let len = literal.len() as u64;
+                    if len <= ADD_MAX_INLINE {
+                        instructions.push(ADD_1 + (len - 1) as u8);
+                    } else {
+                        instructions.push(ADD_0);
+                        write_varint(len, &mut instructions);
+                    }
+                    data.extend_from_slice(literal);
+                }
+                Command::Copy { offset, len } => {
+                    if (4..=COPY_MAX_INLINE).contains(&len) {
+                        instructions.push(COPY_SELF_4 + (len - 4) as u8);
+                    } else {
+                        instructions.push(COPY_SELF_0);
+                        write_varint(len, &mut instructions);
+                    }
+                    write_varint(offset - source_start, &mut addresses);
+                }
+            }
+        }
+
+        let mut encoding = Vec::new();
+        write_varint(self.target_len, &mut encoding);
+        // Delta_Indicator: no section is compressed
+        encoding.push(0);
+        write_varint(data.len() as u64, &mut encoding);
+        write_varint(instructions.len() as u64, &mut encoding);
+        write_varint(addresses.len() as u64, &mut encoding);
+        let encoding_len = encoding.len() + data.len() + instructions.len() + addresses.len();
+
+        let mut header = Vec::new();
+        if self.source.is_some() {
+            header.push(VCD_SOURCE);
+            write_varint(source_end - source_start, &mut header);
+            write_varint(source_start, &mut header);
+        } else {
+            header.push(0);
+        }
+        write_varint(encoding_len as u64, &mut header);
+        out.write_all(&header)?;
+        out.write_all(&encoding)?;
+        out.write_all(&data)?;
+        out.write_all(&instructions)?;
+        out.write_all(&addresses)
+    }
+}
+
+/// Re-encode the librsync delta `delta` as VCDIFF, with windows of at most `max_window_size`
+/// bytes of output.
+fn encode(
+    delta: &[u8],
+    out: &mut LimitedWriter<impl Write>,
+    max_window_size: u64,
+) -> Result<(), DiffError> {
+    out.write_all(&VCDIFF_HEADER)?;
+    // Hdr_Indicator: no secondary compressor, code table or application data
+    out.write_all(&[0])?;
+    let mut window = Window::default();
+    for_each_command(delta, |mut command| loop {
+        if window.target_len == max_window_size {
+            window.write_to(out)?;
+            window = Window::default();
+        }
+        let room = max_window_size - window.target_len;
+        // split commands which don't fit in the current window
+        let rest = match command {
+            Command::Literal(literal) if literal.len() as u64 > room => {
+                let (head, tail) = literal.split_at(room as usize);
+                window.push(Command::Literal(head));
+                Command::Literal(tail)
+            }
+            Command::Copy { offset, len } if len > room => {
+                window.push(Command::Copy { offset, len: room });
+                Command::Copy {
+                    offset: offset + room,
+                    len: len - room,
+                }
+            }
+            _ => {
+                window.push(command);
+                return Ok(());
+            }
+        };
+        command = rest;
+    })?;
+    if window.target_len > 0 {
+        window.write_to(out)?;
+    }
+    Ok(())
+}
+
+/// Write `val` as a VCDIFF integer: big-endian base 128, with the top bit set on all but the last
+/// byte.
+fn write_varint(mut val: u64, out: &mut Vec<u8>) {
+    let mut buf = [0; 10];
+    let mut start = buf.len() - 1;
+    buf[start] = (val & 0x7f) as u8;
+    val >>= 7;
+    while val != 0 {
+        start -= 1;
+        buf[start] = 0x80 | (val & 0x7f) as u8;
+        val >>= 7;
+    }
+    out.extend_from_slice(&buf[start..]);
+}
+
+/// Apply `delta`, in the VCDIFF format of [RFC 3284](https://www.rfc-editor.org/rfc/rfc3284),
+/// to `base`, writing the result to `out`, and failing with [ApplyError::OutputLimit] if it would
+/// be longer than `limit` bytes.
+///
+/// Besides the deltas of [diff_vcdiff()], this reads those of other encoders such as xdelta3 and
+/// open-vcdiff, as long as they use the default code table and no secondary compression, and
+/// their windows copy from the base and their own target rather than from earlier windows
+/// (`VCD_TARGET`). The Adler-32 checksums which xdelta3 adds to its windows are checked, and a
+/// window which doesn't match fails with [ApplyError::ChecksumMismatch]. Otherwise, errors are
+/// reported as for librsync deltas where the problem is the same, e.g. a source segment outside
+/// of `base` with [ApplyError::CopyOutOfBounds], and with [ApplyError::InvalidVcdiff] and
+/// [ApplyError::UnsupportedVcdiff] where it isn't.
+///
+/// Each window is decoded in memory and then written to `out`, so this takes as much memory as
+/// the largest target window, which `limit` bounds as well. A window which fails to decode isn't
+/// written at all, but those before it are.
+pub fn apply_vcdiff(
+    base: &[u8],
+    delta: &[u8],
+    out: &mut impl Write,
+    limit: usize,
+) -> Result<(), ApplyError> {
+    let mut input = Reader {
+        data: delta,
+        offset: 0,
+    };
+    let magic = input.take(4, "magic")?;
+    if magic[..3] != VCDIFF_HEADER[..3] {
+        return Err(ApplyError::WrongMagic {
+            magic: u32::from_be_bytes(*array_ref![magic, 0, 4]),
+        });
+    }
+    if magic[3] != VCDIFF_HEADER[3] {
+        return Err(ApplyError::UnsupportedVcdiff {
+            feature: "format version",
+        });
+    }
+    let indicator = input.byte("header indicator")?;
+    if indicator & VCD_DECOMPRESS != 0 {
+        return Err(ApplyError::UnsupportedVcdiff {
+            feature: "secondary compression",
+        });
+    }
+    if indicator & VCD_CODETABLE != 0 {
+        return Err(ApplyError::UnsupportedVcdiff {
+            feature: "custom code table",
+        });
+    }
+    if indicator & !VCD_APPHEADER != 0 {
+        return Err(ApplyError::InvalidVcdiff {
+            reason: "unknown header indicator bits",
+            delta_offset: 4,
+        });
+    }
+    if indicator & VCD_APPHEADER != 0 {
+        let len = input.varint("application data length")?;
+        input.take(len, "application data")?;
+    }
+
+    let table = default_code_table();
+    let mut available = limit;
+    let mut target = Vec::new();
+    while !input.data.is_empty() {
+        decode_window(base, &mut input, &table, &mut target, available)?;
+        available -= target.len();
+        out.write_all(&target)?;
+    }
+    Ok(())
+}
+
+/// Reads a VCDIFF delta, or a section of one, keeping track of where it is in the whole delta.
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: u64,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: u64, reading: &'static str) -> Result<&'a [u8], ApplyError> {
+        if len > self.data.len() as u64 {
+            return Err(ApplyError::UnexpectedEof {
+                reading,
+                expected: len.min(usize::MAX as u64) as usize,
+                available: self.data.len(),
+                delta_offset: self.offset,
+            });
+        }
+        let (prefix, rest) = self.data.split_at(len as usize);
+        self.data = rest;
+        self.offset += len;
+        Ok(prefix)
+    }
+
+    fn byte(&mut self, reading: &'static str) -> Result<u8, ApplyError> {
+        Ok(self.take(1, reading)?[0])
+    }
+
+    /// Read a VCDIFF integer, as written by [write_varint()].
+    fn varint(&mut self, reading: &'static str) -> Result<u64, ApplyError> {
+        let start = self.offset;
+        let mut val = 0u64;
+        loop {
+            let byte = self.byte(reading)?;
+            if val >> 57 != 0 {
+                return Err(ApplyError::InvalidVcdiff {
+                    reason: "integer doesn't fit in 64 bits",
+                    delta_offset: start,
+                });
+            }
+            val = val << 7 | (byte & 0x7f) as u64;
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+    }
+
+    /// Split off the next `len` bytes as a reader of their own.
+    fn section(&mut self, len: u64, reading: &'static str) -> Result<Reader<'a>, ApplyError> {
+        let offset = self.offset;
+        Ok(Reader {
+            data: self.take(len, reading)?,
+            offset,
+        })
+    }
+}
+
+/// The types of instructions, with the address mode of a COPY.
+#[derive(Copy, Clone, Debug)]
+enum Inst {
+    Noop,
+    Add,
+    Run,
+    Copy(u8),
+}
+
+/// An entry of a code table: a pair of instructions, each with its size, or 0 if the size
+/// follows in the instruction section.
+type Code = [(Inst, u8); 2];
+
+/// The default code table of RFC 3284, section 5.6.
+fn default_code_table() -> [Code; 256] {
+    let mut table = [[(Inst::Noop, 0); 2]; 256];
+    let mut codes = table.iter_mut();
+    let mut push = |first, second| *codes.next().expect("256 codes") = [first, second];
+    let noop = (Inst::Noop, 0);
+    push((Inst::Run, 0), noop);
+    for size in 0..=17 {
+        push((Inst::Add, size), noop);
+    }
+    for mode in 0..9 {
+        push((Inst::Copy(mode), 0), noop);
+        for size in 4..=18 {
+            push((Inst::Copy(mode), size), noop);
+        }
+    }
+    for mode in 0..6 {
+        for add in 1..=4 {
+            for copy in 4..=6 {
+                push((Inst::Add, add), (Inst::Copy(mode), copy));
+            }
+        }
+    }
+    for mode in 6..9 {
+        for add in 1..=4 {
+            push((Inst::Add, add), (Inst::Copy(mode), 4));
+        }
+    }
+    for mode in 0..9 {
+        push((Inst::Copy(mode), 4), (Inst::Add, 1));
+    }
+    table
+}
+
+/// The sizes of the caches of recent addresses for the default code table.
+const NEAR_CACHE: usize = 4;
+const SAME_CACHE: usize = 3 * 256;
+
+/// The caches of recent COPY addresses of RFC 3284, section 5.1, which the address modes other
+/// than `VCD_SELF` and `VCD_HERE` encode addresses relative to.
+struct AddressCache {
+    near: [u64; NEAR_CACHE],
+    next_slot: usize,
+    same: [u64; SAME_CACHE],
+}
+
+impl AddressCache {
+    fn new() -> Self {
+        AddressCache {
+            near: [0; NEAR_CACHE],
+            next_slot: 0,
+            same: [0; SAME_CACHE],
+        }
+    }
+
+    /// Read the address of a COPY in `mode` from `addresses`, where the copy is at `here`. An
+    /// address which overflows comes out as `u64::MAX`, which is always out of bounds.
+    fn decode(
+        &mut self,
+        mode: u8,
+        here: u64,
+        addresses: &mut Reader<'_>,
+    ) -> Result<u64, ApplyError> {
+        let mode = mode as usize;
+        let address = match mode {
+            0 => addresses.varint("copy address")?,
+            1 => here
+                .checked_sub(addresses.varint("copy address")?)
+                .unwrap_or(u64::MAX),
+            _ if mode < 2 + NEAR_CACHE => {
+                self.near[mode - 2].saturating_add(addresses.varint("copy address")?)
+            }
+            _ => {
+                let byte = addresses.byte("copy address")?;
+                self.same[(mode - 2 - NEAR_CACHE) * 256 + byte as usize]
+            }
+        };
+        self.near[self.next_slot] = address;
+        self.next_slot = (self.next_slot + 1) % NEAR_CACHE;
+        self.same[(address % SAME_CACHE as u64) as usize] = address;
+        Ok(address)
+    }
+}
+
+/// Decode the window at the start of `input` into `target`, failing if it is longer than
+/// `limit` bytes.
+fn decode_window(
+    base: &[u8],
+    input: &mut Reader<'_>,
+    table: &[Code; 256],
+    target: &mut Vec<u8>,
+    limit: usize,
+) -> Result<(), ApplyError> {
+    let window_start = input.offset;
+    let indicator = input.byte("window indicator")?;
+    if indicator & VCD_TARGET != 0 {
+        return Err(ApplyError::UnsupportedVcdiff {
+            feature: "copies from earlier windows",
+        });
+    }
+    if indicator & !(VCD_SOURCE | VCD_ADLER32) != 0 {
+        return Err(ApplyError::InvalidVcdiff {
+            reason: "unknown window indicator bits",
+            delta_offset: window_start,
+        });
+    }
+    let source = if indicator & VCD_SOURCE != 0 {
+        let len = input.varint("source segment length")?;
+        let start = input.varint("source segment position")?;
+        match start.checked_add(len) {
+            Some(end) if end <= base.len() as u64 => &base[start as usize..end as usize],
+            _ => {
+                return Err(ApplyError::CopyOutOfBounds {
+                    offset: start,
+                    len,
+                    data_len: base.len(),
+                    command: indicator,
+                    delta_offset: window_start,
+                })
+            }
+        }
+    } else {
+        &[]
+    };
+    let encoding_len = input.varint("delta encoding length")?;
+    let mut encoding = input.section(encoding_len, "delta encoding")?;
+    let target_len = encoding.varint("target window length")?;
+    if target_len > limit as u64 {
+        return Err(ApplyError::OutputLimit {
+            what: "window",
+            wanted: target_len.min(usize::MAX as u64) as usize,
+            available: limit,
+        });
+    }
+    let indicator_offset = encoding.offset;
+    match encoding.byte("delta indicator")? {
+        0 => {}
+        compressed if compressed & !0x07 == 0 => {
+            return Err(ApplyError::UnsupportedVcdiff {
+                feature: "secondary compression",
+            })
+        }
+        _ => {
+            return Err(ApplyError::InvalidVcdiff {
+                reason: "unknown delta indicator bits",
+                delta_offset: indicator_offset,
+            })
+        }
+    }
+    let data_len = encoding.varint("data section length")?;
+    let instructions_len = encoding.varint("instructions section length")?;
+    let addresses_len = encoding.varint("addresses section length")?;
+    let checksum = match indicator & VCD_ADLER32 {
+        0 => None,
+        _ => Some(u32::from_be_bytes(*array_ref![
+            encoding.take(4, "checksum")?,
+            0,
+            4
+        ])),
+    };
+    let mut data = encoding.section(data_len, "data section")?;
+    let mut instructions = encoding.section(instructions_len, "instructions section")?;
+    let mut addresses = encoding.section(addresses_len, "addresses section")?;
+    if !encoding.data.is_empty() {
+        return Err(ApplyError::InvalidVcdiff {
+            reason: "delta encoding is longer than its sections",
+            delta_offset: encoding.offset,
+        });
+    }
+
+    target.clear();
+    target.reserve(target_len.min(MAX_WINDOW_SIZE) as usize);
+    let source_len = source.len() as u64;
+    let mut cache = AddressCache::new();
+    while !instructions.data.is_empty() {
+        let start = instructions.offset;
+        let code = instructions.byte("instruction")?;
+        for &(inst, size) in &table[code as usize] {
+            let size = match (inst, size) {
+                (Inst::Noop, _) => continue,
+                (_, 0) => instructions.varint("instruction size")?,
+                (_, size) => size as u64,
+            };
+            if size > target_len - target.len() as u64 {
+                return Err(ApplyError::InvalidVcdiff {
+                    reason: "instructions are longer than the target window",
+                    delta_offset: start,
+                });
+            }
+            match inst {
+                Inst::Add => target.extend_from_slice(data.take(size, "added data")?),
+                Inst::Run => {
+                    let byte = data.byte("run byte")?;
+                    target.resize(target.len() + size as usize, byte);
+                }
+                Inst::Copy(mode) => {
+                    // addresses are into the source segment followed by the target window
+                    let here = source_len + target.len() as u64;
+                    let mut address = cache.decode(mode, here, &mut addresses)?;
+                    if address >= here {
+                        return Err(ApplyError::SelfCopyOutOfBounds {
+                            offset: address,
+                            len: size,
+                            written: here,
+                            command: code,
+                            delta_offset: start,
+                        });
+                    }
+                    let mut size = size as usize;
+                    if address < source_len {
+                        let n = ((source_len - address) as usize).min(size);
+                        target.extend_from_slice(&source[address as usize..address as usize + n]);
+                        address += n as u64;
+                        size -= n;
+                    }
+                    // the rest comes from the target, and may overlap what it writes
+                    let mut from = address.saturating_sub(source_len) as usize;
+                    while size > 0 {
+                        let n = (target.len() - from).min(size);
+                        target.extend_from_within(from..from + n);
+                        from += n;
+                        size -= n;
+                    }
+                }
+                Inst::Noop => unreachable!("skipped above"),
+            }
+        }
+    }
+    if target.len() as u64 != target_len {
+        return Err(ApplyError::InvalidVcdiff {
+            reason: "instructions are shorter than the target window",
+            delta_offset: instructions.offset,
+        });
+    }
+    if !data.data.is_empty() || !addresses.data.is_empty() {
+        return Err(ApplyError::InvalidVcdiff {
+            reason: "data or addresses are left over after the instructions",
+            delta_offset: if data.data.is_empty() {
+                addresses.offset
+            } else {
+                data.offset
+            },
+        });
+    }
+    if let Some(checksum) = checksum {
+        if adler32(target) != checksum {
+            return Err(ApplyError::ChecksumMismatch);
+        }
+    }
+    Ok(())
+}
+
+/// The Adler-32 checksum of `data`, as in zlib.
+fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // the most bytes which can be summed before `b` could overflow
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        adler32, apply_vcdiff, diff_vcdiff, encode, LimitedWriter, VCDIFF_HEADER, VCD_ADLER32,
+        VCD_APPHEADER, VCD_SOURCE,
+    };
+    use crate::{diff, ApplyError, DiffError, DiffOptions, Signature, SignatureOptions};
+    use quickcheck_macros::quickcheck;
+
+    /// Decode the subset of VCDIFF which [encode()] writes, returning the output and how many
+    /// windows it was in.
+    fn decode(base: &[u8], mut delta: &[u8]) -> (Vec<u8>, usize) {
+        fn take<'a>(delta: &mut &'a [u8], n: usize) -> &'a [u8] {
+            let (prefix, rest) = delta.split_at(n);
+            *delta = rest;
+            prefix
+        }
+        fn varint(delta: &mut &[u8]) -> usize {
+            let mut val = 0;
+            loop {
+                let byte = take(delta, 1)[0];
+                val = val << 7 | (byte & 0x7f) as usize;
+                if byte & 0x80 == 0 {
+                    return val;
+                }
+            }
+        }
+        assert_eq!(take(&mut delta, 5), [&VCDIFF_HEADER[..], &[0]].concat());
+        let mut out = Vec::new();
+        let mut windows = 0;
+        while !delta.is_empty() {
+            windows += 1;
+            let source = if take(&mut delta, 1)[0] == VCD_SOURCE {
+                let len = varint(&mut delta);
+                let start = varint(&mut delta);
+                &base[start..start + len]
+            } else {
+                &[]
+            };
+            let encoding_len = varint(&mut delta);
+            let mut encoding = take(&mut delta, encoding_len);
+            let target_len = varint(&mut encoding);
+            assert_eq!(take(&mut encoding, 1), [0]);
+            let data_len = varint(&mut encoding);
+            let instructions_len = varint(&mut encoding);
+            let addresses_len = varint(&mut encoding);
+            let mut data = take(&mut encoding, data_len);
+            let mut instructions = take(&mut encoding, instructions_len);
+            let mut addresses = take(&mut encoding, addresses_len);
+            assert!(encoding.is_empty());
+            let window_start = out.len();
+            while !instructions.is_empty() {
+                match take(&mut instructions, 1)[0] {
+                    code @ 1..=18 => {
+                        let len = match code {
+                            1 => varint(&mut instructions),
+                            _ => code as usize - 1,
+                        };
+                        out.extend_from_slice(take(&mut data, len));
+                    }
+                    code @ 19..=34 => {
+                        let len = match code {
+                            19 => varint(&mut instructions),
+                            _ => code as usize - 16,
+                        };
+                        let address = varint(&mut addresses);
+                        out.extend_from_slice(&source[address..address + len]);
+                    }
+                    code => panic!("unexpected instruction {}", code),
+                }
+            }
+            assert!(data.is_empty() && addresses.is_empty());
+            assert_eq!(out.len() - window_start, target_len);
+        }
+        (out, windows)
+    }
+
+    fn vcdiff(base: &[u8], data: &[u8], block_size: u32, max_window_size: u64) -> Vec<u8> {
+        let signature = Signature::calculate(
+            base,
+            SignatureOptions {
+                block_size,
+                crypto_hash_size: 8,
+                ..Default::default()
+            },
+        );
+        let mut delta = Vec::new();
+        diff(&signature.index(), data, &mut delta).unwrap();
+        let mut out = Vec::new();
+        let mut limited = LimitedWriter {
+            inner: &mut out,
+            available: u64::MAX,
+        };
+        encode(&delta, &mut limited, max_window_size).unwrap();
+        out
+    }
+
+    #[quickcheck]
+    fn roundtrip(base: Vec<u8>, data: Vec<u8>, block_size: u8, max_window_size: u8) -> bool {
+        let block_size = block_size as u32 % 8 + 1;
+        let max_window_size = max_window_size as u64 % 32 + 1;
+        let data = [&data[..], &base[..], &data[..]].concat();
+        let delta = vcdiff(&base, &data, block_size, max_window_size);
+        let mut out = Vec::new();
+        apply_vcdiff(&base, &delta, &mut out, usize::MAX).unwrap();
+        decode(&base, &delta).0 == data && out == data
+    }
+
+    /// A delta with the instructions and address modes which [encode()] doesn't use, in a window
+    /// with a checksum.
+    fn handwritten(base: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let expected = b"cdefxyz-----------hijcdef!abcd".to_vec();
+        let data = b"xyz-!";
+        let instructions = [
+            20, // COPY 4 from 2, in VCD_SELF mode
+            4,  // ADD 3
+            0, 5,  // RUN 5
+            38, // COPY 6 from 3 back, in VCD_HERE mode, overlapping what it writes
+            51, 3,   // COPY 3 from 5 after the first near address
+            116, // COPY 4 from the same address as the first copy
+            163, // ADD 1, then COPY 4 from 0
+        ];
+        let addresses = [2, 3, 5, 2, 0];
+        let mut encoding = vec![expected.len() as u8, 0, 5, 9, 5];
+        encoding.extend_from_slice(&adler32(&expected).to_be_bytes());
+        encoding.extend_from_slice(data);
+        encoding.extend_from_slice(&instructions);
+        encoding.extend_from_slice(&addresses);
+        let mut delta = VCDIFF_HEADER.to_vec();
+        delta.extend_from_slice(&[VCD_APPHEADER, 2, b'h', b'i']);
+        delta.extend_from_slice(&[VCD_SOURCE | VCD_ADLER32, base.len() as u8, 0]);
+        delta.push(encoding.len() as u8);
+        delta.extend_from_slice(&encoding);
+        (delta, expected)
+    }
+
+    #[test]
+    fn apply_handwritten() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        let base = b"abcdefghij";
+        let (delta, expected) = handwritten(base);
+        let mut out = Vec::new();
+        apply_vcdiff(base, &delta, &mut out, usize::MAX).unwrap();
+        assert_eq!(out, expected);
+
+        let mut corrupt = delta.clone();
+        *corrupt.last_mut().unwrap() = 1;
+        assert!(matches!(
+            apply_vcdiff(base, &corrupt, &mut Vec::new(), usize::MAX),
+            Err(ApplyError::ChecksumMismatch)
+        ));
+        assert!(matches!(
+            apply_vcdiff(base, &delta[..delta.len() - 1], &mut Vec::new(), usize::MAX),
+            Err(ApplyError::UnexpectedEof {
+                reading: "delta encoding",
+                ..
+            })
+        ));
+        assert!(matches!(
+            apply_vcdiff(base, &delta, &mut Vec::new(), expected.len() - 1),
+            Err(ApplyError::OutputLimit { .. })
+        ));
+        assert!(matches!(
+            apply_vcdiff(&base[..9], &delta, &mut Vec::new(), usize::MAX),
+            Err(ApplyError::CopyOutOfBounds { .. })
+        ));
+        // the second copy from one byte further back than the start
+        let mut corrupt = delta.clone();
+        let addresses = corrupt.len() - 5;
+        corrupt[addresses + 1] = 23;
+        assert!(matches!(
+            apply_vcdiff(base, &corrupt, &mut Vec::new(), usize::MAX),
+            Err(ApplyError::SelfCopyOutOfBounds { offset, written: 22, .. }) if offset == u64::MAX
+        ));
+        let mut compressed = delta.clone();
+        compressed[4] |= 0x01;
+        assert!(matches!(
+            apply_vcdiff(base, &compressed, &mut Vec::new(), usize::MAX),
+            Err(ApplyError::UnsupportedVcdiff { .. })
+        ));
+    }
+
+    #[test]
+    fn windows() {
+        let base: Vec<u8> = (0..200u32).map(|i| (i * 7 % 251) as u8).collect();
+        let data = [&base[100..], b"new data", &base[..100]].concat();
+        let delta = vcdiff(&base, &data, 10, 64);
+        assert_eq!(decode(&base, &delta), (data.clone(), 4));
+        assert!(delta.len() < data.len() / 2);
+    }
+
+    #[test]
+    fn max_delta_len() {
+        let base = vec![7; 100];
+        let signature = Signature::calculate(&base, SignatureOptions::default());
+        let data = [&base[..], &[8; 100][..]].concat();
+        let mut delta = Vec::new();
+        diff_vcdiff(
+            &signature.index(),
+            &data,
+            &mut delta,
+            &DiffOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(decode(&base, &delta).0, data);
+        let options = DiffOptions {
+            max_delta_len: Some(delta.len() as u64 - 1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            diff_vcdiff(&signature.index(), &data, Vec::new(), &options),
+            Err(DiffError::OutputLimit)
+        ));
+    }
+}
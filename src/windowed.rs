@@ -0,0 +1,137 @@
+//! Diffing inputs too large to index or hold in memory, a window at a time.
+
+use std::io::{Read, Write};
+use std::num::NonZeroU64;
+
+use crate::diff::{diff_commands, DeltaWriter, DiffCommand, DiffError, DiffOptions};
+use crate::signature::SignatureRef;
+
+/// Like [diff_from_reader()](crate::diff_from_reader), but in bounded memory however large the
+/// base and `data` are, for data which mostly stays in place, like disk images or block devices.
+///
+/// `data` is read `window_size` bytes at a time, rounded up to a multiple of the block size, and
+/// each window is matched against an index of just the blocks of `signature` which are at most
+/// `window_size` bytes away from it. So besides the signature itself, which can be mapped into
+/// memory with [Signature::deserialize_ref](crate::Signature::deserialize_ref), this needs
+/// about one window of data plus the index of three windows' worth of blocks. Data which moved
+/// further than that from where it was in the base, or which straddles two windows, is sent as
+/// literals instead.
+///
+/// Copies are merged across windows, so unchanged data still makes for a small delta. Since
+/// the delta is written a window at a time, the options which only affect the serialized format
+/// are ignored, as with [diff_commands()](crate::diff_commands).
+///
+/// # Security
+/// The caveats for [diff()](crate::diff) apply here as well.
+pub fn diff_windowed(
+    signature: SignatureRef<'_>,
+    mut data: impl Read,
+    out: impl Write,
+    window_size: NonZeroU64,
+    options: &DiffOptions,
+) -> Result<(), DiffError> {
+    signature
+        .validate()
+        .map_err(|_| DiffError::InvalidSignature)?;
+    let block_size = signature.block_size as u64;
+    let window_size = window_size.get().div_ceil(block_size) * block_size;
+    let mut writer = DeltaWriter::new(out)?;
+    let mut window = Vec::new();
+    let mut start = 0u64;
+    loop {
+        window.clear();
+        (&mut data).take(window_size).read_to_end(&mut window)?;
+        if window.is_empty() {
+            break;
+        }
+        // a multiple of the block size, so the sliced signature starts right there
+        let sliced_from = start.saturating_sub(window_size);
+        let sliced =
+            signature.slice(sliced_from..start.saturating_add(window_size.saturating_mul(2)));
+        diff_commands(&sliced.index(), &window, options, |command| match command {
+            DiffCommand::Copy { offset, len } => writer.copy(sliced_from + offset, len),
+            DiffCommand::Literal(literal) => writer.literal(literal),
+        })?;
+        if (window.len() as u64) < window_size {
+            break;
+        }
+        start += window_size;
+    }
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::diff_windowed;
+    use crate::{apply, DiffOptions, Signature, SignatureOptions, SignatureRef};
+    use rand::Rng;
+    use std::num::NonZeroU64;
+
+    #[test]
+    fn windows() {
+        let mut rng = rand::thread_rng();
+        let mut base = vec![0; 100_000];
+        rng.fill(&mut base[..]);
+        let mut data = base.clone();
+        // changed in place
+        rng.fill(&mut data[5000..5100]);
+        // moved a little, within reach
+        data.copy_within(40_000..44_000, 41_000);
+        // moved too far to be found
+        data.copy_within(90_000..94_000, 10_000);
+        data.extend_from_slice(b"appended");
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions {
+                block_size: 100,
+                crypto_hash_size: 8,
+                ..Default::default()
+            },
+        );
+        let window_size = NonZeroU64::new(9_950).unwrap();
+        let mut delta = vec![];
+        diff_windowed(
+            SignatureRef::from(&signature),
+            &data[..],
+            &mut delta,
+            window_size,
+            &DiffOptions::default(),
+        )
+        .unwrap();
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).unwrap();
+        assert_eq!(out, data);
+        // the far move is sent as a literal, but everything else is copied
+        assert!(
+            delta.len() > 4000 && delta.len() < 4000 + 1000,
+            "{}",
+            delta.len()
+        );
+
+        // an unchanged base is a single copy
+        let mut delta = vec![];
+        diff_windowed(
+            SignatureRef::from(&signature),
+            &base[..],
+            &mut delta,
+            window_size,
+            &DiffOptions::default(),
+        )
+        .unwrap();
+        assert!(delta.len() < 20, "{}", delta.len());
+
+        // the output is flushed
+        let mut buffered = std::io::BufWriter::new(vec![]);
+        diff_windowed(
+            SignatureRef::from(&signature),
+            &base[..],
+            &mut buffered,
+            window_size,
+            &DiffOptions::default(),
+        )
+        .unwrap();
+        assert!(buffered.buffer().is_empty());
+        assert_eq!(buffered.get_ref(), &delta);
+    }
+}